@@ -1 +1,311 @@
 ﻿//! BPM detection implementation
+//!
+//! Tempo is estimated in three steps:
+//! 1. An onset strength envelope is computed from the spectral flux of short,
+//!    Hann-windowed frames.
+//! 2. The envelope's autocorrelation is evaluated through a comb of lags at
+//!    every candidate tempo, weighted by a log-normal prior so that ambiguous
+//!    material resolves to the common metrical level.
+//! 3. The beat phase is found by folding the envelope at the winning period.
+
+use crate::{
+    audio::analysis::fft::{
+        magnitude_spectrum, plan_forward, plan_inverse, Complex, LogFrequencyBands, Stft, StftConfig, WindowType,
+    },
+    error::types::AudioError,
+};
+
+/// Analysis frame length for the onset envelope.
+const FRAME_SIZE: usize = 1024;
+
+/// Hop between analysis frames.
+const HOP_SIZE: usize = 256;
+
+/// Compression applied to magnitudes before taking the flux.
+const LOG_COMPRESSION: f32 = 1.0;
+
+/// Resolution of the frequency bands the flux is summed over.
+const BANDS_PER_OCTAVE: f32 = 3.0;
+
+/// Lower edge of the lowest band, in Hz.
+const BAND_LOW_HZ: f32 = 40.0;
+
+/// Length of the moving average removed from the onset envelope, in seconds.
+const DETREND_SECONDS: f32 = 0.4;
+
+/// Tempo the prior is centred on when it lies within the search range.
+const PRIOR_CENTER_BPM: f64 = 120.0;
+
+/// Width of the tempo prior, in octaves.
+const PRIOR_OCTAVES: f64 = 1.0;
+
+/// Weights of the comb teeth at 1, 2, 3 and 4 beat periods.
+const COMB_WEIGHTS: [f64; 4] = [1.0, 0.5, 0.33, 0.25];
+
+/// Resolution of the tempo search.
+const BPM_STEP: f64 = 0.05;
+
+/// Shortest signal accepted, in seconds.
+const MIN_SECONDS: f32 = 2.0;
+
+/// Result of a tempo analysis.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BpmEstimate {
+    /// Estimated tempo in beats per minute
+    pub bpm: f32,
+    /// How clearly the winning tempo stands out, from 0.0 to 1.0
+    pub confidence: f32,
+    /// Sample position of the first beat
+    pub beat_offset: usize,
+}
+
+/// Tempo estimator for recorded audio.
+#[derive(Debug, Clone)]
+pub struct BpmDetector {
+    sample_rate: u32,
+    min_bpm: f32,
+    max_bpm: f32,
+}
+
+impl BpmDetector {
+    /// Creates a new `BpmDetector` searching 60 to 200 BPM.
+    ///
+    /// # Arguments
+    /// * `sample_rate` - The sample rate of the audio to analyse.
+    ///
+    /// # Returns
+    /// * `BpmDetector` - A new detector.
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            min_bpm: 60.0,
+            max_bpm: 200.0,
+        }
+    }
+
+    /// Sets the tempo range searched.
+    pub fn set_range(&mut self, min_bpm: f32, max_bpm: f32) {
+        self.min_bpm = min_bpm.min(max_bpm).max(1.0);
+        self.max_bpm = max_bpm.max(min_bpm);
+    }
+
+    /// Gets the tempo range searched.
+    pub fn range(&self) -> (f32, f32) {
+        (self.min_bpm, self.max_bpm)
+    }
+
+    /// Estimates the tempo of a mono signal.
+    ///
+    /// # Arguments
+    /// * `samples` - The audio to analyse.
+    ///
+    /// # Returns
+    /// * `Result<f32, AudioError>` - The tempo in BPM, or an error if the signal is too short or silent.
+    pub fn detect(&self, samples: &[f32]) -> Result<f32, AudioError> {
+        self.analyze(samples).map(|estimate| estimate.bpm)
+    }
+
+    /// Estimates tempo, confidence and beat phase of a mono signal.
+    ///
+    /// # Arguments
+    /// * `samples` - The audio to analyse.
+    ///
+    /// # Returns
+    /// * `Result<BpmEstimate, AudioError>` - The estimate, or an error if the signal is too short or silent.
+    pub fn analyze(&self, samples: &[f32]) -> Result<BpmEstimate, AudioError> {
+        if samples.len() < (MIN_SECONDS * self.sample_rate as f32) as usize {
+            return Err(AudioError::BufferError(format!(
+                "Tempo detection needs at least {} seconds of audio",
+                MIN_SECONDS
+            )));
+        }
+
+        let envelope = self.onset_strength(samples)?;
+        let acf = autocorrelate(&envelope)?;
+        if acf[0] <= f32::EPSILON {
+            return Err(AudioError::BufferError("No onsets found".into()));
+        }
+
+        // A range that excludes the usual prior centre gets a prior centred on the range instead
+        let (min_bpm, max_bpm) = (self.min_bpm as f64, self.max_bpm as f64);
+        let prior_center = if (min_bpm..=max_bpm).contains(&PRIOR_CENTER_BPM) {
+            PRIOR_CENTER_BPM
+        } else {
+            (min_bpm * max_bpm).sqrt()
+        };
+
+        let frame_rate = self.sample_rate as f64 / HOP_SIZE as f64;
+        let mut scores = Vec::new();
+        let mut bpm = min_bpm;
+        while bpm <= max_bpm {
+            let period = frame_rate * 60.0 / bpm;
+            let comb: f64 = COMB_WEIGHTS
+                .iter()
+                .enumerate()
+                .map(|(k, weight)| weight * interpolate(&acf, period * (k + 1) as f64) as f64)
+                .sum();
+            let prior = (-0.5 * ((bpm / prior_center).log2() / PRIOR_OCTAVES).powi(2)).exp();
+            scores.push((bpm, comb.max(0.0) * prior));
+            bpm += BPM_STEP;
+        }
+
+        let best = scores
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1 .1.total_cmp(&b.1 .1))
+            .map(|(i, _)| i)
+            .ok_or_else(|| AudioError::BufferError("Empty tempo range".into()))?;
+        let bpm = refine_peak(&scores, best);
+
+        let mean = scores.iter().map(|s| s.1).sum::<f64>() / scores.len() as f64;
+        let peak = scores[best].1;
+        let confidence = if peak > 0.0 { (1.0 - mean / peak).clamp(0.0, 1.0) } else { 0.0 };
+
+        let period = frame_rate * 60.0 / bpm;
+        let beat_offset = beat_phase(&envelope, period) * HOP_SIZE as f64;
+
+        Ok(BpmEstimate {
+            bpm: bpm as f32,
+            confidence: confidence as f32,
+            beat_offset: beat_offset.round() as usize,
+        })
+    }
+
+    /// Computes the onset strength envelope, one value per hop.
+    ///
+    /// # Arguments
+    /// * `samples` - The audio to analyse.
+    ///
+    /// # Returns
+    /// * `Result<Vec<f32>, AudioError>` - Half-wave rectified, detrended spectral flux.
+    pub fn onset_strength(&self, samples: &[f32]) -> Result<Vec<f32>, AudioError> {
+        let mut stft = Stft::new(StftConfig {
+            frame_size: FRAME_SIZE,
+            hop_size: HOP_SIZE,
+            window: WindowType::Hann,
+        })?;
+        let spectrogram = stft.analyze(samples)?;
+
+        // Summing flux per log-spaced band rather than per bin keeps broadband noise
+        // such as hi-hats from outweighing the low-frequency hits that carry the beat
+        let bands = LogFrequencyBands::new(FRAME_SIZE, self.sample_rate, BAND_LOW_HZ, f32::MAX, BANDS_PER_OCTAVE);
+        let mut magnitudes = vec![0.0f32; FRAME_SIZE / 2 + 1];
+        let mut energies = vec![0.0f32; bands.len()];
+        let mut previous = vec![0.0f32; bands.len()];
+
+        let mut flux = Vec::with_capacity(spectrogram.num_frames());
+        for (index, spectrum) in spectrogram.frames.iter().enumerate() {
+            magnitude_spectrum(spectrum, &mut magnitudes);
+            bands.apply(&magnitudes, &mut energies);
+            let sum: f32 = energies
+                .iter()
+                .zip(previous.iter_mut())
+                .map(|(&energy, previous)| {
+                    let energy = (1.0 + LOG_COMPRESSION * energy).ln();
+                    let rise = (energy - *previous).max(0.0);
+                    *previous = energy;
+                    rise
+                })
+                .sum();
+            flux.push(if index == 0 { 0.0 } else { sum });
+        }
+
+        let radius = ((DETREND_SECONDS * self.sample_rate as f32 / HOP_SIZE as f32) as usize / 2).max(1);
+        Ok(detrend(&flux, radius))
+    }
+}
+
+/// Subtracts a centred moving average and rectifies the result.
+fn detrend(values: &[f32], radius: usize) -> Vec<f32> {
+    let mut prefix = Vec::with_capacity(values.len() + 1);
+    prefix.push(0.0f64);
+    for &value in values {
+        prefix.push(prefix[prefix.len() - 1] + value as f64);
+    }
+    (0..values.len())
+        .map(|i| {
+            let start = i.saturating_sub(radius);
+            let end = (i + radius + 1).min(values.len());
+            let mean = (prefix[end] - prefix[start]) / (end - start) as f64;
+            (values[i] - mean as f32).max(0.0)
+        })
+        .collect()
+}
+
+/// Autocorrelation through the power spectrum, normalized so that lag zero is 1.0.
+fn autocorrelate(values: &[f32]) -> Result<Vec<f32>, AudioError> {
+    let len = (values.len() * 2).next_power_of_two();
+    let forward = plan_forward(len);
+    let inverse = plan_inverse(len);
+
+    let mut padded = forward.make_input_vec();
+    padded[..values.len()].copy_from_slice(values);
+    let mut spectrum = forward.make_output_vec();
+    forward
+        .process(&mut padded, &mut spectrum)
+        .map_err(|e| AudioError::BufferError(e.to_string()))?;
+    for bin in spectrum.iter_mut() {
+        *bin = Complex::new(bin.norm_sqr(), 0.0);
+    }
+    let mut acf = inverse.make_output_vec();
+    inverse
+        .process(&mut spectrum, &mut acf)
+        .map_err(|e| AudioError::BufferError(e.to_string()))?;
+
+    acf.truncate(values.len());
+    let zero = acf[0];
+    if zero > 0.0 {
+        acf.iter_mut().for_each(|value| *value /= zero);
+    }
+    Ok(acf)
+}
+
+/// Reads `values` at a fractional index with linear interpolation.
+fn interpolate(values: &[f32], position: f64) -> f32 {
+    let index = position.floor() as usize;
+    if index + 1 >= values.len() {
+        return 0.0;
+    }
+    let frac = (position - index as f64) as f32;
+    values[index] + (values[index + 1] - values[index]) * frac
+}
+
+/// Parabolic interpolation around the best scoring tempo.
+fn refine_peak(scores: &[(f64, f64)], best: usize) -> f64 {
+    if best == 0 || best + 1 >= scores.len() {
+        return scores[best].0;
+    }
+    let (a, b, c) = (scores[best - 1].1, scores[best].1, scores[best + 1].1);
+    let denominator = a - 2.0 * b + c;
+    let offset = if denominator.abs() > f64::EPSILON { 0.5 * (a - c) / denominator } else { 0.0 };
+    scores[best].0 + offset.clamp(-0.5, 0.5) * BPM_STEP
+}
+
+/// Finds the offset, in frames, at which a pulse train of `period` best matches the envelope.
+fn beat_phase(envelope: &[f32], period: f64) -> f64 {
+    let steps = (period.ceil() as usize).max(1);
+    (0..steps)
+        .map(|step| {
+            let phase = step as f64 * period / steps as f64;
+            let mut sum = 0.0;
+            let mut position = phase;
+            while position < envelope.len() as f64 {
+                sum += interpolate(envelope, position) as f64;
+                position += period;
+            }
+            (phase, sum)
+        })
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map_or(0.0, |(phase, _)| phase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_too_short() {
+        let detector = BpmDetector::new(44100);
+        assert!(detector.detect(&[0.0; 4410]).is_err());
+    }
+}
@@ -1 +1,652 @@
 ﻿//! FFT utilities
+//!
+//! Shared spectral analysis building blocks: window functions, a plan cache
+//! around `realfft`, offline STFT/ISTFT on whole signals or `AudioBuffer`s,
+//! a streaming STFT for the audio thread and log-frequency band grouping.
+//!
+//! Frames are centred on multiples of the hop size, so frame `i` describes the
+//! signal around sample `i * hop_size`. Resynthesis uses weighted overlap-add
+//! and reconstructs the input exactly when the spectra are left untouched.
+
+use std::{
+    collections::HashMap,
+    f32::consts::PI,
+    sync::{Arc, OnceLock},
+};
+use parking_lot::Mutex;
+use realfft::{ComplexToReal, RealFftPlanner, RealToComplex};
+pub use realfft::num_complex::Complex;
+use crate::{core::buffer::AudioBuffer, error::types::AudioError};
+
+/// Window functions for spectral analysis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WindowType {
+    /// No tapering
+    Rectangular,
+    /// Raised cosine; good general-purpose default
+    Hann,
+    /// Raised cosine with a lower first side lobe
+    Hamming,
+    /// Three-term Blackman window
+    Blackman,
+    /// Four-term Blackman-Harris window with very low side lobes
+    BlackmanHarris,
+}
+
+impl WindowType {
+    /// Generates the periodic window of the given length.
+    ///
+    /// Periodic windows overlap-add to a constant at the usual hop sizes,
+    /// which is what STFT processing needs.
+    pub fn coefficients(&self, len: usize) -> Vec<f32> {
+        let cosine_sum = |a: &[f32], i: usize| {
+            let x = 2.0 * PI * i as f32 / len as f32;
+            a.iter()
+                .enumerate()
+                .map(|(k, &c)| if k % 2 == 0 { c } else { -c } * (k as f32 * x).cos())
+                .sum::<f32>()
+        };
+        (0..len)
+            .map(|i| match self {
+                WindowType::Rectangular => 1.0,
+                WindowType::Hann => cosine_sum(&[0.5, 0.5], i),
+                WindowType::Hamming => cosine_sum(&[0.54, 0.46], i),
+                WindowType::Blackman => cosine_sum(&[0.42, 0.5, 0.08], i),
+                WindowType::BlackmanHarris => cosine_sum(&[0.35875, 0.48829, 0.14128, 0.01168], i),
+            })
+            .collect()
+    }
+}
+
+/// Process-wide cache of FFT plans.
+#[derive(Default)]
+struct PlanCache {
+    planner: RealFftPlanner<f32>,
+    forward: HashMap<usize, Arc<dyn RealToComplex<f32>>>,
+    inverse: HashMap<usize, Arc<dyn ComplexToReal<f32>>>,
+}
+
+fn plan_cache() -> &'static Mutex<PlanCache> {
+    static CACHE: OnceLock<Mutex<PlanCache>> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(PlanCache::default()))
+}
+
+/// Gets a cached forward (real to complex) FFT plan.
+///
+/// Planning allocates; call this when setting up, not on the audio thread.
+pub fn plan_forward(len: usize) -> Arc<dyn RealToComplex<f32>> {
+    let mut cache = plan_cache().lock();
+    let cache = &mut *cache;
+    cache
+        .forward
+        .entry(len)
+        .or_insert_with(|| cache.planner.plan_fft_forward(len))
+        .clone()
+}
+
+/// Gets a cached inverse (complex to real) FFT plan.
+///
+/// Planning allocates; call this when setting up, not on the audio thread.
+pub fn plan_inverse(len: usize) -> Arc<dyn ComplexToReal<f32>> {
+    let mut cache = plan_cache().lock();
+    let cache = &mut *cache;
+    cache
+        .inverse
+        .entry(len)
+        .or_insert_with(|| cache.planner.plan_fft_inverse(len))
+        .clone()
+}
+
+/// Gets the centre frequency of an FFT bin in Hz.
+pub fn bin_frequency(bin: usize, frame_size: usize, sample_rate: u32) -> f32 {
+    bin as f32 * sample_rate as f32 / frame_size as f32
+}
+
+/// Writes the magnitude of each bin into `output`.
+pub fn magnitude_spectrum(spectrum: &[Complex<f32>], output: &mut [f32]) {
+    for (out, bin) in output.iter_mut().zip(spectrum) {
+        *out = bin.norm();
+    }
+}
+
+/// Writes the phase of each bin, in radians, into `output`.
+pub fn phase_spectrum(spectrum: &[Complex<f32>], output: &mut [f32]) {
+    for (out, bin) in output.iter_mut().zip(spectrum) {
+        *out = bin.arg();
+    }
+}
+
+/// Converts a magnitude to decibels, flooring silence at -120 dB.
+pub fn to_db(magnitude: f32) -> f32 {
+    20.0 * magnitude.max(1e-6).log10()
+}
+
+/// Frame, hop and window settings for an STFT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StftConfig {
+    /// FFT length in samples; must be even
+    pub frame_size: usize,
+    /// Distance between frames in samples; at most `frame_size`
+    pub hop_size: usize,
+    /// Analysis and synthesis window
+    pub window: WindowType,
+}
+
+impl Default for StftConfig {
+    fn default() -> Self {
+        Self {
+            frame_size: 2048,
+            hop_size: 512,
+            window: WindowType::Hann,
+        }
+    }
+}
+
+impl StftConfig {
+    /// Number of frequency bins per frame.
+    pub fn bins(&self) -> usize {
+        self.frame_size / 2 + 1
+    }
+
+    fn validate(&self) -> Result<(), AudioError> {
+        if self.frame_size < 2 || self.frame_size % 2 == 1 {
+            return Err(AudioError::BufferError(format!(
+                "STFT frame size must be even and at least 2, got {}",
+                self.frame_size
+            )));
+        }
+        if self.hop_size == 0 || self.hop_size > self.frame_size {
+            return Err(AudioError::BufferError(format!(
+                "STFT hop size must be between 1 and {}, got {}",
+                self.frame_size, self.hop_size
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Complex spectra of consecutive frames.
+#[derive(Debug, Clone)]
+pub struct Spectrogram {
+    /// One spectrum of `frame_size / 2 + 1` bins per frame
+    pub frames: Vec<Vec<Complex<f32>>>,
+    /// Settings the spectrogram was computed with
+    pub config: StftConfig,
+    /// Number of samples that were analysed
+    pub signal_len: usize,
+}
+
+impl Spectrogram {
+    /// Number of frames.
+    pub fn num_frames(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Magnitude of every bin, frame by frame.
+    pub fn magnitudes(&self) -> Vec<Vec<f32>> {
+        self.frames
+            .iter()
+            .map(|frame| {
+                let mut magnitudes = vec![0.0; frame.len()];
+                magnitude_spectrum(frame, &mut magnitudes);
+                magnitudes
+            })
+            .collect()
+    }
+
+    /// Phase of every bin, frame by frame.
+    pub fn phases(&self) -> Vec<Vec<f32>> {
+        self.frames
+            .iter()
+            .map(|frame| {
+                let mut phases = vec![0.0; frame.len()];
+                phase_spectrum(frame, &mut phases);
+                phases
+            })
+            .collect()
+    }
+}
+
+/// Offline short-time Fourier transform.
+pub struct Stft {
+    config: StftConfig,
+    window: Vec<f32>,
+    forward: Arc<dyn RealToComplex<f32>>,
+    inverse: Arc<dyn ComplexToReal<f32>>,
+    frame: Vec<f32>,
+    spectrum: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+}
+
+impl Stft {
+    /// Creates a new `Stft`.
+    ///
+    /// # Arguments
+    /// * `config` - Frame, hop and window settings.
+    ///
+    /// # Returns
+    /// * `Result<Stft, AudioError>` - The transform, or an error if the settings are invalid.
+    pub fn new(config: StftConfig) -> Result<Self, AudioError> {
+        config.validate()?;
+        let forward = plan_forward(config.frame_size);
+        let inverse = plan_inverse(config.frame_size);
+        let scratch_len = forward.get_scratch_len().max(inverse.get_scratch_len());
+        Ok(Self {
+            window: config.window.coefficients(config.frame_size),
+            frame: forward.make_input_vec(),
+            spectrum: forward.make_output_vec(),
+            scratch: vec![Complex::default(); scratch_len],
+            forward,
+            inverse,
+            config,
+        })
+    }
+
+    /// Gets the settings.
+    pub fn config(&self) -> &StftConfig {
+        &self.config
+    }
+
+    /// Computes the spectrogram of a mono signal.
+    ///
+    /// # Arguments
+    /// * `samples` - The signal to analyse.
+    ///
+    /// # Returns
+    /// * `Result<Spectrogram, AudioError>` - One spectrum per hop, covering the whole signal.
+    pub fn analyze(&mut self, samples: &[f32]) -> Result<Spectrogram, AudioError> {
+        let StftConfig { frame_size, hop_size, .. } = self.config;
+        let num_frames = samples.len() / hop_size + 1;
+        let mut frames = Vec::with_capacity(num_frames);
+
+        for index in 0..num_frames {
+            let start = (index * hop_size) as isize - (frame_size / 2) as isize;
+            for (i, value) in self.frame.iter_mut().enumerate() {
+                let position = start + i as isize;
+                let sample = usize::try_from(position)
+                    .ok()
+                    .and_then(|p| samples.get(p))
+                    .copied()
+                    .unwrap_or(0.0);
+                *value = sample * self.window[i];
+            }
+            self.forward
+                .process_with_scratch(&mut self.frame, &mut self.spectrum, &mut self.scratch)
+                .map_err(|e| AudioError::BufferError(e.to_string()))?;
+            frames.push(self.spectrum.clone());
+        }
+
+        Ok(Spectrogram {
+            frames,
+            config: self.config,
+            signal_len: samples.len(),
+        })
+    }
+
+    /// Computes one spectrogram per channel of an `AudioBuffer`.
+    pub fn analyze_buffer(&mut self, buffer: &AudioBuffer) -> Result<Vec<Spectrogram>, AudioError> {
+        buffer
+            .samples()
+            .iter()
+            .map(|channel| self.analyze(channel))
+            .collect()
+    }
+
+    /// Resynthesizes a signal from a spectrogram with weighted overlap-add.
+    ///
+    /// # Arguments
+    /// * `spectrogram` - Spectra computed with the same settings, possibly modified.
+    ///
+    /// # Returns
+    /// * `Result<Vec<f32>, AudioError>` - The signal, `spectrogram.signal_len` samples long.
+    pub fn synthesize(&mut self, spectrogram: &Spectrogram) -> Result<Vec<f32>, AudioError> {
+        if spectrogram.config != self.config {
+            return Err(AudioError::BufferError("Spectrogram was computed with different STFT settings".into()));
+        }
+        let StftConfig { frame_size, hop_size, .. } = self.config;
+        let padded_len = spectrogram.signal_len + frame_size;
+        let mut output = vec![0.0f32; padded_len];
+        let mut weights = vec![0.0f32; padded_len];
+        let scale = 1.0 / frame_size as f32;
+
+        for (index, spectrum) in spectrogram.frames.iter().enumerate() {
+            self.spectrum.copy_from_slice(spectrum);
+            clear_edge_phases(&mut self.spectrum);
+            self.inverse
+                .process_with_scratch(&mut self.spectrum, &mut self.frame, &mut self.scratch)
+                .map_err(|e| AudioError::BufferError(e.to_string()))?;
+
+            // Output is offset by half a frame so that centred frames start at index zero
+            let start = index * hop_size;
+            for i in 0..frame_size.min(padded_len.saturating_sub(start)) {
+                output[start + i] += self.frame[i] * scale * self.window[i];
+                weights[start + i] += self.window[i] * self.window[i];
+            }
+        }
+
+        Ok(output[frame_size / 2..]
+            .iter()
+            .zip(&weights[frame_size / 2..])
+            .take(spectrogram.signal_len)
+            .map(|(&sample, &weight)| if weight > 1e-6 { sample / weight } else { 0.0 })
+            .collect())
+    }
+
+    /// Resynthesizes one channel per spectrogram into an `AudioBuffer`.
+    pub fn synthesize_buffer(&mut self, spectrograms: &[Spectrogram], sample_rate: u32) -> Result<AudioBuffer, AudioError> {
+        let channels = spectrograms
+            .iter()
+            .map(|spectrogram| self.synthesize(spectrogram))
+            .collect::<Result<Vec<_>, _>>()?;
+        AudioBuffer::from_data(channels, sample_rate)
+    }
+}
+
+/// Streaming short-time Fourier transform for the audio thread.
+///
+/// All buffers are allocated up front; `analyze` and `process` do not
+/// allocate. Processing adds `frame_size` samples of latency.
+pub struct StreamingStft {
+    config: StftConfig,
+    window: Vec<f32>,
+    forward: Arc<dyn RealToComplex<f32>>,
+    inverse: Arc<dyn ComplexToReal<f32>>,
+    /// Circular history of the last `frame_size` input samples
+    input: Vec<f32>,
+    input_pos: usize,
+    /// Overlap-add accumulator, read out one hop behind
+    output: Vec<f32>,
+    output_pos: usize,
+    hop_counter: usize,
+    /// Normalization for the summed analysis and synthesis windows
+    ola_gain: f32,
+    frame: Vec<f32>,
+    spectrum: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+}
+
+impl StreamingStft {
+    /// Creates a new `StreamingStft`.
+    ///
+    /// # Arguments
+    /// * `config` - Frame, hop and window settings.
+    ///
+    /// # Returns
+    /// * `Result<StreamingStft, AudioError>` - The transform, or an error if the settings are invalid.
+    pub fn new(config: StftConfig) -> Result<Self, AudioError> {
+        config.validate()?;
+        let forward = plan_forward(config.frame_size);
+        let inverse = plan_inverse(config.frame_size);
+        let scratch_len = forward.get_scratch_len().max(inverse.get_scratch_len());
+        let window = config.window.coefficients(config.frame_size);
+
+        let overlap_sum: f32 = window.iter().map(|w| w * w).sum::<f32>() / config.hop_size as f32;
+        Ok(Self {
+            input: vec![0.0; config.frame_size],
+            input_pos: 0,
+            output: vec![0.0; config.frame_size],
+            output_pos: 0,
+            hop_counter: 0,
+            ola_gain: if overlap_sum > 0.0 { 1.0 / overlap_sum } else { 0.0 },
+            frame: forward.make_input_vec(),
+            spectrum: forward.make_output_vec(),
+            scratch: vec![Complex::default(); scratch_len],
+            forward,
+            inverse,
+            window,
+            config,
+        })
+    }
+
+    /// Gets the settings.
+    pub fn config(&self) -> &StftConfig {
+        &self.config
+    }
+
+    /// Latency of `process` in samples.
+    pub fn latency(&self) -> usize {
+        self.config.frame_size
+    }
+
+    /// Clears all internal state.
+    pub fn reset(&mut self) {
+        self.input.fill(0.0);
+        self.output.fill(0.0);
+        self.input_pos = 0;
+        self.output_pos = 0;
+        self.hop_counter = 0;
+    }
+
+    /// Feeds input and calls `on_frame` with the spectrum of every completed frame.
+    ///
+    /// # Arguments
+    /// * `input` - The next block of audio.
+    /// * `on_frame` - Receives each new spectrum.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - An error if the FFT fails.
+    pub fn analyze<F>(&mut self, input: &[f32], mut on_frame: F) -> Result<(), AudioError>
+    where
+        F: FnMut(&[Complex<f32>]),
+    {
+        for &sample in input {
+            if self.push(sample) {
+                self.transform_frame()?;
+                on_frame(&self.spectrum);
+            }
+        }
+        Ok(())
+    }
+
+    /// Feeds input, lets `on_frame` modify each spectrum and writes the resynthesized signal.
+    ///
+    /// # Arguments
+    /// * `input` - The next block of audio.
+    /// * `output` - Receives the processed audio, delayed by `latency()` samples; same length as `input`.
+    /// * `on_frame` - Receives each new spectrum and may modify it in place.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - An error if the buffers differ in length or the FFT fails.
+    pub fn process<F>(&mut self, input: &[f32], output: &mut [f32], mut on_frame: F) -> Result<(), AudioError>
+    where
+        F: FnMut(&mut [Complex<f32>]),
+    {
+        if input.len() != output.len() {
+            return Err(AudioError::BufferMismatch);
+        }
+        let frame_size = self.config.frame_size;
+
+        for (&sample, out) in input.iter().zip(output.iter_mut()) {
+            *out = self.output[self.output_pos];
+            self.output[self.output_pos] = 0.0;
+            self.output_pos = (self.output_pos + 1) % frame_size;
+
+            if self.push(sample) {
+                self.transform_frame()?;
+                on_frame(&mut self.spectrum);
+                clear_edge_phases(&mut self.spectrum);
+                self.inverse
+                    .process_with_scratch(&mut self.spectrum, &mut self.frame, &mut self.scratch)
+                    .map_err(|e| AudioError::BufferError(e.to_string()))?;
+
+                let scale = self.ola_gain / frame_size as f32;
+                for i in 0..frame_size {
+                    let index = (self.output_pos + i) % frame_size;
+                    self.output[index] += self.frame[i] * self.window[i] * scale;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Stores one input sample; returns true when a new frame is due.
+    fn push(&mut self, sample: f32) -> bool {
+        self.input[self.input_pos] = sample;
+        self.input_pos = (self.input_pos + 1) % self.config.frame_size;
+        self.hop_counter += 1;
+        if self.hop_counter >= self.config.hop_size {
+            self.hop_counter = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Windows the most recent frame of input and transforms it into `self.spectrum`.
+    fn transform_frame(&mut self) -> Result<(), AudioError> {
+        let frame_size = self.config.frame_size;
+        for i in 0..frame_size {
+            self.frame[i] = self.input[(self.input_pos + i) % frame_size] * self.window[i];
+        }
+        self.forward
+            .process_with_scratch(&mut self.frame, &mut self.spectrum, &mut self.scratch)
+            .map_err(|e| AudioError::BufferError(e.to_string()))
+    }
+}
+
+/// The DC and Nyquist bins of a real signal's spectrum have no imaginary part.
+fn clear_edge_phases(spectrum: &mut [Complex<f32>]) {
+    if let Some(first) = spectrum.first_mut() {
+        first.im = 0.0;
+    }
+    if let Some(last) = spectrum.last_mut() {
+        last.im = 0.0;
+    }
+}
+
+/// Groups FFT bins into bands of equal width on a log-frequency scale.
+#[derive(Debug, Clone)]
+pub struct LogFrequencyBands {
+    /// Bin index where each band starts, followed by the end of the last band
+    edges: Vec<usize>,
+    centers: Vec<f32>,
+}
+
+impl LogFrequencyBands {
+    /// Creates bands between `min_hz` and `max_hz`.
+    ///
+    /// Bands narrower than one bin are merged with their neighbours, so low
+    /// frequencies may get fewer bands than requested.
+    ///
+    /// # Arguments
+    /// * `frame_size` - FFT length the spectra come from.
+    /// * `sample_rate` - The sample rate of the analysed audio.
+    /// * `min_hz` - Lower edge of the first band.
+    /// * `max_hz` - Upper edge of the last band; clamped to Nyquist.
+    /// * `bands_per_octave` - Band resolution.
+    ///
+    /// # Returns
+    /// * `LogFrequencyBands` - The band layout.
+    pub fn new(frame_size: usize, sample_rate: u32, min_hz: f32, max_hz: f32, bands_per_octave: f32) -> Self {
+        let bins = frame_size / 2 + 1;
+        let bin_hz = sample_rate as f32 / frame_size as f32;
+        let min_hz = min_hz.max(bin_hz);
+        let max_hz = max_hz.min(sample_rate as f32 / 2.0).max(min_hz);
+        let count = ((max_hz / min_hz).log2() * bands_per_octave).ceil().max(1.0) as usize;
+
+        let mut edges = vec![((min_hz / bin_hz).round() as usize).min(bins - 1)];
+        for band in 1..=count {
+            let hz = min_hz * (max_hz / min_hz).powf(band as f32 / count as f32);
+            let edge = ((hz / bin_hz).round() as usize).min(bins);
+            if edge > edges[edges.len() - 1] {
+                edges.push(edge);
+            }
+        }
+        if edges.len() < 2 {
+            edges.push(edges[0] + 1);
+        }
+        let centers = edges
+            .windows(2)
+            .map(|pair| ((pair[0] as f32 * bin_hz) * (pair[1] as f32 * bin_hz)).sqrt())
+            .collect();
+        Self { edges, centers }
+    }
+
+    /// Number of bands.
+    pub fn len(&self) -> usize {
+        self.edges.len() - 1
+    }
+
+    /// Whether there are no bands.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Geometric centre frequency of each band, in Hz.
+    pub fn center_frequencies(&self) -> &[f32] {
+        &self.centers
+    }
+
+    /// Averages bin magnitudes into bands.
+    ///
+    /// # Arguments
+    /// * `magnitudes` - Magnitude spectrum of one frame.
+    /// * `output` - Receives one RMS magnitude per band.
+    pub fn apply(&self, magnitudes: &[f32], output: &mut [f32]) {
+        for (out, pair) in output.iter_mut().zip(self.edges.windows(2)) {
+            let bins = &magnitudes[pair[0].min(magnitudes.len())..pair[1].min(magnitudes.len())];
+            *out = if bins.is_empty() {
+                0.0
+            } else {
+                (bins.iter().map(|m| m * m).sum::<f32>() / bins.len() as f32).sqrt()
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequency: f32, sample_rate: u32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| (2.0 * PI * frequency * i as f32 / sample_rate as f32).sin())
+            .collect()
+    }
+
+    #[test]
+    fn test_stft_round_trip() {
+        let signal: Vec<f32> = sine(440.0, 44100, 10000).iter().zip(sine(3000.0, 44100, 10000)).map(|(a, b)| a + 0.3 * b).collect();
+        for window in [WindowType::Hann, WindowType::Hamming, WindowType::BlackmanHarris] {
+            let mut stft = Stft::new(StftConfig { frame_size: 1024, hop_size: 256, window }).unwrap();
+            let spectrogram = stft.analyze(&signal).unwrap();
+            let resynthesized = stft.synthesize(&spectrogram).unwrap();
+            assert_eq!(resynthesized.len(), signal.len());
+            assert!(signal.iter().zip(&resynthesized).all(|(a, b)| (a - b).abs() < 1e-4));
+        }
+    }
+
+    #[test]
+    fn test_spectrum_peak() {
+        let sample_rate = 48000;
+        let frame_size = 2048;
+        let mut stft = Stft::new(StftConfig { frame_size, ..Default::default() }).unwrap();
+        let spectrogram = stft.analyze(&sine(1500.0, sample_rate, 8192)).unwrap();
+        let magnitudes = &spectrogram.magnitudes()[4];
+        let peak = (0..magnitudes.len()).max_by(|&a, &b| magnitudes[a].total_cmp(&magnitudes[b])).unwrap();
+        assert!((bin_frequency(peak, frame_size, sample_rate) - 1500.0).abs() < 24.0);
+
+        let bands = LogFrequencyBands::new(frame_size, sample_rate, 50.0, 20000.0, 3.0);
+        let mut levels = vec![0.0; bands.len()];
+        bands.apply(magnitudes, &mut levels);
+        let loudest = (0..levels.len()).max_by(|&a, &b| levels[a].total_cmp(&levels[b])).unwrap();
+        let center = bands.center_frequencies()[loudest];
+        assert!((center / 1500.0).log2().abs() < 1.0 / 3.0);
+    }
+
+    #[test]
+    fn test_streaming_matches_input_after_latency() {
+        let config = StftConfig { frame_size: 512, hop_size: 128, window: WindowType::Hann };
+        let mut stft = StreamingStft::new(config).unwrap();
+        let signal = sine(700.0, 44100, 4096);
+        let mut output = vec![0.0; signal.len()];
+        for (input, output) in signal.chunks(100).zip(output.chunks_mut(100)) {
+            stft.process(input, output, |_| {}).unwrap();
+        }
+
+        let latency = stft.latency();
+        for i in latency + 512..signal.len() {
+            assert!((output[i] - signal[i - latency]).abs() < 1e-3, "sample {}", i);
+        }
+    }
+}
@@ -0,0 +1,395 @@
+﻿//! Key and chord estimation
+//!
+//! Builds a chromagram (energy per pitch class) from the STFT of a loop. The
+//! key is the major or minor Krumhansl-Kessler profile best correlated with
+//! the whole loop's chroma; each bar's chord is the triad or seventh template
+//! closest to that bar's chroma.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audio::analysis::{
+        fft::{bin_frequency, magnitude_spectrum, Stft, StftConfig, WindowType},
+        pitch::NOTE_NAMES,
+    },
+    error::types::AudioError,
+};
+
+/// Analysis frame length; long enough to separate semitones in the bass.
+const FRAME_SIZE: usize = 8192;
+
+/// Distance between analysis frames.
+const HOP_SIZE: usize = 2048;
+
+/// Lowest frequency folded into the chromagram (C2).
+const MIN_HZ: f32 = 65.0;
+
+/// Highest frequency folded into the chromagram.
+const MAX_HZ: f32 = 2100.0;
+
+/// Lowest template similarity reported as a chord.
+const MIN_CHORD_SIMILARITY: f32 = 0.6;
+
+/// Krumhansl-Kessler major key profile, starting at the tonic.
+const MAJOR_PROFILE: [f32; 12] = [6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88];
+
+/// Krumhansl-Kessler minor key profile, starting at the tonic.
+const MINOR_PROFILE: [f32; 12] = [6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17];
+
+/// Energy per pitch class, starting at C.
+pub type Chroma = [f32; 12];
+
+/// Major or minor mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Mode {
+    /// Major (Ionian)
+    Major,
+    /// Natural, harmonic or melodic minor
+    Minor,
+}
+
+/// An estimated musical key.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Key {
+    /// Pitch class of the tonic, 0 = C
+    pub tonic: u8,
+    /// Major or minor
+    pub mode: Mode,
+    /// Correlation with the key profile, from 0.0 to 1.0
+    pub confidence: f32,
+}
+
+impl Key {
+    /// Gets the tonic name, e.g. "F#".
+    pub fn tonic_name(&self) -> &'static str {
+        NOTE_NAMES[self.tonic as usize % 12]
+    }
+
+    /// Gets the pitch classes of the key's scale, starting at the tonic.
+    pub fn scale(&self) -> [u8; 7] {
+        let steps = match self.mode {
+            Mode::Major => [0, 2, 4, 5, 7, 9, 11],
+            Mode::Minor => [0, 2, 3, 5, 7, 8, 10],
+        };
+        steps.map(|step| (self.tonic + step) % 12)
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = match self.mode {
+            Mode::Major => "major",
+            Mode::Minor => "minor",
+        };
+        write!(f, "{} {}", self.tonic_name(), mode)
+    }
+}
+
+/// Chord types recognised by the detector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChordQuality {
+    /// Root, major third, fifth
+    Major,
+    /// Root, minor third, fifth
+    Minor,
+    /// Root, minor third, diminished fifth
+    Diminished,
+    /// Root, major third, augmented fifth
+    Augmented,
+    /// Major triad with minor seventh
+    Dominant7,
+    /// Major triad with major seventh
+    Major7,
+    /// Minor triad with minor seventh
+    Minor7,
+}
+
+impl ChordQuality {
+    /// All qualities, in the order they are tried.
+    pub const ALL: [ChordQuality; 7] = [
+        ChordQuality::Major,
+        ChordQuality::Minor,
+        ChordQuality::Diminished,
+        ChordQuality::Augmented,
+        ChordQuality::Dominant7,
+        ChordQuality::Major7,
+        ChordQuality::Minor7,
+    ];
+
+    /// Gets the chord tones as semitones above the root.
+    pub fn intervals(&self) -> &'static [u8] {
+        match self {
+            ChordQuality::Major => &[0, 4, 7],
+            ChordQuality::Minor => &[0, 3, 7],
+            ChordQuality::Diminished => &[0, 3, 6],
+            ChordQuality::Augmented => &[0, 4, 8],
+            ChordQuality::Dominant7 => &[0, 4, 7, 10],
+            ChordQuality::Major7 => &[0, 4, 7, 11],
+            ChordQuality::Minor7 => &[0, 3, 7, 10],
+        }
+    }
+
+    /// Gets the chord symbol suffix, e.g. "m7".
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            ChordQuality::Major => "",
+            ChordQuality::Minor => "m",
+            ChordQuality::Diminished => "dim",
+            ChordQuality::Augmented => "aug",
+            ChordQuality::Dominant7 => "7",
+            ChordQuality::Major7 => "maj7",
+            ChordQuality::Minor7 => "m7",
+        }
+    }
+}
+
+/// An estimated chord.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Chord {
+    /// Pitch class of the root, 0 = C
+    pub root: u8,
+    /// Chord type
+    pub quality: ChordQuality,
+    /// Similarity between the chroma and the chord template, from 0.0 to 1.0
+    pub confidence: f32,
+}
+
+impl Chord {
+    /// Gets the pitch classes of the chord tones.
+    pub fn pitch_classes(&self) -> impl Iterator<Item = u8> + '_ {
+        self.quality.intervals().iter().map(move |interval| (self.root + interval) % 12)
+    }
+}
+
+impl fmt::Display for Chord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", NOTE_NAMES[self.root as usize % 12], self.quality.suffix())
+    }
+}
+
+/// Key and chord sequence of a loop.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HarmonyAnalysis {
+    /// Estimated key, or `None` if the loop has no pitched content
+    pub key: Option<Key>,
+    /// One entry per bar; `None` where no chord is recognised
+    pub chords: Vec<Option<Chord>>,
+}
+
+/// Chromagram-based key and chord estimator.
+#[derive(Debug, Clone)]
+pub struct HarmonyAnalyzer {
+    sample_rate: u32,
+    /// Pitch class and weight for each FFT bin in range
+    bin_map: Vec<(usize, u8, f32)>,
+}
+
+impl HarmonyAnalyzer {
+    /// Creates a new `HarmonyAnalyzer`.
+    ///
+    /// # Arguments
+    /// * `sample_rate` - The sample rate of the audio to analyse.
+    ///
+    /// # Returns
+    /// * `HarmonyAnalyzer` - A new analyzer.
+    pub fn new(sample_rate: u32) -> Self {
+        let bin_map = (1..=FRAME_SIZE / 2)
+            .filter_map(|bin| {
+                let frequency = bin_frequency(bin, FRAME_SIZE, sample_rate);
+                if !(MIN_HZ..=MAX_HZ).contains(&frequency) {
+                    return None;
+                }
+                let semitones = 12.0 * (frequency / 440.0).log2() + 69.0;
+                let nearest = semitones.round();
+                // Bins between two notes count for neither
+                let weight = (std::f32::consts::PI * (semitones - nearest)).cos().powi(2);
+                Some((bin, (nearest as i32).rem_euclid(12) as u8, weight))
+            })
+            .collect();
+        Self { sample_rate, bin_map }
+    }
+
+    /// Gets the sample rate.
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Computes one chroma vector per analysis frame of a mono signal.
+    ///
+    /// Frame `i` is centred on sample `i * 2048`.
+    pub fn chromagram(&self, samples: &[f32]) -> Result<Vec<Chroma>, AudioError> {
+        let mut stft = Stft::new(StftConfig {
+            frame_size: FRAME_SIZE,
+            hop_size: HOP_SIZE,
+            window: WindowType::Hann,
+        })?;
+        let spectrogram = stft.analyze(samples)?;
+        let mut magnitudes = vec![0.0f32; FRAME_SIZE / 2 + 1];
+        Ok(spectrogram
+            .frames
+            .iter()
+            .map(|spectrum| {
+                magnitude_spectrum(spectrum, &mut magnitudes);
+                let mut chroma = [0.0f32; 12];
+                for &(bin, pitch_class, weight) in &self.bin_map {
+                    chroma[pitch_class as usize] += weight * magnitudes[bin];
+                }
+                chroma
+            })
+            .collect())
+    }
+
+    /// Estimates the key and the chord in each bar of a loop.
+    ///
+    /// # Arguments
+    /// * `channels` - The recorded audio, one `Vec` per channel; analysed mixed to mono.
+    /// * `bar_length` - Samples per bar.
+    ///
+    /// # Returns
+    /// * `Result<HarmonyAnalysis, AudioError>` - The key and one chord slot per started bar.
+    pub fn analyze(&self, channels: &[Vec<f32>], bar_length: usize) -> Result<HarmonyAnalysis, AudioError> {
+        if bar_length == 0 {
+            return Err(AudioError::BufferError("Bar length must be positive".into()));
+        }
+        let len = channels.iter().map(|c| c.len()).min().unwrap_or(0);
+        let mono: Vec<f32> = (0..len).map(|i| channels.iter().map(|c| c[i]).sum()).collect();
+        let frames = self.chromagram(&mono)?;
+
+        let bars = len.div_ceil(bar_length);
+        let mut bar_chroma = vec![[0.0f32; 12]; bars];
+        let mut total = [0.0f32; 12];
+        for (index, chroma) in frames.iter().enumerate() {
+            let bar = index * HOP_SIZE / bar_length;
+            if bar >= bars {
+                break;
+            }
+            for pitch_class in 0..12 {
+                bar_chroma[bar][pitch_class] += chroma[pitch_class];
+                total[pitch_class] += chroma[pitch_class];
+            }
+        }
+
+        Ok(HarmonyAnalysis {
+            key: estimate_key(&total),
+            chords: bar_chroma.iter().map(detect_chord).collect(),
+        })
+    }
+}
+
+/// Pearson correlation between a chroma vector and a profile rotated to `tonic`.
+fn correlation(chroma: &Chroma, profile: &[f32; 12], tonic: usize) -> f32 {
+    let mean_c = chroma.iter().sum::<f32>() / 12.0;
+    let mean_p = profile.iter().sum::<f32>() / 12.0;
+    let (mut covariance, mut var_c, mut var_p) = (0.0, 0.0, 0.0);
+    for pitch_class in 0..12 {
+        let c = chroma[pitch_class] - mean_c;
+        let p = profile[(pitch_class + 12 - tonic) % 12] - mean_p;
+        covariance += c * p;
+        var_c += c * c;
+        var_p += p * p;
+    }
+    if var_c <= f32::EPSILON {
+        0.0
+    } else {
+        covariance / (var_c * var_p).sqrt()
+    }
+}
+
+/// Finds the major or minor key whose profile best matches a chroma vector.
+///
+/// # Returns
+/// * `Option<Key>` - The best key, or `None` if the chroma is flat.
+pub fn estimate_key(chroma: &Chroma) -> Option<Key> {
+    (0..12)
+        .flat_map(|tonic| [(tonic, Mode::Major, &MAJOR_PROFILE), (tonic, Mode::Minor, &MINOR_PROFILE)])
+        .map(|(tonic, mode, profile)| Key {
+            tonic: tonic as u8,
+            mode,
+            confidence: correlation(chroma, profile, tonic),
+        })
+        .max_by(|a, b| a.confidence.total_cmp(&b.confidence))
+        .filter(|key| key.confidence > 0.0)
+}
+
+/// Finds the chord template closest to a chroma vector.
+///
+/// # Returns
+/// * `Option<Chord>` - The best chord, or `None` if nothing matches well enough.
+pub fn detect_chord(chroma: &Chroma) -> Option<Chord> {
+    let norm = chroma.iter().map(|c| c * c).sum::<f32>().sqrt();
+    if norm <= f32::EPSILON {
+        return None;
+    }
+    (0..12u8)
+        .flat_map(|root| ChordQuality::ALL.iter().map(move |&quality| (root, quality)))
+        .map(|(root, quality)| {
+            let tones = quality.intervals();
+            let energy: f32 = tones.iter().map(|i| chroma[((root + i) % 12) as usize]).sum();
+            Chord {
+                root,
+                quality,
+                // Cosine similarity with a binary template
+                confidence: energy / (norm * (tones.len() as f32).sqrt()),
+            }
+        })
+        .max_by(|a, b| a.confidence.total_cmp(&b.confidence))
+        .filter(|chord| chord.confidence >= MIN_CHORD_SIMILARITY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Renders one bar per chord, each tone with a few harmonics plus the root an octave down.
+    fn progression(chords: &[(u8, ChordQuality)], bar_length: usize, sample_rate: u32) -> Vec<f32> {
+        let mut out = vec![0.0; bar_length * chords.len()];
+        for (bar, &(root, quality)) in chords.iter().enumerate() {
+            let mut notes: Vec<i32> = quality.intervals().iter().map(|&i| 60 + root as i32 + i as i32).collect();
+            notes.push(48 + root as i32);
+            for note in notes {
+                let frequency = 440.0 * 2f32.powf((note - 69) as f32 / 12.0);
+                for i in 0..bar_length {
+                    let t = i as f32 / sample_rate as f32;
+                    let tone: f32 = (1..=3)
+                        .map(|h| (2.0 * std::f32::consts::PI * frequency * h as f32 * t).sin() / h as f32)
+                        .sum();
+                    out[bar * bar_length + i] += 0.1 * tone;
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_key_and_chords_major() {
+        use ChordQuality::*;
+        let sample_rate = 44100;
+        let bar_length = sample_rate as usize * 2;
+        let chords = [(0, Major), (9, Minor), (5, Major), (7, Dominant7)];
+        let audio = vec![progression(&chords, bar_length, sample_rate)];
+
+        let analysis = HarmonyAnalyzer::new(sample_rate).analyze(&audio, bar_length).unwrap();
+        let key = analysis.key.unwrap();
+        assert_eq!(key.to_string(), "C major");
+        let names: Vec<String> = analysis.chords.iter().map(|c| c.unwrap().to_string()).collect();
+        assert_eq!(names, ["C", "Am", "F", "G7"]);
+    }
+
+    #[test]
+    fn test_key_minor() {
+        use ChordQuality::*;
+        let sample_rate = 44100;
+        let bar_length = sample_rate as usize * 2;
+        let chords = [(9, Minor), (2, Minor), (4, Major), (9, Minor)];
+        let audio = vec![progression(&chords, bar_length, sample_rate)];
+
+        let analysis = HarmonyAnalyzer::new(sample_rate).analyze(&audio, bar_length).unwrap();
+        assert_eq!(analysis.key.unwrap().to_string(), "A minor");
+        assert_eq!(analysis.chords[2].unwrap().to_string(), "E");
+
+        let silence = HarmonyAnalyzer::new(sample_rate).analyze(&[vec![0.0; bar_length]], bar_length).unwrap();
+        assert_eq!(silence, HarmonyAnalysis { key: None, chords: vec![None] });
+    }
+}
@@ -0,0 +1,555 @@
+﻿//! Level metering
+//!
+//! Sample peak, true peak, RMS and EBU R128 loudness for a group of channels.
+//! Loudness follows ITU-R BS.1770-4: K-weighted mean square over 400 ms
+//! (momentary) and 3 s (short-term) windows, and gated integration over the
+//! whole measurement. All state is allocated up front, so meters can run on
+//! the audio thread.
+
+/// Level reported for silence, in dB.
+pub const SILENCE_DB: f32 = -120.0;
+
+/// Absolute gate for integrated loudness, in LUFS.
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+
+/// Relative gate for integrated loudness, in LU below the ungated level.
+const RELATIVE_GATE_LU: f64 = -10.0;
+
+/// Resolution of the integrated loudness histogram, in LU.
+const HISTOGRAM_STEP: f64 = 0.1;
+
+/// Loudness covered by the histogram above the absolute gate, in LU.
+const HISTOGRAM_RANGE: f64 = 80.0;
+
+/// Loudness measurements are updated every 100 ms.
+const SUBBLOCK_SECONDS: f64 = 0.1;
+
+/// Sub-blocks in a momentary (400 ms) window.
+const MOMENTARY_SUBBLOCKS: usize = 4;
+
+/// Sub-blocks in a short-term (3 s) window.
+const SHORT_TERM_SUBBLOCKS: usize = 30;
+
+/// Time constant of the RMS detector, in seconds.
+const RMS_SECONDS: f32 = 0.3;
+
+/// Fall rate of the peak and peak-hold displays, in dB per second.
+const PEAK_FALL_DB_PER_SECOND: f32 = 20.0;
+
+/// Settings for level meters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MeterSettings {
+    /// How long the highest peak is held before it starts to fall, in seconds
+    pub peak_hold: f32,
+    /// Sample or true peak level that sets the clip indicator, in dBFS
+    pub clip_level: f32,
+}
+
+impl Default for MeterSettings {
+    fn default() -> Self {
+        Self {
+            peak_hold: 1.5,
+            clip_level: 0.0,
+        }
+    }
+}
+
+/// Meter values at one point in time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MeterReading {
+    /// Sample peak with fall-back, in dBFS
+    pub peak: f32,
+    /// Held maximum sample peak, in dBFS
+    pub peak_hold: f32,
+    /// True (inter-sample) peak with fall-back, in dBTP
+    pub true_peak: f32,
+    /// Highest true peak since the last reset, in dBTP
+    pub max_true_peak: f32,
+    /// RMS level, in dBFS
+    pub rms: f32,
+    /// Momentary loudness (400 ms), in LUFS
+    pub momentary: f32,
+    /// Short-term loudness (3 s), in LUFS
+    pub short_term: f32,
+    /// Integrated loudness since the last reset, in LUFS
+    pub integrated: f32,
+    /// Whether the clip level was reached since the last reset
+    pub clipped: bool,
+}
+
+impl Default for MeterReading {
+    fn default() -> Self {
+        Self {
+            peak: SILENCE_DB,
+            peak_hold: SILENCE_DB,
+            true_peak: SILENCE_DB,
+            max_true_peak: SILENCE_DB,
+            rms: SILENCE_DB,
+            momentary: SILENCE_DB,
+            short_term: SILENCE_DB,
+            integrated: SILENCE_DB,
+            clipped: false,
+        }
+    }
+}
+
+/// Converts a linear amplitude to dB, flooring at `SILENCE_DB`.
+pub fn amplitude_to_db(amplitude: f32) -> f32 {
+    if amplitude > 0.0 {
+        (20.0 * amplitude.log10()).max(SILENCE_DB)
+    } else {
+        SILENCE_DB
+    }
+}
+
+/// Converts a K-weighted mean square to LUFS.
+fn loudness(mean_square: f64) -> f64 {
+    if mean_square > 0.0 {
+        -0.691 + 10.0 * mean_square.log10()
+    } else {
+        SILENCE_DB as f64
+    }
+}
+
+/// Direct form I biquad used for K-weighting.
+#[derive(Debug, Clone, Copy, Default)]
+struct KFilterStage {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl KFilterStage {
+    fn process(&mut self, input: f64) -> f64 {
+        let output = self.b[0] * input + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [input, self.x[0]];
+        self.y = [output, self.y[0]];
+        output
+    }
+}
+
+/// BS.1770 K-weighting: a high shelf modelling the head, then a high pass.
+#[derive(Debug, Clone, Copy)]
+struct KWeighting {
+    shelf: KFilterStage,
+    high_pass: KFilterStage,
+}
+
+impl KWeighting {
+    /// Derives the filter for any sample rate; matches the tabulated 48 kHz coefficients.
+    fn new(sample_rate: u32) -> Self {
+        let fs = sample_rate as f64;
+
+        let f0 = 1681.974450955533;
+        let gain_db = 3.999843853973347;
+        let q = 0.7071752369554196;
+        let k = (std::f64::consts::PI * f0 / fs).tan();
+        let vh = 10f64.powf(gain_db / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = KFilterStage {
+            b: [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            ..Default::default()
+        };
+
+        let f0 = 38.13547087602444;
+        let q = 0.5003270373238773;
+        let k = (std::f64::consts::PI * f0 / fs).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = KFilterStage {
+            b: [1.0, -2.0, 1.0],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            ..Default::default()
+        };
+
+        Self { shelf, high_pass }
+    }
+
+    fn process(&mut self, input: f32) -> f64 {
+        self.high_pass.process(self.shelf.process(input as f64))
+    }
+}
+
+/// Polyphase 4x oversampling filter from ITU-R BS.1770-4 Annex 2.
+///
+/// The coefficients are exact in binary and written as the standard lists them.
+#[allow(clippy::excessive_precision)]
+const TRUE_PEAK_TAPS: [[f32; 12]; 4] = [
+    [
+        0.0017089843750, 0.0109863281250, -0.0196533203125, 0.0332031250000, -0.0594482421875, 0.1373291015625,
+        0.9721679687500, -0.1022949218750, 0.0476074218750, -0.0266113281250, 0.0148925781250, -0.0083007812500,
+    ],
+    [
+        -0.0291748046875, 0.0292968750000, -0.0517578125000, 0.0891113281250, -0.1665039062500, 0.4650878906250,
+        0.7797851562500, -0.2003173828125, 0.1015625000000, -0.0582275390625, 0.0330810546875, -0.0189208984375,
+    ],
+    [
+        -0.0189208984375, 0.0330810546875, -0.0582275390625, 0.1015625000000, -0.2003173828125, 0.7797851562500,
+        0.4650878906250, -0.1665039062500, 0.0891113281250, -0.0517578125000, 0.0292968750000, -0.0291748046875,
+    ],
+    [
+        -0.0083007812500, 0.0148925781250, -0.0266113281250, 0.0476074218750, -0.1022949218750, 0.9721679687500,
+        0.1373291015625, -0.0594482421875, 0.0332031250000, -0.0196533203125, 0.0109863281250, 0.0017089843750,
+    ],
+];
+
+/// Inter-sample peak detector using 4x oversampling.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TruePeakDetector {
+    history: [f32; 12],
+    position: usize,
+}
+
+impl TruePeakDetector {
+    /// Creates a new detector.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feeds one sample and returns the largest absolute value among its four oversampled points.
+    ///
+    /// The oversampled signal lags the input by six samples.
+    pub fn process(&mut self, sample: f32) -> f32 {
+        self.history[self.position] = sample;
+        self.position = (self.position + 1) % self.history.len();
+
+        let mut peak = 0.0f32;
+        for taps in &TRUE_PEAK_TAPS {
+            let mut sum = 0.0;
+            for (k, tap) in taps.iter().enumerate() {
+                // taps[0] applies to the newest sample
+                let index = (self.position + self.history.len() - 1 - k) % self.history.len();
+                sum += tap * self.history[index];
+            }
+            peak = peak.max(sum.abs());
+        }
+        peak
+    }
+
+    /// Clears the filter history.
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+/// Histogram of gating-block loudness for integrated measurement.
+#[derive(Debug, Clone)]
+struct LoudnessHistogram {
+    counts: Vec<u32>,
+    energies: Vec<f64>,
+}
+
+impl LoudnessHistogram {
+    fn new() -> Self {
+        let bins = (HISTOGRAM_RANGE / HISTOGRAM_STEP) as usize;
+        Self {
+            counts: vec![0; bins],
+            energies: vec![0.0; bins],
+        }
+    }
+
+    fn add(&mut self, mean_square: f64) {
+        let lufs = loudness(mean_square);
+        if lufs <= ABSOLUTE_GATE_LUFS {
+            return;
+        }
+        let bin = (((lufs - ABSOLUTE_GATE_LUFS) / HISTOGRAM_STEP) as usize).min(self.counts.len() - 1);
+        self.counts[bin] += 1;
+        self.energies[bin] += mean_square;
+    }
+
+    fn integrated(&self) -> f64 {
+        let total = |from: usize| {
+            let count: u64 = self.counts[from..].iter().map(|&c| c as u64).sum();
+            let energy: f64 = self.energies[from..].iter().sum();
+            (count, energy)
+        };
+        let (count, energy) = total(0);
+        if count == 0 {
+            return SILENCE_DB as f64;
+        }
+
+        let relative_gate = loudness(energy / count as f64) + RELATIVE_GATE_LU;
+        let from = ((relative_gate - ABSOLUTE_GATE_LUFS) / HISTOGRAM_STEP).ceil().max(0.0) as usize;
+        let (count, energy) = total(from.min(self.counts.len()));
+        if count == 0 {
+            SILENCE_DB as f64
+        } else {
+            loudness(energy / count as f64)
+        }
+    }
+
+    fn clear(&mut self) {
+        self.counts.fill(0);
+        self.energies.fill(0.0);
+    }
+}
+
+/// Per-channel detector state.
+#[derive(Debug, Clone)]
+struct ChannelState {
+    k_weighting: KWeighting,
+    true_peak: TruePeakDetector,
+    mean_square: f32,
+}
+
+/// Level and loudness meter for a group of channels.
+#[derive(Debug, Clone)]
+pub struct LevelMeter {
+    sample_rate: u32,
+    settings: MeterSettings,
+    channels: Vec<ChannelState>,
+    rms_coefficient: f32,
+    fall_per_sample: f32,
+    hold_samples: usize,
+
+    peak: f32,
+    peak_hold: f32,
+    hold_remaining: usize,
+    true_peak: f32,
+    max_true_peak: f32,
+    clipped: bool,
+
+    /// K-weighted energy summed over channels in the current sub-block
+    subblock_energy: f64,
+    subblock_len: usize,
+    subblock_pos: usize,
+    /// Mean square of the most recent sub-blocks, oldest first after `subblock_index`
+    subblocks: [f64; SHORT_TERM_SUBBLOCKS],
+    subblock_index: usize,
+    subblocks_seen: usize,
+    histogram: LoudnessHistogram,
+    momentary: f32,
+    short_term: f32,
+    integrated: f32,
+}
+
+impl LevelMeter {
+    /// Creates a new `LevelMeter`.
+    ///
+    /// # Arguments
+    /// * `sample_rate` - The sample rate of the metered audio.
+    /// * `channels` - Number of channels metered together.
+    /// * `settings` - Peak hold and clip settings.
+    ///
+    /// # Returns
+    /// * `LevelMeter` - A meter reading silence.
+    pub fn new(sample_rate: u32, channels: usize, settings: MeterSettings) -> Self {
+        let rate = sample_rate as f32;
+        Self {
+            sample_rate,
+            settings,
+            channels: vec![
+                ChannelState {
+                    k_weighting: KWeighting::new(sample_rate),
+                    true_peak: TruePeakDetector::new(),
+                    mean_square: 0.0,
+                };
+                channels.max(1)
+            ],
+            rms_coefficient: 1.0 - (-1.0 / (RMS_SECONDS * rate)).exp(),
+            fall_per_sample: 10f32.powf(-PEAK_FALL_DB_PER_SECOND / 20.0 / rate),
+            hold_samples: (settings.peak_hold * rate) as usize,
+            peak: 0.0,
+            peak_hold: 0.0,
+            hold_remaining: 0,
+            true_peak: 0.0,
+            max_true_peak: 0.0,
+            clipped: false,
+            subblock_energy: 0.0,
+            subblock_len: ((SUBBLOCK_SECONDS * sample_rate as f64) as usize).max(1),
+            subblock_pos: 0,
+            subblocks: [0.0; SHORT_TERM_SUBBLOCKS],
+            subblock_index: 0,
+            subblocks_seen: 0,
+            histogram: LoudnessHistogram::new(),
+            momentary: SILENCE_DB,
+            short_term: SILENCE_DB,
+            integrated: SILENCE_DB,
+        }
+    }
+
+    /// Gets the number of metered channels.
+    pub fn channels(&self) -> usize {
+        self.channels.len()
+    }
+
+    /// Gets the sample rate.
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Meters one block of audio.
+    ///
+    /// # Arguments
+    /// * `block` - One slice per channel, all of the same length; extra slices are ignored.
+    pub fn process(&mut self, block: &[&[f32]]) {
+        let frames = block.iter().map(|channel| channel.len()).min().unwrap_or(0);
+        let clip = 10f32.powf(self.settings.clip_level / 20.0);
+
+        for frame in 0..frames {
+            let mut frame_peak = 0.0f32;
+            let mut frame_true_peak = 0.0f32;
+            let mut energy = 0.0f64;
+            for (state, channel) in self.channels.iter_mut().zip(block) {
+                let sample = channel[frame];
+                frame_peak = frame_peak.max(sample.abs());
+                frame_true_peak = frame_true_peak.max(state.true_peak.process(sample));
+                state.mean_square += (sample * sample - state.mean_square) * self.rms_coefficient;
+                let weighted = state.k_weighting.process(sample);
+                energy += weighted * weighted;
+            }
+
+            self.peak = (self.peak * self.fall_per_sample).max(frame_peak);
+            if frame_peak >= self.peak_hold {
+                self.peak_hold = frame_peak;
+                self.hold_remaining = self.hold_samples;
+            } else if self.hold_remaining > 0 {
+                self.hold_remaining -= 1;
+            } else {
+                self.peak_hold *= self.fall_per_sample;
+            }
+            let true_peak = frame_true_peak.max(frame_peak);
+            self.true_peak = (self.true_peak * self.fall_per_sample).max(true_peak);
+            self.max_true_peak = self.max_true_peak.max(true_peak);
+            if frame_peak >= clip || true_peak >= clip {
+                self.clipped = true;
+            }
+
+            self.subblock_energy += energy;
+            self.subblock_pos += 1;
+            if self.subblock_pos == self.subblock_len {
+                self.finish_subblock();
+            }
+        }
+    }
+
+    /// Closes a 100 ms sub-block and updates the loudness measurements.
+    fn finish_subblock(&mut self) {
+        self.subblocks[self.subblock_index] = self.subblock_energy / self.subblock_len as f64;
+        self.subblock_index = (self.subblock_index + 1) % SHORT_TERM_SUBBLOCKS;
+        self.subblocks_seen += 1;
+        self.subblock_energy = 0.0;
+        self.subblock_pos = 0;
+
+        let recent = |count: usize| {
+            (1..=count)
+                .map(|age| self.subblocks[(self.subblock_index + SHORT_TERM_SUBBLOCKS - age) % SHORT_TERM_SUBBLOCKS])
+                .sum::<f64>()
+                / count as f64
+        };
+        let momentary = recent(MOMENTARY_SUBBLOCKS);
+        self.momentary = loudness(momentary) as f32;
+        self.short_term = loudness(recent(SHORT_TERM_SUBBLOCKS)) as f32;
+
+        // Gating blocks are 400 ms long and overlap by 75%
+        if self.subblocks_seen >= MOMENTARY_SUBBLOCKS {
+            self.histogram.add(momentary);
+            self.integrated = self.histogram.integrated() as f32;
+        }
+    }
+
+    /// Gets the current meter values.
+    pub fn reading(&self) -> MeterReading {
+        let rms = self
+            .channels
+            .iter()
+            .map(|state| state.mean_square)
+            .fold(0.0f32, f32::max)
+            .sqrt();
+        MeterReading {
+            peak: amplitude_to_db(self.peak),
+            peak_hold: amplitude_to_db(self.peak_hold),
+            true_peak: amplitude_to_db(self.true_peak),
+            max_true_peak: amplitude_to_db(self.max_true_peak),
+            rms: amplitude_to_db(rms),
+            momentary: self.momentary,
+            short_term: self.short_term,
+            integrated: self.integrated,
+            clipped: self.clipped,
+        }
+    }
+
+    /// Clears the clip indicator and the held peaks.
+    pub fn reset_clip(&mut self) {
+        self.clipped = false;
+        self.peak_hold = 0.0;
+        self.hold_remaining = 0;
+        self.max_true_peak = 0.0;
+    }
+
+    /// Restarts integrated loudness measurement.
+    pub fn reset_integrated(&mut self) {
+        self.histogram.clear();
+        self.integrated = SILENCE_DB;
+    }
+
+    /// Clears all meter state.
+    pub fn reset(&mut self) {
+        *self = Self::new(self.sample_rate, self.channels.len(), self.settings);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    fn sine(frequency: f32, amplitude: f32, sample_rate: u32, seconds: f32) -> Vec<f32> {
+        (0..(seconds * sample_rate as f32) as usize)
+            .map(|i| amplitude * (2.0 * PI * frequency * i as f32 / sample_rate as f32).sin())
+            .collect()
+    }
+
+    #[test]
+    fn test_reference_loudness() {
+        // BS.1770: a 0 dBFS 1 kHz sine in one channel reads -3.01 LUFS
+        let signal = sine(1000.0, 1.0, 48000, 5.0);
+        let mut meter = LevelMeter::new(48000, 1, MeterSettings::default());
+        for block in signal.chunks(512) {
+            meter.process(&[block]);
+        }
+        let reading = meter.reading();
+        assert!((reading.momentary + 3.01).abs() < 0.1, "{:?}", reading);
+        assert!((reading.short_term + 3.01).abs() < 0.1, "{:?}", reading);
+        assert!((reading.integrated + 3.01).abs() < 0.1, "{:?}", reading);
+        assert!((reading.rms + 3.01).abs() < 0.1, "{:?}", reading);
+        assert!(reading.clipped);
+    }
+
+    #[test]
+    fn test_gating_ignores_silence() {
+        let mut signal = sine(1000.0, 0.1, 48000, 3.0);
+        signal.extend(vec![0.0; 48000 * 3]);
+        let mut meter = LevelMeter::new(48000, 2, MeterSettings::default());
+        for block in signal.chunks(480) {
+            meter.process(&[block, block]);
+        }
+        // Two channels of -20 dBFS sine: -23.01 + 3.01 = -20 LUFS, less a little for the fade-out blocks
+        let reading = meter.reading();
+        assert!((reading.integrated + 20.0).abs() < 0.5, "{:?}", reading);
+        assert!(reading.momentary < -100.0);
+        assert!(!reading.clipped);
+    }
+
+    #[test]
+    fn test_true_peak_exceeds_sample_peak() {
+        // A quarter-sample-rate sine sampled at +-45 degrees peaks between samples
+        let signal: Vec<f32> = (0..4800)
+            .map(|i| 0.7 * (PI / 2.0 * i as f32 + PI / 4.0).sin())
+            .collect();
+        let mut meter = LevelMeter::new(48000, 1, MeterSettings::default());
+        meter.process(&[&signal]);
+        let reading = meter.reading();
+        assert!((reading.peak_hold - amplitude_to_db(0.7 * 0.5f32.sqrt())).abs() < 0.1);
+        assert!((reading.max_true_peak - amplitude_to_db(0.7)).abs() < 0.5, "{:?}", reading);
+    }
+}
@@ -1,3 +1,8 @@
 ﻿//! Audio analysis utilities
 pub mod bpm;
 pub mod fft;
+pub mod harmony;
+pub mod meter;
+pub mod onset;
+pub mod pitch;
+pub mod tuner;
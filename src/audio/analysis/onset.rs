@@ -0,0 +1,275 @@
+﻿//! Onset detection
+//!
+//! Marks transients in recorded audio. A detection function (spectral flux or
+//! high-frequency content) is computed per STFT frame, and peaks that rise
+//! above a moving median by a relative threshold are reported as onsets.
+//! Positions are refined to the sample where the attack starts.
+
+use crate::{
+    audio::analysis::fft::{magnitude_spectrum, Stft, StftConfig, WindowType},
+    core::buffer::AudioBuffer,
+    error::types::AudioError,
+};
+
+/// Detection function used to find onsets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnsetFunction {
+    /// Rise in log-magnitude across all bins; good for most material
+    SpectralFlux,
+    /// Frequency-weighted energy; favours percussive, noisy attacks
+    HighFrequencyContent,
+}
+
+/// Settings for onset detection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OnsetSettings {
+    /// Detection function
+    pub function: OnsetFunction,
+    /// Height above the local median, relative to the strongest peak, needed to count as an onset
+    pub threshold: f32,
+    /// Minimum time between onsets, in seconds
+    pub min_interval: f32,
+    /// Analysis frame length
+    pub frame_size: usize,
+    /// Distance between analysis frames
+    pub hop_size: usize,
+}
+
+impl Default for OnsetSettings {
+    fn default() -> Self {
+        Self {
+            function: OnsetFunction::SpectralFlux,
+            threshold: 0.1,
+            min_interval: 0.03,
+            frame_size: 1024,
+            hop_size: 256,
+        }
+    }
+}
+
+/// A detected transient.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Onset {
+    /// Sample position where the attack starts
+    pub position: usize,
+    /// Height of the detection peak, from 0.0 to 1.0 relative to the strongest onset
+    pub strength: f32,
+}
+
+/// Length of the moving median used as adaptive threshold, in seconds.
+const MEDIAN_SECONDS: f32 = 0.25;
+
+/// Fraction of the local peak level that marks the start of an attack.
+const ATTACK_LEVEL: f32 = 0.25;
+
+/// Onset detector for recorded audio.
+#[derive(Debug, Clone)]
+pub struct OnsetDetector {
+    sample_rate: u32,
+    settings: OnsetSettings,
+}
+
+impl OnsetDetector {
+    /// Creates a new `OnsetDetector`.
+    ///
+    /// # Arguments
+    /// * `sample_rate` - The sample rate of the audio to analyse.
+    /// * `settings` - Detection function, threshold and framing.
+    ///
+    /// # Returns
+    /// * `OnsetDetector` - A new detector.
+    pub fn new(sample_rate: u32, settings: OnsetSettings) -> Self {
+        Self { sample_rate, settings }
+    }
+
+    /// Gets the current settings.
+    pub fn settings(&self) -> &OnsetSettings {
+        &self.settings
+    }
+
+    /// Updates the settings.
+    pub fn set_settings(&mut self, settings: OnsetSettings) {
+        self.settings = settings;
+    }
+
+    /// Detects onsets in a multi-channel `AudioBuffer`, analysing the channels mixed to mono.
+    pub fn detect_buffer(&self, buffer: &AudioBuffer) -> Result<Vec<Onset>, AudioError> {
+        self.detect_channels(buffer.samples())
+    }
+
+    /// Detects onsets in multi-channel audio, analysing the channels mixed to mono.
+    pub fn detect_channels(&self, channels: &[Vec<f32>]) -> Result<Vec<Onset>, AudioError> {
+        let len = channels.iter().map(|c| c.len()).min().unwrap_or(0);
+        let mono: Vec<f32> = (0..len).map(|i| channels.iter().map(|c| c[i]).sum()).collect();
+        self.detect(&mono)
+    }
+
+    /// Detects onsets in a mono signal.
+    ///
+    /// # Arguments
+    /// * `samples` - The audio to analyse.
+    ///
+    /// # Returns
+    /// * `Result<Vec<Onset>, AudioError>` - Onsets in ascending order of position.
+    pub fn detect(&self, samples: &[f32]) -> Result<Vec<Onset>, AudioError> {
+        let function = self.detection_function(samples)?;
+        let peak = function.iter().copied().fold(0.0f32, f32::max);
+        if peak <= f32::EPSILON {
+            return Ok(Vec::new());
+        }
+
+        let hop = self.settings.hop_size;
+        let radius = ((MEDIAN_SECONDS * self.sample_rate as f32 / hop as f32) as usize / 2).max(1);
+        let min_frames = ((self.settings.min_interval * self.sample_rate as f32 / hop as f32) as usize).max(1);
+        let mut window = Vec::with_capacity(2 * radius + 1);
+        let mut onsets: Vec<Onset> = Vec::new();
+        let mut last_frame: Option<usize> = None;
+
+        // Frames reaching past the end see the cut itself as a transient
+        let last_valid = samples.len().saturating_sub(self.settings.frame_size / 2) / hop;
+        for i in 0..function.len().min(last_valid + 1) {
+            let value = function[i];
+            let is_local_max = (i == 0 || value > function[i - 1])
+                && (i + 1 == function.len() || value >= function[i + 1]);
+            if !is_local_max {
+                continue;
+            }
+
+            window.clear();
+            window.extend_from_slice(&function[i.saturating_sub(radius)..(i + radius + 1).min(function.len())]);
+            window.sort_by(f32::total_cmp);
+            let median = window[window.len() / 2];
+            if value - median < self.settings.threshold * peak {
+                continue;
+            }
+
+            let strength = value / peak;
+            if last_frame.is_some_and(|last| i - last < min_frames) {
+                // Keep the stronger of two peaks that are too close together
+                if onsets.last().is_some_and(|onset| onset.strength >= strength) {
+                    continue;
+                }
+                onsets.pop();
+            }
+            onsets.push(Onset {
+                position: refine_attack(samples, i * hop, hop, self.settings.frame_size / 2),
+                strength,
+            });
+            last_frame = Some(i);
+        }
+
+        Ok(onsets)
+    }
+
+    /// Computes the detection function, one value per hop.
+    pub fn detection_function(&self, samples: &[f32]) -> Result<Vec<f32>, AudioError> {
+        let mut stft = Stft::new(StftConfig {
+            frame_size: self.settings.frame_size,
+            hop_size: self.settings.hop_size,
+            window: WindowType::Hann,
+        })?;
+        let spectrogram = stft.analyze(samples)?;
+        let mut magnitudes = vec![0.0f32; self.settings.frame_size / 2 + 1];
+        let mut previous = vec![0.0f32; magnitudes.len()];
+
+        Ok(spectrogram
+            .frames
+            .iter()
+            .enumerate()
+            .map(|(index, spectrum)| {
+                magnitude_spectrum(spectrum, &mut magnitudes);
+                match self.settings.function {
+                    OnsetFunction::SpectralFlux => {
+                        let mut flux = 0.0;
+                        for (magnitude, previous) in magnitudes.iter().zip(previous.iter_mut()) {
+                            let compressed = magnitude.ln_1p();
+                            flux += (compressed - *previous).max(0.0);
+                            *previous = compressed;
+                        }
+                        if index == 0 { 0.0 } else { flux }
+                    }
+                    OnsetFunction::HighFrequencyContent => {
+                        let hfc: f32 = magnitudes
+                            .iter()
+                            .enumerate()
+                            .map(|(bin, magnitude)| bin as f32 * magnitude * magnitude)
+                            .sum();
+                        let rise = (hfc - previous[0]).max(0.0);
+                        previous[0] = hfc;
+                        rise
+                    }
+                }
+            })
+            .collect())
+    }
+}
+
+/// Moves a frame-level onset to the first sample reaching a fraction of the local peak.
+///
+/// A centred frame responds once the attack enters its second half, so the
+/// search extends half a frame past the frame centre.
+fn refine_attack(samples: &[f32], position: usize, hop: usize, half_frame: usize) -> usize {
+    let start = position.saturating_sub(hop).min(samples.len());
+    let end = (position + half_frame).min(samples.len());
+    let peak = samples[start..end].iter().fold(0.0f32, |max, s| max.max(s.abs()));
+    samples[start..end]
+        .iter()
+        .position(|s| s.abs() >= peak * ATTACK_LEVEL)
+        .map_or(position.min(samples.len()), |offset| start + offset)
+}
+
+/// Finds the zero crossing closest to `position` within `radius` samples.
+///
+/// # Arguments
+/// * `samples` - The signal to search.
+/// * `position` - The intended cut point.
+/// * `radius` - How far to search on either side.
+///
+/// # Returns
+/// * `usize` - The first sample after the nearest sign change, or `position` if there is none.
+pub fn nearest_zero_crossing(samples: &[f32], position: usize, radius: usize) -> usize {
+    let crosses = |i: usize| i > 0 && i < samples.len() && (samples[i - 1] <= 0.0) != (samples[i] <= 0.0);
+    (0..=radius)
+        .flat_map(|distance| [position.checked_sub(distance), Some(position + distance)])
+        .flatten()
+        .find(|&i| crosses(i))
+        .unwrap_or(position)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone_bursts(len: usize, positions: &[usize], sample_rate: u32) -> Vec<f32> {
+        let mut out = vec![0.0; len];
+        for &pos in positions {
+            for i in 0..(sample_rate as usize / 5).min(len - pos) {
+                let t = i as f32 / sample_rate as f32;
+                out[pos + i] += (2.0 * std::f32::consts::PI * 440.0 * t).sin() * (-t * 30.0).exp();
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_detects_bursts() {
+        let positions = [4410, 20000, 31000, 40000];
+        let signal = tone_bursts(44100, &positions, 44100);
+        for function in [OnsetFunction::SpectralFlux, OnsetFunction::HighFrequencyContent] {
+            let detector = OnsetDetector::new(44100, OnsetSettings { function, ..Default::default() });
+            let onsets = detector.detect(&signal).unwrap();
+            assert_eq!(onsets.len(), positions.len(), "{:?}: {:?}", function, onsets);
+            for (onset, &expected) in onsets.iter().zip(&positions) {
+                assert!(onset.position.abs_diff(expected) < 64, "{:?}: {:?}", function, onsets);
+            }
+        }
+    }
+
+    #[test]
+    fn test_nearest_zero_crossing() {
+        let signal: Vec<f32> = (0..100).map(|i| (i as f32 * 0.1).sin()).collect();
+        // sin crosses zero between samples 31 and 32 (x = pi)
+        assert_eq!(nearest_zero_crossing(&signal, 28, 8), 32);
+        assert_eq!(nearest_zero_crossing(&signal, 10, 4), 10);
+    }
+}
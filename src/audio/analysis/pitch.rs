@@ -0,0 +1,313 @@
+﻿//! Monophonic pitch detection
+//!
+//! Implements the McLeod Pitch Method: the normalized square difference
+//! function (NSDF) is built from an FFT autocorrelation, and the first "key
+//! maximum" close to the highest one gives the period. This avoids the octave
+//! errors of picking the global autocorrelation peak. Buffers are allocated
+//! up front so detection can run on the audio thread.
+
+use std::sync::Arc;
+
+use realfft::{ComplexToReal, RealToComplex};
+
+use crate::{
+    audio::analysis::fft::{plan_forward, plan_inverse, Complex},
+    error::types::AudioError,
+};
+
+/// Names of the twelve pitch classes, starting at C.
+pub const NOTE_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+/// Fraction of the highest key maximum the chosen peak must reach.
+const KEY_MAXIMUM_RATIO: f32 = 0.93;
+
+/// A detected pitch.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PitchEstimate {
+    /// Fundamental frequency in Hz
+    pub frequency: f32,
+    /// How periodic the frame is, from 0.0 (noise) to 1.0 (perfectly periodic)
+    pub clarity: f32,
+}
+
+/// A frequency expressed as the nearest equal-tempered note.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Note {
+    /// MIDI note number; 69 is the reference A
+    pub midi: i32,
+    /// Deviation from the note, in cents
+    pub cents: f32,
+}
+
+impl Note {
+    /// Finds the nearest note to a frequency.
+    ///
+    /// # Arguments
+    /// * `frequency` - The frequency in Hz.
+    /// * `reference_a` - Frequency of A4 in Hz.
+    ///
+    /// # Returns
+    /// * `Note` - The nearest note and the deviation from it.
+    pub fn from_frequency(frequency: f32, reference_a: f32) -> Self {
+        let semitones = 12.0 * (frequency / reference_a).log2() + 69.0;
+        let midi = semitones.round();
+        Self {
+            midi: midi as i32,
+            cents: (semitones - midi) * 100.0,
+        }
+    }
+
+    /// Gets the pitch class name, e.g. "F#".
+    pub fn name(&self) -> &'static str {
+        NOTE_NAMES[self.midi.rem_euclid(12) as usize]
+    }
+
+    /// Gets the octave in scientific pitch notation; A4 is the reference A.
+    pub fn octave(&self) -> i32 {
+        self.midi.div_euclid(12) - 1
+    }
+
+    /// Gets the exact frequency of the note.
+    pub fn frequency(&self, reference_a: f32) -> f32 {
+        reference_a * 2f32.powf((self.midi - 69) as f32 / 12.0)
+    }
+}
+
+/// McLeod pitch detector for fixed-size frames.
+pub struct PitchDetector {
+    sample_rate: u32,
+    frame_size: usize,
+    min_frequency: f32,
+    max_frequency: f32,
+    /// Frames below this RMS level are treated as silence
+    silence_threshold: f32,
+    forward: Arc<dyn RealToComplex<f32>>,
+    inverse: Arc<dyn ComplexToReal<f32>>,
+    padded: Vec<f32>,
+    spectrum: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+    nsdf: Vec<f32>,
+}
+
+impl PitchDetector {
+    /// Creates a new `PitchDetector`.
+    ///
+    /// # Arguments
+    /// * `sample_rate` - The sample rate of the audio to analyse.
+    /// * `frame_size` - Samples per analysis frame; must span at least two periods of the lowest pitch.
+    ///
+    /// # Returns
+    /// * `PitchDetector` - A detector covering 40 Hz to 2 kHz.
+    pub fn new(sample_rate: u32, frame_size: usize) -> Self {
+        let len = (frame_size * 2).next_power_of_two();
+        let forward = plan_forward(len);
+        let inverse = plan_inverse(len);
+        let scratch_len = forward.get_scratch_len().max(inverse.get_scratch_len());
+        Self {
+            sample_rate,
+            frame_size,
+            min_frequency: 40.0,
+            max_frequency: 2000.0,
+            silence_threshold: 0.001,
+            padded: forward.make_input_vec(),
+            spectrum: forward.make_output_vec(),
+            scratch: vec![Complex::default(); scratch_len],
+            nsdf: vec![0.0; frame_size],
+            forward,
+            inverse,
+        }
+    }
+
+    /// Gets the frame size.
+    pub fn frame_size(&self) -> usize {
+        self.frame_size
+    }
+
+    /// Sets the frequency range searched, in Hz.
+    pub fn set_range(&mut self, min_frequency: f32, max_frequency: f32) {
+        self.min_frequency = min_frequency.max(1.0);
+        self.max_frequency = max_frequency.max(self.min_frequency);
+    }
+
+    /// Gets the frequency range searched, in Hz.
+    pub fn range(&self) -> (f32, f32) {
+        (self.min_frequency, self.max_frequency)
+    }
+
+    /// Sets the RMS level below which frames are treated as silence.
+    pub fn set_silence_threshold(&mut self, threshold: f32) {
+        self.silence_threshold = threshold;
+    }
+
+    /// Detects the pitch of one frame.
+    ///
+    /// # Arguments
+    /// * `frame` - Exactly `frame_size` samples.
+    ///
+    /// # Returns
+    /// * `Result<Option<PitchEstimate>, AudioError>` - The pitch, or `None` for silence and unpitched frames.
+    pub fn detect(&mut self, frame: &[f32]) -> Result<Option<PitchEstimate>, AudioError> {
+        let n = self.frame_size;
+        if frame.len() != n {
+            return Err(AudioError::BufferMismatch);
+        }
+        let energy: f32 = frame.iter().map(|s| s * s).sum();
+        if (energy / n as f32).sqrt() < self.silence_threshold {
+            return Ok(None);
+        }
+
+        // Autocorrelation r(tau) through the power spectrum of the zero-padded frame
+        self.padded.fill(0.0);
+        self.padded[..n].copy_from_slice(frame);
+        self.forward
+            .process_with_scratch(&mut self.padded, &mut self.spectrum, &mut self.scratch)
+            .map_err(|e| AudioError::BufferError(e.to_string()))?;
+        for bin in self.spectrum.iter_mut() {
+            *bin = Complex::new(bin.norm_sqr(), 0.0);
+        }
+        self.inverse
+            .process_with_scratch(&mut self.spectrum, &mut self.padded, &mut self.scratch)
+            .map_err(|e| AudioError::BufferError(e.to_string()))?;
+        let scale = 1.0 / self.padded.len() as f32;
+
+        // m(tau) = sum of x[j]^2 + x[j + tau]^2 over the overlap, updated incrementally
+        let mut m = 2.0 * energy;
+        for tau in 0..n {
+            if tau > 0 {
+                m -= frame[tau - 1] * frame[tau - 1] + frame[n - tau] * frame[n - tau];
+            }
+            self.nsdf[tau] = if m > f32::EPSILON { 2.0 * self.padded[tau] * scale / m } else { 0.0 };
+        }
+
+        let min_lag = ((self.sample_rate as f32 / self.max_frequency) as usize).max(1);
+        let max_lag = ((self.sample_rate as f32 / self.min_frequency).ceil() as usize).min(n - 2);
+        let Some((lag, clarity)) = self.pick_key_maximum(min_lag, max_lag) else {
+            return Ok(None);
+        };
+        Ok(Some(PitchEstimate {
+            frequency: self.sample_rate as f32 / lag,
+            clarity,
+        }))
+    }
+
+    /// Finds the first key maximum above `KEY_MAXIMUM_RATIO` of the highest one.
+    ///
+    /// A key maximum is the highest NSDF value between a positive-going zero
+    /// crossing and the next negative-going one.
+    fn pick_key_maximum(&self, min_lag: usize, max_lag: usize) -> Option<(f32, f32)> {
+        let nsdf = &self.nsdf;
+        let mut maxima: [(usize, f32); 32] = [(0, 0.0); 32];
+        let mut count = 0;
+        let mut current: Option<(usize, f32)> = None;
+
+        // Skip the lobe around lag zero
+        let mut tau = 1;
+        while tau < nsdf.len() && nsdf[tau] > 0.0 {
+            tau += 1;
+        }
+        while tau <= max_lag + 1 && tau < nsdf.len() {
+            let value = nsdf[tau];
+            if nsdf[tau - 1] <= 0.0 && value > 0.0 {
+                current = Some((tau, value));
+            } else if nsdf[tau - 1] > 0.0 && value <= 0.0 {
+                if let Some(peak) = current.take() {
+                    if count < maxima.len() {
+                        maxima[count] = peak;
+                        count += 1;
+                    }
+                }
+            } else if let Some((_, best)) = &mut current {
+                if value > *best {
+                    current = Some((tau, value));
+                }
+            }
+            tau += 1;
+        }
+        if let Some(peak) = current {
+            if count < maxima.len() {
+                maxima[count] = peak;
+                count += 1;
+            }
+        }
+
+        let maxima = &maxima[..count];
+        let highest = maxima.iter().map(|m| m.1).fold(0.0f32, f32::max);
+        let &(lag, _) = maxima
+            .iter()
+            .find(|&&(lag, value)| lag >= min_lag && lag <= max_lag && value >= KEY_MAXIMUM_RATIO * highest)?;
+
+        // Parabolic interpolation around the chosen lag
+        let (a, b, c) = (nsdf[lag - 1], nsdf[lag], nsdf[lag + 1]);
+        let denominator = a - 2.0 * b + c;
+        let (offset, value) = if denominator.abs() > f32::EPSILON {
+            let offset = 0.5 * (a - c) / denominator;
+            (offset, b - 0.25 * (a - c) * offset)
+        } else {
+            (0.0, b)
+        };
+        Some((lag as f32 + offset, value.min(1.0)))
+    }
+}
+
+impl std::fmt::Debug for PitchDetector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PitchDetector")
+            .field("sample_rate", &self.sample_rate)
+            .field("frame_size", &self.frame_size)
+            .field("min_frequency", &self.min_frequency)
+            .field("max_frequency", &self.max_frequency)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    /// Sawtooth-like tone with a weak fundamental, prone to octave errors.
+    fn tone(frequency: f32, sample_rate: u32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| {
+                let t = i as f32 / sample_rate as f32;
+                (1..=8)
+                    .map(|h| {
+                        let weight = if h == 1 { 0.3 } else { 1.0 / h as f32 };
+                        weight * (2.0 * PI * frequency * h as f32 * t).sin()
+                    })
+                    .sum::<f32>()
+                    * 0.3
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_detects_guitar_and_bass_range() {
+        let sample_rate = 48000;
+        let mut detector = PitchDetector::new(sample_rate, 4096);
+        for frequency in [41.2, 82.41, 110.0, 196.0, 329.63, 440.0, 1046.5] {
+            let estimate = detector.detect(&tone(frequency, sample_rate, 4096)).unwrap().unwrap();
+            let cents = 1200.0 * (estimate.frequency / frequency).log2();
+            assert!(cents.abs() < 2.0, "{} Hz read as {:?}", frequency, estimate);
+            assert!(estimate.clarity > 0.9);
+        }
+        assert_eq!(detector.detect(&vec![0.0; 4096]).unwrap(), None);
+    }
+
+    #[test]
+    fn test_note_from_frequency() {
+        let note = Note::from_frequency(440.0, 440.0);
+        assert_eq!((note.name(), note.octave()), ("A", 4));
+        assert!(note.cents.abs() < 1e-3);
+
+        let note = Note::from_frequency(82.0, 440.0);
+        assert_eq!((note.name(), note.octave()), ("E", 2));
+        assert!((note.cents + 8.65).abs() < 0.1);
+
+        // Tuned to A = 432 Hz, 440 Hz is sharp
+        let note = Note::from_frequency(440.0, 432.0);
+        assert_eq!(note.name(), "A");
+        assert!((note.cents - 31.77).abs() < 0.1);
+        assert!((note.frequency(432.0) - 432.0).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,240 @@
+﻿//! Chromatic tuner
+//!
+//! Collects one input channel into a sliding window, runs pitch detection
+//! several times per frame length and reports the nearest note with its
+//! deviation in cents. Readings are smoothed while the note stays the same
+//! and held briefly after the string stops ringing.
+
+use crate::{
+    audio::analysis::pitch::{Note, PitchDetector},
+    error::types::AudioError,
+};
+
+/// Analyses per frame length.
+const OVERLAP: usize = 4;
+
+/// Lowest clarity accepted as a pitched note.
+const MIN_CLARITY: f32 = 0.8;
+
+/// How long the last reading is shown after the pitch is lost, in seconds.
+const HOLD_SECONDS: f32 = 0.5;
+
+/// Weight of a new reading when the note has not changed.
+const SMOOTHING: f32 = 0.4;
+
+/// Settings for the chromatic tuner.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TunerSettings {
+    /// Frequency of A4 in Hz
+    pub reference_a: f32,
+    /// Input channel to tune from
+    pub input_channel: usize,
+    /// Whether the outputs are silenced while the tuner is on
+    pub mute_outputs: bool,
+    /// Lowest frequency detected, in Hz
+    pub min_frequency: f32,
+    /// Highest frequency detected, in Hz
+    pub max_frequency: f32,
+}
+
+impl Default for TunerSettings {
+    fn default() -> Self {
+        Self {
+            reference_a: 440.0,
+            input_channel: 0,
+            mute_outputs: true,
+            min_frequency: 30.0,
+            max_frequency: 1500.0,
+        }
+    }
+}
+
+/// What the tuner displays.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TunerReading {
+    /// Pitch class name, e.g. "F#"
+    pub note: &'static str,
+    /// Octave in scientific pitch notation
+    pub octave: i32,
+    /// Deviation from the note, from -50 to +50 cents
+    pub cents: f32,
+    /// Detected frequency in Hz
+    pub frequency: f32,
+    /// Periodicity of the signal, from 0.0 to 1.0
+    pub clarity: f32,
+}
+
+/// Chromatic tuner fed from the audio thread.
+#[derive(Debug)]
+pub struct Tuner {
+    settings: TunerSettings,
+    enabled: bool,
+    detector: PitchDetector,
+    /// Sliding window of the most recent input
+    window: Vec<f32>,
+    /// Samples received since the last analysis
+    pending: usize,
+    hop: usize,
+    hold_samples: usize,
+    held_for: usize,
+    reading: Option<TunerReading>,
+}
+
+impl Tuner {
+    /// Creates a new `Tuner`, switched off.
+    ///
+    /// # Arguments
+    /// * `sample_rate` - The sample rate of the input.
+    /// * `settings` - Reference pitch, input and range.
+    ///
+    /// # Returns
+    /// * `Tuner` - A tuner with a window spanning two periods of the lowest frequency.
+    pub fn new(sample_rate: u32, settings: TunerSettings) -> Self {
+        let frame_size = frame_size_for(sample_rate, settings.min_frequency);
+        let mut detector = PitchDetector::new(sample_rate, frame_size);
+        detector.set_range(settings.min_frequency, settings.max_frequency);
+        Self {
+            settings,
+            enabled: false,
+            detector,
+            window: vec![0.0; frame_size],
+            pending: 0,
+            hop: frame_size / OVERLAP,
+            hold_samples: (HOLD_SECONDS * sample_rate as f32) as usize,
+            held_for: 0,
+            reading: None,
+        }
+    }
+
+    /// Gets the current settings.
+    pub fn settings(&self) -> &TunerSettings {
+        &self.settings
+    }
+
+    /// Updates the settings.
+    ///
+    /// Changing the lowest frequency reallocates the analysis window, so do it
+    /// outside the audio thread.
+    pub fn set_settings(&mut self, settings: TunerSettings, sample_rate: u32) {
+        if frame_size_for(sample_rate, settings.min_frequency) != self.window.len() {
+            let enabled = self.enabled;
+            *self = Self::new(sample_rate, settings);
+            self.enabled = enabled;
+        } else {
+            self.detector.set_range(settings.min_frequency, settings.max_frequency);
+            self.settings = settings;
+        }
+    }
+
+    /// Whether the tuner is on.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Switches the tuner on or off, clearing the reading.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.window.fill(0.0);
+        self.pending = 0;
+        self.reading = None;
+    }
+
+    /// Whether the outputs should currently be silenced.
+    pub fn mutes_outputs(&self) -> bool {
+        self.enabled && self.settings.mute_outputs
+    }
+
+    /// Gets the latest reading, or `None` if no pitch is heard.
+    pub fn reading(&self) -> Option<TunerReading> {
+        self.reading
+    }
+
+    /// Feeds one block of the tuned input channel.
+    pub fn process(&mut self, input: &[f32]) -> Result<(), AudioError> {
+        if !self.enabled {
+            return Ok(());
+        }
+        for chunk in input.chunks(self.hop) {
+            let len = self.window.len();
+            self.window.copy_within(chunk.len().., 0);
+            self.window[len - chunk.len()..].copy_from_slice(chunk);
+            self.pending += chunk.len();
+            if self.pending >= self.hop {
+                self.analyze(self.pending)?;
+                self.pending = 0;
+            }
+        }
+        Ok(())
+    }
+
+    fn analyze(&mut self, elapsed: usize) -> Result<(), AudioError> {
+        let estimate = self
+            .detector
+            .detect(&self.window)?
+            .filter(|estimate| estimate.clarity >= MIN_CLARITY);
+        let Some(estimate) = estimate else {
+            self.held_for += elapsed;
+            if self.held_for >= self.hold_samples {
+                self.reading = None;
+            }
+            return Ok(());
+        };
+        self.held_for = 0;
+
+        let mut frequency = estimate.frequency;
+        let reference_a = self.settings.reference_a;
+        if let Some(previous) = self.reading {
+            let same_note = Note::from_frequency(previous.frequency, reference_a).midi
+                == Note::from_frequency(frequency, reference_a).midi;
+            if same_note {
+                // Smooth on a log scale so the cents readout settles evenly
+                frequency = previous.frequency * (frequency / previous.frequency).powf(SMOOTHING);
+            }
+        }
+        let note = Note::from_frequency(frequency, reference_a);
+        self.reading = Some(TunerReading {
+            note: note.name(),
+            octave: note.octave(),
+            cents: note.cents,
+            frequency,
+            clarity: estimate.clarity,
+        });
+        Ok(())
+    }
+}
+
+/// Power-of-two frame spanning at least two periods of `min_frequency`.
+fn frame_size_for(sample_rate: u32, min_frequency: f32) -> usize {
+    ((2.0 * sample_rate as f32 / min_frequency.max(1.0)).ceil() as usize)
+        .next_power_of_two()
+        .max(256)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reads_note_and_cents() {
+        let sample_rate = 44100;
+        let mut tuner = Tuner::new(sample_rate, TunerSettings::default());
+        let signal: Vec<f32> = (0..sample_rate as usize / 2)
+            .map(|i| 0.2 * (2.0 * std::f32::consts::PI * 442.0 * i as f32 / sample_rate as f32).sin())
+            .collect();
+
+        tuner.process(&signal).unwrap();
+        assert_eq!(tuner.reading(), None);
+
+        tuner.set_enabled(true);
+        for block in signal.chunks(256) {
+            tuner.process(block).unwrap();
+        }
+        let reading = tuner.reading().unwrap();
+        assert_eq!((reading.note, reading.octave), ("A", 4));
+        assert!((reading.cents - 7.85).abs() < 1.0, "{:?}", reading);
+
+        // The reading is dropped once the hold time has passed in silence
+        tuner.process(&vec![0.0; sample_rate as usize]).unwrap();
+        assert_eq!(tuner.reading(), None);
+    }
+}
@@ -0,0 +1,245 @@
+﻿//! Auto-filter implementation
+//!
+//! A resonant `Filter` whose cutoff is swept by an `Lfo` or by the input's
+//! envelope (auto-wah). Sweeps are exponential: the modulation moves the
+//! cutoff up to `depth` octaves above the base frequency. The cutoff is
+//! recomputed every `CONTROL_INTERVAL` samples.
+
+use std::sync::Arc;
+
+use crate::{
+    audio::effects::{
+        filter::{Filter, FilterParams, FilterType, Slope, CONTROL_INTERVAL},
+        lfo::{Lfo, LfoRate, LfoShape},
+        time_coefficient, AudioEffect,
+    },
+    error::types::AudioError,
+    sync::clock::MasterClock,
+};
+
+/// Signal moving the cutoff.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterModulation {
+    /// The LFO, at `rate` with `shape`
+    Lfo,
+    /// The input level, scaled by `sensitivity` (auto-wah)
+    Envelope,
+}
+
+/// Settings for the auto-filter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AutoFilterSettings {
+    /// Low-pass, band-pass or high-pass response
+    pub filter_type: FilterType,
+    /// Roll-off of low- and high-pass responses
+    pub slope: Slope,
+    /// Cutoff with no modulation, in Hz
+    pub frequency: f32,
+    /// Resonance (Q)
+    pub resonance: f32,
+    /// Modulation source
+    pub modulation: FilterModulation,
+    /// Largest upward sweep, in octaves
+    pub depth: f32,
+    /// LFO rate
+    pub rate: LfoRate,
+    /// LFO waveform
+    pub shape: LfoShape,
+    /// Envelope gain; higher values open the filter on quieter playing
+    pub sensitivity: f32,
+    /// Envelope rise time, in seconds
+    pub attack: f32,
+    /// Envelope fall time, in seconds
+    pub release: f32,
+    /// Level of the filtered signal against the dry signal, from 0.0 to 1.0
+    pub mix: f32,
+}
+
+impl Default for AutoFilterSettings {
+    fn default() -> Self {
+        Self {
+            filter_type: FilterType::BandPass,
+            slope: Slope::Db12,
+            frequency: 350.0,
+            resonance: 4.0,
+            modulation: FilterModulation::Envelope,
+            depth: 3.0,
+            rate: LfoRate::Hertz(1.0),
+            shape: LfoShape::Sine,
+            sensitivity: 4.0,
+            attack: 0.005,
+            release: 0.12,
+            mix: 1.0,
+        }
+    }
+}
+
+/// Resonant filter swept by an LFO or envelope follower.
+#[derive(Debug, Clone)]
+pub struct AutoFilter {
+    sample_rate: u32,
+    settings: AutoFilterSettings,
+    filter: Filter,
+    lfo: Lfo,
+    envelope: f32,
+    attack: f32,
+    release: f32,
+    countdown: usize,
+}
+
+impl AutoFilter {
+    /// Creates a new `AutoFilter` with default (auto-wah) settings.
+    ///
+    /// # Arguments
+    /// * `sample_rate` - The sample rate of the audio.
+    ///
+    /// # Returns
+    /// * `AutoFilter` - A filter resting at the base frequency.
+    pub fn new(sample_rate: u32) -> Self {
+        let settings = AutoFilterSettings::default();
+        let mut auto_filter = Self {
+            sample_rate,
+            settings,
+            filter: Filter::new(sample_rate, FilterParams::default()),
+            lfo: Lfo::new(sample_rate),
+            envelope: 0.0,
+            attack: 0.0,
+            release: 0.0,
+            countdown: 0,
+        };
+        auto_filter.set_settings(settings);
+        auto_filter.filter.jump_to_target();
+        auto_filter
+    }
+
+    /// Gets the current settings.
+    pub fn settings(&self) -> &AutoFilterSettings {
+        &self.settings
+    }
+
+    /// Updates the settings.
+    pub fn set_settings(&mut self, settings: AutoFilterSettings) {
+        self.settings = AutoFilterSettings {
+            depth: settings.depth.max(0.0),
+            sensitivity: settings.sensitivity.max(0.0),
+            mix: settings.mix.clamp(0.0, 1.0),
+            ..settings
+        };
+        self.attack = time_coefficient(self.settings.attack, self.sample_rate);
+        self.release = time_coefficient(self.settings.release, self.sample_rate);
+        self.lfo.set(self.settings.shape, self.settings.rate);
+        self.filter.set_params(FilterParams {
+            filter_type: self.settings.filter_type,
+            frequency: self.filter.current_frequency(),
+            q: self.settings.resonance,
+            gain: 0.0,
+            slope: self.settings.slope,
+        });
+    }
+
+    /// Locks note-value LFO rates to a clock's tempo.
+    pub fn set_clock(&mut self, clock: Option<Arc<MasterClock>>) {
+        self.lfo.set_clock(clock);
+    }
+
+    /// Gets the cutoff currently heard, in Hz.
+    pub fn cutoff(&self) -> f32 {
+        self.filter.current_frequency()
+    }
+
+    /// Clears the filter and envelope state.
+    pub fn reset(&mut self) {
+        self.filter.reset();
+        self.envelope = 0.0;
+        self.lfo.reset();
+    }
+
+    /// Recomputes the cutoff from the modulation source.
+    fn update_cutoff(&mut self) {
+        let amount = match self.settings.modulation {
+            FilterModulation::Lfo => {
+                // A quarter cycle in, so the sweep starts from the base frequency
+                let value = self.lfo.value_at(0.75);
+                self.lfo.advance(CONTROL_INTERVAL);
+                0.5 + 0.5 * value
+            }
+            FilterModulation::Envelope => (self.envelope * self.settings.sensitivity).min(1.0),
+        };
+        self.filter
+            .set_modulated_frequency(self.settings.frequency * 2f32.powf(self.settings.depth * amount));
+    }
+
+    /// Filters a buffer in place.
+    ///
+    /// # Arguments
+    /// * `buffer` - The audio to process.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - Always `Ok(())`.
+    pub fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        let mix = self.settings.mix;
+        self.lfo.update();
+        for sample in buffer.iter_mut() {
+            let level = sample.abs();
+            let coefficient = if level > self.envelope { self.attack } else { self.release };
+            self.envelope += (level - self.envelope) * coefficient;
+
+            if self.countdown == 0 {
+                self.update_cutoff();
+                self.countdown = CONTROL_INTERVAL;
+            }
+            self.countdown -= 1;
+
+            let filtered = self.filter.process_sample(*sample);
+            *sample += (filtered - *sample) * mix;
+        }
+        Ok(())
+    }
+}
+
+impl AudioEffect for AutoFilter {
+    fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        AutoFilter::process(self, buffer)
+    }
+
+    fn reset(&mut self) {
+        AutoFilter::reset(self)
+    }
+
+    fn on_loop_start(&mut self) {
+        self.lfo.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_envelope_and_lfo_sweep_the_cutoff() {
+        let mut wah = AutoFilter::new(48000);
+        let mut quiet = vec![0.0; 4800];
+        wah.process(&mut quiet).unwrap();
+        assert!((wah.cutoff() - 350.0).abs() < 1.0);
+
+        // A loud note opens the filter the full three octaves, then it closes again
+        let mut note: Vec<f32> = (0..4800).map(|i| 0.5 * (i as f32 * 0.05).sin()).collect();
+        wah.process(&mut note).unwrap();
+        assert!((wah.cutoff() - 2800.0).abs() < 50.0, "{}", wah.cutoff());
+        for _ in 0..5 {
+            wah.process(&mut quiet).unwrap();
+        }
+        assert!(wah.cutoff() < 400.0, "{}", wah.cutoff());
+
+        // One LFO cycle reaches the top halfway through and returns
+        wah.set_settings(AutoFilterSettings { modulation: FilterModulation::Lfo, rate: LfoRate::Hertz(2.0), depth: 2.0, ..*wah.settings() });
+        wah.reset();
+        let mut highest: f32 = 0.0;
+        for block in vec![0.0; 24000].chunks_mut(480) {
+            wah.process(block).unwrap();
+            highest = highest.max(wah.cutoff());
+        }
+        assert!((highest - 1400.0).abs() < 10.0, "{}", highest);
+        assert!(wah.cutoff() < 360.0, "{}", wah.cutoff());
+    }
+}
@@ -0,0 +1,334 @@
+﻿//! Beat repeat (stutter) effect implementation
+//!
+//! While engaged, the effect waits for the next grid line of the slice
+//! length on the `MasterClock`, captures one slice of the passing audio and
+//! then replays it from every following grid line until released. Each
+//! repeat can be quieter and pitched further than the one before, and short
+//! fades keep the slice edges and the hand-over to the live signal clean.
+
+use std::sync::Arc;
+
+use crate::{
+    audio::effects::{
+        delay::{NoteDivision, NoteModifier, NoteValue},
+        AudioEffect,
+    },
+    error::types::AudioError,
+    sync::clock::MasterClock,
+};
+
+/// Longest slice that can be captured, in seconds.
+pub const MAX_SLICE_SECONDS: f32 = 4.0;
+
+/// Largest pitch change per repeat, in semitones.
+pub const MAX_PITCH_STEP: f32 = 12.0;
+
+/// Length of the fades at slice edges and on engage and release, in seconds.
+const FADE_SECONDS: f32 = 0.002;
+
+/// Settings for the beat repeat.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BeatRepeatSettings {
+    /// Length of the captured slice and of the grid it repeats on
+    pub length: NoteValue,
+    /// Gain of each repeat relative to the one before, from 0.0 to 1.0
+    pub decay: f32,
+    /// Pitch of each repeat relative to the one before, in semitones
+    pub pitch: f32,
+    /// Balance of the repeats against the live signal while engaged, from 0.0 to 1.0
+    pub mix: f32,
+}
+
+impl Default for BeatRepeatSettings {
+    fn default() -> Self {
+        Self {
+            length: NoteValue::new(NoteDivision::Sixteenth, NoteModifier::Straight),
+            decay: 1.0,
+            pitch: 0.0,
+            mix: 1.0,
+        }
+    }
+}
+
+/// Where the effect is in its cycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    /// Passing the live signal
+    Idle,
+    /// Engaged and waiting for a grid line
+    Armed,
+    /// Recording the slice while the live signal plays
+    Capturing,
+    /// Replaying the slice
+    Repeating,
+}
+
+/// Clock-synced beat repeat with decay and pitch.
+pub struct BeatRepeat {
+    clock: Arc<MasterClock>,
+    settings: BeatRepeatSettings,
+    engaged: bool,
+    phase: Phase,
+    slices: [Vec<f32>; 2],
+    slice_len: usize,
+    /// Position in the current pass of the slice
+    position: usize,
+    /// Index of the current repeat, counting from zero
+    repeat: u32,
+    /// Grid slot of the previous sample
+    last_slot: Option<i64>,
+    /// Crossfade from the live signal (0.0) to the repeats
+    blend: f32,
+    fade_len: usize,
+}
+
+impl BeatRepeat {
+    /// Creates a new `BeatRepeat` with default settings.
+    ///
+    /// # Arguments
+    /// * `clock` - The clock the grid follows.
+    ///
+    /// # Returns
+    /// * `BeatRepeat` - A released beat repeat with its slice buffers allocated.
+    pub fn new(clock: Arc<MasterClock>) -> Self {
+        let sample_rate = clock.sample_rate() as f32;
+        let capacity = (MAX_SLICE_SECONDS * sample_rate) as usize;
+        Self {
+            clock,
+            settings: BeatRepeatSettings::default(),
+            engaged: false,
+            phase: Phase::Idle,
+            slices: [vec![0.0; capacity], vec![0.0; capacity]],
+            slice_len: 0,
+            position: 0,
+            repeat: 0,
+            last_slot: None,
+            blend: 0.0,
+            fade_len: ((FADE_SECONDS * sample_rate) as usize).max(1),
+        }
+    }
+
+    /// Gets the current settings.
+    pub fn settings(&self) -> &BeatRepeatSettings {
+        &self.settings
+    }
+
+    /// Updates the settings; values are clamped to their ranges.
+    ///
+    /// A new length takes effect from the next capture.
+    pub fn set_settings(&mut self, settings: BeatRepeatSettings) {
+        self.settings = BeatRepeatSettings {
+            decay: settings.decay.clamp(0.0, 1.0),
+            pitch: settings.pitch.clamp(-MAX_PITCH_STEP, MAX_PITCH_STEP),
+            mix: settings.mix.clamp(0.0, 1.0),
+            ..settings
+        };
+    }
+
+    /// Starts repeating from the next grid line.
+    pub fn engage(&mut self) {
+        self.engaged = true;
+        if self.phase == Phase::Idle {
+            self.phase = Phase::Armed;
+        }
+    }
+
+    /// Fades back to the live signal.
+    pub fn release(&mut self) {
+        self.engaged = false;
+        if matches!(self.phase, Phase::Armed | Phase::Capturing) {
+            self.phase = Phase::Idle;
+        }
+    }
+
+    /// Checks whether the effect is held.
+    pub fn is_engaged(&self) -> bool {
+        self.engaged
+    }
+
+    /// Checks whether the slice is being replayed.
+    pub fn is_repeating(&self) -> bool {
+        self.phase == Phase::Repeating
+    }
+
+    /// Drops the captured slice and returns to the live signal.
+    pub fn reset(&mut self) {
+        self.engaged = false;
+        self.phase = Phase::Idle;
+        self.slice_len = 0;
+        self.last_slot = None;
+        self.blend = 0.0;
+    }
+
+    /// Reads the current pass of the slice with its decay, pitch and edge fades.
+    #[inline]
+    fn read(&self, channel: usize) -> f32 {
+        let rate = 2f32.powf(self.settings.pitch * self.repeat as f32 / 12.0);
+        let index = self.position as f32 * rate;
+        let floor = index as usize;
+        if floor + 1 >= self.slice_len {
+            return 0.0;
+        }
+        let slice = &self.slices[channel];
+        let fraction = index - floor as f32;
+        let sample = slice[floor] + (slice[floor + 1] - slice[floor]) * fraction;
+        let fade_in = self.position as f32 / self.fade_len as f32;
+        let fade_out = (self.slice_len as f32 - 1.0 - index) / (self.fade_len as f32 * rate);
+        sample * fade_in.min(fade_out).min(1.0) * self.settings.decay.powi(self.repeat as i32)
+    }
+
+    /// Runs one frame at a position, in slices.
+    #[inline]
+    fn tick(&mut self, position: f64, frame: [f32; 2], channels: usize) -> [f32; 2] {
+        let slot = position.floor() as i64;
+        let on_grid = self.last_slot.is_some_and(|last| last != slot);
+        self.last_slot = Some(slot);
+        if on_grid {
+            match self.phase {
+                Phase::Armed => {
+                    self.phase = Phase::Capturing;
+                    self.position = 0;
+                }
+                Phase::Capturing => {
+                    self.phase = Phase::Repeating;
+                    self.slice_len = self.position;
+                    self.position = 0;
+                    self.repeat = 0;
+                }
+                Phase::Repeating => {
+                    self.position = 0;
+                    self.repeat = self.repeat.saturating_add(1);
+                }
+                Phase::Idle => {}
+            }
+        }
+
+        let mut wet = frame;
+        match self.phase {
+            Phase::Capturing => {
+                if self.position < self.slices[0].len() {
+                    for (slice, &sample) in self.slices.iter_mut().zip(&frame).take(channels) {
+                        slice[self.position] = sample;
+                    }
+                    self.position += 1;
+                }
+            }
+            Phase::Repeating => {
+                for (channel, wet) in wet.iter_mut().enumerate().take(channels) {
+                    *wet = self.read(channel);
+                }
+                self.position += 1;
+            }
+            Phase::Idle | Phase::Armed => {}
+        }
+
+        let target = if self.engaged { self.settings.mix } else { 0.0 };
+        let step = 1.0 / self.fade_len as f32;
+        self.blend = if self.blend < target {
+            (self.blend + step).min(target)
+        } else {
+            (self.blend - step).max(target)
+        };
+        if !self.engaged && self.blend == 0.0 {
+            self.phase = Phase::Idle;
+        }
+        [0, 1].map(|channel| frame[channel] + (wet[channel] - frame[channel]) * self.blend)
+    }
+
+    /// Position of the block start and advance per sample, both in slices.
+    fn timeline(&self) -> (f64, f64) {
+        let length = self.settings.length.beats(self.clock.time_signature().denominator);
+        (self.clock.beat_position() / length, 1.0 / (self.clock.samples_per_beat_exact() * length))
+    }
+
+    /// Processes a mono buffer in place.
+    ///
+    /// # Arguments
+    /// * `buffer` - The audio to process, starting at the clock's current position.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - Always `Ok(())`.
+    pub fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        let (start, slices_per_sample) = self.timeline();
+        for (i, sample) in buffer.iter_mut().enumerate() {
+            *sample = self.tick(start + i as f64 * slices_per_sample, [*sample, 0.0], 1)[0];
+        }
+        Ok(())
+    }
+
+    /// Processes a stereo pair in place.
+    ///
+    /// # Arguments
+    /// * `left` - The left channel, starting at the clock's current position.
+    /// * `right` - The right channel, the same length as `left`.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - `BufferMismatch` if the channel lengths differ.
+    pub fn process_stereo(&mut self, left: &mut [f32], right: &mut [f32]) -> Result<(), AudioError> {
+        if left.len() != right.len() {
+            return Err(AudioError::BufferMismatch);
+        }
+        let (start, slices_per_sample) = self.timeline();
+        for (i, (l, r)) in left.iter_mut().zip(right.iter_mut()).enumerate() {
+            [*l, *r] = self.tick(start + i as f64 * slices_per_sample, [*l, *r], 2);
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for BeatRepeat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BeatRepeat")
+            .field("settings", &self.settings)
+            .field("engaged", &self.engaged)
+            .field("phase", &self.phase)
+            .finish()
+    }
+}
+
+impl AudioEffect for BeatRepeat {
+    fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        BeatRepeat::process(self, buffer)
+    }
+
+    fn reset(&mut self) {
+        BeatRepeat::reset(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repeats_the_slice_on_the_grid() {
+        // 120 BPM at 48 kHz: a sixteenth note is 6000 samples
+        let clock = Arc::new(MasterClock::new(48000, 120.0));
+        let mut repeat = BeatRepeat::new(clock.clone());
+        repeat.set_settings(BeatRepeatSettings { decay: 0.5, ..Default::default() });
+        let ramp = |block: usize| -> Vec<f32> { (0..1000).map(|i| (block * 1000 + i) as f32 / 1e5).collect() };
+        let mut output = Vec::new();
+        for block in 0..30 {
+            if block == 3 {
+                repeat.engage();
+            }
+            if block == 24 {
+                repeat.release();
+            }
+            let mut buffer = ramp(block);
+            repeat.process(&mut buffer).unwrap();
+            output.extend(buffer);
+            clock.advance(1000);
+        }
+
+        // Engaged at 3000, captured 6000..12000, repeated from 12000 at halving levels
+        assert!(output[..12000].iter().enumerate().all(|(i, &s)| s == i as f32 / 1e5));
+        for (pass, start) in [12000, 18000].into_iter().enumerate() {
+            let expected = 0.08 * 0.5f32.powi(pass as i32);
+            assert!((output[start + 2000] - expected).abs() < 1e-5, "{} {}", output[start + 2000], expected);
+        }
+        // Released at 24000: back to live after the fade
+        assert_eq!(output[25000], 0.25);
+        assert!(!repeat.is_repeating());
+    }
+}
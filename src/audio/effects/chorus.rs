@@ -0,0 +1,230 @@
+﻿//! Chorus effect implementation
+//!
+//! Up to three copies of the input, each read from a delay line whose time is
+//! swept by the shared `Lfo` at evenly spread phases. In stereo the right
+//! channel's voices run `spread` of a cycle ahead of the left's.
+
+use std::sync::Arc;
+
+use crate::{
+    audio::effects::{
+        delay::DelayLine,
+        lfo::{Lfo, LfoRate, LfoShape},
+        AudioEffect,
+    },
+    error::types::AudioError,
+    sync::clock::MasterClock,
+};
+
+/// Most voices a chorus runs.
+pub const MAX_VOICES: usize = 3;
+
+/// Longest delay plus depth supported, in milliseconds.
+const MAX_DELAY_MS: f32 = 50.0;
+
+/// Settings for the chorus.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChorusSettings {
+    /// LFO rate
+    pub rate: LfoRate,
+    /// LFO waveform
+    pub shape: LfoShape,
+    /// Centre delay of the voices, in milliseconds
+    pub delay: f32,
+    /// Sweep either side of the centre delay, in milliseconds
+    pub depth: f32,
+    /// Number of voices, from 1 to `MAX_VOICES`
+    pub voices: usize,
+    /// Phase offset of the right channel, as a fraction of a cycle
+    pub spread: f32,
+    /// Balance of the voices against the dry signal, from 0.0 to 1.0
+    pub mix: f32,
+}
+
+impl Default for ChorusSettings {
+    fn default() -> Self {
+        Self {
+            rate: LfoRate::Hertz(0.8),
+            shape: LfoShape::Sine,
+            delay: 12.0,
+            depth: 3.0,
+            voices: 2,
+            spread: 0.25,
+            mix: 0.5,
+        }
+    }
+}
+
+/// Multi-voice stereo chorus.
+#[derive(Debug, Clone)]
+pub struct Chorus {
+    sample_rate: u32,
+    settings: ChorusSettings,
+    lfo: Lfo,
+    lines: [DelayLine; 2],
+}
+
+impl Chorus {
+    /// Creates a new `Chorus` with default settings.
+    ///
+    /// # Arguments
+    /// * `sample_rate` - The sample rate of the audio.
+    ///
+    /// # Returns
+    /// * `Chorus` - A chorus with its delay lines allocated.
+    pub fn new(sample_rate: u32) -> Self {
+        let len = (MAX_DELAY_MS / 1000.0 * sample_rate as f32) as usize + 2;
+        let mut chorus = Self {
+            sample_rate,
+            settings: ChorusSettings::default(),
+            lfo: Lfo::new(sample_rate),
+            lines: [DelayLine::new(len), DelayLine::new(len)],
+        };
+        chorus.set_settings(ChorusSettings::default());
+        chorus
+    }
+
+    /// Gets the current settings.
+    pub fn settings(&self) -> &ChorusSettings {
+        &self.settings
+    }
+
+    /// Updates the settings; delay and depth are limited to the delay line.
+    pub fn set_settings(&mut self, settings: ChorusSettings) {
+        let depth = settings.depth.clamp(0.0, MAX_DELAY_MS / 2.0);
+        self.settings = ChorusSettings {
+            depth,
+            delay: settings.delay.clamp(depth + 0.1, MAX_DELAY_MS - depth),
+            voices: settings.voices.clamp(1, MAX_VOICES),
+            mix: settings.mix.clamp(0.0, 1.0),
+            ..settings
+        };
+        self.lfo.set(self.settings.shape, self.settings.rate);
+    }
+
+    /// Locks note-value rates to a clock's tempo.
+    pub fn set_clock(&mut self, clock: Option<Arc<MasterClock>>) {
+        self.lfo.set_clock(clock);
+    }
+
+    /// Clears the delay lines and restarts the LFO.
+    pub fn reset(&mut self) {
+        for line in &mut self.lines {
+            line.clear();
+        }
+        self.lfo.reset();
+    }
+
+    /// Reads the voices of one channel and averages them.
+    #[inline]
+    fn voices(&self, channel: usize, offset: f32) -> f32 {
+        let samples_per_ms = self.sample_rate as f32 / 1000.0;
+        let voices = self.settings.voices;
+        let sum: f32 = (0..voices)
+            .map(|voice| {
+                let modulation = self.lfo.value_at(offset + voice as f32 / voices as f32);
+                let delay = (self.settings.delay + self.settings.depth * modulation) * samples_per_ms;
+                self.lines[channel].read(delay as f64)
+            })
+            .sum();
+        sum / voices as f32
+    }
+
+    /// Processes a mono buffer in place.
+    ///
+    /// # Arguments
+    /// * `buffer` - The audio to process.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - Always `Ok(())`.
+    pub fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        self.lfo.update();
+        let mix = self.settings.mix;
+        for sample in buffer.iter_mut() {
+            let wet = self.voices(0, 0.0);
+            self.lines[0].write(*sample);
+            *sample = *sample * (1.0 - mix) + wet * mix;
+            self.lfo.advance(1);
+        }
+        Ok(())
+    }
+
+    /// Processes a stereo pair in place.
+    ///
+    /// # Arguments
+    /// * `left` - The left channel.
+    /// * `right` - The right channel, the same length as `left`.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - `BufferMismatch` if the channel lengths differ.
+    pub fn process_stereo(&mut self, left: &mut [f32], right: &mut [f32]) -> Result<(), AudioError> {
+        if left.len() != right.len() {
+            return Err(AudioError::BufferMismatch);
+        }
+        self.lfo.update();
+        let ChorusSettings { mix, spread, .. } = self.settings;
+        for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+            let (wet_left, wet_right) = (self.voices(0, 0.0), self.voices(1, spread));
+            self.lines[0].write(*l);
+            self.lines[1].write(*r);
+            *l = *l * (1.0 - mix) + wet_left * mix;
+            *r = *r * (1.0 - mix) + wet_right * mix;
+            self.lfo.advance(1);
+        }
+        Ok(())
+    }
+}
+
+impl AudioEffect for Chorus {
+    fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        Chorus::process(self, buffer)
+    }
+
+    fn reset(&mut self) {
+        Chorus::reset(self)
+    }
+
+    fn on_loop_start(&mut self) {
+        self.lfo.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_voices_sweep_around_the_centre_delay() {
+        let sample_rate = 48000;
+        let mut chorus = Chorus::new(sample_rate);
+        chorus.set_settings(ChorusSettings { voices: 1, mix: 1.0, depth: 2.0, delay: 10.0, ..Default::default() });
+
+        // An impulse reappears near the centre delay while the sine LFO starts
+        // from zero; by then the sweep has lengthened it by about 5 samples
+        let mut buffer = vec![0.0; 1000];
+        buffer[0] = 1.0;
+        chorus.process(&mut buffer).unwrap();
+        let arrival = buffer.iter().position(|s| s.abs() > 0.1).unwrap();
+        assert!(arrival.abs_diff(485) <= 1, "{}", arrival);
+
+        // A quarter cycle later the voice is at its longest delay, 12 ms
+        chorus.reset();
+        chorus.process(&mut vec![0.0; 15000 - 1]).unwrap();
+        let mut buffer = vec![0.0; 1000];
+        buffer[0] = 1.0;
+        chorus.process(&mut buffer).unwrap();
+        let arrival = buffer.iter().position(|s| s.abs() > 0.1).unwrap();
+        assert!(arrival.abs_diff(576) <= 2, "{}", arrival);
+
+        // Stereo channels sweep out of phase
+        chorus.reset();
+        let mut left = vec![0.0; 1000];
+        let mut right = vec![0.0; 1000];
+        left[0] = 1.0;
+        right[0] = 1.0;
+        chorus.process_stereo(&mut left, &mut right).unwrap();
+        let left_arrival = left.iter().position(|s| s.abs() > 0.1).unwrap();
+        let right_arrival = right.iter().position(|s| s.abs() > 0.1).unwrap();
+        assert!(right_arrival > left_arrival + 50);
+    }
+}
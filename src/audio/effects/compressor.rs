@@ -1 +1,306 @@
 ﻿//! Compressor effect implementation
+//!
+//! A feed-forward compressor: the detector follows the sidechain (the input
+//! itself unless another signal is supplied), a soft-knee gain computer turns
+//! its level into gain reduction, and attack/release smoothing is applied in
+//! the dB domain. With lookahead the audio is delayed so the gain can start
+//! falling before a transient arrives.
+
+use crate::{
+    audio::effects::{time_coefficient, AudioEffect},
+    error::types::AudioError,
+};
+
+/// Longest supported lookahead, in seconds.
+pub const MAX_LOOKAHEAD: f32 = 0.02;
+
+/// Averaging time of the RMS detector, in seconds.
+const RMS_SECONDS: f32 = 0.01;
+
+/// Level treated as silence by the detector, in dB.
+const FLOOR_DB: f32 = -120.0;
+
+/// How the detector measures level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Detection {
+    /// Instantaneous absolute value; catches every transient
+    Peak,
+    /// Short-term RMS; reacts to loudness rather than peaks
+    Rms,
+}
+
+/// Signal driving the detector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sidechain {
+    /// The compressed signal itself
+    Internal,
+    /// The output of another track
+    Track(usize),
+    /// An engine input channel
+    Input(usize),
+}
+
+/// Settings for the compressor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompressorSettings {
+    /// Level above which gain is reduced, in dBFS
+    pub threshold: f32,
+    /// Input change per output change above the threshold, at least 1.0
+    pub ratio: f32,
+    /// Width of the soft knee around the threshold, in dB
+    pub knee: f32,
+    /// Time to reach the target reduction when the level rises, in seconds
+    pub attack: f32,
+    /// Time to recover when the level falls, in seconds
+    pub release: f32,
+    /// Gain added after compression, in dB
+    pub makeup: f32,
+    /// How far the detector looks ahead of the audio, in seconds, up to `MAX_LOOKAHEAD`
+    pub lookahead: f32,
+    /// Peak or RMS detection
+    pub detection: Detection,
+    /// Signal driving the detector; resolved by the engine
+    pub sidechain: Sidechain,
+}
+
+impl Default for CompressorSettings {
+    fn default() -> Self {
+        Self {
+            threshold: -18.0,
+            ratio: 4.0,
+            knee: 6.0,
+            attack: 0.01,
+            release: 0.1,
+            makeup: 0.0,
+            lookahead: 0.0,
+            detection: Detection::Peak,
+            sidechain: Sidechain::Internal,
+        }
+    }
+}
+
+/// Feed-forward compressor with soft knee, lookahead and sidechain input.
+#[derive(Debug, Clone)]
+pub struct Compressor {
+    sample_rate: u32,
+    settings: CompressorSettings,
+    attack: f32,
+    release: f32,
+    rms: f32,
+    mean_square: f32,
+    /// Smoothed gain reduction, in dB
+    reduction: f32,
+    lookahead: Vec<f32>,
+    lookahead_index: usize,
+    latency: usize,
+}
+
+impl Compressor {
+    /// Creates a new `Compressor` with default settings.
+    ///
+    /// # Arguments
+    /// * `sample_rate` - The sample rate of the audio.
+    ///
+    /// # Returns
+    /// * `Compressor` - A compressor with its lookahead buffer allocated.
+    pub fn new(sample_rate: u32) -> Self {
+        let mut compressor = Self {
+            sample_rate,
+            settings: CompressorSettings::default(),
+            attack: 0.0,
+            release: 0.0,
+            rms: time_coefficient(RMS_SECONDS, sample_rate),
+            mean_square: 0.0,
+            reduction: 0.0,
+            lookahead: vec![0.0; (MAX_LOOKAHEAD * sample_rate as f32) as usize + 1],
+            lookahead_index: 0,
+            latency: 0,
+        };
+        compressor.set_settings(CompressorSettings::default());
+        compressor
+    }
+
+    /// Gets the current settings.
+    pub fn settings(&self) -> &CompressorSettings {
+        &self.settings
+    }
+
+    /// Updates the settings; values are clamped to their ranges.
+    pub fn set_settings(&mut self, settings: CompressorSettings) {
+        self.settings = CompressorSettings {
+            ratio: settings.ratio.max(1.0),
+            knee: settings.knee.max(0.0),
+            attack: settings.attack.max(0.0),
+            release: settings.release.max(0.0),
+            lookahead: settings.lookahead.clamp(0.0, MAX_LOOKAHEAD),
+            ..settings
+        };
+        self.attack = time_coefficient(self.settings.attack, self.sample_rate);
+        self.release = time_coefficient(self.settings.release, self.sample_rate);
+        let latency = ((self.settings.lookahead * self.sample_rate as f32) as usize).min(self.lookahead.len() - 1);
+        if latency != self.latency {
+            self.lookahead.fill(0.0);
+            self.latency = latency;
+        }
+    }
+
+    /// Gets the delay added by lookahead, in samples.
+    pub fn latency(&self) -> usize {
+        self.latency
+    }
+
+    /// Gets the current gain reduction, in dB (zero or positive).
+    pub fn gain_reduction(&self) -> f32 {
+        self.reduction
+    }
+
+    /// Clears the detector and lookahead state.
+    pub fn reset(&mut self) {
+        self.mean_square = 0.0;
+        self.reduction = 0.0;
+        self.lookahead.fill(0.0);
+    }
+
+    /// Static curve: gain reduction in dB for a detector level in dB.
+    pub fn compute_reduction(&self, level_db: f32) -> f32 {
+        let CompressorSettings { threshold, ratio, knee, .. } = self.settings;
+        let over = level_db - threshold;
+        let slope = 1.0 - 1.0 / ratio;
+        if 2.0 * over <= -knee {
+            0.0
+        } else if 2.0 * over.abs() < knee {
+            slope * (over + knee / 2.0).powi(2) / (2.0 * knee)
+        } else {
+            slope * over
+        }
+    }
+
+    /// Advances the detector by one sidechain sample and returns the linear gain.
+    #[inline]
+    fn next_gain(&mut self, sidechain: f32) -> f32 {
+        let level = match self.settings.detection {
+            Detection::Peak => sidechain.abs(),
+            Detection::Rms => {
+                self.mean_square += (sidechain * sidechain - self.mean_square) * self.rms;
+                self.mean_square.sqrt()
+            }
+        };
+        let level_db = if level > 0.0 { (20.0 * level.log10()).max(FLOOR_DB) } else { FLOOR_DB };
+        let target = self.compute_reduction(level_db);
+        let coefficient = if target > self.reduction { self.attack } else { self.release };
+        self.reduction += (target - self.reduction) * coefficient;
+        10f32.powf((self.settings.makeup - self.reduction) / 20.0)
+    }
+
+    /// Delays a sample by the lookahead time.
+    #[inline]
+    fn delay(&mut self, sample: f32) -> f32 {
+        if self.latency == 0 {
+            return sample;
+        }
+        let len = self.lookahead.len();
+        self.lookahead[self.lookahead_index] = sample;
+        let delayed = self.lookahead[(self.lookahead_index + len - self.latency) % len];
+        self.lookahead_index = (self.lookahead_index + 1) % len;
+        delayed
+    }
+
+    /// Compresses a buffer in place, detecting on the buffer itself.
+    ///
+    /// # Arguments
+    /// * `buffer` - The audio to process.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - Always `Ok(())`.
+    pub fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        for sample in buffer.iter_mut() {
+            let gain = self.next_gain(*sample);
+            *sample = self.delay(*sample) * gain;
+        }
+        Ok(())
+    }
+
+    /// Compresses a buffer in place, detecting on a separate sidechain signal.
+    ///
+    /// # Arguments
+    /// * `buffer` - The audio to process.
+    /// * `sidechain` - The signal driving the detector, the same length as `buffer`.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - `BufferMismatch` if the lengths differ.
+    pub fn process_with_sidechain(&mut self, buffer: &mut [f32], sidechain: &[f32]) -> Result<(), AudioError> {
+        if buffer.len() != sidechain.len() {
+            return Err(AudioError::BufferMismatch);
+        }
+        for (sample, &key) in buffer.iter_mut().zip(sidechain) {
+            let gain = self.next_gain(key);
+            *sample = self.delay(*sample) * gain;
+        }
+        Ok(())
+    }
+}
+
+impl AudioEffect for Compressor {
+    fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        Compressor::process(self, buffer)
+    }
+
+    fn reset(&mut self) {
+        Compressor::reset(self)
+    }
+
+    fn latency(&self) -> usize {
+        self.latency
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(amplitude: f32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| amplitude * (2.0 * std::f32::consts::PI * 1000.0 * i as f32 / 48000.0).sin())
+            .collect()
+    }
+
+    #[test]
+    fn test_static_curve_and_steady_reduction() {
+        let mut compressor = Compressor::new(48000);
+        compressor.set_settings(CompressorSettings { threshold: -20.0, ratio: 4.0, knee: 0.0, ..Default::default() });
+        assert_eq!(compressor.compute_reduction(-30.0), 0.0);
+        assert!((compressor.compute_reduction(-8.0) - 9.0).abs() < 1e-4);
+
+        compressor.set_settings(CompressorSettings { knee: 10.0, ..*compressor.settings() });
+        // At the threshold a soft knee already reduces by slope * knee / 8
+        assert!((compressor.compute_reduction(-20.0) - 0.9375).abs() < 1e-4);
+
+        // RMS of a -8 dBFS-peak sine is -11 dB: 9 dB over, 6.75 dB reduction
+        compressor.set_settings(CompressorSettings { knee: 0.0, detection: Detection::Rms, ..*compressor.settings() });
+        let mut buffer = sine(10f32.powf(-8.0 / 20.0), 48000);
+        compressor.process(&mut buffer).unwrap();
+        assert!((compressor.gain_reduction() - 6.75).abs() < 0.2, "{}", compressor.gain_reduction());
+    }
+
+    #[test]
+    fn test_lookahead_and_sidechain() {
+        let mut compressor = Compressor::new(48000);
+        compressor.set_settings(CompressorSettings { lookahead: 0.005, attack: 0.001, ..Default::default() });
+        assert_eq!(AudioEffect::latency(&compressor), 240);
+
+        // The step reaches the output 240 samples late, already turned down
+        let mut buffer = vec![0.0; 1000];
+        buffer[500..].fill(1.0);
+        compressor.process(&mut buffer).unwrap();
+        assert!(buffer[..740].iter().all(|&s| s == 0.0));
+        assert!(buffer[740] < 0.7, "{}", buffer[740]);
+
+        // A loud sidechain ducks a quiet signal
+        let mut ducked = Compressor::new(48000);
+        ducked.set_settings(CompressorSettings { ratio: 20.0, ..Default::default() });
+        let mut quiet = vec![0.05; 4800];
+        ducked.process_with_sidechain(&mut quiet, &vec![1.0; 4800]).unwrap();
+        assert!(ducked.gain_reduction() > 15.0);
+        assert!(quiet[4799] < 0.01);
+    }
+}
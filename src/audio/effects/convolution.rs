@@ -0,0 +1,575 @@
+﻿//! Convolution reverb and cabinet simulation
+//!
+//! Convolves the signal with a recorded impulse response using uniformly
+//! partitioned overlap-save convolution. The impulse response is cut into
+//! blocks of `PARTITION_SIZE` samples and each block's spectrum is computed
+//! once; every input block is transformed once, kept in a frequency-domain
+//! delay line, and multiplied with all partitions. The latency is one
+//! partition no matter how long the response is, so cabinet IRs and long
+//! rooms both stay playable. All buffers are allocated when the impulse
+//! response is loaded; processing never allocates.
+
+use std::sync::Arc;
+
+use realfft::{ComplexToReal, RealToComplex};
+
+use crate::{
+    audio::{
+        analysis::fft::{plan_forward, plan_inverse, Complex},
+        effects::{delay::DelayLine, AudioEffect},
+    },
+    core::buffer::AudioBuffer,
+    error::types::AudioError,
+};
+
+/// Length of each impulse response partition, and the latency, in samples.
+pub const PARTITION_SIZE: usize = 128;
+
+/// Longest supported pre-delay, in seconds.
+pub const MAX_PRE_DELAY: f32 = 0.5;
+
+/// Half the length of the resampling kernel, in input samples.
+const RESAMPLE_TAPS: usize = 16;
+
+/// Length of the fade applied where `trim` cuts a tail short, in seconds.
+const TRIM_FADE_SECONDS: f32 = 0.005;
+
+/// A recorded impulse response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImpulseResponse {
+    channels: Vec<Vec<f32>>,
+    sample_rate: u32,
+}
+
+impl ImpulseResponse {
+    /// Creates an impulse response from channel data.
+    ///
+    /// # Arguments
+    /// * `channels` - One to four channels of the same length.
+    /// * `sample_rate` - The rate the response was recorded at.
+    ///
+    /// # Returns
+    /// * `Result<ImpulseResponse, AudioError>` - The response, or `InvalidBuffer` if the channels are empty, too many or uneven.
+    pub fn new(channels: Vec<Vec<f32>>, sample_rate: u32) -> Result<Self, AudioError> {
+        let len = channels.first().map_or(0, Vec::len);
+        if len == 0 || channels.len() > 4 || channels.iter().any(|channel| channel.len() != len) || sample_rate == 0 {
+            return Err(AudioError::InvalidBuffer);
+        }
+        Ok(Self { channels, sample_rate })
+    }
+
+    /// Creates an impulse response from a buffer.
+    pub fn from_buffer(buffer: &AudioBuffer) -> Result<Self, AudioError> {
+        Self::new(buffer.samples().to_vec(), buffer.sample_rate())
+    }
+
+    /// Loads an impulse response from a WAV file.
+    ///
+    /// # Arguments
+    /// * `path` - The file to read.
+    ///
+    /// # Returns
+    /// * `Result<ImpulseResponse, AudioError>` - The response at the file's sample rate, or an error if it cannot be read.
+    #[cfg(feature = "file_io")]
+    pub fn load(path: impl AsRef<std::path::Path>) -> Result<Self, AudioError> {
+        Self::from_buffer(&crate::audio::io::file::read_wav(path)?)
+    }
+
+    /// Gets the channel data.
+    pub fn channels(&self) -> &[Vec<f32>] {
+        &self.channels
+    }
+
+    /// Gets the sample rate.
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Gets the length in samples.
+    pub fn len(&self) -> usize {
+        self.channels[0].len()
+    }
+
+    /// Always `false`; an impulse response holds at least one sample.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// Converts the response to another sample rate.
+    ///
+    /// Uses a Blackman-windowed sinc that band-limits to the lower of the two
+    /// rates, and scales the result so the frequency response is unchanged.
+    ///
+    /// # Arguments
+    /// * `sample_rate` - The new rate.
+    ///
+    /// # Returns
+    /// * `ImpulseResponse` - The resampled response.
+    pub fn resample(&self, sample_rate: u32) -> Self {
+        if sample_rate == self.sample_rate || sample_rate == 0 {
+            return self.clone();
+        }
+        let ratio = sample_rate as f64 / self.sample_rate as f64;
+        let cutoff = ratio.min(1.0);
+        let reach = RESAMPLE_TAPS as f64 / cutoff;
+        let len = ((self.len() as f64 * ratio).ceil() as usize).max(1);
+        let gain = cutoff / ratio;
+        let channels = self
+            .channels
+            .iter()
+            .map(|input| {
+                (0..len)
+                    .map(|n| {
+                        let centre = n as f64 / ratio;
+                        let first = (centre - reach).ceil().max(0.0) as usize;
+                        let last = ((centre + reach).floor() as usize).min(input.len() - 1);
+                        let sum: f64 = (first..=last)
+                            .map(|k| {
+                                let distance = centre - k as f64;
+                                input[k] as f64 * sinc(distance * cutoff) * blackman(distance / reach)
+                            })
+                            .sum();
+                        (sum * gain) as f32
+                    })
+                    .collect()
+            })
+            .collect();
+        Self { channels, sample_rate }
+    }
+
+    /// Cuts the response down, fading out the end if the tail is shortened.
+    ///
+    /// # Arguments
+    /// * `start` - Seconds removed from the start, such as silence before the direct sound.
+    /// * `length` - Seconds kept after `start`; `None` keeps the whole tail.
+    pub fn trim(&mut self, start: f32, length: Option<f32>) {
+        let rate = self.sample_rate as f32;
+        let len = self.len();
+        let first = ((start.max(0.0) * rate) as usize).min(len - 1);
+        let last = length.map_or(len, |length| (first + (length.max(0.0) * rate) as usize).clamp(first + 1, len));
+        let fade = if last < len { ((TRIM_FADE_SECONDS * rate) as usize).min(last - first) } else { 0 };
+        for channel in &mut self.channels {
+            channel.truncate(last);
+            channel.drain(..first);
+            let kept = channel.len();
+            for (i, sample) in channel[kept - fade..].iter_mut().enumerate() {
+                *sample *= 1.0 - (i + 1) as f32 / fade as f32;
+            }
+        }
+    }
+}
+
+/// Normalised sinc.
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-9 {
+        1.0
+    } else {
+        let x = std::f64::consts::PI * x;
+        x.sin() / x
+    }
+}
+
+/// Blackman window over -1.0 to 1.0.
+fn blackman(x: f64) -> f64 {
+    if x.abs() >= 1.0 {
+        0.0
+    } else {
+        let x = std::f64::consts::PI * x;
+        0.42 + 0.5 * x.cos() + 0.08 * (2.0 * x).cos()
+    }
+}
+
+/// How the channels of the impulse response are routed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConvolutionMode {
+    /// Each input feeds its own output through the matching channel; a mono response is used for both
+    Stereo,
+    /// Every input feeds both outputs through a four-channel response, in the order
+    /// left to left, left to right, right to left, right to right
+    TrueStereo,
+}
+
+/// Settings for the convolution.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConvolutionSettings {
+    /// Delay before the convolved signal, in seconds, up to `MAX_PRE_DELAY`
+    pub pre_delay: f32,
+    /// Level of the convolved signal, from 0.0 to 1.0
+    pub wet: f32,
+    /// Level of the unprocessed signal, from 0.0 to 1.0
+    pub dry: f32,
+}
+
+impl Default for ConvolutionSettings {
+    /// Fully wet, as for a cabinet; rooms usually want some dry signal.
+    fn default() -> Self {
+        Self {
+            pre_delay: 0.0,
+            wet: 1.0,
+            dry: 0.0,
+        }
+    }
+}
+
+/// One input-to-output route and the spectra of its partitions.
+struct Route {
+    input: usize,
+    output: usize,
+    partitions: Vec<Vec<Complex<f32>>>,
+}
+
+/// Uniformly partitioned FFT convolution with pre-delay and dry/wet mix.
+pub struct Convolution {
+    settings: ConvolutionSettings,
+    mode: ConvolutionMode,
+    sample_rate: u32,
+    forward: Arc<dyn RealToComplex<f32>>,
+    inverse: Arc<dyn ComplexToReal<f32>>,
+    routes: Vec<Route>,
+    partitions: usize,
+    /// Spectra of past input blocks, per input channel, newest at `history_index`
+    history: [Vec<Vec<Complex<f32>>>; 2],
+    history_index: usize,
+    /// Previous and current input block, per input channel
+    frames: [Vec<f32>; 2],
+    /// Output of the previous block, per output channel
+    wet: [Vec<f32>; 2],
+    /// Input of the previous block, per channel, mixed back in as the dry signal
+    dry: [Vec<f32>; 2],
+    /// Samples of the current block received so far
+    fill: usize,
+    pre_delay: [DelayLine; 2],
+    pre_delay_samples: usize,
+    time: Vec<f32>,
+    spectrum: Vec<Complex<f32>>,
+    accumulator: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+}
+
+impl Convolution {
+    /// Creates a new `Convolution` with default settings.
+    ///
+    /// Resampling and partitioning the response allocate; call this when
+    /// setting up, not on the audio thread.
+    ///
+    /// # Arguments
+    /// * `sample_rate` - The sample rate of the audio; the response is resampled to it.
+    /// * `impulse` - The impulse response.
+    /// * `mode` - How the response's channels are routed.
+    ///
+    /// # Returns
+    /// * `Result<Convolution, AudioError>` - The effect, or `EffectError` if true stereo is asked of a response without four channels.
+    pub fn new(sample_rate: u32, impulse: &ImpulseResponse, mode: ConvolutionMode) -> Result<Self, AudioError> {
+        let impulse = impulse.resample(sample_rate);
+        let channels = impulse.channels();
+        let routes: Vec<(usize, usize, &[f32])> = match mode {
+            ConvolutionMode::Stereo => vec![(0, 0, &channels[0]), (1, 1, &channels[channels.len().min(2) - 1])],
+            ConvolutionMode::TrueStereo if channels.len() == 4 => {
+                vec![(0, 0, &channels[0]), (0, 1, &channels[1]), (1, 0, &channels[2]), (1, 1, &channels[3])]
+            }
+            ConvolutionMode::TrueStereo => {
+                return Err(AudioError::EffectError(format!(
+                    "True stereo needs a four-channel impulse response, not {}",
+                    channels.len()
+                )))
+            }
+        };
+
+        let fft_size = 2 * PARTITION_SIZE;
+        let forward = plan_forward(fft_size);
+        let inverse = plan_inverse(fft_size);
+        let mut time = forward.make_input_vec();
+        let mut spectrum = forward.make_output_vec();
+        let mut scratch = vec![Complex::default(); forward.get_scratch_len().max(inverse.get_scratch_len())];
+        let partitions = impulse.len().div_ceil(PARTITION_SIZE);
+        // The inverse transform is unscaled, so the scale is folded into the partitions
+        let scale = 1.0 / fft_size as f32;
+        let mut partition_spectra = |response: &[f32]| -> Result<Vec<Vec<Complex<f32>>>, AudioError> {
+            response
+                .chunks(PARTITION_SIZE)
+                .map(|chunk| {
+                    time.fill(0.0);
+                    for (t, &sample) in time.iter_mut().zip(chunk) {
+                        *t = sample * scale;
+                    }
+                    forward
+                        .process_with_scratch(&mut time, &mut spectrum, &mut scratch)
+                        .map_err(|e| AudioError::BufferError(e.to_string()))?;
+                    Ok(spectrum.clone())
+                })
+                .collect()
+        };
+        let routes = routes
+            .into_iter()
+            .map(|(input, output, response)| {
+                Ok(Route { input, output, partitions: partition_spectra(response)? })
+            })
+            .collect::<Result<Vec<_>, AudioError>>()?;
+
+        let bins = PARTITION_SIZE + 1;
+        let history = || vec![vec![Complex::default(); bins]; partitions];
+        let pre_delay_len = (MAX_PRE_DELAY * sample_rate as f32) as usize + 2;
+        let mut convolution = Self {
+            settings: ConvolutionSettings::default(),
+            mode,
+            sample_rate,
+            routes,
+            partitions,
+            history: [history(), history()],
+            history_index: 0,
+            frames: [vec![0.0; fft_size], vec![0.0; fft_size]],
+            wet: [vec![0.0; PARTITION_SIZE], vec![0.0; PARTITION_SIZE]],
+            dry: [vec![0.0; PARTITION_SIZE], vec![0.0; PARTITION_SIZE]],
+            fill: 0,
+            pre_delay: [DelayLine::new(pre_delay_len), DelayLine::new(pre_delay_len)],
+            pre_delay_samples: 0,
+            time,
+            spectrum,
+            accumulator: vec![Complex::default(); bins],
+            scratch,
+            forward,
+            inverse,
+        };
+        convolution.set_settings(ConvolutionSettings::default());
+        Ok(convolution)
+    }
+
+    /// Replaces the impulse response, keeping the settings.
+    ///
+    /// Allocates like `new`; call this off the audio thread and swap the result in if needed.
+    pub fn load(&mut self, impulse: &ImpulseResponse, mode: ConvolutionMode) -> Result<(), AudioError> {
+        let settings = self.settings;
+        *self = Self::new(self.sample_rate, impulse, mode)?;
+        self.set_settings(settings);
+        Ok(())
+    }
+
+    /// Gets the current settings.
+    pub fn settings(&self) -> &ConvolutionSettings {
+        &self.settings
+    }
+
+    /// Updates the settings; values are clamped to their ranges.
+    pub fn set_settings(&mut self, settings: ConvolutionSettings) {
+        self.settings = ConvolutionSettings {
+            pre_delay: settings.pre_delay.clamp(0.0, MAX_PRE_DELAY),
+            wet: settings.wet.clamp(0.0, 1.0),
+            dry: settings.dry.clamp(0.0, 1.0),
+        };
+        self.pre_delay_samples = ((self.settings.pre_delay * self.sample_rate as f32).round() as usize)
+            .min(self.pre_delay[0].len() - 2);
+    }
+
+    /// Gets the channel routing.
+    pub fn mode(&self) -> ConvolutionMode {
+        self.mode
+    }
+
+    /// Gets the delay added by the partitioning, in samples.
+    pub fn latency(&self) -> usize {
+        PARTITION_SIZE
+    }
+
+    /// Clears the tail and all buffered audio.
+    pub fn reset(&mut self) {
+        for channel in 0..2 {
+            self.history[channel].iter_mut().for_each(|spectrum| spectrum.fill(Complex::default()));
+            self.frames[channel].fill(0.0);
+            self.wet[channel].fill(0.0);
+            self.dry[channel].fill(0.0);
+            self.pre_delay[channel].clear();
+        }
+        self.history_index = 0;
+        self.fill = 0;
+    }
+
+    /// Convolves the completed input block and stores the result for the next block.
+    fn process_block(&mut self, channels: usize) -> Result<(), AudioError> {
+        for channel in 0..channels {
+            self.time.copy_from_slice(&self.frames[channel]);
+            self.forward
+                .process_with_scratch(&mut self.time, &mut self.history[channel][self.history_index], &mut self.scratch)
+                .map_err(|e| AudioError::BufferError(e.to_string()))?;
+            self.frames[channel].copy_within(PARTITION_SIZE.., 0);
+        }
+
+        for output in 0..channels {
+            self.accumulator.fill(Complex::default());
+            for route in self.routes.iter().filter(|route| route.output == output && route.input < channels) {
+                for (age, partition) in route.partitions.iter().enumerate() {
+                    let index = (self.history_index + self.partitions - age) % self.partitions;
+                    let input = &self.history[route.input][index];
+                    for ((sum, x), h) in self.accumulator.iter_mut().zip(input).zip(partition) {
+                        *sum += x * h;
+                    }
+                }
+            }
+            self.spectrum.copy_from_slice(&self.accumulator);
+            // Rounding can leave the DC and Nyquist bins with an imaginary part
+            self.spectrum[0].im = 0.0;
+            self.spectrum[PARTITION_SIZE].im = 0.0;
+            self.inverse
+                .process_with_scratch(&mut self.spectrum, &mut self.time, &mut self.scratch)
+                .map_err(|e| AudioError::BufferError(e.to_string()))?;
+            // Overlap-save: only the second half is free of wrap-around
+            self.wet[output].copy_from_slice(&self.time[PARTITION_SIZE..]);
+        }
+        self.history_index = (self.history_index + 1) % self.partitions;
+        Ok(())
+    }
+
+    /// Runs one frame, returning the output of one block earlier.
+    #[inline]
+    fn tick(&mut self, frame: [f32; 2], channels: usize) -> Result<[f32; 2], AudioError> {
+        let ConvolutionSettings { wet, dry, .. } = self.settings;
+        let mut output = [0.0; 2];
+        for channel in 0..channels {
+            output[channel] = self.dry[channel][self.fill] * dry + self.wet[channel][self.fill] * wet;
+            self.dry[channel][self.fill] = frame[channel];
+            self.pre_delay[channel].write(frame[channel]);
+            self.frames[channel][PARTITION_SIZE + self.fill] =
+                self.pre_delay[channel].read(self.pre_delay_samples as f64 + 1.0);
+        }
+        self.fill += 1;
+        if self.fill == PARTITION_SIZE {
+            self.fill = 0;
+            self.process_block(channels)?;
+        }
+        Ok(output)
+    }
+
+    /// Processes a mono buffer in place, through the left-to-left channel of the response.
+    ///
+    /// # Arguments
+    /// * `buffer` - The audio to process.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - An error if the FFT fails.
+    pub fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        for sample in buffer.iter_mut() {
+            *sample = self.tick([*sample, 0.0], 1)?[0];
+        }
+        Ok(())
+    }
+
+    /// Processes a stereo pair in place.
+    ///
+    /// # Arguments
+    /// * `left` - The left channel.
+    /// * `right` - The right channel, the same length as `left`.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - `BufferMismatch` if the channel lengths differ, or an error if the FFT fails.
+    pub fn process_stereo(&mut self, left: &mut [f32], right: &mut [f32]) -> Result<(), AudioError> {
+        if left.len() != right.len() {
+            return Err(AudioError::BufferMismatch);
+        }
+        for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+            [*l, *r] = self.tick([*l, *r], 2)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Convolution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Convolution")
+            .field("settings", &self.settings)
+            .field("mode", &self.mode)
+            .field("partitions", &self.partitions)
+            .finish()
+    }
+}
+
+impl AudioEffect for Convolution {
+    fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        Convolution::process(self, buffer)
+    }
+
+    fn reset(&mut self) {
+        Convolution::reset(self)
+    }
+
+    fn latency(&self) -> usize {
+        Convolution::latency(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decaying noise from a fixed xorshift seed.
+    fn tail(len: usize, seed: u32) -> Vec<f32> {
+        let mut state = seed;
+        (0..len)
+            .map(|i| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state as f32 / u32::MAX as f32 * 2.0 - 1.0) * (-(i as f32) / 2000.0).exp()
+            })
+            .collect()
+    }
+
+    /// Direct convolution for reference.
+    fn convolve(signal: &[f32], response: &[f32]) -> Vec<f32> {
+        (0..signal.len())
+            .map(|n| (0..=n.min(response.len() - 1)).map(|k| signal[n - k] * response[k]).sum())
+            .collect()
+    }
+
+    #[test]
+    fn test_matches_direct_convolution() {
+        let responses: Vec<Vec<f32>> = (0..4).map(|seed| tail(1000, 0x9e37_79b9 + seed)).collect();
+        let impulse = ImpulseResponse::new(responses.clone(), 48000).unwrap();
+        let mut convolution = Convolution::new(48000, &impulse, ConvolutionMode::TrueStereo).unwrap();
+        convolution.set_settings(ConvolutionSettings { pre_delay: 0.001, ..Default::default() });
+
+        let left = tail(3000, 1);
+        let right = tail(3000, 2);
+        let (mut out_left, mut out_right) = (left.clone(), right.clone());
+        for (l, r) in out_left.chunks_mut(100).zip(out_right.chunks_mut(100)) {
+            convolution.process_stereo(l, r).unwrap();
+        }
+
+        // Each output sums both inputs through their routes, after latency and 48 samples of pre-delay
+        let delay = convolution.latency() + 48;
+        let sum = |a: Vec<f32>, b: Vec<f32>| -> Vec<f32> { a.iter().zip(&b).map(|(x, y)| x + y).collect() };
+        let expected_left = sum(convolve(&left, &responses[0]), convolve(&right, &responses[2]));
+        let expected_right = sum(convolve(&left, &responses[1]), convolve(&right, &responses[3]));
+        assert!(out_left[..delay].iter().chain(&out_right[..delay]).all(|s| s.abs() < 1e-5));
+        for (output, expected) in [(&out_left, &expected_left), (&out_right, &expected_right)] {
+            let error = output[delay..].iter().zip(expected.iter()).fold(0.0f32, |e, (a, b)| e.max((a - b).abs()));
+            assert!(error < 1e-3, "{}", error);
+        }
+
+        // True stereo needs all four routes
+        let stereo = ImpulseResponse::new(responses[..2].to_vec(), 48000).unwrap();
+        assert!(Convolution::new(48000, &stereo, ConvolutionMode::TrueStereo).is_err());
+    }
+
+    #[test]
+    fn test_resample_and_trim() {
+        // A delayed click at 44.1 kHz keeps its timing and level at 48 kHz
+        let mut click = vec![0.0; 4410];
+        click[441] = 1.0;
+        let mut impulse = ImpulseResponse::new(vec![click], 44100).unwrap();
+        let resampled = impulse.resample(48000);
+        assert_eq!(resampled.len(), 4800);
+        let channel = &resampled.channels()[0];
+        let peak = channel.iter().enumerate().fold(0, |best, (i, s)| if s.abs() > channel[best].abs() { i } else { best });
+        assert_eq!(peak, 480);
+        let dc_gain: f32 = channel.iter().sum();
+        assert!((dc_gain - 1.0).abs() < 0.01, "{}", dc_gain);
+
+        // Trimming the start removes the silence; trimming the length fades the cut
+        impulse.trim(0.01, Some(0.05));
+        assert_eq!(impulse.len(), 2205);
+        assert_eq!(impulse.channels()[0][0], 1.0);
+        let mut ramp = ImpulseResponse::new(vec![vec![1.0; 4410]], 44100).unwrap();
+        ramp.trim(0.0, Some(0.05));
+        assert_eq!(*ramp.channels()[0].last().unwrap(), 0.0);
+        assert_eq!(ramp.channels()[0][1900], 1.0);
+    }
+}
@@ -1 +1,526 @@
 ﻿//! Delay effect implementation
+//!
+//! A stereo delay whose time is set in milliseconds or as a note value locked
+//! to the `MasterClock`. Time changes glide like a tape delay instead of
+//! jumping, so the repeats bend smoothly through tempo changes. The feedback
+//! path is band-limited by a high-pass and a low-pass filter, and the wet
+//! signal can be ducked while the input is playing.
+
+use std::{sync::Arc, time::Instant};
+
+use crate::{
+    audio::effects::{lfo::{Lfo, LfoRate, LfoShape}, time_coefficient, AudioEffect},
+    error::types::AudioError,
+    sync::{clock::MasterClock, tap_tempo::TapTempo},
+};
+
+/// Longest supported delay, in seconds.
+pub const MAX_DELAY: f32 = 5.0;
+
+/// Time constant of the delay time glide, in seconds.
+const GLIDE_SECONDS: f32 = 0.05;
+
+/// Attack of the ducking envelope, in seconds.
+const DUCK_ATTACK_SECONDS: f32 = 0.005;
+
+/// Input level at which ducking reaches its full amount.
+const DUCK_FULL_SCALE: f32 = 0.25;
+
+/// Tempo used for note values when no clock is attached.
+const DEFAULT_BPM: f64 = 120.0;
+
+/// Length of a note relative to the whole note.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoteDivision {
+    /// 1/1
+    Whole,
+    /// 1/2
+    Half,
+    /// 1/4
+    Quarter,
+    /// 1/8
+    Eighth,
+    /// 1/16
+    Sixteenth,
+    /// 1/32
+    ThirtySecond,
+}
+
+/// Rhythmic modifier of a note value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoteModifier {
+    /// Plain note value
+    Straight,
+    /// One and a half times as long
+    Dotted,
+    /// Three in the time of two
+    Triplet,
+}
+
+/// A note value such as a dotted eighth.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NoteValue {
+    /// Base length
+    pub division: NoteDivision,
+    /// Dotted or triplet modifier
+    pub modifier: NoteModifier,
+}
+
+impl NoteValue {
+    /// Creates a note value.
+    pub const fn new(division: NoteDivision, modifier: NoteModifier) -> Self {
+        Self { division, modifier }
+    }
+
+    /// Gets the length in quarter notes.
+    pub fn quarter_notes(&self) -> f64 {
+        let base = match self.division {
+            NoteDivision::Whole => 4.0,
+            NoteDivision::Half => 2.0,
+            NoteDivision::Quarter => 1.0,
+            NoteDivision::Eighth => 0.5,
+            NoteDivision::Sixteenth => 0.25,
+            NoteDivision::ThirtySecond => 0.125,
+        };
+        match self.modifier {
+            NoteModifier::Straight => base,
+            NoteModifier::Dotted => base * 1.5,
+            NoteModifier::Triplet => base * 2.0 / 3.0,
+        }
+    }
+
+    /// Gets the length in clock beats.
+    ///
+    /// # Arguments
+    /// * `denominator` - The time signature denominator, the note value of one beat.
+    pub fn beats(&self, denominator: u8) -> f64 {
+        self.quarter_notes() * denominator as f64 / 4.0
+    }
+}
+
+/// How the delay time is specified.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DelayTime {
+    /// Fixed time in milliseconds
+    Milliseconds(f32),
+    /// Note value following the clock tempo
+    Note(NoteValue),
+}
+
+/// Settings for the delay.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DelaySettings {
+    /// Delay time
+    pub time: DelayTime,
+    /// Level of each repeat relative to the previous one, from 0.0 to 0.98
+    pub feedback: f32,
+    /// Cutoff of the high-pass filter in the feedback path, in Hz
+    pub low_cut: f32,
+    /// Cutoff of the low-pass filter in the feedback path, in Hz
+    pub high_cut: f32,
+    /// Whether repeats alternate between the left and right channels
+    pub ping_pong: bool,
+    /// Depth of the delay time modulation, in milliseconds
+    pub modulation_depth: f32,
+    /// Rate of the delay time modulation, in Hz
+    pub modulation_rate: f32,
+    /// How far the repeats are lowered while the input plays, from 0.0 to 1.0
+    pub ducking: f32,
+    /// Time for the repeats to come back after the input stops, in seconds
+    pub duck_release: f32,
+    /// Level of the repeats, from 0.0 to 1.0
+    pub wet: f32,
+    /// Level of the unprocessed signal, from 0.0 to 1.0
+    pub dry: f32,
+}
+
+impl Default for DelaySettings {
+    fn default() -> Self {
+        Self {
+            time: DelayTime::Note(NoteValue::new(NoteDivision::Eighth, NoteModifier::Dotted)),
+            feedback: 0.4,
+            low_cut: 80.0,
+            high_cut: 6000.0,
+            ping_pong: false,
+            modulation_depth: 0.0,
+            modulation_rate: 0.5,
+            ducking: 0.0,
+            duck_release: 0.25,
+            wet: 0.35,
+            dry: 1.0,
+        }
+    }
+}
+
+/// Circular buffer read at fractional positions.
+#[derive(Debug, Clone)]
+pub(crate) struct DelayLine {
+    buffer: Vec<f32>,
+    write: usize,
+}
+
+impl DelayLine {
+    pub(crate) fn new(len: usize) -> Self {
+        Self {
+            buffer: vec![0.0; len],
+            write: 0,
+        }
+    }
+
+    /// Gets the capacity in samples.
+    pub(crate) fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Silences the line.
+    pub(crate) fn clear(&mut self) {
+        self.buffer.fill(0.0);
+    }
+
+    /// Reads `delay` samples behind the next write position, interpolating linearly.
+    #[inline]
+    pub(crate) fn read(&self, delay: f64) -> f32 {
+        let len = self.buffer.len();
+        let position = self.write as f64 + len as f64 - delay;
+        let index = position.floor();
+        let fraction = (position - index) as f32;
+        let a = self.buffer[index as usize % len];
+        let b = self.buffer[(index as usize + 1) % len];
+        a + (b - a) * fraction
+    }
+
+    #[inline]
+    pub(crate) fn write(&mut self, sample: f32) {
+        self.buffer[self.write] = sample;
+        self.write += 1;
+        if self.write == self.buffer.len() {
+            self.write = 0;
+        }
+    }
+}
+
+/// High-pass and low-pass one-pole filters in the feedback path.
+#[derive(Debug, Clone, Copy, Default)]
+struct FeedbackFilter {
+    low_pass: f32,
+    high_pass_state: f32,
+}
+
+impl FeedbackFilter {
+    #[inline]
+    fn process(&mut self, input: f32, low_pass: f32, high_pass: f32) -> f32 {
+        self.low_pass += (input - self.low_pass) * low_pass;
+        self.high_pass_state += (self.low_pass - self.high_pass_state) * high_pass;
+        self.low_pass - self.high_pass_state
+    }
+}
+
+/// Tempo-synced stereo delay.
+pub struct Delay {
+    sample_rate: u32,
+    settings: DelaySettings,
+    clock: Option<Arc<MasterClock>>,
+    tempo: f64,
+    tap_tempo: TapTempo,
+    lines: [DelayLine; 2],
+    filters: [FeedbackFilter; 2],
+    /// Delay time the glide is heading for, in samples
+    target_delay: f64,
+    /// Current, gliding delay time, in samples
+    current_delay: f64,
+    glide: f64,
+    lfo: Lfo,
+    envelope: f32,
+    low_pass: f32,
+    high_pass: f32,
+    duck_attack: f32,
+    duck_release: f32,
+}
+
+impl Delay {
+    /// Creates a new `Delay` with default settings.
+    ///
+    /// # Arguments
+    /// * `sample_rate` - The sample rate of the audio.
+    ///
+    /// # Returns
+    /// * `Delay` - A delay with room for `MAX_DELAY` seconds plus modulation.
+    pub fn new(sample_rate: u32) -> Self {
+        let len = (MAX_DELAY * sample_rate as f32) as usize + sample_rate as usize / 10;
+        let mut delay = Self {
+            sample_rate,
+            settings: DelaySettings::default(),
+            clock: None,
+            tempo: DEFAULT_BPM,
+            tap_tempo: TapTempo::default(),
+            lines: [DelayLine::new(len), DelayLine::new(len)],
+            filters: [FeedbackFilter::default(); 2],
+            target_delay: 0.0,
+            current_delay: 0.0,
+            glide: 1.0 - (-1.0 / (GLIDE_SECONDS as f64 * sample_rate as f64)).exp(),
+            lfo: Lfo::new(sample_rate),
+            envelope: 0.0,
+            low_pass: 1.0,
+            high_pass: 0.0,
+            duck_attack: time_coefficient(DUCK_ATTACK_SECONDS, sample_rate),
+            duck_release: 0.0,
+        };
+        delay.set_settings(DelaySettings::default());
+        delay.current_delay = delay.target_delay;
+        delay
+    }
+
+    /// Gets the current settings.
+    pub fn settings(&self) -> &DelaySettings {
+        &self.settings
+    }
+
+    /// Updates the settings; a new delay time is reached with a short glide.
+    pub fn set_settings(&mut self, settings: DelaySettings) {
+        self.settings = DelaySettings {
+            feedback: settings.feedback.clamp(0.0, 0.98),
+            ducking: settings.ducking.clamp(0.0, 1.0),
+            wet: settings.wet.clamp(0.0, 1.0),
+            dry: settings.dry.clamp(0.0, 1.0),
+            modulation_depth: settings.modulation_depth.max(0.0),
+            ..settings
+        };
+        let nyquist = self.sample_rate as f32 / 2.0;
+        let coefficient = |hz: f32| 1.0 - (-2.0 * std::f32::consts::PI * hz.clamp(1.0, nyquist) / self.sample_rate as f32).exp();
+        self.low_pass = coefficient(self.settings.high_cut);
+        self.high_pass = coefficient(self.settings.low_cut);
+        self.duck_release = time_coefficient(self.settings.duck_release.max(0.001), self.sample_rate);
+        self.lfo.set(LfoShape::Sine, LfoRate::Hertz(self.settings.modulation_rate));
+        self.update_target();
+    }
+
+    /// Locks note-value delay times to a clock's tempo.
+    pub fn set_clock(&mut self, clock: Option<Arc<MasterClock>>) {
+        self.clock = clock;
+        self.update_target();
+    }
+
+    /// Sets the tempo, in quarter notes per minute, used for note values when no clock is attached.
+    pub fn set_tempo(&mut self, bpm: f64) {
+        self.tempo = bpm;
+        self.update_target();
+    }
+
+    /// Registers a tap; after two or more taps the delay time is set to the tapped interval.
+    ///
+    /// # Returns
+    /// * `Option<f32>` - The new delay time in milliseconds, once known.
+    pub fn tap(&mut self, now: Instant) -> Option<f32> {
+        let bpm = self.tap_tempo.register_tap(now)?;
+        let milliseconds = (60_000.0 / bpm) as f32;
+        self.set_settings(DelaySettings {
+            time: DelayTime::Milliseconds(milliseconds),
+            ..self.settings
+        });
+        Some(milliseconds)
+    }
+
+    /// Gets the delay time currently heard, in seconds.
+    pub fn current_time(&self) -> f32 {
+        (self.current_delay / self.sample_rate as f64) as f32
+    }
+
+    /// Clears the repeats.
+    pub fn reset(&mut self) {
+        for line in &mut self.lines {
+            line.clear();
+        }
+        self.filters = [FeedbackFilter::default(); 2];
+        self.envelope = 0.0;
+        self.current_delay = self.target_delay;
+    }
+
+    /// Recomputes the target delay from the settings and the tempo.
+    fn update_target(&mut self) {
+        let seconds = match self.settings.time {
+            DelayTime::Milliseconds(ms) => ms as f64 / 1000.0,
+            DelayTime::Note(note) => {
+                let (bpm, denominator) = self.clock.as_ref().map_or((self.tempo, 4), |clock| {
+                    (clock.bpm() as f64, clock.time_signature().denominator)
+                });
+                note.beats(denominator) * 60.0 / bpm.max(1.0)
+            }
+        };
+        self.target_delay = (seconds * self.sample_rate as f64).clamp(1.0, MAX_DELAY as f64 * self.sample_rate as f64);
+    }
+
+    /// Advances the glide and the modulation by one sample and returns the delay to read.
+    #[inline]
+    fn next_delay(&mut self) -> f64 {
+        self.current_delay += (self.target_delay - self.current_delay) * self.glide;
+        if self.settings.modulation_depth <= 0.0 {
+            return self.current_delay;
+        }
+        let depth = self.settings.modulation_depth as f64 / 1000.0 * self.sample_rate as f64;
+        let modulated = self.current_delay + depth * self.lfo.tick() as f64;
+        modulated.clamp(1.0, (self.lines[0].len() - 2) as f64)
+    }
+
+    /// Follows the input level and returns the gain applied to the repeats.
+    #[inline]
+    fn duck_gain(&mut self, input: f32) -> f32 {
+        if self.settings.ducking <= 0.0 {
+            return 1.0;
+        }
+        let level = input.abs();
+        let coefficient = if level > self.envelope { self.duck_attack } else { self.duck_release };
+        self.envelope += (level - self.envelope) * coefficient;
+        1.0 - self.settings.ducking * (self.envelope / DUCK_FULL_SCALE).min(1.0)
+    }
+
+    /// Processes a mono buffer in place.
+    ///
+    /// # Arguments
+    /// * `buffer` - The audio to process.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - Always `Ok(())`.
+    pub fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        self.update_target();
+        let feedback = self.settings.feedback;
+        for sample in buffer.iter_mut() {
+            let input = *sample;
+            let delay = self.next_delay();
+            let echo = self.lines[0].read(delay);
+            let filtered = self.filters[0].process(echo, self.low_pass, self.high_pass);
+            self.lines[0].write(input + filtered * feedback);
+            let wet = self.settings.wet * self.duck_gain(input);
+            *sample = input * self.settings.dry + echo * wet;
+        }
+        Ok(())
+    }
+
+    /// Processes a stereo pair in place.
+    ///
+    /// With ping-pong on, the summed input enters on the left and each repeat
+    /// crosses to the other side.
+    ///
+    /// # Arguments
+    /// * `left` - The left channel.
+    /// * `right` - The right channel, the same length as `left`.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - `BufferMismatch` if the channel lengths differ.
+    pub fn process_stereo(&mut self, left: &mut [f32], right: &mut [f32]) -> Result<(), AudioError> {
+        if left.len() != right.len() {
+            return Err(AudioError::BufferMismatch);
+        }
+        self.update_target();
+        let feedback = self.settings.feedback;
+        for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+            let delay = self.next_delay();
+            let echo_left = self.lines[0].read(delay);
+            let echo_right = self.lines[1].read(delay);
+            let filtered_left = self.filters[0].process(echo_left, self.low_pass, self.high_pass);
+            let filtered_right = self.filters[1].process(echo_right, self.low_pass, self.high_pass);
+            if self.settings.ping_pong {
+                self.lines[0].write((*l + *r) * 0.5 + filtered_right * feedback);
+                self.lines[1].write(filtered_left * feedback);
+            } else {
+                self.lines[0].write(*l + filtered_left * feedback);
+                self.lines[1].write(*r + filtered_right * feedback);
+            }
+            let wet = self.settings.wet * self.duck_gain(l.abs().max(r.abs()));
+            *l = *l * self.settings.dry + echo_left * wet;
+            *r = *r * self.settings.dry + echo_right * wet;
+        }
+        Ok(())
+    }
+}
+
+impl AudioEffect for Delay {
+    fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        Delay::process(self, buffer)
+    }
+
+    fn reset(&mut self) {
+        Delay::reset(self)
+    }
+
+    fn on_loop_start(&mut self) {
+        self.lfo.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::clock::TimeSignature;
+    use std::time::Duration;
+
+    fn first_echo(samples: &[f32], from: usize) -> usize {
+        from + samples[from..].iter().position(|s| s.abs() > 0.05).unwrap()
+    }
+
+    #[test]
+    fn test_note_value_follows_clock() {
+        let sample_rate = 48000;
+        let clock = Arc::new(MasterClock::new(sample_rate, 120.0));
+        let mut delay = Delay::new(sample_rate);
+        delay.set_clock(Some(clock.clone()));
+        delay.set_settings(DelaySettings { dry: 0.0, wet: 1.0, low_cut: 1.0, high_cut: 24000.0, ..Default::default() });
+
+        // Dotted eighth at 120 BPM: 0.375 s
+        let mut buffer = vec![0.0; sample_rate as usize];
+        buffer[0] = 1.0;
+        delay.process(&mut buffer).unwrap();
+        assert!(first_echo(&buffer, 1).abs_diff(18000) <= 1);
+
+        // At 100 BPM the repeat time glides to 0.45 s
+        clock.set_bpm(100.0);
+        let mut buffer = vec![0.0; sample_rate as usize];
+        delay.process(&mut buffer).unwrap();
+        assert!((delay.current_time() - 0.45).abs() < 1e-3, "{}", delay.current_time());
+
+        let triplet = NoteValue::new(NoteDivision::Quarter, NoteModifier::Triplet);
+        assert!((triplet.beats(4) - 2.0 / 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_note_value_counts_beats_in_the_meter() {
+        let sample_rate = 48000;
+        let clock = Arc::new(MasterClock::new(sample_rate, 120.0));
+        clock.set_time_signature(TimeSignature::new(6, 8).unwrap());
+        let mut delay = Delay::new(sample_rate);
+        delay.set_clock(Some(clock));
+        delay.set_settings(DelaySettings { dry: 0.0, wet: 1.0, low_cut: 1.0, high_cut: 24000.0, ..Default::default() });
+
+        // In 6/8 at 120 BPM the beat is an eighth note lasting 0.5 s, so a dotted eighth is 0.75 s
+        let mut buffer = vec![0.0; sample_rate as usize];
+        buffer[0] = 1.0;
+        delay.process(&mut buffer).unwrap();
+        assert!(first_echo(&buffer, 1).abs_diff(36000) <= 1);
+
+        let quarter = NoteValue::new(NoteDivision::Quarter, NoteModifier::Straight);
+        assert_eq!(quarter.beats(8), 2.0);
+        assert_eq!(quarter.beats(2), 0.5);
+    }
+
+    #[test]
+    fn test_ping_pong_and_tap() {
+        let sample_rate = 44100;
+        let mut delay = Delay::new(sample_rate);
+        let start = Instant::now();
+        delay.tap(start);
+        assert_eq!(delay.tap(start + Duration::from_millis(250)), Some(250.0));
+        delay.reset();
+        delay.set_settings(DelaySettings { ping_pong: true, dry: 0.0, wet: 1.0, feedback: 0.5, ..*delay.settings() });
+
+        let mut left = vec![0.0; sample_rate as usize];
+        let mut right = vec![0.0; sample_rate as usize];
+        left[0] = 1.0;
+        delay.process_stereo(&mut left, &mut right).unwrap();
+
+        let step = 11025;
+        let first_left = first_echo(&left, 1);
+        let first_right = first_echo(&right, 1);
+        assert!(first_left.abs_diff(step) <= 1);
+        assert!(first_right.abs_diff(2 * step) <= 1);
+        assert!(right[step - 10..step + 10].iter().all(|s| s.abs() < 1e-3));
+    }
+}
@@ -0,0 +1,242 @@
+﻿//! Parametric EQ implementation
+//!
+//! A series of `Filter` bands. Each band glides to new settings, and
+//! switching a band on or off crossfades between its input and output, so
+//! the EQ can be adjusted while a loop plays without clicks.
+
+use crate::{
+    audio::effects::{
+        filter::{Filter, FilterParams, FilterType, Slope},
+        AudioEffect,
+    },
+    error::types::AudioError,
+};
+
+/// Length of the crossfade when a band is switched on or off, in seconds.
+const BYPASS_FADE_SECONDS: f32 = 0.01;
+
+/// One EQ band.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EqBand {
+    /// Whether the band is applied
+    pub enabled: bool,
+    /// Type, frequency, Q, gain and slope of the band
+    pub params: FilterParams,
+}
+
+/// Settings for the parametric EQ.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EqSettings {
+    /// Bands, applied in order
+    pub bands: Vec<EqBand>,
+    /// Gain applied after the bands, in dB
+    pub output_gain: f32,
+}
+
+impl Default for EqSettings {
+    fn default() -> Self {
+        let band = |enabled, filter_type, frequency, q, slope| EqBand {
+            enabled,
+            params: FilterParams { filter_type, frequency, q, gain: 0.0, slope },
+        };
+        Self {
+            bands: vec![
+                band(false, FilterType::HighPass, 40.0, 0.707, Slope::Db24),
+                band(true, FilterType::LowShelf, 120.0, 0.707, Slope::Db12),
+                band(true, FilterType::Peak, 400.0, 1.0, Slope::Db12),
+                band(true, FilterType::Peak, 2500.0, 1.0, Slope::Db12),
+                band(true, FilterType::HighShelf, 8000.0, 0.707, Slope::Db12),
+                band(false, FilterType::LowPass, 18000.0, 0.707, Slope::Db24),
+            ],
+            output_gain: 0.0,
+        }
+    }
+}
+
+/// A filter with a click-free bypass.
+#[derive(Debug, Clone)]
+struct BandState {
+    filter: Filter,
+    /// Crossfade position, from 0.0 (bypassed) to 1.0 (applied)
+    mix: f32,
+}
+
+/// Multi-band parametric EQ.
+#[derive(Debug, Clone)]
+pub struct ParametricEq {
+    sample_rate: u32,
+    settings: EqSettings,
+    bands: Vec<BandState>,
+    fade_step: f32,
+    output_gain: f32,
+}
+
+impl ParametricEq {
+    /// Creates a new `ParametricEq` with the default six bands, all flat.
+    ///
+    /// # Arguments
+    /// * `sample_rate` - The sample rate of the audio.
+    ///
+    /// # Returns
+    /// * `ParametricEq` - An EQ that leaves the signal unchanged until bands are set.
+    pub fn new(sample_rate: u32) -> Self {
+        let mut eq = Self {
+            sample_rate,
+            settings: EqSettings::default(),
+            bands: Vec::new(),
+            fade_step: 1.0 / (BYPASS_FADE_SECONDS * sample_rate as f32),
+            output_gain: 1.0,
+        };
+        eq.set_settings(EqSettings::default());
+        eq
+    }
+
+    /// Gets the current settings.
+    pub fn settings(&self) -> &EqSettings {
+        &self.settings
+    }
+
+    /// Updates the settings.
+    ///
+    /// Existing bands glide to their new parameters; bands added or removed
+    /// are allocated or dropped, so change the band count off the audio thread.
+    pub fn set_settings(&mut self, settings: EqSettings) {
+        self.bands.truncate(settings.bands.len());
+        for (index, band) in settings.bands.iter().enumerate() {
+            match self.bands.get_mut(index) {
+                Some(state) => state.filter.set_params(band.params),
+                None => self.bands.push(BandState {
+                    filter: Filter::new(self.sample_rate, band.params),
+                    mix: if band.enabled { 1.0 } else { 0.0 },
+                }),
+            }
+        }
+        self.output_gain = 10f32.powf(settings.output_gain / 20.0);
+        self.settings = settings;
+    }
+
+    /// Replaces one band.
+    ///
+    /// # Arguments
+    /// * `index` - Position of the band.
+    /// * `band` - The new band settings.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - `EffectError` if there is no band at `index`.
+    pub fn set_band(&mut self, index: usize, band: EqBand) -> Result<(), AudioError> {
+        let state = self
+            .bands
+            .get_mut(index)
+            .ok_or_else(|| AudioError::EffectError(format!("EQ has no band {}", index)))?;
+        state.filter.set_params(band.params);
+        self.settings.bands[index] = band;
+        Ok(())
+    }
+
+    /// Gets the magnitude response of the enabled bands at a frequency, in dB.
+    pub fn response_db(&self, frequency: f32) -> f32 {
+        let bands: f32 = self
+            .settings
+            .bands
+            .iter()
+            .zip(&self.bands)
+            .filter(|(band, _)| band.enabled)
+            .map(|(_, state)| state.filter.response_db(frequency))
+            .sum();
+        bands + self.settings.output_gain
+    }
+
+    /// Clears the state of every band.
+    pub fn reset(&mut self) {
+        for state in &mut self.bands {
+            state.filter.reset();
+        }
+    }
+
+    /// Equalizes a buffer in place.
+    ///
+    /// # Arguments
+    /// * `buffer` - The audio to process.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - Always `Ok(())`.
+    pub fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        for (band, state) in self.settings.bands.iter().zip(self.bands.iter_mut()) {
+            let target = if band.enabled { 1.0 } else { 0.0 };
+            if state.mix == target {
+                if band.enabled {
+                    for sample in buffer.iter_mut() {
+                        *sample = state.filter.process_sample(*sample);
+                    }
+                }
+                continue;
+            }
+            for sample in buffer.iter_mut() {
+                state.mix = if band.enabled {
+                    (state.mix + self.fade_step).min(1.0)
+                } else {
+                    (state.mix - self.fade_step).max(0.0)
+                };
+                let filtered = state.filter.process_sample(*sample);
+                *sample += (filtered - *sample) * state.mix;
+            }
+            if state.mix == 0.0 {
+                state.filter.reset();
+            }
+        }
+        if self.output_gain != 1.0 {
+            for sample in buffer.iter_mut() {
+                *sample *= self.output_gain;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AudioEffect for ParametricEq {
+    fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        ParametricEq::process(self, buffer)
+    }
+
+    fn reset(&mut self) {
+        ParametricEq::reset(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bands_shape_the_response_and_bypass_smoothly() {
+        let mut eq = ParametricEq::new(48000);
+        assert!(eq.response_db(1000.0).abs() < 1e-3);
+
+        let mut settings = eq.settings().clone();
+        settings.bands[2].params.gain = -6.0;
+        settings.bands[4].params.gain = 3.0;
+        settings.bands[0].enabled = true;
+        eq.set_settings(settings);
+        let mut noise: Vec<f32> = (0..48000).map(|i| ((i * 7919) % 1000) as f32 / 1000.0 - 0.5).collect();
+        eq.process(&mut noise).unwrap();
+        assert!((eq.response_db(400.0) + 6.0).abs() < 0.2);
+        assert!((eq.response_db(16000.0) - 3.0).abs() < 0.2);
+        assert!(eq.response_db(10.0) < -20.0);
+        assert!(eq.set_band(6, eq.settings().bands[0]).is_err());
+
+        // Disabling a boosted band fades it out instead of stepping
+        let mut band = eq.settings().bands[3];
+        band.params.gain = 12.0;
+        eq.set_band(3, band).unwrap();
+        let tone = |i: usize| 0.2 * (2.0 * std::f32::consts::PI * 2500.0 * i as f32 / 48000.0).sin();
+        let mut buffer: Vec<f32> = (0..9600).map(tone).collect();
+        eq.process(&mut buffer).unwrap();
+        eq.set_band(3, EqBand { enabled: false, ..band }).unwrap();
+        let mut faded: Vec<f32> = (9600..19200).map(tone).collect();
+        eq.process(&mut faded).unwrap();
+        let largest_step = buffer.iter().chain(&faded).collect::<Vec<_>>().windows(2).map(|w| (w[1] - w[0]).abs()).fold(0.0, f32::max);
+        assert!(largest_step < 0.35, "{}", largest_step);
+        let peak = faded[4800..].iter().fold(0.0f32, |p, s| p.max(s.abs()));
+        assert!((peak - 0.2).abs() < 0.02, "{}", peak);
+    }
+}
@@ -0,0 +1,470 @@
+﻿//! Biquad filter implementation
+//!
+//! Second-order sections with the Audio EQ Cookbook (RBJ) coefficients,
+//! run in transposed direct form II, which tolerates coefficients changing
+//! while audio flows. Low- and high-pass filters cascade up to four sections
+//! for slopes of 12 to 48 dB per octave, using Butterworth stage Qs.
+//!
+//! `Filter` smooths frequency, Q and gain towards their targets and
+//! recomputes coefficients every `CONTROL_INTERVAL` samples, so parameter
+//! changes sweep instead of clicking.
+
+use std::f64::consts::PI;
+
+use crate::{
+    audio::{analysis::fft::Complex, effects::AudioEffect},
+    error::types::AudioError,
+};
+
+/// Samples between coefficient updates while parameters are moving.
+pub const CONTROL_INTERVAL: usize = 16;
+
+/// Time constant of the parameter smoothing, in seconds.
+const SMOOTHING_SECONDS: f32 = 0.02;
+
+/// Lowest supported cutoff or centre frequency, in Hz.
+const MIN_FREQUENCY: f32 = 10.0;
+
+/// Most cascaded sections a filter uses.
+const MAX_STAGES: usize = 4;
+
+/// Response shape of a filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterType {
+    /// Passes frequencies below the cutoff
+    LowPass,
+    /// Passes frequencies above the cutoff
+    HighPass,
+    /// Passes a band around the centre frequency, peaking at 0 dB
+    BandPass,
+    /// Removes a narrow band around the centre frequency
+    Notch,
+    /// Boosts or cuts a band around the centre frequency
+    Peak,
+    /// Boosts or cuts everything below the corner frequency
+    LowShelf,
+    /// Boosts or cuts everything above the corner frequency
+    HighShelf,
+    /// Passes every frequency, shifting phase around the centre frequency
+    AllPass,
+}
+
+/// Roll-off of low- and high-pass filters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slope {
+    /// 12 dB per octave, one section
+    Db12,
+    /// 24 dB per octave, two sections
+    Db24,
+    /// 36 dB per octave, three sections
+    Db36,
+    /// 48 dB per octave, four sections
+    Db48,
+}
+
+impl Slope {
+    /// Number of second-order sections needed for the slope.
+    pub fn stages(&self) -> usize {
+        match self {
+            Slope::Db12 => 1,
+            Slope::Db24 => 2,
+            Slope::Db36 => 3,
+            Slope::Db48 => 4,
+        }
+    }
+}
+
+/// Parameters of a filter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FilterParams {
+    /// Response shape
+    pub filter_type: FilterType,
+    /// Cutoff, corner or centre frequency, in Hz
+    pub frequency: f32,
+    /// Resonance or bandwidth; 0.707 is flat for low- and high-pass filters
+    pub q: f32,
+    /// Boost or cut of peaking and shelving filters, in dB
+    pub gain: f32,
+    /// Roll-off of low- and high-pass filters; ignored by the other types
+    pub slope: Slope,
+}
+
+impl Default for FilterParams {
+    fn default() -> Self {
+        Self {
+            filter_type: FilterType::LowPass,
+            frequency: 1000.0,
+            q: std::f32::consts::FRAC_1_SQRT_2,
+            gain: 0.0,
+            slope: Slope::Db12,
+        }
+    }
+}
+
+/// Normalized coefficients of one second-order section.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BiquadCoefficients {
+    /// Feed-forward coefficients
+    pub b: [f32; 3],
+    /// Feedback coefficients, with `a0` normalized to 1
+    pub a: [f32; 2],
+}
+
+impl Default for BiquadCoefficients {
+    fn default() -> Self {
+        Self { b: [1.0, 0.0, 0.0], a: [0.0, 0.0] }
+    }
+}
+
+impl BiquadCoefficients {
+    /// Computes cookbook coefficients.
+    ///
+    /// # Arguments
+    /// * `filter_type` - Response shape.
+    /// * `frequency` - Cutoff, corner or centre frequency, in Hz.
+    /// * `q` - Resonance or bandwidth.
+    /// * `gain` - Boost or cut in dB, for peaking and shelving filters.
+    /// * `sample_rate` - The sample rate of the audio.
+    ///
+    /// # Returns
+    /// * `BiquadCoefficients` - The section's coefficients.
+    pub fn new(filter_type: FilterType, frequency: f32, q: f32, gain: f32, sample_rate: u32) -> Self {
+        let nyquist = sample_rate as f32 * 0.49;
+        let w0 = 2.0 * PI * frequency.clamp(MIN_FREQUENCY, nyquist) as f64 / sample_rate as f64;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * (q as f64).max(0.01));
+        let a = 10f64.powf(gain as f64 / 40.0);
+        let shelf = 2.0 * a.sqrt() * alpha;
+
+        let (b0, b1, b2, a0, a1, a2) = match filter_type {
+            FilterType::LowPass => ((1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0, 1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+            FilterType::HighPass => ((1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0, 1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+            FilterType::BandPass => (alpha, 0.0, -alpha, 1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+            FilterType::Notch => (1.0, -2.0 * cos, 1.0, 1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+            FilterType::AllPass => (1.0 - alpha, -2.0 * cos, 1.0 + alpha, 1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+            FilterType::Peak => (1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a, 1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a),
+            FilterType::LowShelf => (
+                a * ((a + 1.0) - (a - 1.0) * cos + shelf),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - shelf),
+                (a + 1.0) + (a - 1.0) * cos + shelf,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - shelf,
+            ),
+            FilterType::HighShelf => (
+                a * ((a + 1.0) + (a - 1.0) * cos + shelf),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - shelf),
+                (a + 1.0) - (a - 1.0) * cos + shelf,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - shelf,
+            ),
+        };
+        Self {
+            b: [(b0 / a0) as f32, (b1 / a0) as f32, (b2 / a0) as f32],
+            a: [(a1 / a0) as f32, (a2 / a0) as f32],
+        }
+    }
+
+    /// Gets the complex response at a frequency.
+    pub fn response(&self, frequency: f32, sample_rate: u32) -> Complex<f32> {
+        let w = 2.0 * std::f32::consts::PI * frequency / sample_rate as f32;
+        let z1 = Complex::from_polar(1.0, -w);
+        let z2 = z1 * z1;
+        (self.b[0] + z1 * self.b[1] + z2 * self.b[2]) / (1.0 + z1 * self.a[0] + z2 * self.a[1])
+    }
+}
+
+/// One second-order section in transposed direct form II.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Biquad {
+    /// Current coefficients; may be replaced between samples
+    pub coefficients: BiquadCoefficients,
+    s1: f32,
+    s2: f32,
+}
+
+impl Biquad {
+    /// Creates a section with the given coefficients and cleared state.
+    pub fn new(coefficients: BiquadCoefficients) -> Self {
+        Self { coefficients, s1: 0.0, s2: 0.0 }
+    }
+
+    /// Filters one sample.
+    #[inline]
+    pub fn process(&mut self, input: f32) -> f32 {
+        let BiquadCoefficients { b, a } = self.coefficients;
+        let output = b[0] * input + self.s1;
+        self.s1 = b[1] * input - a[0] * output + self.s2;
+        self.s2 = b[2] * input - a[1] * output;
+        output
+    }
+
+    /// Clears the filter state.
+    pub fn reset(&mut self) {
+        self.s1 = 0.0;
+        self.s2 = 0.0;
+    }
+}
+
+/// A smoothed filter of up to four cascaded sections.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    sample_rate: u32,
+    target: FilterParams,
+    /// Frequency, Q and gain currently heard
+    frequency: f32,
+    q: f32,
+    gain: f32,
+    smoothing: f32,
+    settled: bool,
+    countdown: usize,
+    stages: [Biquad; MAX_STAGES],
+    stage_count: usize,
+}
+
+impl Filter {
+    /// Creates a new `Filter`.
+    ///
+    /// # Arguments
+    /// * `sample_rate` - The sample rate of the audio.
+    /// * `params` - Initial parameters, applied without smoothing.
+    ///
+    /// # Returns
+    /// * `Filter` - A filter with cleared state.
+    pub fn new(sample_rate: u32, params: FilterParams) -> Self {
+        let interval = CONTROL_INTERVAL as f32 / (SMOOTHING_SECONDS * sample_rate as f32);
+        let mut filter = Self {
+            sample_rate,
+            target: params,
+            frequency: params.frequency,
+            q: params.q,
+            gain: params.gain,
+            smoothing: 1.0 - (-interval).exp(),
+            settled: true,
+            countdown: 0,
+            stages: [Biquad::default(); MAX_STAGES],
+            stage_count: 1,
+        };
+        filter.set_params(params);
+        filter.jump_to_target();
+        filter
+    }
+
+    /// Gets the target parameters.
+    pub fn params(&self) -> &FilterParams {
+        &self.target
+    }
+
+    /// Sets new parameters; frequency, Q and gain glide to them.
+    ///
+    /// Changing the type or slope takes effect immediately.
+    pub fn set_params(&mut self, params: FilterParams) {
+        let nyquist = self.sample_rate as f32 * 0.49;
+        let params = FilterParams {
+            frequency: params.frequency.clamp(MIN_FREQUENCY, nyquist),
+            q: params.q.max(0.01),
+            ..params
+        };
+        let stage_count = match params.filter_type {
+            FilterType::LowPass | FilterType::HighPass => params.slope.stages(),
+            _ => 1,
+        };
+        let reshaped = params.filter_type != self.target.filter_type || stage_count != self.stage_count;
+        if stage_count > self.stage_count {
+            for stage in &mut self.stages[self.stage_count..stage_count] {
+                stage.reset();
+            }
+        }
+        self.stage_count = stage_count;
+        self.target = params;
+        self.settled = false;
+        if reshaped {
+            self.update_coefficients();
+        }
+    }
+
+    /// Sets the frequency without smoothing.
+    ///
+    /// For modulation sources that already move smoothly and update at
+    /// least every `CONTROL_INTERVAL` samples.
+    pub fn set_modulated_frequency(&mut self, frequency: f32) {
+        self.target.frequency = frequency.clamp(MIN_FREQUENCY, self.sample_rate as f32 * 0.49);
+        self.frequency = self.target.frequency;
+        self.update_coefficients();
+    }
+
+    /// Gets the frequency currently heard, in Hz.
+    pub fn current_frequency(&self) -> f32 {
+        self.frequency
+    }
+
+    /// Skips the remaining glide.
+    pub fn jump_to_target(&mut self) {
+        self.frequency = self.target.frequency;
+        self.q = self.target.q;
+        self.gain = self.target.gain;
+        self.settled = true;
+        self.update_coefficients();
+    }
+
+    /// Clears the filter state.
+    pub fn reset(&mut self) {
+        for stage in &mut self.stages {
+            stage.reset();
+        }
+    }
+
+    /// Gets the magnitude response at a frequency of the current coefficients, in dB.
+    pub fn response_db(&self, frequency: f32) -> f32 {
+        let magnitude: f32 = self.stages[..self.stage_count]
+            .iter()
+            .map(|stage| stage.coefficients.response(frequency, self.sample_rate).norm())
+            .product();
+        20.0 * magnitude.max(1e-9).log10()
+    }
+
+    /// Moves the heard parameters one control step towards the target.
+    fn glide(&mut self) {
+        let k = self.smoothing;
+        self.frequency *= (self.target.frequency / self.frequency).powf(k);
+        self.q *= (self.target.q / self.q).powf(k);
+        self.gain += (self.target.gain - self.gain) * k;
+        let close = (self.frequency / self.target.frequency - 1.0).abs() < 1e-4
+            && (self.q / self.target.q - 1.0).abs() < 1e-4
+            && (self.gain - self.target.gain).abs() < 1e-3;
+        if close {
+            self.frequency = self.target.frequency;
+            self.q = self.target.q;
+            self.gain = self.target.gain;
+            self.settled = true;
+        }
+        self.update_coefficients();
+    }
+
+    /// Recomputes the section coefficients from the heard parameters.
+    fn update_coefficients(&mut self) {
+        let FilterParams { filter_type, .. } = self.target;
+        let count = self.stage_count;
+        for (index, stage) in self.stages[..count].iter_mut().enumerate() {
+            let q = if count == 1 {
+                self.q
+            } else {
+                // Butterworth stage Q, with the resonance applied to the sharpest stage
+                let butterworth = 1.0 / (2.0 * ((2 * index + 1) as f32 * std::f32::consts::PI / (4 * count) as f32).cos());
+                if index == count - 1 {
+                    butterworth * self.q / std::f32::consts::FRAC_1_SQRT_2
+                } else {
+                    butterworth
+                }
+            };
+            stage.coefficients = BiquadCoefficients::new(filter_type, self.frequency, q, self.gain, self.sample_rate);
+        }
+    }
+
+    /// Filters one sample.
+    #[inline]
+    pub fn process_sample(&mut self, input: f32) -> f32 {
+        if !self.settled {
+            if self.countdown == 0 {
+                self.glide();
+                self.countdown = CONTROL_INTERVAL;
+            }
+            self.countdown -= 1;
+        }
+        self.stages[..self.stage_count]
+            .iter_mut()
+            .fold(input, |sample, stage| stage.process(sample))
+    }
+
+    /// Filters a buffer in place.
+    ///
+    /// # Arguments
+    /// * `buffer` - The audio to process.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - Always `Ok(())`.
+    pub fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        for sample in buffer.iter_mut() {
+            *sample = self.process_sample(*sample);
+        }
+        Ok(())
+    }
+}
+
+impl AudioEffect for Filter {
+    fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        Filter::process(self, buffer)
+    }
+
+    fn reset(&mut self) {
+        Filter::reset(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequency: f32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| (2.0 * std::f32::consts::PI * frequency * i as f32 / 48000.0).sin())
+            .collect()
+    }
+
+    fn level_db(samples: &[f32]) -> f32 {
+        let peak = samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        20.0 * peak.log10()
+    }
+
+    #[test]
+    fn test_responses_match_the_cookbook() {
+        let params = |filter_type, frequency, gain, slope| FilterParams { filter_type, frequency, gain, slope, ..Default::default() };
+        let cases = [
+            (params(FilterType::LowPass, 1000.0, 0.0, Slope::Db12), 1000.0, -3.01),
+            (params(FilterType::LowPass, 1000.0, 0.0, Slope::Db24), 1000.0, -3.01),
+            (params(FilterType::LowPass, 1000.0, 0.0, Slope::Db48), 2000.0, -48.2),
+            (params(FilterType::HighPass, 1000.0, 0.0, Slope::Db24), 250.0, -48.2),
+            (params(FilterType::Peak, 2000.0, 6.0, Slope::Db12), 2000.0, 6.0),
+            (params(FilterType::LowShelf, 200.0, -9.0, Slope::Db12), 20.0, -9.0),
+            (params(FilterType::HighShelf, 5000.0, 4.0, Slope::Db12), 18000.0, 4.0),
+            (params(FilterType::Notch, 60.0, 0.0, Slope::Db12), 1000.0, 0.0),
+            (params(FilterType::AllPass, 500.0, 0.0, Slope::Db12), 3000.0, 0.0),
+        ];
+        for (params, frequency, expected) in cases {
+            let filter = Filter::new(48000, params);
+            let response = filter.response_db(frequency);
+            assert!((response - expected).abs() < 0.5, "{:?} at {} Hz: {} dB", params, frequency, response);
+        }
+
+        // The processed signal follows the computed response
+        let mut filter = Filter::new(48000, params(FilterType::Peak, 2000.0, 6.0, Slope::Db12));
+        let mut notch = Filter::new(48000, FilterParams { filter_type: FilterType::Notch, frequency: 60.0, q: 4.0, ..Default::default() });
+        let mut boosted = sine(2000.0, 9600);
+        let mut hum = sine(60.0, 96000);
+        filter.process(&mut boosted).unwrap();
+        notch.process(&mut hum).unwrap();
+        assert!((level_db(&boosted[4800..]) - 6.0).abs() < 0.1);
+        assert!(level_db(&hum[48000..]) < -40.0);
+    }
+
+    #[test]
+    fn test_parameter_changes_do_not_click() {
+        let mut filter = Filter::new(48000, FilterParams { frequency: 200.0, q: 2.0, ..Default::default() });
+        let mut buffer = sine(150.0, 48000);
+        let input_step = 2.0 * std::f32::consts::PI * 150.0 / 48000.0;
+        for (index, block) in buffer.chunks_mut(480).enumerate() {
+            if index == 20 {
+                filter.set_params(FilterParams { frequency: 8000.0, q: 0.5, ..*filter.params() });
+            }
+            if index == 60 {
+                filter.set_params(FilterParams { frequency: 100.0, q: 8.0, ..*filter.params() });
+            }
+            filter.process(block).unwrap();
+        }
+        assert_eq!(filter.current_frequency(), 100.0);
+        // A click shows up as a jump far larger than the sine's own slope
+        let largest_step = buffer.windows(2).map(|w| (w[1] - w[0]).abs()).fold(0.0, f32::max);
+        assert!(largest_step < 8.0 * input_step, "{}", largest_step);
+    }
+}
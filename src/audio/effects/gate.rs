@@ -0,0 +1,291 @@
+﻿//! Noise gate and downward expander implementation
+//!
+//! The detector follows the peak level of the sidechain, optionally band
+//! limited so rumble or cymbals do not open the gate. Separate open and close
+//! thresholds give hysteresis, and a hold time keeps the gate open through
+//! short dips. While closed the gain falls by `ratio - 1` dB for every dB
+//! below the open threshold, down to `range`; a high ratio makes a gate, a
+//! low one a gentle expander. Gain changes are smoothed in dB.
+
+use crate::{
+    audio::effects::{
+        filter::{Filter, FilterParams, FilterType, Slope},
+        time_coefficient, AudioEffect,
+    },
+    error::types::AudioError,
+};
+
+/// Release time of the peak detector, in seconds.
+const DETECTOR_RELEASE: f32 = 0.01;
+
+/// Level treated as silence by the detector, in dB.
+const FLOOR_DB: f32 = -120.0;
+
+/// Settings for the noise gate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GateSettings {
+    /// Level at which the gate opens, in dBFS
+    pub open_threshold: f32,
+    /// Level below which the gate starts to close, in dBFS, at most `open_threshold`
+    pub close_threshold: f32,
+    /// Attenuation per dB below the open threshold while closed, at least 1.0
+    pub ratio: f32,
+    /// Most attenuation, in dB
+    pub range: f32,
+    /// Time to open, in seconds
+    pub attack: f32,
+    /// Time the gate stays open after the level falls below the close threshold, in seconds
+    pub hold: f32,
+    /// Time to close, in seconds
+    pub release: f32,
+    /// Cutoff of the sidechain high-pass, in Hz; `None` leaves the lows in
+    pub sidechain_high_pass: Option<f32>,
+    /// Cutoff of the sidechain low-pass, in Hz; `None` leaves the highs in
+    pub sidechain_low_pass: Option<f32>,
+}
+
+impl Default for GateSettings {
+    fn default() -> Self {
+        Self {
+            open_threshold: -40.0,
+            close_threshold: -46.0,
+            ratio: f32::INFINITY,
+            range: 80.0,
+            attack: 0.001,
+            hold: 0.05,
+            release: 0.1,
+            sidechain_high_pass: Some(80.0),
+            sidechain_low_pass: None,
+        }
+    }
+}
+
+/// Noise gate and expander with hysteresis, hold and a filtered sidechain.
+#[derive(Debug, Clone)]
+pub struct NoiseGate {
+    sample_rate: u32,
+    settings: GateSettings,
+    high_pass: Filter,
+    low_pass: Filter,
+    attack: f32,
+    release: f32,
+    detector_release: f32,
+    hold_samples: usize,
+    /// Peak level of the filtered sidechain
+    envelope: f32,
+    open: bool,
+    hold_remaining: usize,
+    /// Smoothed attenuation, in dB
+    reduction: f32,
+}
+
+impl NoiseGate {
+    /// Creates a new `NoiseGate` with default settings.
+    ///
+    /// # Arguments
+    /// * `sample_rate` - The sample rate of the audio.
+    ///
+    /// # Returns
+    /// * `NoiseGate` - A closed gate.
+    pub fn new(sample_rate: u32) -> Self {
+        let filter = |filter_type| {
+            Filter::new(sample_rate, FilterParams { filter_type, slope: Slope::Db12, ..Default::default() })
+        };
+        let mut gate = Self {
+            sample_rate,
+            settings: GateSettings::default(),
+            high_pass: filter(FilterType::HighPass),
+            low_pass: filter(FilterType::LowPass),
+            attack: 1.0,
+            release: 1.0,
+            detector_release: time_coefficient(DETECTOR_RELEASE, sample_rate),
+            hold_samples: 0,
+            envelope: 0.0,
+            open: false,
+            hold_remaining: 0,
+            reduction: 0.0,
+        };
+        gate.set_settings(GateSettings::default());
+        gate.reduction = gate.settings.range;
+        gate
+    }
+
+    /// Gets the current settings.
+    pub fn settings(&self) -> &GateSettings {
+        &self.settings
+    }
+
+    /// Updates the settings; values are clamped to their ranges.
+    pub fn set_settings(&mut self, settings: GateSettings) {
+        self.settings = GateSettings {
+            close_threshold: settings.close_threshold.min(settings.open_threshold),
+            ratio: settings.ratio.max(1.0),
+            range: settings.range.max(0.0),
+            attack: settings.attack.max(0.0),
+            hold: settings.hold.max(0.0),
+            release: settings.release.max(0.0),
+            ..settings
+        };
+        self.attack = time_coefficient(self.settings.attack, self.sample_rate);
+        self.release = time_coefficient(self.settings.release, self.sample_rate);
+        self.hold_samples = (self.settings.hold * self.sample_rate as f32) as usize;
+        if let Some(frequency) = self.settings.sidechain_high_pass {
+            self.high_pass.set_params(FilterParams { frequency, ..*self.high_pass.params() });
+        }
+        if let Some(frequency) = self.settings.sidechain_low_pass {
+            self.low_pass.set_params(FilterParams { frequency, ..*self.low_pass.params() });
+        }
+    }
+
+    /// Checks whether the gate is open, including while holding.
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    /// Gets the current attenuation, in dB (zero or positive).
+    pub fn gain_reduction(&self) -> f32 {
+        self.reduction
+    }
+
+    /// Closes the gate and clears the detector.
+    pub fn reset(&mut self) {
+        self.high_pass.reset();
+        self.low_pass.reset();
+        self.envelope = 0.0;
+        self.open = false;
+        self.hold_remaining = 0;
+        self.reduction = self.settings.range;
+    }
+
+    /// Advances the detector by one sidechain sample and returns the linear gain.
+    #[inline]
+    fn next_gain(&mut self, sidechain: f32) -> f32 {
+        let GateSettings { open_threshold, close_threshold, ratio, range, .. } = self.settings;
+        let mut key = sidechain;
+        if self.settings.sidechain_high_pass.is_some() {
+            key = self.high_pass.process_sample(key);
+        }
+        if self.settings.sidechain_low_pass.is_some() {
+            key = self.low_pass.process_sample(key);
+        }
+        let peak = key.abs();
+        self.envelope = if peak > self.envelope {
+            peak
+        } else {
+            self.envelope + (peak - self.envelope) * self.detector_release
+        };
+        let level_db = if self.envelope > 0.0 { (20.0 * self.envelope.log10()).max(FLOOR_DB) } else { FLOOR_DB };
+
+        if level_db >= open_threshold || (self.open && level_db >= close_threshold) {
+            self.open = true;
+            self.hold_remaining = self.hold_samples;
+        } else if self.open {
+            if self.hold_remaining == 0 {
+                self.open = false;
+            } else {
+                self.hold_remaining -= 1;
+            }
+        }
+        let target = if self.open {
+            0.0
+        } else {
+            ((open_threshold - level_db) * (ratio - 1.0)).min(range)
+        };
+        let coefficient = if target < self.reduction { self.attack } else { self.release };
+        self.reduction += (target - self.reduction) * coefficient;
+        10f32.powf(-self.reduction / 20.0)
+    }
+
+    /// Gates a buffer in place, detecting on the buffer itself.
+    ///
+    /// # Arguments
+    /// * `buffer` - The audio to process.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - Always `Ok(())`.
+    pub fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        for sample in buffer.iter_mut() {
+            *sample *= self.next_gain(*sample);
+        }
+        Ok(())
+    }
+
+    /// Gates a buffer in place, detecting on a separate sidechain signal.
+    ///
+    /// # Arguments
+    /// * `buffer` - The audio to process.
+    /// * `sidechain` - The signal driving the detector, the same length as `buffer`.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - `BufferMismatch` if the lengths differ.
+    pub fn process_with_sidechain(&mut self, buffer: &mut [f32], sidechain: &[f32]) -> Result<(), AudioError> {
+        if buffer.len() != sidechain.len() {
+            return Err(AudioError::BufferMismatch);
+        }
+        for (sample, &key) in buffer.iter_mut().zip(sidechain) {
+            *sample *= self.next_gain(key);
+        }
+        Ok(())
+    }
+}
+
+impl AudioEffect for NoiseGate {
+    fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        NoiseGate::process(self, buffer)
+    }
+
+    fn reset(&mut self) {
+        NoiseGate::reset(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(level_db: f32, len: usize) -> Vec<f32> {
+        let amplitude = 10f32.powf(level_db / 20.0);
+        (0..len)
+            .map(|i| amplitude * (2.0 * std::f32::consts::PI * 1000.0 * i as f32 / 48000.0).sin())
+            .collect()
+    }
+
+    #[test]
+    fn test_hysteresis_hold_and_expansion() {
+        let mut gate = NoiseGate::new(48000);
+        gate.set_settings(GateSettings { sidechain_high_pass: None, ..Default::default() });
+
+        // Between the thresholds a closed gate stays closed...
+        gate.process(&mut sine(-43.0, 4800)).unwrap();
+        assert!(!gate.is_open());
+        assert!(gate.gain_reduction() > 79.0);
+        // ...and an open one stays open
+        gate.process(&mut sine(-20.0, 480)).unwrap();
+        gate.process(&mut sine(-43.0, 4800)).unwrap();
+        assert!(gate.is_open());
+        assert!(gate.gain_reduction() < 0.01);
+
+        // Below the close threshold it holds for 50 ms, then closes
+        gate.process(&mut sine(-60.0, 1920)).unwrap();
+        assert!(gate.is_open());
+        gate.process(&mut sine(-60.0, 1920)).unwrap();
+        assert!(!gate.is_open());
+        let mut tail = sine(-60.0, 48000);
+        gate.process(&mut tail).unwrap();
+        assert!(tail[47000..].iter().all(|s| s.abs() < 1e-6));
+
+        // A 2:1 expander turns a signal 10 dB under the threshold down by 10 dB more
+        gate.set_settings(GateSettings { ratio: 2.0, ..*gate.settings() });
+        gate.process(&mut sine(-50.0, 48000)).unwrap();
+        assert!((gate.gain_reduction() - 10.0).abs() < 0.5, "{}", gate.gain_reduction());
+
+        // Filtering the sidechain keeps low rumble from opening the gate
+        let mut filtered = NoiseGate::new(48000);
+        filtered.set_settings(GateSettings { sidechain_high_pass: Some(500.0), ..Default::default() });
+        let mut rumble: Vec<f32> = (0..9600)
+            .map(|i| 0.05 * (2.0 * std::f32::consts::PI * 40.0 * i as f32 / 48000.0).sin())
+            .collect();
+        filtered.process(&mut rumble).unwrap();
+        assert!(!filtered.is_open());
+    }
+}
@@ -0,0 +1,251 @@
+﻿//! Low-frequency oscillator shared by the modulation effects
+//!
+//! The rate is either free in Hz or a note value following the
+//! `MasterClock` tempo, re-read at the start of every block. Effects restart
+//! the phase from `AudioEffect::on_loop_start`, so a sweep lines up with
+//! every pass of the loop.
+
+use std::sync::Arc;
+
+use crate::{audio::effects::delay::NoteValue, sync::clock::MasterClock};
+
+/// Tempo used for note values when no clock is attached.
+const DEFAULT_BPM: f64 = 120.0;
+
+/// Waveform of an LFO.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LfoShape {
+    /// Smooth sine
+    Sine,
+    /// Linear ramps up and down
+    Triangle,
+    /// Alternates between the extremes
+    Square,
+    /// A new random value held for each cycle
+    SampleAndHold,
+}
+
+/// Speed of an LFO.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LfoRate {
+    /// Cycles per second
+    Hertz(f32),
+    /// One cycle per note value at the clock tempo
+    Note(NoteValue),
+}
+
+/// Low-frequency oscillator producing values from -1.0 to 1.0.
+#[derive(Clone)]
+pub struct Lfo {
+    sample_rate: u32,
+    shape: LfoShape,
+    rate: LfoRate,
+    clock: Option<Arc<MasterClock>>,
+    tempo: f64,
+    /// Position in the cycle, from 0.0 to 1.0
+    phase: f64,
+    /// Phase advance per sample
+    increment: f64,
+    held: f32,
+    noise: u32,
+}
+
+impl Lfo {
+    /// Creates a new `Lfo`: a 1 Hz sine starting at phase zero.
+    ///
+    /// # Arguments
+    /// * `sample_rate` - The sample rate of the audio.
+    ///
+    /// # Returns
+    /// * `Lfo` - An oscillator with no clock attached.
+    pub fn new(sample_rate: u32) -> Self {
+        let mut lfo = Self {
+            sample_rate,
+            shape: LfoShape::Sine,
+            rate: LfoRate::Hertz(1.0),
+            clock: None,
+            tempo: DEFAULT_BPM,
+            phase: 0.0,
+            increment: 0.0,
+            held: 0.0,
+            noise: 0x9e37_79b9,
+        };
+        lfo.update();
+        lfo.sample_new_value();
+        lfo
+    }
+
+    /// Gets the waveform.
+    pub fn shape(&self) -> LfoShape {
+        self.shape
+    }
+
+    /// Gets the rate.
+    pub fn rate(&self) -> LfoRate {
+        self.rate
+    }
+
+    /// Sets the waveform and rate.
+    pub fn set(&mut self, shape: LfoShape, rate: LfoRate) {
+        self.shape = shape;
+        self.rate = rate;
+        self.update();
+    }
+
+    /// Locks note-value rates to a clock's tempo.
+    pub fn set_clock(&mut self, clock: Option<Arc<MasterClock>>) {
+        self.clock = clock;
+        self.update();
+    }
+
+    /// Sets the tempo, in quarter notes per minute, used for note values when no clock is attached.
+    pub fn set_tempo(&mut self, bpm: f64) {
+        self.tempo = bpm;
+        self.update();
+    }
+
+    /// Gets the current rate in Hz.
+    pub fn frequency(&self) -> f64 {
+        match self.rate {
+            LfoRate::Hertz(hz) => hz.max(0.0) as f64,
+            LfoRate::Note(note) => {
+                let (bpm, denominator) = self.clock.as_ref().map_or((self.tempo, 4), |clock| {
+                    (clock.bpm() as f64, clock.time_signature().denominator)
+                });
+                bpm.max(1.0) / 60.0 / note.beats(denominator)
+            }
+        }
+    }
+
+    /// Re-reads the clock tempo; call once per block.
+    pub fn update(&mut self) {
+        self.increment = self.frequency() / self.sample_rate as f64;
+    }
+
+    /// Restarts the cycle, e.g. at the start of a loop.
+    pub fn reset(&mut self) {
+        self.phase = 0.0;
+        self.sample_new_value();
+    }
+
+    /// Gets the value at the current phase.
+    #[inline]
+    pub fn value(&self) -> f32 {
+        self.value_at(0.0)
+    }
+
+    /// Gets the value a fraction of a cycle ahead of the current phase.
+    ///
+    /// Used for stereo spread and multiple voices; sample-and-hold returns
+    /// the held value for any offset.
+    #[inline]
+    pub fn value_at(&self, offset: f32) -> f32 {
+        let phase = (self.phase as f32 + offset).rem_euclid(1.0);
+        match self.shape {
+            LfoShape::Sine => (2.0 * std::f32::consts::PI * phase).sin(),
+            LfoShape::Triangle => 4.0 * ((phase + 0.75).fract() - 0.5).abs() - 1.0,
+            LfoShape::Square => {
+                if phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            LfoShape::SampleAndHold => self.held,
+        }
+    }
+
+    /// Advances the phase by a number of samples.
+    #[inline]
+    pub fn advance(&mut self, samples: usize) {
+        self.phase += self.increment * samples as f64;
+        if self.phase >= 1.0 {
+            self.phase = self.phase.fract();
+            self.sample_new_value();
+        }
+    }
+
+    /// Returns the current value and advances by one sample.
+    #[inline]
+    pub fn tick(&mut self) -> f32 {
+        let value = self.value();
+        self.advance(1);
+        value
+    }
+
+    /// Draws the next sample-and-hold value (xorshift32).
+    fn sample_new_value(&mut self) {
+        self.noise ^= self.noise << 13;
+        self.noise ^= self.noise >> 17;
+        self.noise ^= self.noise << 5;
+        self.held = (self.noise as f64 / u32::MAX as f64 * 2.0 - 1.0) as f32;
+    }
+}
+
+impl std::fmt::Debug for Lfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Lfo")
+            .field("shape", &self.shape)
+            .field("rate", &self.rate)
+            .field("phase", &self.phase)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::effects::delay::{NoteDivision, NoteModifier};
+    use crate::sync::clock::TimeSignature;
+
+    #[test]
+    fn test_shapes() {
+        let mut lfo = Lfo::new(1000);
+        let quarter_cycle = |lfo: &mut Lfo| {
+            let values: Vec<f32> = (0..4).map(|_| {
+                let value = lfo.value();
+                lfo.advance(250);
+                value
+            }).collect();
+            lfo.reset();
+            values
+        };
+        let close = |a: &[f32], b: &[f32]| a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-4);
+        assert!(close(&quarter_cycle(&mut lfo), &[0.0, 1.0, 0.0, -1.0]));
+        lfo.set(LfoShape::Triangle, LfoRate::Hertz(1.0));
+        assert!(close(&quarter_cycle(&mut lfo), &[0.0, 1.0, 0.0, -1.0]));
+        lfo.set(LfoShape::Square, LfoRate::Hertz(1.0));
+        assert!(close(&quarter_cycle(&mut lfo), &[1.0, 1.0, -1.0, -1.0]));
+
+        // Sample-and-hold changes once per cycle and stays in range
+        lfo.set(LfoShape::SampleAndHold, LfoRate::Hertz(1.0));
+        let first = lfo.tick();
+        assert!((0..998).all(|_| lfo.tick() == first));
+        lfo.advance(2);
+        assert_ne!(lfo.value(), first);
+        assert!(lfo.value().abs() <= 1.0);
+    }
+
+    #[test]
+    fn test_note_rate_follows_clock() {
+        let clock = Arc::new(MasterClock::new(48000, 120.0));
+        let mut lfo = Lfo::new(48000);
+        lfo.set(LfoShape::Sine, LfoRate::Note(NoteValue::new(NoteDivision::Quarter, NoteModifier::Straight)));
+        assert!((lfo.frequency() - 2.0).abs() < 1e-9);
+        lfo.set_clock(Some(clock.clone()));
+        clock.set_bpm(90.0);
+        lfo.update();
+        assert!((lfo.frequency() - 1.5).abs() < 1e-6);
+
+        // In 6/8 the clock counts eighth notes, so a quarter note is two beats
+        clock.set_time_signature(TimeSignature::new(6, 8).unwrap());
+        lfo.update();
+        assert!((lfo.frequency() - 0.75).abs() < 1e-6);
+
+        // Resetting on the loop start brings the phase back to zero
+        lfo.advance(5000);
+        assert!(lfo.value().abs() > 0.1);
+        lfo.reset();
+        assert_eq!(lfo.value(), 0.0);
+    }
+}
@@ -0,0 +1,276 @@
+﻿//! True-peak brickwall limiter implementation
+//!
+//! The detector measures inter-sample peaks with the 4x oversampled
+//! `TruePeakDetector`, and the audio is delayed by the lookahead so the gain
+//! can be brought down before a peak arrives. The gain each peak needs is
+//! held for the lookahead and then averaged over it, which turns every drop
+//! into a ramp that reaches its target exactly when the peak is played; the
+//! release then lets the gain back up with a one-pole curve. Both channels
+//! share the gain so the stereo image does not shift.
+
+use std::collections::VecDeque;
+
+use crate::{
+    audio::{
+        analysis::meter::TruePeakDetector,
+        effects::{time_coefficient, AudioEffect},
+    },
+    error::types::AudioError,
+};
+
+/// Longest supported lookahead, in seconds.
+pub const MAX_LOOKAHEAD: f32 = 0.02;
+
+/// How far the true-peak detector lags its input, in samples.
+const DETECTOR_LAG: usize = 6;
+
+/// Settings for the limiter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LimiterSettings {
+    /// Highest output level, in dBTP
+    pub ceiling: f32,
+    /// Time for the gain to recover, in seconds
+    pub release: f32,
+    /// How far the detector looks ahead of the audio, in seconds, from 0.5 ms up to `MAX_LOOKAHEAD`
+    pub lookahead: f32,
+}
+
+impl Default for LimiterSettings {
+    fn default() -> Self {
+        Self {
+            ceiling: -1.0,
+            release: 0.1,
+            lookahead: 0.005,
+        }
+    }
+}
+
+/// Stereo-linked lookahead limiter with a true-peak ceiling.
+#[derive(Debug, Clone)]
+pub struct Limiter {
+    sample_rate: u32,
+    settings: LimiterSettings,
+    detectors: [TruePeakDetector; 2],
+    /// Audio waiting for the gain, one ring per channel
+    delay: [Vec<f32>; 2],
+    delay_index: usize,
+    /// Lookahead in samples
+    window: usize,
+    /// Required gains still in the hold window, increasing from front to back
+    minimum: VecDeque<(usize, f32)>,
+    /// Held gains being averaged
+    held: Vec<f32>,
+    held_index: usize,
+    held_sum: f64,
+    counter: usize,
+    ceiling: f32,
+    release: f32,
+    gain: f32,
+}
+
+impl Limiter {
+    /// Creates a new `Limiter` with default settings.
+    ///
+    /// # Arguments
+    /// * `sample_rate` - The sample rate of the audio.
+    ///
+    /// # Returns
+    /// * `Limiter` - A limiter with its lookahead buffers allocated.
+    pub fn new(sample_rate: u32) -> Self {
+        let capacity = (MAX_LOOKAHEAD * sample_rate as f32).ceil() as usize + DETECTOR_LAG + 1;
+        let mut limiter = Self {
+            sample_rate,
+            settings: LimiterSettings::default(),
+            detectors: [TruePeakDetector::new(); 2],
+            delay: [vec![0.0; capacity], vec![0.0; capacity]],
+            delay_index: 0,
+            window: 1,
+            minimum: VecDeque::with_capacity(capacity),
+            held: vec![1.0; capacity],
+            held_index: 0,
+            held_sum: 0.0,
+            counter: 0,
+            ceiling: 1.0,
+            release: 1.0,
+            gain: 1.0,
+        };
+        limiter.set_settings(LimiterSettings::default());
+        limiter
+    }
+
+    /// Gets the current settings.
+    pub fn settings(&self) -> &LimiterSettings {
+        &self.settings
+    }
+
+    /// Updates the settings; values are clamped to their ranges.
+    ///
+    /// Changing the lookahead changes the latency and clears the limiter.
+    pub fn set_settings(&mut self, settings: LimiterSettings) {
+        let previous = self.settings.lookahead;
+        self.settings = LimiterSettings {
+            ceiling: settings.ceiling.min(0.0),
+            release: settings.release.max(0.001),
+            lookahead: settings.lookahead.clamp(0.0005, MAX_LOOKAHEAD),
+        };
+        self.ceiling = 10f32.powf(self.settings.ceiling / 20.0);
+        self.release = time_coefficient(self.settings.release, self.sample_rate);
+        self.window = ((self.settings.lookahead * self.sample_rate as f32).round() as usize).max(1);
+        if self.settings.lookahead != previous || self.held_sum == 0.0 {
+            self.reset();
+        }
+    }
+
+    /// Gets the current gain reduction, in dB (zero or positive).
+    pub fn gain_reduction(&self) -> f32 {
+        -20.0 * self.gain.log10()
+    }
+
+    /// Gets the delay added by the lookahead, in samples.
+    pub fn latency(&self) -> usize {
+        self.window + DETECTOR_LAG
+    }
+
+    /// Clears the audio and detector state.
+    pub fn reset(&mut self) {
+        self.detectors.iter_mut().for_each(TruePeakDetector::reset);
+        self.delay.iter_mut().for_each(|line| line.fill(0.0));
+        self.minimum.clear();
+        self.held.fill(1.0);
+        self.held_index = 0;
+        self.held_sum = self.window as f64;
+        self.counter = 0;
+        self.gain = 1.0;
+    }
+
+    /// Advances the gain computer by one detected peak and returns the gain.
+    ///
+    /// The hold covers the lookahead plus the samples either side of an
+    /// inter-sample peak, so the averaged ramp is at its target for both.
+    #[inline]
+    fn next_gain(&mut self, peak: f32) -> f32 {
+        let required = if peak > self.ceiling { self.ceiling / peak } else { 1.0 };
+        while self.minimum.back().is_some_and(|&(_, gain)| gain >= required) {
+            self.minimum.pop_back();
+        }
+        self.minimum.push_back((self.counter, required));
+        while self.minimum.front().is_some_and(|&(index, _)| index + self.window + 2 <= self.counter) {
+            self.minimum.pop_front();
+        }
+        self.counter += 1;
+        let held = self.minimum.front().map_or(1.0, |&(_, gain)| gain);
+
+        self.held_sum += (held - self.held[self.held_index]) as f64;
+        self.held[self.held_index] = held;
+        self.held_index = (self.held_index + 1) % self.window;
+        let average = (self.held_sum / self.window as f64) as f32;
+
+        self.gain = if average < self.gain {
+            average
+        } else {
+            self.gain + (average - self.gain) * self.release
+        };
+        self.gain
+    }
+
+    /// Runs one frame through the detectors, the gain computer and the delay.
+    ///
+    /// The detected peak also includes the sample itself, lined up with the
+    /// detector's lag, since the interpolated points miss it slightly.
+    #[inline]
+    fn tick(&mut self, frame: [f32; 2], channels: usize) -> [f32; 2] {
+        let len = self.delay[0].len();
+        let lagged = (self.delay_index + len - DETECTOR_LAG) % len;
+        let mut peak = 0.0f32;
+        let lines = self.delay.iter_mut().zip(self.detectors.iter_mut());
+        for (&sample, (line, detector)) in frame.iter().take(channels).zip(lines) {
+            line[self.delay_index] = sample;
+            peak = peak.max(detector.process(sample)).max(line[lagged].abs());
+        }
+        let gain = self.next_gain(peak);
+        let read = (self.delay_index + len - self.latency()) % len;
+        let mut output = [0.0; 2];
+        for (channel, output) in output.iter_mut().enumerate().take(channels) {
+            *output = self.delay[channel][read] * gain;
+        }
+        self.delay_index = (self.delay_index + 1) % len;
+        output
+    }
+
+    /// Processes a mono buffer in place.
+    ///
+    /// # Arguments
+    /// * `buffer` - The audio to process.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - Always `Ok(())`.
+    pub fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        for sample in buffer.iter_mut() {
+            *sample = self.tick([*sample, 0.0], 1)[0];
+        }
+        Ok(())
+    }
+
+    /// Processes a stereo pair in place with linked gain.
+    ///
+    /// # Arguments
+    /// * `left` - The left channel.
+    /// * `right` - The right channel, the same length as `left`.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - `BufferMismatch` if the channel lengths differ.
+    pub fn process_stereo(&mut self, left: &mut [f32], right: &mut [f32]) -> Result<(), AudioError> {
+        if left.len() != right.len() {
+            return Err(AudioError::BufferMismatch);
+        }
+        for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+            [*l, *r] = self.tick([*l, *r], 2);
+        }
+        Ok(())
+    }
+}
+
+impl AudioEffect for Limiter {
+    fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        Limiter::process(self, buffer)
+    }
+
+    fn reset(&mut self) {
+        Limiter::reset(self)
+    }
+
+    fn latency(&self) -> usize {
+        Limiter::latency(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_true_peaks_stay_under_the_ceiling() {
+        let mut limiter = Limiter::new(48000);
+        let ceiling = 10f32.powf(-1.0 / 20.0);
+
+        // A quarter-rate sine sampled 45° off its crests peaks 3 dB above its samples
+        let mut left = vec![0.0; 62400];
+        for (i, sample) in left[4800..14400].iter_mut().enumerate() {
+            *sample = 2.0 * (std::f32::consts::FRAC_PI_2 * i as f32 + std::f32::consts::FRAC_PI_4).sin();
+        }
+        let mut right = vec![0.0; left.len()];
+        right[4000] = 3.0;
+        limiter.process_stereo(&mut left, &mut right).unwrap();
+
+        // Every sample and every inter-sample peak is held down, including the sudden onset
+        let mut meter = TruePeakDetector::new();
+        let true_peak = left.iter().map(|&s| meter.process(s)).fold(0.0, f32::max);
+        assert!(true_peak <= ceiling * 1.01, "{}", true_peak);
+        assert!(right.iter().all(|s| s.abs() <= ceiling));
+        let latency = limiter.latency();
+        assert!((right[4000 + latency] - ceiling).abs() < 0.01, "{}", right[4000 + latency]);
+
+        // The gain recovers once the loud part has passed
+        assert!(limiter.gain_reduction() < 0.01, "{}", limiter.gain_reduction());
+    }
+}
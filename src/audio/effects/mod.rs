@@ -4,11 +4,61 @@ pub mod reverb;
 pub mod delay;
 pub mod compressor;
 pub mod pitch;
+pub mod filter;
+pub mod eq;
+pub mod auto_filter;
+pub mod lfo;
+pub mod chorus;
+pub mod flanger;
+pub mod phaser;
+pub mod tremolo;
+pub mod oversampling;
+pub mod saturation;
+pub mod beat_repeat;
+pub mod slicer;
+pub mod gate;
+pub mod noise_reduction;
+pub mod limiter;
+pub mod convolution;
+
+pub use auto_filter::{AutoFilter, AutoFilterSettings, FilterModulation};
+pub use beat_repeat::{BeatRepeat, BeatRepeatSettings};
+pub use chorus::{Chorus, ChorusSettings};
+pub use convolution::{Convolution, ConvolutionMode, ConvolutionSettings, ImpulseResponse};
+pub use compressor::{Compressor, CompressorSettings, Sidechain};
+pub use delay::{Delay, DelaySettings, DelayTime};
+pub use eq::{EqBand, EqSettings, ParametricEq};
+pub use filter::{Filter, FilterParams, FilterType, Slope};
+pub use flanger::{Flanger, FlangerSettings};
+pub use gate::{GateSettings, NoiseGate};
+pub use lfo::{Lfo, LfoRate, LfoShape};
+pub use limiter::{Limiter, LimiterSettings};
+pub use noise_reduction::{NoiseReduction, NoiseReductionSettings};
+pub use oversampling::{Oversampler, Oversampling};
+pub use phaser::{Phaser, PhaserSettings};
+pub use pitch::{Harmonizer, HarmonizerSettings, HarmonyVoice, PitchShiftSettings, PitchShifter};
+pub use reverb::{Reverb, ReverbSettings};
+pub use saturation::{Bitcrusher, BitcrusherSettings, Drive, DriveSettings, Saturation};
+pub use slicer::{Slicer, SlicerSettings};
+pub use tremolo::{AutoPan, AutoPanSettings, Tremolo, TremoloSettings};
 
 use crate::error::types::AudioError;
 
+/// One-pole coefficient reaching ~63% of a step in `seconds`.
+///
+/// A time of zero or less gives 1.0, so the filter follows its input at once.
+pub(crate) fn time_coefficient(seconds: f32, sample_rate: u32) -> f32 {
+    if seconds <= 0.0 {
+        1.0
+    } else {
+        1.0 - (-1.0 / (seconds * sample_rate as f32)).exp()
+    }
+}
+
 /// Trait for audio effects that can process audio buffers.
-pub trait AudioEffect {
+///
+/// Effects are `Send` so they can be moved onto the audio thread.
+pub trait AudioEffect: Send {
     /// Processes an audio buffer in place.
     ///
     /// # Arguments
@@ -17,6 +67,26 @@ pub trait AudioEffect {
     /// # Returns
     /// * `Result<(), AudioError>` - Returns `Ok(())` if processing succeeds, or an error otherwise.
     fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError>;
+
+    /// Clears all internal state, as if the effect had just been created.
+    ///
+    /// Called before the effect is reused on an unrelated signal, such as
+    /// the next channel of a buffer, so nothing carries over from the last one.
+    fn reset(&mut self);
+
+    /// Delay the effect adds to the signal, in samples.
+    ///
+    /// # Returns
+    /// * `usize` - The latency to compensate for; zero for most effects.
+    fn latency(&self) -> usize {
+        0
+    }
+
+    /// Called when the loop the effect runs on wraps around.
+    ///
+    /// Modulation effects restart their LFO here so every pass of the loop
+    /// sounds the same.
+    fn on_loop_start(&mut self) {}
 }
 
 /// A chain of audio effects that can be applied sequentially.
@@ -25,7 +95,26 @@ pub struct EffectsChain {
     pub enabled: bool,                      // Whether the effects chain is enabled.
 }
 
+impl Default for EffectsChain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl EffectsChain {
+    /// Creates an empty, enabled effects chain.
+    pub fn new() -> Self {
+        Self {
+            effects: Vec::new(),
+            enabled: true,
+        }
+    }
+
+    /// Appends an effect to the end of the chain.
+    pub fn add(&mut self, effect: Box<dyn AudioEffect>) {
+        self.effects.push(effect);
+    }
+
     /// Processes an audio buffer through the chain of effects.
     ///
     /// # Arguments
@@ -44,6 +133,31 @@ impl EffectsChain {
 
         Ok(())
     }
+
+    /// Total latency of the effects in the chain, in samples.
+    ///
+    /// # Returns
+    /// * `usize` - The sum of each effect's latency, or zero if the chain is disabled.
+    pub fn latency(&self) -> usize {
+        if !self.enabled {
+            return 0;
+        }
+        self.effects.iter().map(|effect| effect.latency()).sum()
+    }
+
+    /// Clears the state of every effect in the chain.
+    pub fn reset(&mut self) {
+        for effect in &mut self.effects {
+            effect.reset();
+        }
+    }
+
+    /// Tells every effect in the chain that the loop has wrapped around.
+    pub fn on_loop_start(&mut self) {
+        for effect in &mut self.effects {
+            effect.on_loop_start();
+        }
+    }
 }
 
 /// A processor for handling audio effects with a specific sample rate.
@@ -0,0 +1,240 @@
+﻿//! Phaser effect implementation
+//!
+//! A chain of first-order allpass stages whose break frequency is swept
+//! exponentially by the shared `Lfo`. Mixing the phase-shifted signal with
+//! the dry one cancels a notch for every two stages; feedback deepens them.
+
+use std::sync::Arc;
+
+use crate::{
+    audio::effects::{
+        lfo::{Lfo, LfoRate, LfoShape},
+        AudioEffect,
+    },
+    error::types::AudioError,
+    sync::clock::MasterClock,
+};
+
+/// Most allpass stages a phaser runs.
+pub const MAX_STAGES: usize = 12;
+
+/// Settings for the phaser.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PhaserSettings {
+    /// LFO rate
+    pub rate: LfoRate,
+    /// LFO waveform
+    pub shape: LfoShape,
+    /// Number of allpass stages, an even number from 2 to `MAX_STAGES`
+    pub stages: usize,
+    /// Bottom of the sweep, in Hz
+    pub min_frequency: f32,
+    /// Top of the sweep, in Hz
+    pub max_frequency: f32,
+    /// Amount of the output fed back into the first stage, from -0.95 to 0.95
+    pub feedback: f32,
+    /// Phase offset of the right channel, as a fraction of a cycle
+    pub spread: f32,
+    /// Balance of the phase-shifted signal against the dry signal, from 0.0 to 1.0
+    pub mix: f32,
+}
+
+impl Default for PhaserSettings {
+    fn default() -> Self {
+        Self {
+            rate: LfoRate::Hertz(0.5),
+            shape: LfoShape::Sine,
+            stages: 4,
+            min_frequency: 200.0,
+            max_frequency: 2000.0,
+            feedback: 0.5,
+            spread: 0.25,
+            mix: 0.5,
+        }
+    }
+}
+
+/// State of one channel's allpass chain.
+#[derive(Debug, Clone, Copy, Default)]
+struct AllpassChain {
+    states: [f32; MAX_STAGES],
+    last: f32,
+}
+
+impl AllpassChain {
+    #[inline]
+    fn process(&mut self, input: f32, coefficient: f32, stages: usize, feedback: f32) -> f32 {
+        let mut sample = input + self.last * feedback;
+        for state in &mut self.states[..stages] {
+            let output = coefficient * sample + *state;
+            *state = sample - coefficient * output;
+            sample = output;
+        }
+        self.last = sample;
+        sample
+    }
+}
+
+/// Stereo phaser with feedback.
+#[derive(Debug, Clone)]
+pub struct Phaser {
+    sample_rate: u32,
+    settings: PhaserSettings,
+    lfo: Lfo,
+    chains: [AllpassChain; 2],
+}
+
+impl Phaser {
+    /// Creates a new `Phaser` with default settings.
+    ///
+    /// # Arguments
+    /// * `sample_rate` - The sample rate of the audio.
+    ///
+    /// # Returns
+    /// * `Phaser` - A four-stage phaser.
+    pub fn new(sample_rate: u32) -> Self {
+        let mut phaser = Self {
+            sample_rate,
+            settings: PhaserSettings::default(),
+            lfo: Lfo::new(sample_rate),
+            chains: [AllpassChain::default(); 2],
+        };
+        phaser.set_settings(PhaserSettings::default());
+        phaser
+    }
+
+    /// Gets the current settings.
+    pub fn settings(&self) -> &PhaserSettings {
+        &self.settings
+    }
+
+    /// Updates the settings; values are clamped to their ranges.
+    pub fn set_settings(&mut self, settings: PhaserSettings) {
+        let nyquist = self.sample_rate as f32 * 0.45;
+        let min_frequency = settings.min_frequency.clamp(10.0, nyquist);
+        self.settings = PhaserSettings {
+            stages: (settings.stages.clamp(2, MAX_STAGES) / 2) * 2,
+            min_frequency,
+            max_frequency: settings.max_frequency.clamp(min_frequency, nyquist),
+            feedback: settings.feedback.clamp(-0.95, 0.95),
+            mix: settings.mix.clamp(0.0, 1.0),
+            ..settings
+        };
+        self.lfo.set(self.settings.shape, self.settings.rate);
+    }
+
+    /// Locks note-value rates to a clock's tempo.
+    pub fn set_clock(&mut self, clock: Option<Arc<MasterClock>>) {
+        self.lfo.set_clock(clock);
+    }
+
+    /// Clears the allpass state and restarts the LFO.
+    pub fn reset(&mut self) {
+        self.chains = [AllpassChain::default(); 2];
+        self.lfo.reset();
+    }
+
+    /// Allpass coefficient for the swept break frequency.
+    #[inline]
+    fn coefficient(&self, offset: f32) -> f32 {
+        let PhaserSettings { min_frequency, max_frequency, .. } = self.settings;
+        let sweep = 0.5 + 0.5 * self.lfo.value_at(offset);
+        let frequency = min_frequency * (max_frequency / min_frequency).powf(sweep);
+        let t = (std::f32::consts::PI * frequency / self.sample_rate as f32).tan();
+        (t - 1.0) / (t + 1.0)
+    }
+
+    /// Processes a mono buffer in place.
+    ///
+    /// # Arguments
+    /// * `buffer` - The audio to process.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - Always `Ok(())`.
+    pub fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        self.lfo.update();
+        let PhaserSettings { stages, feedback, mix, .. } = self.settings;
+        for sample in buffer.iter_mut() {
+            let coefficient = self.coefficient(0.0);
+            let shifted = self.chains[0].process(*sample, coefficient, stages, feedback);
+            *sample = *sample * (1.0 - mix) + shifted * mix;
+            self.lfo.advance(1);
+        }
+        Ok(())
+    }
+
+    /// Processes a stereo pair in place.
+    ///
+    /// # Arguments
+    /// * `left` - The left channel.
+    /// * `right` - The right channel, the same length as `left`.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - `BufferMismatch` if the channel lengths differ.
+    pub fn process_stereo(&mut self, left: &mut [f32], right: &mut [f32]) -> Result<(), AudioError> {
+        if left.len() != right.len() {
+            return Err(AudioError::BufferMismatch);
+        }
+        self.lfo.update();
+        let PhaserSettings { stages, feedback, mix, spread, .. } = self.settings;
+        for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+            let (left_coefficient, right_coefficient) = (self.coefficient(0.0), self.coefficient(spread));
+            let shifted_left = self.chains[0].process(*l, left_coefficient, stages, feedback);
+            let shifted_right = self.chains[1].process(*r, right_coefficient, stages, feedback);
+            *l = *l * (1.0 - mix) + shifted_left * mix;
+            *r = *r * (1.0 - mix) + shifted_right * mix;
+            self.lfo.advance(1);
+        }
+        Ok(())
+    }
+}
+
+impl AudioEffect for Phaser {
+    fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        Phaser::process(self, buffer)
+    }
+
+    fn reset(&mut self) {
+        Phaser::reset(self)
+    }
+
+    fn on_loop_start(&mut self) {
+        self.lfo.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notch_follows_the_sweep() {
+        let sample_rate = 48000;
+        let mut phaser = Phaser::new(sample_rate);
+        let level = |phaser: &mut Phaser, frequency: f32| {
+            phaser.reset();
+            let mut tone: Vec<f32> = (0..9600)
+                .map(|i| (2.0 * std::f32::consts::PI * frequency * i as f32 / sample_rate as f32).sin())
+                .collect();
+            phaser.process(&mut tone).unwrap();
+            tone[4800..].iter().fold(0.0f32, |peak, s| peak.max(s.abs()))
+        };
+
+        // Two stages held at 1 kHz shift 1 kHz by 180 degrees and cancel it
+        phaser.set_settings(PhaserSettings {
+            rate: LfoRate::Hertz(0.0),
+            stages: 2,
+            min_frequency: 1000.0,
+            max_frequency: 1000.0,
+            feedback: 0.0,
+            ..Default::default()
+        });
+        assert!(level(&mut phaser, 1000.0) < 0.01);
+        assert!(level(&mut phaser, 100.0) > 0.95);
+
+        // Moving the sweep moves the notch
+        phaser.set_settings(PhaserSettings { min_frequency: 3000.0, max_frequency: 3000.0, ..*phaser.settings() });
+        assert!(level(&mut phaser, 1000.0) > 0.5);
+        assert!(level(&mut phaser, 3000.0) < 0.01);
+    }
+}
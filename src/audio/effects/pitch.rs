@@ -1 +1,576 @@
 ﻿//! Pitch shifting implementation
+//!
+//! A phase vocoder on top of `StreamingStft`: the true frequency of every bin
+//! is recovered from its phase advance between frames, the bins are moved to
+//! the shifted frequencies and their phases re-accumulated at the new rate.
+//! Formants are kept in place by flattening each frame with its spectral
+//! envelope before the shift and re-applying the unshifted envelope after it.
+//!
+//! `Harmonizer` runs a pitch detector next to up to two shifters and keeps
+//! each voice a fixed number of scale steps away from the sung or played note
+//! in a chosen key. Both effects add `FRAME_SIZE` samples of latency, which
+//! they report through `AudioEffect::latency` and compensate on the dry path.
+
+use std::f32::consts::PI;
+
+use crate::{
+    audio::{
+        analysis::{
+            fft::{Complex, StftConfig, StreamingStft, WindowType},
+            harmony::{Key, Mode},
+            pitch::{Note, PitchDetector},
+        },
+        effects::AudioEffect,
+    },
+    error::types::AudioError,
+};
+
+/// Largest shift in either direction, in semitones.
+pub const MAX_SHIFT: f32 = 24.0;
+
+/// STFT frame length in samples.
+const FRAME_SIZE: usize = 2048;
+
+/// Distance between STFT frames in samples (4x overlap).
+const HOP_SIZE: usize = 512;
+
+/// Half-width of the moving average that estimates the spectral envelope, in Hz.
+const ENVELOPE_HZ: f32 = 200.0;
+
+/// Pitch estimates less periodic than this are ignored by the harmonizer.
+const MIN_CLARITY: f32 = 0.8;
+
+/// Settings for the pitch shifter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PitchShiftSettings {
+    /// Shift in semitones, within `MAX_SHIFT`
+    pub semitones: f32,
+    /// Fine shift in cents, added to `semitones`
+    pub cents: f32,
+    /// Keeps the spectral envelope in place so voices do not sound chipmunked
+    pub preserve_formants: bool,
+    /// Level of the shifted signal, from 0.0 to 1.0
+    pub wet: f32,
+    /// Level of the unprocessed signal, from 0.0 to 1.0
+    pub dry: f32,
+}
+
+impl Default for PitchShiftSettings {
+    fn default() -> Self {
+        Self {
+            semitones: 0.0,
+            cents: 0.0,
+            preserve_formants: false,
+            wet: 1.0,
+            dry: 0.0,
+        }
+    }
+}
+
+/// Phase vocoder state for one shifted signal.
+struct Vocoder {
+    /// Shifted over original frequency
+    ratio: f32,
+    preserve_formants: bool,
+    /// Half-width of the envelope smoothing, in bins
+    envelope_bins: usize,
+    last_phase: Vec<f32>,
+    sum_phase: Vec<f32>,
+    magnitude: Vec<f32>,
+    /// True frequency of each analysis bin, in bins
+    frequency: Vec<f32>,
+    envelope: Vec<f32>,
+    shifted_magnitude: Vec<f32>,
+    shifted_frequency: Vec<f32>,
+}
+
+impl Vocoder {
+    fn new(sample_rate: u32) -> Self {
+        let bins = FRAME_SIZE / 2 + 1;
+        let bin_width = sample_rate as f32 / FRAME_SIZE as f32;
+        Self {
+            ratio: 1.0,
+            preserve_formants: false,
+            envelope_bins: ((ENVELOPE_HZ / bin_width).round() as usize).max(1),
+            last_phase: vec![0.0; bins],
+            sum_phase: vec![0.0; bins],
+            magnitude: vec![0.0; bins],
+            frequency: vec![0.0; bins],
+            envelope: vec![0.0; bins],
+            shifted_magnitude: vec![0.0; bins],
+            shifted_frequency: vec![0.0; bins],
+        }
+    }
+
+    fn reset(&mut self) {
+        self.last_phase.fill(0.0);
+        self.sum_phase.fill(0.0);
+    }
+
+    /// Moves every bin of one frame to its shifted frequency.
+    fn shift(&mut self, spectrum: &mut [Complex<f32>]) {
+        // Phase advance of bin 1 over one hop
+        let expected = 2.0 * PI * HOP_SIZE as f32 / FRAME_SIZE as f32;
+        for (k, bin) in spectrum.iter().enumerate() {
+            let phase = bin.arg();
+            let deviation = wrap_phase(phase - self.last_phase[k] - k as f32 * expected);
+            self.last_phase[k] = phase;
+            self.magnitude[k] = bin.norm();
+            self.frequency[k] = k as f32 + deviation / expected;
+        }
+
+        if self.preserve_formants {
+            smooth(&self.magnitude, &mut self.envelope, self.envelope_bins);
+        }
+        self.shifted_magnitude.fill(0.0);
+        self.shifted_frequency.fill(0.0);
+        let bins = spectrum.len();
+        for k in 0..bins {
+            let target = (k as f32 * self.ratio).round() as usize;
+            if target >= bins {
+                break;
+            }
+            let magnitude = if self.preserve_formants {
+                self.magnitude[k] / self.envelope[k]
+            } else {
+                self.magnitude[k]
+            };
+            self.shifted_magnitude[target] += magnitude;
+            self.shifted_frequency[target] = self.frequency[k] * self.ratio;
+        }
+
+        for (j, bin) in spectrum.iter_mut().enumerate() {
+            let magnitude = if self.preserve_formants {
+                self.shifted_magnitude[j] * self.envelope[j]
+            } else {
+                self.shifted_magnitude[j]
+            };
+            self.sum_phase[j] = wrap_phase(self.sum_phase[j] + self.shifted_frequency[j] * expected);
+            *bin = Complex::from_polar(magnitude, self.sum_phase[j]);
+        }
+    }
+}
+
+/// Phase vocoder pitch shifter.
+pub struct PitchShifter {
+    settings: PitchShiftSettings,
+    stft: StreamingStft,
+    vocoder: Vocoder,
+    /// Shifted signal of the current block
+    wet: Vec<f32>,
+    /// Dry signal delayed to line up with the shifted one
+    dry_line: Vec<f32>,
+    dry_index: usize,
+}
+
+impl PitchShifter {
+    /// Creates a new `PitchShifter` with default settings.
+    ///
+    /// # Arguments
+    /// * `sample_rate` - The sample rate of the audio.
+    ///
+    /// # Returns
+    /// * `Result<PitchShifter, AudioError>` - A shifter with all buffers allocated, or an error if the STFT cannot be set up.
+    pub fn new(sample_rate: u32) -> Result<Self, AudioError> {
+        let stft = StreamingStft::new(StftConfig {
+            frame_size: FRAME_SIZE,
+            hop_size: HOP_SIZE,
+            window: WindowType::Hann,
+        })?;
+        let mut shifter = Self {
+            settings: PitchShiftSettings::default(),
+            dry_line: vec![0.0; stft.latency()],
+            stft,
+            vocoder: Vocoder::new(sample_rate),
+            wet: vec![0.0; HOP_SIZE],
+            dry_index: 0,
+        };
+        shifter.set_settings(PitchShiftSettings::default());
+        Ok(shifter)
+    }
+
+    /// Gets the current settings.
+    pub fn settings(&self) -> &PitchShiftSettings {
+        &self.settings
+    }
+
+    /// Updates the settings; the shift is clamped to `MAX_SHIFT`.
+    pub fn set_settings(&mut self, settings: PitchShiftSettings) {
+        self.settings = PitchShiftSettings {
+            semitones: settings.semitones.clamp(-MAX_SHIFT, MAX_SHIFT),
+            wet: settings.wet.clamp(0.0, 1.0),
+            dry: settings.dry.clamp(0.0, 1.0),
+            ..settings
+        };
+        self.set_shift(self.settings.semitones + self.settings.cents / 100.0);
+        self.vocoder.preserve_formants = self.settings.preserve_formants;
+    }
+
+    /// Sets the shift in semitones without touching the other settings.
+    fn set_shift(&mut self, semitones: f32) {
+        let semitones = semitones.clamp(-MAX_SHIFT, MAX_SHIFT);
+        self.vocoder.ratio = 2f32.powf(semitones / 12.0);
+    }
+
+    /// Gets the delay added by the STFT, in samples.
+    pub fn latency(&self) -> usize {
+        self.stft.latency()
+    }
+
+    /// Clears the STFT, phase and dry-path state.
+    pub fn reset(&mut self) {
+        self.stft.reset();
+        self.vocoder.reset();
+        self.dry_line.fill(0.0);
+        self.dry_index = 0;
+    }
+
+    /// Writes the shifted signal only, delayed by `latency()` samples.
+    fn render(&mut self, input: &[f32], output: &mut [f32]) -> Result<(), AudioError> {
+        let vocoder = &mut self.vocoder;
+        self.stft.process(input, output, |spectrum| vocoder.shift(spectrum))
+    }
+
+    /// Shifts a buffer in place, mixing in the latency-compensated dry signal.
+    ///
+    /// # Arguments
+    /// * `buffer` - The audio to process.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - An error if the FFT fails.
+    pub fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        let PitchShiftSettings { wet, dry, .. } = self.settings;
+        for block in buffer.chunks_mut(HOP_SIZE) {
+            let shifted = &mut self.wet[..block.len()];
+            let vocoder = &mut self.vocoder;
+            self.stft.process(block, shifted, |spectrum| vocoder.shift(spectrum))?;
+            for (sample, &shifted) in block.iter_mut().zip(shifted.iter()) {
+                let delayed = delay(&mut self.dry_line, &mut self.dry_index, *sample);
+                *sample = delayed * dry + shifted * wet;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AudioEffect for PitchShifter {
+    fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        PitchShifter::process(self, buffer)
+    }
+
+    fn reset(&mut self) {
+        PitchShifter::reset(self)
+    }
+
+    fn latency(&self) -> usize {
+        self.stft.latency()
+    }
+}
+
+/// One harmony voice.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HarmonyVoice {
+    /// Distance from the input note in scale steps; 2 is a third above, -2 a third below
+    pub steps: i32,
+    /// Level of the voice, from 0.0 to 1.0
+    pub level: f32,
+}
+
+/// Settings for the harmonizer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HarmonizerSettings {
+    /// Key whose scale the voices follow, e.g. the key estimated for a track
+    pub key: Key,
+    /// Up to two voices; `None` disables a voice
+    pub voices: [Option<HarmonyVoice>; 2],
+    /// Keeps the voices' formants at the input's
+    pub preserve_formants: bool,
+    /// Level of the unprocessed signal, from 0.0 to 1.0
+    pub dry: f32,
+}
+
+impl Default for HarmonizerSettings {
+    fn default() -> Self {
+        Self {
+            key: Key { tonic: 0, mode: Mode::Major, confidence: 1.0 },
+            voices: [Some(HarmonyVoice { steps: 2, level: 0.7 }), None],
+            preserve_formants: true,
+            dry: 1.0,
+        }
+    }
+}
+
+/// Scale-aware harmonizer adding up to two pitch-shifted voices.
+pub struct Harmonizer {
+    settings: HarmonizerSettings,
+    detector: PitchDetector,
+    /// Circular history of the last `FRAME_SIZE` input samples
+    history: Vec<f32>,
+    history_index: usize,
+    frame: Vec<f32>,
+    since_detection: usize,
+    /// MIDI note of the last clear pitch, held through unpitched frames
+    note: Option<i32>,
+    voices: [PitchShifter; 2],
+    voice_output: [Vec<f32>; 2],
+    dry_line: Vec<f32>,
+    dry_index: usize,
+}
+
+impl Harmonizer {
+    /// Creates a new `Harmonizer`.
+    ///
+    /// # Arguments
+    /// * `sample_rate` - The sample rate of the audio.
+    /// * `settings` - Key, voices and levels.
+    ///
+    /// # Returns
+    /// * `Result<Harmonizer, AudioError>` - A harmonizer with all buffers allocated, or an error if the STFT cannot be set up.
+    pub fn new(sample_rate: u32, settings: HarmonizerSettings) -> Result<Self, AudioError> {
+        let mut detector = PitchDetector::new(sample_rate, FRAME_SIZE);
+        detector.set_range(60.0, 1500.0);
+        let voices = [PitchShifter::new(sample_rate)?, PitchShifter::new(sample_rate)?];
+        let mut harmonizer = Self {
+            settings,
+            detector,
+            history: vec![0.0; FRAME_SIZE],
+            history_index: 0,
+            frame: vec![0.0; FRAME_SIZE],
+            since_detection: 0,
+            note: None,
+            dry_line: vec![0.0; voices[0].latency()],
+            voices,
+            voice_output: [vec![0.0; HOP_SIZE], vec![0.0; HOP_SIZE]],
+            dry_index: 0,
+        };
+        harmonizer.set_settings(settings);
+        Ok(harmonizer)
+    }
+
+    /// Gets the current settings.
+    pub fn settings(&self) -> &HarmonizerSettings {
+        &self.settings
+    }
+
+    /// Updates the settings.
+    pub fn set_settings(&mut self, settings: HarmonizerSettings) {
+        self.settings = HarmonizerSettings {
+            dry: settings.dry.clamp(0.0, 1.0),
+            ..settings
+        };
+        for voice in &mut self.voices {
+            voice.vocoder.preserve_formants = settings.preserve_formants;
+        }
+    }
+
+    /// Gets the note the voices are currently built on, as a MIDI note number.
+    pub fn current_note(&self) -> Option<i32> {
+        self.note
+    }
+
+    /// Gets the delay added by the voices, in samples.
+    pub fn latency(&self) -> usize {
+        self.voices[0].latency()
+    }
+
+    /// Clears the detector history, the voices and the dry path.
+    pub fn reset(&mut self) {
+        self.history.fill(0.0);
+        self.history_index = 0;
+        self.since_detection = 0;
+        self.note = None;
+        for voice in &mut self.voices {
+            voice.reset();
+        }
+        self.dry_line.fill(0.0);
+        self.dry_index = 0;
+    }
+
+    /// Adds the harmony voices to a buffer in place.
+    ///
+    /// # Arguments
+    /// * `buffer` - The audio to process.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - An error if the FFT or pitch detection fails.
+    pub fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        for block in buffer.chunks_mut(HOP_SIZE) {
+            self.track_pitch(block)?;
+
+            let mut levels = [0.0; 2];
+            for (index, voice) in self.settings.voices.iter().enumerate() {
+                if let (Some(voice), Some(note)) = (voice, self.note) {
+                    let semitones = scale_interval(&self.settings.key, note, voice.steps);
+                    self.voices[index].set_shift(semitones as f32);
+                    levels[index] = voice.level;
+                }
+                self.voices[index].render(block, &mut self.voice_output[index][..block.len()])?;
+            }
+
+            for (i, sample) in block.iter_mut().enumerate() {
+                let delayed = delay(&mut self.dry_line, &mut self.dry_index, *sample);
+                *sample = delayed * self.settings.dry
+                    + self.voice_output[0][i] * levels[0]
+                    + self.voice_output[1][i] * levels[1];
+            }
+        }
+        Ok(())
+    }
+
+    /// Feeds the detector history and updates the note once per hop.
+    fn track_pitch(&mut self, block: &[f32]) -> Result<(), AudioError> {
+        for &sample in block {
+            self.history[self.history_index] = sample;
+            self.history_index = (self.history_index + 1) % FRAME_SIZE;
+        }
+        self.since_detection += block.len();
+        if self.since_detection < HOP_SIZE {
+            return Ok(());
+        }
+        self.since_detection = 0;
+
+        let (older, newer) = self.history.split_at(self.history_index);
+        self.frame[..newer.len()].copy_from_slice(newer);
+        self.frame[newer.len()..].copy_from_slice(older);
+        if let Some(estimate) = self.detector.detect(&self.frame)? {
+            if estimate.clarity >= MIN_CLARITY {
+                self.note = Some(Note::from_frequency(estimate.frequency, 440.0).midi);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AudioEffect for Harmonizer {
+    fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        Harmonizer::process(self, buffer)
+    }
+
+    fn reset(&mut self) {
+        Harmonizer::reset(self)
+    }
+
+    fn latency(&self) -> usize {
+        self.voices[0].latency()
+    }
+}
+
+/// Semitones from a note to the note a number of scale steps away.
+///
+/// Notes outside the scale are treated as the scale tone below them, so the
+/// voice always lands on a scale tone.
+///
+/// # Arguments
+/// * `key` - The key whose scale is followed.
+/// * `note` - MIDI note number of the input.
+/// * `steps` - Scale steps to move; 7 is an octave.
+///
+/// # Returns
+/// * `i32` - The interval in semitones, negative for voices below the input.
+pub fn scale_interval(key: &Key, note: i32, steps: i32) -> i32 {
+    let tonic = key.tonic as i32;
+    let degrees = key.scale().map(|pitch_class| (pitch_class as i32 - tonic).rem_euclid(12));
+    let relative = (note - tonic).rem_euclid(12);
+    let degree = degrees.iter().rposition(|&d| d <= relative).unwrap_or(0) as i32;
+    let target = degree + steps;
+    degrees[target.rem_euclid(7) as usize] + 12 * target.div_euclid(7) - relative
+}
+
+/// Writes a sample into a circular delay line and returns the oldest one.
+#[inline]
+fn delay(line: &mut [f32], index: &mut usize, sample: f32) -> f32 {
+    if line.is_empty() {
+        return sample;
+    }
+    let delayed = std::mem::replace(&mut line[*index], sample);
+    *index = (*index + 1) % line.len();
+    delayed
+}
+
+/// Wraps a phase into [-PI, PI].
+#[inline]
+fn wrap_phase(phase: f32) -> f32 {
+    phase - 2.0 * PI * (phase / (2.0 * PI)).round()
+}
+
+/// Centred moving average with a floor, used as the spectral envelope.
+fn smooth(input: &[f32], output: &mut [f32], half_width: usize) {
+    let len = input.len();
+    let mut sum: f32 = input[..half_width.min(len)].iter().sum();
+    for i in 0..len {
+        if i + half_width < len {
+            sum += input[i + half_width];
+        }
+        if i > half_width {
+            sum -= input[i - half_width - 1];
+        }
+        let count = (i + half_width).min(len - 1) + 1 - i.saturating_sub(half_width);
+        output[i] = (sum / count as f32).max(1e-9);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequency: f32, sample_rate: u32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| 0.5 * (2.0 * PI * frequency * i as f32 / sample_rate as f32).sin())
+            .collect()
+    }
+
+    fn detect(samples: &[f32], sample_rate: u32) -> f32 {
+        let mut detector = PitchDetector::new(sample_rate, 4096);
+        detector.detect(&samples[samples.len() - 4096..]).unwrap().unwrap().frequency
+    }
+
+    #[test]
+    fn test_shifts_by_semitones_and_cents() {
+        let sample_rate = 48000;
+        for (semitones, cents, preserve_formants) in [(7.0, 0.0, false), (-5.0, 0.0, true), (0.0, 50.0, false)] {
+            let mut shifter = PitchShifter::new(sample_rate).unwrap();
+            shifter.set_settings(PitchShiftSettings { semitones, cents, preserve_formants, ..Default::default() });
+            let mut buffer = sine(220.0, sample_rate, 24000);
+            for block in buffer.chunks_mut(300) {
+                shifter.process(block).unwrap();
+            }
+            let expected = 220.0 * 2f32.powf((semitones + cents / 100.0) / 12.0);
+            let detected = detect(&buffer, sample_rate);
+            let error = 1200.0 * (detected / expected).log2();
+            assert!(error.abs() < 10.0, "{} st {} ct: {} Hz", semitones, cents, detected);
+        }
+    }
+
+    #[test]
+    fn test_harmony_follows_the_scale() {
+        let c_major = Key { tonic: 0, mode: Mode::Major, confidence: 1.0 };
+        let a_minor = Key { tonic: 9, mode: Mode::Minor, confidence: 1.0 };
+        // A third above C and D in C major is major and minor respectively
+        assert_eq!(scale_interval(&c_major, 60, 2), 4);
+        assert_eq!(scale_interval(&c_major, 62, 2), 3);
+        assert_eq!(scale_interval(&c_major, 71, 2), 3);
+        assert_eq!(scale_interval(&c_major, 64, -2), -4);
+        assert_eq!(scale_interval(&c_major, 60, 7), 12);
+        // F# is out of C major and is harmonized from F
+        assert_eq!(scale_interval(&c_major, 66, 2), 3);
+        assert_eq!(scale_interval(&a_minor, 57, 2), 3);
+
+        // E in C major gets a G above it
+        let sample_rate = 48000;
+        let mut harmonizer = Harmonizer::new(
+            sample_rate,
+            HarmonizerSettings {
+                key: c_major,
+                voices: [Some(HarmonyVoice { steps: 2, level: 1.0 }), None],
+                dry: 0.0,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let mut buffer = sine(329.63, sample_rate, 24000);
+        harmonizer.process(&mut buffer).unwrap();
+        assert_eq!(harmonizer.current_note(), Some(64));
+        let detected = detect(&buffer, sample_rate);
+        assert!((1200.0 * (detected / 392.0).log2()).abs() < 10.0, "{} Hz", detected);
+    }
+}
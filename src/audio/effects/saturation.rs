@@ -0,0 +1,551 @@
+﻿//! Drive, fuzz, wavefolder and bitcrusher effects
+//!
+//! The nonlinear stage of each effect runs through an `Oversampler` so its
+//! harmonics are filtered before they can alias. The dry signal is delayed by
+//! the oversampler's latency so the mix stays phase-aligned, and a low-pass
+//! tone control tames the fizz the shaping adds.
+
+use crate::{
+    audio::effects::{
+        delay::DelayLine,
+        filter::{Filter, FilterParams, FilterType, Slope},
+        oversampling::{Oversampler, Oversampling},
+        AudioEffect,
+    },
+    error::types::AudioError,
+};
+
+/// Most drive applied before the shaper, in dB.
+pub const MAX_DRIVE: f32 = 48.0;
+
+/// Bias that makes the tube curve asymmetric, adding even harmonics.
+const TUBE_BIAS: f32 = 0.3;
+
+/// Level at which fuzz clips negative half-cycles; positive ones clip at 1.0.
+const FUZZ_NEGATIVE_CLIP: f32 = 0.6;
+
+/// Corner of the DC blocker after the asymmetric curves, in Hz.
+const DC_BLOCKER_FREQUENCY: f32 = 10.0;
+
+/// Lowest tone cutoff, in Hz.
+const MIN_TONE: f32 = 200.0;
+
+/// Transfer curve of the drive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Saturation {
+    /// Symmetric `tanh` soft clipping; odd harmonics only
+    SoftClip,
+    /// Biased soft clipping; adds the even harmonics of a tube stage
+    Tube,
+    /// Hard, uneven clipping
+    Fuzz,
+    /// Folds peaks back on themselves instead of flattening them
+    Wavefold,
+}
+
+impl Saturation {
+    /// Shapes one sample.
+    #[inline]
+    pub fn shape(&self, sample: f32) -> f32 {
+        match self {
+            Saturation::SoftClip => sample.tanh(),
+            Saturation::Tube => (sample + TUBE_BIAS).tanh() - TUBE_BIAS.tanh(),
+            Saturation::Fuzz => sample.clamp(-FUZZ_NEGATIVE_CLIP, 1.0),
+            Saturation::Wavefold => (sample * std::f32::consts::FRAC_PI_2).sin(),
+        }
+    }
+}
+
+/// Settings for the drive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DriveSettings {
+    /// Transfer curve
+    pub saturation: Saturation,
+    /// Gain into the shaper, from 0.0 to `MAX_DRIVE` dB
+    pub drive: f32,
+    /// Cutoff of the low-pass after the shaper, in Hz; `None` leaves the tone open
+    pub tone: Option<f32>,
+    /// Gain after the shaper, in dB
+    pub output: f32,
+    /// Balance of the driven signal against the dry signal, from 0.0 to 1.0
+    pub mix: f32,
+    /// Rate the shaper runs at
+    pub oversampling: Oversampling,
+}
+
+impl Default for DriveSettings {
+    fn default() -> Self {
+        Self {
+            saturation: Saturation::SoftClip,
+            drive: 12.0,
+            tone: Some(8000.0),
+            output: 0.0,
+            mix: 1.0,
+            oversampling: Oversampling::X4,
+        }
+    }
+}
+
+/// Settings for the bitcrusher.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BitcrusherSettings {
+    /// Resolution of the output, from 1.0 to 24.0 bits; fractional values sweep smoothly
+    pub bits: f32,
+    /// Rate the output is held at, in Hz, up to the sample rate
+    pub rate: f32,
+    /// Cutoff of the low-pass after the crusher, in Hz; `None` leaves the tone open
+    pub tone: Option<f32>,
+    /// Balance of the crushed signal against the dry signal, from 0.0 to 1.0
+    pub mix: f32,
+    /// Rate the quantizer runs at
+    pub oversampling: Oversampling,
+}
+
+impl Default for BitcrusherSettings {
+    fn default() -> Self {
+        Self {
+            bits: 8.0,
+            rate: 11025.0,
+            tone: None,
+            mix: 1.0,
+            oversampling: Oversampling::X2,
+        }
+    }
+}
+
+/// Per-channel state shared by the saturation effects.
+#[derive(Debug, Clone)]
+struct Channel {
+    oversampler: Oversampler,
+    /// Dry signal delayed to line up with the oversampler
+    dry: DelayLine,
+    tone: Filter,
+    dc_input: f32,
+    dc_output: f32,
+    held: f32,
+    hold_phase: f32,
+}
+
+impl Channel {
+    fn new(sample_rate: u32, oversampling: Oversampling) -> Self {
+        let oversampler = Oversampler::new(oversampling);
+        let dry = DelayLine::new(oversampler.latency() + 2);
+        let tone = Filter::new(
+            sample_rate,
+            FilterParams {
+                filter_type: FilterType::LowPass,
+                frequency: sample_rate as f32 * 0.45,
+                slope: Slope::Db12,
+                ..Default::default()
+            },
+        );
+        Self {
+            oversampler,
+            dry,
+            tone,
+            dc_input: 0.0,
+            dc_output: 0.0,
+            held: 0.0,
+            hold_phase: 1.0,
+        }
+    }
+
+    /// Rebuilds the oversampler and dry delay if the rate changed.
+    fn set_oversampling(&mut self, oversampling: Oversampling) {
+        if self.oversampler.oversampling() != oversampling {
+            self.oversampler = Oversampler::new(oversampling);
+            self.dry = DelayLine::new(self.oversampler.latency() + 2);
+        }
+    }
+
+    fn set_tone(&mut self, tone: Option<f32>) {
+        if let Some(frequency) = tone {
+            self.tone.set_params(FilterParams { frequency, ..*self.tone.params() });
+        }
+    }
+
+    fn reset(&mut self) {
+        self.oversampler.reset();
+        self.dry.clear();
+        self.tone.reset();
+        self.dc_input = 0.0;
+        self.dc_output = 0.0;
+        self.held = 0.0;
+        self.hold_phase = 1.0;
+    }
+
+    /// Stores the dry sample and returns it delayed by the oversampler latency.
+    #[inline]
+    fn delay_dry(&mut self, sample: f32) -> f32 {
+        self.dry.write(sample);
+        self.dry.read(self.oversampler.latency() as f64 + 1.0)
+    }
+
+    /// Removes the offset the asymmetric curves add.
+    #[inline]
+    fn block_dc(&mut self, sample: f32, pole: f32) -> f32 {
+        self.dc_output = sample - self.dc_input + pole * self.dc_output;
+        self.dc_input = sample;
+        self.dc_output
+    }
+
+    /// Holds a sample for `1 / increment` samples.
+    #[inline]
+    fn hold(&mut self, sample: f32, increment: f32) -> f32 {
+        if self.hold_phase >= 1.0 {
+            self.hold_phase -= 1.0;
+            self.held = sample;
+        }
+        self.hold_phase += increment;
+        self.held
+    }
+
+    /// Applies the tone control and mixes with the dry signal.
+    #[inline]
+    fn finish(&mut self, dry: f32, wet: f32, tone: bool, mix: f32) -> f32 {
+        let wet = if tone { self.tone.process_sample(wet) } else { wet };
+        dry * (1.0 - mix) + wet * mix
+    }
+}
+
+/// Oversampled drive with soft clip, tube, fuzz and wavefolder curves.
+#[derive(Debug, Clone)]
+pub struct Drive {
+    sample_rate: u32,
+    settings: DriveSettings,
+    channels: [Channel; 2],
+    input_gain: f32,
+    output_gain: f32,
+    dc_pole: f32,
+}
+
+impl Drive {
+    /// Creates a new `Drive` with default settings.
+    ///
+    /// # Arguments
+    /// * `sample_rate` - The sample rate of the audio.
+    ///
+    /// # Returns
+    /// * `Drive` - A soft clipper at 4× oversampling.
+    pub fn new(sample_rate: u32) -> Self {
+        let settings = DriveSettings::default();
+        let mut drive = Self {
+            sample_rate,
+            settings,
+            channels: [
+                Channel::new(sample_rate, settings.oversampling),
+                Channel::new(sample_rate, settings.oversampling),
+            ],
+            input_gain: 1.0,
+            output_gain: 1.0,
+            dc_pole: 1.0 - 2.0 * std::f32::consts::PI * DC_BLOCKER_FREQUENCY / sample_rate as f32,
+        };
+        drive.set_settings(settings);
+        drive
+    }
+
+    /// Gets the current settings.
+    pub fn settings(&self) -> &DriveSettings {
+        &self.settings
+    }
+
+    /// Updates the settings; values are clamped to their ranges.
+    ///
+    /// Changing the oversampling reallocates the filters, so it belongs on
+    /// the control thread.
+    pub fn set_settings(&mut self, settings: DriveSettings) {
+        self.settings = DriveSettings {
+            drive: settings.drive.clamp(0.0, MAX_DRIVE),
+            tone: settings.tone.map(|tone| tone.clamp(MIN_TONE, self.sample_rate as f32 * 0.45)),
+            mix: settings.mix.clamp(0.0, 1.0),
+            ..settings
+        };
+        self.input_gain = 10f32.powf(self.settings.drive / 20.0);
+        self.output_gain = 10f32.powf(self.settings.output / 20.0);
+        for channel in &mut self.channels {
+            channel.set_oversampling(self.settings.oversampling);
+            channel.set_tone(self.settings.tone);
+        }
+    }
+
+    /// Gets the delay added by oversampling, in samples.
+    pub fn latency(&self) -> usize {
+        self.channels[0].oversampler.latency()
+    }
+
+    /// Clears all internal state.
+    pub fn reset(&mut self) {
+        for channel in &mut self.channels {
+            channel.reset();
+        }
+    }
+
+    /// Runs one channel for one sample.
+    #[inline]
+    fn drive(&mut self, channel: usize, input: f32) -> f32 {
+        let DriveSettings { saturation, tone, mix, .. } = self.settings;
+        let (input_gain, output_gain, dc_pole) = (self.input_gain, self.output_gain, self.dc_pole);
+        let channel = &mut self.channels[channel];
+        let dry = channel.delay_dry(input);
+        let shaped = channel
+            .oversampler
+            .process_sample(input * input_gain, |sample| saturation.shape(sample));
+        let wet = channel.block_dc(shaped, dc_pole) * output_gain;
+        channel.finish(dry, wet, tone.is_some(), mix)
+    }
+
+    /// Processes a mono buffer in place.
+    ///
+    /// # Arguments
+    /// * `buffer` - The audio to process.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - Always `Ok(())`.
+    pub fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        for sample in buffer.iter_mut() {
+            *sample = self.drive(0, *sample);
+        }
+        Ok(())
+    }
+
+    /// Processes a stereo pair in place.
+    ///
+    /// # Arguments
+    /// * `left` - The left channel.
+    /// * `right` - The right channel, the same length as `left`.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - `BufferMismatch` if the channel lengths differ.
+    pub fn process_stereo(&mut self, left: &mut [f32], right: &mut [f32]) -> Result<(), AudioError> {
+        if left.len() != right.len() {
+            return Err(AudioError::BufferMismatch);
+        }
+        for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+            *l = self.drive(0, *l);
+            *r = self.drive(1, *r);
+        }
+        Ok(())
+    }
+}
+
+impl AudioEffect for Drive {
+    fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        Drive::process(self, buffer)
+    }
+
+    fn reset(&mut self) {
+        Drive::reset(self)
+    }
+
+    fn latency(&self) -> usize {
+        Drive::latency(self)
+    }
+}
+
+/// Bit-depth and sample-rate reducer.
+#[derive(Debug, Clone)]
+pub struct Bitcrusher {
+    sample_rate: u32,
+    settings: BitcrusherSettings,
+    channels: [Channel; 2],
+    /// Quantization steps per unit of amplitude
+    levels: f32,
+    /// Hold phase advance per sample
+    increment: f32,
+}
+
+impl Bitcrusher {
+    /// Creates a new `Bitcrusher` with default settings.
+    ///
+    /// # Arguments
+    /// * `sample_rate` - The sample rate of the audio.
+    ///
+    /// # Returns
+    /// * `Bitcrusher` - An 8-bit, 11 kHz crusher.
+    pub fn new(sample_rate: u32) -> Self {
+        let settings = BitcrusherSettings::default();
+        let mut bitcrusher = Self {
+            sample_rate,
+            settings,
+            channels: [
+                Channel::new(sample_rate, settings.oversampling),
+                Channel::new(sample_rate, settings.oversampling),
+            ],
+            levels: 1.0,
+            increment: 1.0,
+        };
+        bitcrusher.set_settings(settings);
+        bitcrusher
+    }
+
+    /// Gets the current settings.
+    pub fn settings(&self) -> &BitcrusherSettings {
+        &self.settings
+    }
+
+    /// Updates the settings; values are clamped to their ranges.
+    ///
+    /// Changing the oversampling reallocates the filters, so it belongs on
+    /// the control thread.
+    pub fn set_settings(&mut self, settings: BitcrusherSettings) {
+        let nyquist = self.sample_rate as f32 * 0.45;
+        self.settings = BitcrusherSettings {
+            bits: settings.bits.clamp(1.0, 24.0),
+            rate: settings.rate.clamp(1.0, self.sample_rate as f32),
+            tone: settings.tone.map(|tone| tone.clamp(MIN_TONE, nyquist)),
+            mix: settings.mix.clamp(0.0, 1.0),
+            ..settings
+        };
+        self.levels = 2f32.powf(self.settings.bits - 1.0);
+        self.increment = self.settings.rate / self.sample_rate as f32;
+        for channel in &mut self.channels {
+            channel.set_oversampling(self.settings.oversampling);
+            channel.set_tone(self.settings.tone);
+        }
+    }
+
+    /// Gets the delay added by oversampling, in samples.
+    pub fn latency(&self) -> usize {
+        self.channels[0].oversampler.latency()
+    }
+
+    /// Clears all internal state.
+    pub fn reset(&mut self) {
+        for channel in &mut self.channels {
+            channel.reset();
+        }
+    }
+
+    /// Runs one channel for one sample.
+    ///
+    /// Quantizing is oversampled; the rate reduction is not, since its
+    /// aliasing is the sound being asked for.
+    #[inline]
+    fn crush(&mut self, channel: usize, input: f32) -> f32 {
+        let BitcrusherSettings { tone, mix, .. } = self.settings;
+        let (levels, increment) = (self.levels, self.increment);
+        let channel = &mut self.channels[channel];
+        let dry = channel.delay_dry(input);
+        let quantized = channel
+            .oversampler
+            .process_sample(input, |sample| (sample * levels).round() / levels);
+        let wet = channel.hold(quantized, increment);
+        channel.finish(dry, wet, tone.is_some(), mix)
+    }
+
+    /// Processes a mono buffer in place.
+    ///
+    /// # Arguments
+    /// * `buffer` - The audio to process.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - Always `Ok(())`.
+    pub fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        for sample in buffer.iter_mut() {
+            *sample = self.crush(0, *sample);
+        }
+        Ok(())
+    }
+
+    /// Processes a stereo pair in place.
+    ///
+    /// # Arguments
+    /// * `left` - The left channel.
+    /// * `right` - The right channel, the same length as `left`.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - `BufferMismatch` if the channel lengths differ.
+    pub fn process_stereo(&mut self, left: &mut [f32], right: &mut [f32]) -> Result<(), AudioError> {
+        if left.len() != right.len() {
+            return Err(AudioError::BufferMismatch);
+        }
+        for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+            *l = self.crush(0, *l);
+            *r = self.crush(1, *r);
+        }
+        Ok(())
+    }
+}
+
+impl AudioEffect for Bitcrusher {
+    fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        Bitcrusher::process(self, buffer)
+    }
+
+    fn reset(&mut self) {
+        Bitcrusher::reset(self)
+    }
+
+    fn latency(&self) -> usize {
+        Bitcrusher::latency(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequency: f32, amplitude: f32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| amplitude * (2.0 * std::f32::consts::PI * frequency * i as f32 / 48000.0).sin())
+            .collect()
+    }
+
+    /// Amplitude of one frequency over a whole number of its cycles.
+    fn level(signal: &[f32], frequency: f32) -> f32 {
+        let (re, im) = signal.iter().enumerate().fold((0.0, 0.0), |(re, im), (i, s)| {
+            let phase = 2.0 * std::f32::consts::PI * frequency * i as f32 / 48000.0;
+            (re + s * phase.cos(), im - s * phase.sin())
+        });
+        (re * re + im * im).sqrt() * 2.0 / signal.len() as f32
+    }
+
+    #[test]
+    fn test_curves_and_dry_alignment() {
+        let mut drive = Drive::new(48000);
+        let harmonics = |drive: &mut Drive, saturation: Saturation| {
+            drive.set_settings(DriveSettings { saturation, tone: None, drive: 18.0, ..Default::default() });
+            drive.reset();
+            let mut buffer = sine(500.0, 0.5, 9600);
+            drive.process(&mut buffer).unwrap();
+            let steady = &buffer[4800..];
+            (level(steady, 1000.0) / level(steady, 500.0), level(steady, 1500.0) / level(steady, 500.0))
+        };
+
+        // Soft clipping adds odd harmonics, the tube curve even ones as well
+        let (second, third) = harmonics(&mut drive, Saturation::SoftClip);
+        assert!(second < 0.001 && third > 0.1, "{} {}", second, third);
+        let (second, _) = harmonics(&mut drive, Saturation::Tube);
+        assert!(second > 0.05, "{}", second);
+        for saturation in [Saturation::Fuzz, Saturation::Wavefold] {
+            let (_, third) = harmonics(&mut drive, saturation);
+            assert!(third > 0.1, "{:?}: {}", saturation, third);
+        }
+
+        // With the mix fully dry the output is the input, delayed like the wet signal
+        drive.set_settings(DriveSettings { mix: 0.0, ..Default::default() });
+        let latency = drive.latency();
+        assert!(latency > 0);
+        let input = sine(440.0, 0.5, 1000);
+        let mut buffer = input.clone();
+        drive.process(&mut buffer).unwrap();
+        assert!(buffer[latency..].iter().zip(&input).all(|(a, b)| (a - b).abs() < 1e-6));
+    }
+
+    #[test]
+    fn test_bitcrusher_reduces_depth_and_rate() {
+        let mut bitcrusher = Bitcrusher::new(48000);
+        bitcrusher.set_settings(BitcrusherSettings {
+            bits: 2.0,
+            rate: 12000.0,
+            oversampling: Oversampling::None,
+            ..Default::default()
+        });
+        let mut buffer = sine(440.0, 0.9, 4800);
+        bitcrusher.process(&mut buffer).unwrap();
+        // Two bits leave steps of 0.5, each held for four samples
+        assert!(buffer.iter().all(|s| (s * 2.0).fract() == 0.0));
+        assert!(buffer.chunks(4).all(|hold| hold.iter().all(|&s| s == hold[0])));
+        assert!(buffer.contains(&0.5) && buffer.contains(&-0.5));
+    }
+}
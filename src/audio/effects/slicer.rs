@@ -0,0 +1,231 @@
+﻿//! Slicer (trance gate) effect implementation
+//!
+//! Chops the signal with a step pattern locked to the `MasterClock`: each
+//! step is a note value long, step zero falls on beat zero, and an open step
+//! lets the signal through for part of its length. Edges are smoothed so the
+//! gate stays click-free even at full depth.
+
+use std::sync::Arc;
+
+use crate::{
+    audio::effects::{
+        delay::{NoteDivision, NoteModifier, NoteValue},
+        AudioEffect,
+    },
+    error::types::AudioError,
+    sync::clock::MasterClock,
+};
+
+/// Longest pattern, in steps.
+pub const MAX_STEPS: usize = 32;
+
+/// Settings for the slicer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SlicerSettings {
+    /// Length of one step
+    pub step: NoteValue,
+    /// Open steps, one bit per step starting from bit 0
+    pub pattern: u32,
+    /// Steps before the pattern repeats, from 1 to `MAX_STEPS`
+    pub steps: usize,
+    /// Fraction of an open step the gate stays open, from 0.05 to 1.0
+    pub duty: f32,
+    /// How far the gate closes, from 0.0 (not at all) to 1.0 (to silence)
+    pub depth: f32,
+    /// Time constant of the gate edges, in milliseconds
+    pub smoothing: f32,
+}
+
+impl Default for SlicerSettings {
+    fn default() -> Self {
+        Self {
+            step: NoteValue::new(NoteDivision::Sixteenth, NoteModifier::Straight),
+            pattern: u32::MAX,
+            steps: 16,
+            duty: 0.5,
+            depth: 1.0,
+            smoothing: 2.0,
+        }
+    }
+}
+
+/// Clock-synced rhythmic gate.
+pub struct Slicer {
+    clock: Arc<MasterClock>,
+    settings: SlicerSettings,
+    engaged: bool,
+    gain: f32,
+    smoothing: f32,
+}
+
+impl Slicer {
+    /// Creates a new `Slicer` with default settings.
+    ///
+    /// # Arguments
+    /// * `clock` - The clock the pattern follows.
+    ///
+    /// # Returns
+    /// * `Slicer` - A released slicer chopping sixteenth notes.
+    pub fn new(clock: Arc<MasterClock>) -> Self {
+        let mut slicer = Self {
+            clock,
+            settings: SlicerSettings::default(),
+            engaged: false,
+            gain: 1.0,
+            smoothing: 1.0,
+        };
+        slicer.set_settings(SlicerSettings::default());
+        slicer
+    }
+
+    /// Gets the current settings.
+    pub fn settings(&self) -> &SlicerSettings {
+        &self.settings
+    }
+
+    /// Updates the settings; values are clamped to their ranges.
+    pub fn set_settings(&mut self, settings: SlicerSettings) {
+        self.settings = SlicerSettings {
+            steps: settings.steps.clamp(1, MAX_STEPS),
+            duty: settings.duty.clamp(0.05, 1.0),
+            depth: settings.depth.clamp(0.0, 1.0),
+            smoothing: settings.smoothing.max(0.0),
+            ..settings
+        };
+        let samples = self.settings.smoothing / 1000.0 * self.clock.sample_rate() as f32;
+        self.smoothing = if samples < 1.0 { 1.0 } else { 1.0 - (-1.0 / samples).exp() };
+    }
+
+    /// Starts gating.
+    pub fn engage(&mut self) {
+        self.engaged = true;
+    }
+
+    /// Stops gating; the gate opens smoothly.
+    pub fn release(&mut self) {
+        self.engaged = false;
+    }
+
+    /// Checks whether the effect is held.
+    pub fn is_engaged(&self) -> bool {
+        self.engaged
+    }
+
+    /// Opens the gate immediately.
+    pub fn reset(&mut self) {
+        self.gain = 1.0;
+    }
+
+    /// Advances the gate to a position, in steps, and returns its gain.
+    #[inline]
+    fn next_gain(&mut self, position: f64) -> f32 {
+        let SlicerSettings { pattern, steps, duty, depth, .. } = self.settings;
+        let index = position.floor().rem_euclid(steps as f64) as usize;
+        let open = (pattern >> index) & 1 == 1 && position.fract() < duty as f64;
+        let target = if !self.engaged || open { 1.0 } else { 1.0 - depth };
+        self.gain += (target - self.gain) * self.smoothing;
+        self.gain
+    }
+
+    /// Position of the block start and advance per sample, both in steps.
+    fn timeline(&self) -> (f64, f64) {
+        let step = self.settings.step.beats(self.clock.time_signature().denominator);
+        (self.clock.beat_position() / step, 1.0 / (self.clock.samples_per_beat_exact() * step))
+    }
+
+    /// Processes a mono buffer in place.
+    ///
+    /// # Arguments
+    /// * `buffer` - The audio to process, starting at the clock's current position.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - Always `Ok(())`.
+    pub fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        let (start, steps_per_sample) = self.timeline();
+        for (i, sample) in buffer.iter_mut().enumerate() {
+            *sample *= self.next_gain(start + i as f64 * steps_per_sample);
+        }
+        Ok(())
+    }
+
+    /// Processes a stereo pair in place.
+    ///
+    /// # Arguments
+    /// * `left` - The left channel, starting at the clock's current position.
+    /// * `right` - The right channel, the same length as `left`.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - `BufferMismatch` if the channel lengths differ.
+    pub fn process_stereo(&mut self, left: &mut [f32], right: &mut [f32]) -> Result<(), AudioError> {
+        if left.len() != right.len() {
+            return Err(AudioError::BufferMismatch);
+        }
+        let (start, steps_per_sample) = self.timeline();
+        for (i, (l, r)) in left.iter_mut().zip(right.iter_mut()).enumerate() {
+            let gain = self.next_gain(start + i as f64 * steps_per_sample);
+            *l *= gain;
+            *r *= gain;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Slicer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Slicer")
+            .field("settings", &self.settings)
+            .field("engaged", &self.engaged)
+            .finish()
+    }
+}
+
+impl AudioEffect for Slicer {
+    fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        Slicer::process(self, buffer)
+    }
+
+    fn reset(&mut self) {
+        Slicer::reset(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pattern_follows_the_clock() {
+        // 120 BPM at 48 kHz: an eighth note is 12000 samples
+        let clock = Arc::new(MasterClock::new(48000, 120.0));
+        let mut slicer = Slicer::new(clock.clone());
+        slicer.set_settings(SlicerSettings {
+            step: NoteValue::new(NoteDivision::Eighth, NoteModifier::Straight),
+            pattern: 0b0101,
+            steps: 4,
+            duty: 1.0,
+            ..Default::default()
+        });
+
+        // Released, the signal passes untouched
+        let mut buffer = vec![1.0; 1000];
+        slicer.process(&mut buffer).unwrap();
+        assert!(buffer.iter().all(|&s| s == 1.0));
+        clock.seek_beats(0.0);
+
+        slicer.engage();
+        let mut output = Vec::new();
+        for _ in 0..60 {
+            let mut buffer = vec![1.0; 1000];
+            slicer.process(&mut buffer).unwrap();
+            output.extend(buffer);
+            clock.advance(1000);
+        }
+        // Steps 0 and 2 are open, 1 and 3 closed, and the pattern repeats after four
+        for (step, open) in [true, false, true, false, true].into_iter().enumerate() {
+            let sample = output[step * 12000 + 6000];
+            assert!(if open { sample > 0.999 } else { sample < 1e-3 }, "step {}: {}", step, sample);
+        }
+        let largest_step = output.windows(2).map(|w| (w[1] - w[0]).abs()).fold(0.0, f32::max);
+        assert!(largest_step < 0.02, "{}", largest_step);
+    }
+}
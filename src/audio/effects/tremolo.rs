@@ -0,0 +1,327 @@
+﻿//! Tremolo and auto-pan implementation
+//!
+//! Both modulate gain with the shared `Lfo`: tremolo moves the level of the
+//! signal, auto-pan moves it between the channels with an equal-power law.
+//! The gain is smoothed over a couple of milliseconds so square and
+//! sample-and-hold shapes chop without clicking.
+
+use std::sync::Arc;
+
+use crate::{
+    audio::effects::{
+        lfo::{Lfo, LfoRate, LfoShape},
+        time_coefficient, AudioEffect,
+    },
+    error::types::AudioError,
+    sync::clock::MasterClock,
+};
+
+/// Time constant of the gain smoothing, in seconds.
+const GAIN_SMOOTHING_SECONDS: f32 = 0.002;
+
+/// Settings for the tremolo.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TremoloSettings {
+    /// LFO rate
+    pub rate: LfoRate,
+    /// LFO waveform
+    pub shape: LfoShape,
+    /// How far the level dips, from 0.0 (none) to 1.0 (to silence)
+    pub depth: f32,
+}
+
+impl Default for TremoloSettings {
+    fn default() -> Self {
+        Self {
+            rate: LfoRate::Hertz(5.0),
+            shape: LfoShape::Sine,
+            depth: 0.5,
+        }
+    }
+}
+
+/// Amplitude modulation.
+#[derive(Debug, Clone)]
+pub struct Tremolo {
+    settings: TremoloSettings,
+    lfo: Lfo,
+    gain: f32,
+    smoothing: f32,
+}
+
+impl Tremolo {
+    /// Creates a new `Tremolo` with default settings.
+    ///
+    /// # Arguments
+    /// * `sample_rate` - The sample rate of the audio.
+    ///
+    /// # Returns
+    /// * `Tremolo` - A 5 Hz sine tremolo.
+    pub fn new(sample_rate: u32) -> Self {
+        let mut tremolo = Self {
+            settings: TremoloSettings::default(),
+            lfo: Lfo::new(sample_rate),
+            gain: 1.0,
+            smoothing: time_coefficient(GAIN_SMOOTHING_SECONDS, sample_rate),
+        };
+        tremolo.set_settings(TremoloSettings::default());
+        tremolo
+    }
+
+    /// Gets the current settings.
+    pub fn settings(&self) -> &TremoloSettings {
+        &self.settings
+    }
+
+    /// Updates the settings; depth is clamped to 0.0 to 1.0.
+    pub fn set_settings(&mut self, settings: TremoloSettings) {
+        self.settings = TremoloSettings {
+            depth: settings.depth.clamp(0.0, 1.0),
+            ..settings
+        };
+        self.lfo.set(self.settings.shape, self.settings.rate);
+    }
+
+    /// Locks note-value rates to a clock's tempo.
+    pub fn set_clock(&mut self, clock: Option<Arc<MasterClock>>) {
+        self.lfo.set_clock(clock);
+    }
+
+    /// Restarts the LFO.
+    pub fn reset(&mut self) {
+        self.lfo.reset();
+    }
+
+    /// Advances the LFO and returns the smoothed gain.
+    #[inline]
+    fn next_gain(&mut self) -> f32 {
+        let target = 1.0 - self.settings.depth * (0.5 - 0.5 * self.lfo.tick());
+        self.gain += (target - self.gain) * self.smoothing;
+        self.gain
+    }
+
+    /// Processes a mono buffer in place.
+    ///
+    /// # Arguments
+    /// * `buffer` - The audio to process.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - Always `Ok(())`.
+    pub fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        self.lfo.update();
+        for sample in buffer.iter_mut() {
+            *sample *= self.next_gain();
+        }
+        Ok(())
+    }
+
+    /// Processes a stereo pair in place, with the same gain on both channels.
+    ///
+    /// # Arguments
+    /// * `left` - The left channel.
+    /// * `right` - The right channel, the same length as `left`.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - `BufferMismatch` if the channel lengths differ.
+    pub fn process_stereo(&mut self, left: &mut [f32], right: &mut [f32]) -> Result<(), AudioError> {
+        if left.len() != right.len() {
+            return Err(AudioError::BufferMismatch);
+        }
+        self.lfo.update();
+        for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+            let gain = self.next_gain();
+            *l *= gain;
+            *r *= gain;
+        }
+        Ok(())
+    }
+}
+
+impl AudioEffect for Tremolo {
+    fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        Tremolo::process(self, buffer)
+    }
+
+    fn reset(&mut self) {
+        Tremolo::reset(self)
+    }
+
+    fn on_loop_start(&mut self) {
+        self.lfo.reset();
+    }
+}
+
+/// Settings for the auto-pan.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AutoPanSettings {
+    /// LFO rate
+    pub rate: LfoRate,
+    /// LFO waveform
+    pub shape: LfoShape,
+    /// How far the signal moves from the centre, from 0.0 to 1.0 (hard left and right)
+    pub width: f32,
+}
+
+impl Default for AutoPanSettings {
+    fn default() -> Self {
+        Self {
+            rate: LfoRate::Hertz(0.5),
+            shape: LfoShape::Sine,
+            width: 1.0,
+        }
+    }
+}
+
+/// Equal-power stereo auto-pan.
+#[derive(Debug, Clone)]
+pub struct AutoPan {
+    settings: AutoPanSettings,
+    lfo: Lfo,
+    gains: [f32; 2],
+    smoothing: f32,
+}
+
+impl AutoPan {
+    /// Creates a new `AutoPan` with default settings.
+    ///
+    /// # Arguments
+    /// * `sample_rate` - The sample rate of the audio.
+    ///
+    /// # Returns
+    /// * `AutoPan` - A full-width pan cycling every two seconds.
+    pub fn new(sample_rate: u32) -> Self {
+        let mut auto_pan = Self {
+            settings: AutoPanSettings::default(),
+            lfo: Lfo::new(sample_rate),
+            gains: [1.0; 2],
+            smoothing: time_coefficient(GAIN_SMOOTHING_SECONDS, sample_rate),
+        };
+        auto_pan.set_settings(AutoPanSettings::default());
+        auto_pan
+    }
+
+    /// Gets the current settings.
+    pub fn settings(&self) -> &AutoPanSettings {
+        &self.settings
+    }
+
+    /// Updates the settings; width is clamped to 0.0 to 1.0.
+    pub fn set_settings(&mut self, settings: AutoPanSettings) {
+        self.settings = AutoPanSettings {
+            width: settings.width.clamp(0.0, 1.0),
+            ..settings
+        };
+        self.lfo.set(self.settings.shape, self.settings.rate);
+    }
+
+    /// Locks note-value rates to a clock's tempo.
+    pub fn set_clock(&mut self, clock: Option<Arc<MasterClock>>) {
+        self.lfo.set_clock(clock);
+    }
+
+    /// Restarts the LFO.
+    pub fn reset(&mut self) {
+        self.lfo.reset();
+    }
+
+    /// Advances the LFO and returns the smoothed left and right gains.
+    ///
+    /// Both gains are 1.0 in the centre, so a centred pan leaves the signal unchanged.
+    #[inline]
+    fn next_gains(&mut self) -> [f32; 2] {
+        let pan = self.settings.width * self.lfo.tick();
+        let angle = (pan + 1.0) * std::f32::consts::FRAC_PI_4;
+        let targets = [angle.cos(), angle.sin()].map(|gain| gain * std::f32::consts::SQRT_2);
+        for (gain, target) in self.gains.iter_mut().zip(targets) {
+            *gain += (target - *gain) * self.smoothing;
+        }
+        self.gains
+    }
+
+    /// Processes a mono buffer in place as the left channel.
+    ///
+    /// On its own this is heard as a tremolo; use `process_stereo` for panning.
+    ///
+    /// # Arguments
+    /// * `buffer` - The audio to process.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - Always `Ok(())`.
+    pub fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        self.lfo.update();
+        for sample in buffer.iter_mut() {
+            *sample *= self.next_gains()[0];
+        }
+        Ok(())
+    }
+
+    /// Pans a stereo pair in place.
+    ///
+    /// # Arguments
+    /// * `left` - The left channel.
+    /// * `right` - The right channel, the same length as `left`.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - `BufferMismatch` if the channel lengths differ.
+    pub fn process_stereo(&mut self, left: &mut [f32], right: &mut [f32]) -> Result<(), AudioError> {
+        if left.len() != right.len() {
+            return Err(AudioError::BufferMismatch);
+        }
+        self.lfo.update();
+        for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+            let [left_gain, right_gain] = self.next_gains();
+            *l *= left_gain;
+            *r *= right_gain;
+        }
+        Ok(())
+    }
+}
+
+impl AudioEffect for AutoPan {
+    fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+        AutoPan::process(self, buffer)
+    }
+
+    fn reset(&mut self) {
+        AutoPan::reset(self)
+    }
+
+    fn on_loop_start(&mut self) {
+        self.lfo.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::effects::delay::{NoteDivision, NoteModifier, NoteValue};
+
+    #[test]
+    fn test_tremolo_and_pan_follow_the_lfo() {
+        let sample_rate = 48000;
+        let clock = Arc::new(MasterClock::new(sample_rate, 120.0));
+        let mut tremolo = Tremolo::new(sample_rate);
+        tremolo.set_clock(Some(clock));
+        // Eighth notes at 120 BPM: four dips per second
+        let eighth = LfoRate::Note(NoteValue::new(NoteDivision::Eighth, NoteModifier::Straight));
+        tremolo.set_settings(TremoloSettings { rate: eighth, shape: LfoShape::Square, depth: 1.0 });
+        let mut buffer = vec![1.0; sample_rate as usize];
+        tremolo.process(&mut buffer).unwrap();
+        // Full level for the first half of each 12000-sample cycle, silent for the second
+        for cycle in 0..4 {
+            assert!((buffer[cycle * 12000 + 5000] - 1.0).abs() < 1e-3);
+            assert!(buffer[cycle * 12000 + 11000] < 1e-3);
+        }
+        let largest_step = buffer.windows(2).map(|w| (w[1] - w[0]).abs()).fold(0.0, f32::max);
+        assert!(largest_step < 0.02, "{}", largest_step);
+
+        let mut pan = AutoPan::new(sample_rate);
+        pan.set_settings(AutoPanSettings { rate: LfoRate::Hertz(1.0), ..Default::default() });
+        let mut left = vec![1.0; 12000];
+        let mut right = vec![1.0; 12000];
+        pan.process_stereo(&mut left, &mut right).unwrap();
+        // A quarter cycle in the signal is hard right, at equal power
+        assert!(left[11999] < 0.01 && (right[11999] - 2f32.sqrt()).abs() < 0.01);
+        assert!((left[0] * left[0] + right[0] * right[0] - 2.0).abs() < 0.01);
+    }
+}
@@ -1 +1,42 @@
 ﻿//! WAV file handling
+//!
+//! Reading is part of the `file_io` feature, which brings in `hound`.
+
+#[cfg(feature = "file_io")]
+use std::path::Path;
+
+#[cfg(feature = "file_io")]
+use crate::{core::buffer::AudioBuffer, error::types::AudioError};
+
+/// Reads a WAV file into a buffer at the file's sample rate.
+///
+/// Integer samples are scaled to the range -1.0 to 1.0.
+///
+/// # Arguments
+/// * `path` - The file to read.
+///
+/// # Returns
+/// * `Result<AudioBuffer, AudioError>` - One channel per channel in the file, or `FileError` if it cannot be read.
+#[cfg(feature = "file_io")]
+pub fn read_wav(path: impl AsRef<Path>) -> Result<AudioBuffer, AudioError> {
+    let file_error = |e: hound::Error| AudioError::FileError(e.to_string());
+    let mut reader = hound::WavReader::open(path).map_err(file_error)?;
+    let spec = reader.spec();
+    let interleaved: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>(),
+        hound::SampleFormat::Int => {
+            let scale = 1.0 / (1u64 << (spec.bits_per_sample - 1)) as f32;
+            reader.samples::<i32>().map(|sample| sample.map(|s| s as f32 * scale)).collect()
+        }
+    }
+    .map_err(file_error)?;
+
+    let channels = usize::from(spec.channels).max(1);
+    let mut data = vec![Vec::with_capacity(interleaved.len() / channels); channels];
+    for frame in interleaved.chunks_exact(channels) {
+        for (channel, &sample) in data.iter_mut().zip(frame) {
+            channel.push(sample);
+        }
+    }
+    AudioBuffer::from_data(data, spec.sample_rate)
+}
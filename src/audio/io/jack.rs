@@ -5,35 +5,67 @@
 //! - Automatic client activation
 //! - Sample-accurate timing
 //! - Error recovery
+//! - JACK transport sync, as timebase master or follower
 
 //! JACK audio backend implementation
 
 use crate::{
     core::engine::AudioEngine,
     prelude::{AudioError, JackError},
+    sync::clock::{MasterClock, TimeSignature, TICKS_PER_BEAT},
 };
 use jack::{
     AsyncClient, Client, ClientOptions, Control,
-    AudioIn, AudioOut, PortFlags,
+    AudioIn, AudioOut, Port, TransportBBT, TransportState,
 };
 use std::{
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicU8, Ordering},
         Arc,
     },
     time::Duration,
 };
 use tracing::{info, error};
 
+/// How the looper takes part in JACK transport
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportMode {
+    /// Ignore JACK transport
+    Independent = 0,
+    /// Publish tempo, meter and BBT position from the master clock
+    TimebaseMaster = 1,
+    /// Follow JACK transport start/stop, locate and tempo
+    Follow = 2,
+}
+
+impl TransportMode {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => TransportMode::TimebaseMaster,
+            2 => TransportMode::Follow,
+            _ => TransportMode::Independent,
+        }
+    }
+}
+
 pub struct JackAudio {
+    // Released before the client is dropped
+    timebase: Option<timebase::Registration>,
     client: AsyncClient<(), ProcessHandler>,
     sample_rate: u32,
     active: Arc<AtomicBool>,
+    clock: Arc<MasterClock>,
+    transport_mode: Arc<AtomicU8>,
 }
 
 struct ProcessHandler {
     engine: AudioEngine,
+    inputs: Vec<Port<AudioIn>>,
+    outputs: Vec<Port<AudioOut>>,
     active: Arc<AtomicBool>,
+    transport_mode: Arc<AtomicU8>,
+    /// Frame the transport is expected at in the next cycle, used to detect locates
+    expected_frame: Option<u32>,
 }
 
 impl JackAudio {
@@ -54,29 +86,33 @@ impl JackAudio {
         }
 
         // Register input ports
-        for i in 0..input_channels {
-            client.register_port(
-                &format!("input_{}", i + 1),
-                AudioIn::default(),
-            )
-            .map_err(|e| AudioError::PortRegistration(format!("Input port {}: {}", i + 1, e)))?;
-        }
+        let inputs = (0..input_channels)
+            .map(|i| {
+                client.register_port(&format!("input_{}", i + 1), AudioIn::default())
+                    .map_err(|e| AudioError::PortRegistration(format!("Input port {}: {}", i + 1, e)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
 
         // Register output ports
-        for i in 0..output_channels {
-            client.register_port(
-                &format!("output_{}", i + 1),
-                AudioOut::default(),
-            )
-            .map_err(|e| AudioError::PortRegistration(format!("Output port {}: {}", i + 1, e)))?;
-        }
+        let outputs = (0..output_channels)
+            .map(|i| {
+                client.register_port(&format!("output_{}", i + 1), AudioOut::default())
+                    .map_err(|e| AudioError::PortRegistration(format!("Output port {}: {}", i + 1, e)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
 
         let sample_rate = client.sample_rate();
         let active = Arc::new(AtomicBool::new(true));
+        let clock = engine.clock.clone();
+        let transport_mode = Arc::new(AtomicU8::new(TransportMode::Independent as u8));
 
         let process_handler = ProcessHandler {
             engine,
+            inputs,
+            outputs,
             active: active.clone(),
+            transport_mode: transport_mode.clone(),
+            expected_frame: None,
         };
 
         let async_client = client
@@ -89,17 +125,69 @@ impl JackAudio {
         );
 
         Ok(Self {
+            timebase: None,
             client: async_client,
             sample_rate: sample_rate.try_into().unwrap(),
             active,
+            clock,
+            transport_mode,
         })
     }
 
+    /// Select how the looper takes part in JACK transport
+    ///
+    /// `TimebaseMaster` registers as unconditional timebase master, taking
+    /// over from any other master in the graph.
+    pub fn set_transport_mode(&mut self, mode: TransportMode) -> Result<(), AudioError> {
+        if mode == TransportMode::TimebaseMaster && self.timebase.is_none() {
+            self.timebase = Some(timebase::Registration::new(
+                self.client.as_client(),
+                self.clock.clone(),
+            )?);
+        } else if mode != TransportMode::TimebaseMaster {
+            if let Some(registration) = self.timebase.take() {
+                registration.release(self.client.as_client());
+            }
+        }
+        self.transport_mode.store(mode as u8, Ordering::Release);
+        info!("JACK transport mode: {:?}", mode);
+        Ok(())
+    }
+
+    /// Get the current transport mode
+    pub fn transport_mode(&self) -> TransportMode {
+        TransportMode::from_u8(self.transport_mode.load(Ordering::Acquire))
+    }
+
+    /// Start the JACK transport rolling
+    pub fn start_transport(&self) -> Result<(), AudioError> {
+        Ok(self.client.as_client().transport().start()?)
+    }
+
+    /// Stop the JACK transport
+    pub fn stop_transport(&self) -> Result<(), AudioError> {
+        Ok(self.client.as_client().transport().stop()?)
+    }
+
+    /// Move the JACK transport to a frame
+    pub fn locate_transport(&self, frame: u32) -> Result<(), AudioError> {
+        Ok(self.client.as_client().transport().locate(frame)?)
+    }
+
+    /// Query whether the JACK transport is rolling
+    pub fn transport_rolling(&self) -> Result<bool, AudioError> {
+        let state = self.client.as_client().transport().query_state()?;
+        Ok(state == TransportState::Rolling)
+    }
+
     pub fn is_active(&self) -> bool {
         self.active.load(Ordering::SeqCst)
     }
 
     pub fn shutdown(&mut self) -> Result<(), AudioError> {
+        if let Some(registration) = self.timebase.take() {
+            registration.release(self.client.as_client());
+        }
         self.active.store(false, Ordering::SeqCst);
         info!("JACK client shutdown");
         Ok(())
@@ -128,31 +216,19 @@ impl jack::ProcessHandler for ProcessHandler {
             return Control::Quit;
         }
 
+        if TransportMode::from_u8(self.transport_mode.load(Ordering::Acquire)) == TransportMode::Follow {
+            self.follow_transport(client, ps.n_frames());
+        } else {
+            self.expected_frame = None;
+            self.engine.transport_running = true;
+        }
+
         // Get all input buffers
-        let input_buffers: Vec<&[f32]> = client
-            .ports(None, None, PortFlags::IS_INPUT | PortFlags::IS_PHYSICAL)
-            .iter()
-            .filter_map(|name| {
-                client.port_by_name(name)
-                    .and_then(|port| {
-                        // Use buffer() instead of audio_buffer()
-                        port.buffer(ps).ok()
-                    })
-            })
-            .collect();
+        let input_buffers: Vec<&[f32]> = self.inputs.iter().map(|port| port.as_slice(ps)).collect();
 
         // Get all output buffers
-        let mut output_buffers: Vec<&mut [f32]> = client
-            .ports(None, None, PortFlags::IS_OUTPUT | PortFlags::IS_PHYSICAL)
-            .iter()
-            .filter_map(|name| {
-                client.port_by_name(name)
-                    .and_then(|port| {
-                        // Use buffer() instead of audio_buffer()
-                        port.buffer(ps).ok()
-                    })
-            })
-            .collect();
+        let mut output_buffers: Vec<&mut [f32]> =
+            self.outputs.iter_mut().map(|port| port.as_mut_slice(ps)).collect();
 
         match self.engine.process(&input_buffers, &mut output_buffers) {
             Ok(_) => Control::Continue,
@@ -165,10 +241,131 @@ impl jack::ProcessHandler for ProcessHandler {
     }
 }
 
+impl ProcessHandler {
+    /// Follow JACK transport state, tempo, meter and position for this cycle
+    fn follow_transport(&mut self, client: &jack::Client, n_frames: u32) {
+        let Ok(query) = client.transport().query() else {
+            return;
+        };
+        let clock = &self.engine.clock;
+        let rolling = query.state == TransportState::Rolling;
+        let frame = query.pos.frame();
+        let located = self.expected_frame != Some(frame);
+
+        if let Some(bbt) = query.pos.bbt() {
+            if (bbt.bpm - clock.bpm() as f64).abs() > 1e-6 {
+                clock.set_bpm_exact(bbt.bpm);
+            }
+            if let Some(time_signature) = TimeSignature::new(bbt.sig_num as u8, bbt.sig_denom as u8) {
+                if time_signature != clock.time_signature() {
+                    clock.set_time_signature(time_signature);
+                }
+            }
+            if located {
+                let beats = bbt.bar_start_tick / bbt.ticks_per_beat
+                    + (bbt.beat - 1) as f64
+                    + bbt.tick as f64 / bbt.ticks_per_beat;
+                clock.seek_beats(beats);
+            }
+        } else if located {
+            // No timebase master: derive the position from the frame at the current tempo
+            clock.seek_beats(frame as f64 / clock.samples_per_beat_exact());
+        }
+
+        self.engine.transport_running = rolling;
+        self.expected_frame = Some(if rolling { frame + n_frames } else { frame });
+    }
+}
+
+/// Build the JACK BBT position for the clock's current position
+fn transport_bbt(clock: &MasterClock) -> TransportBBT {
+    let position = clock.musical_position();
+    let time_signature = clock.time_signature();
+    let within_bar = position.beat as f64 + position.tick as f64 / TICKS_PER_BEAT as f64;
+    let bar_start_beat = (clock.beat_position() - within_bar).max(0.0);
+
+    TransportBBT {
+        bar: position.bar as usize + 1,
+        beat: position.beat as usize + 1,
+        tick: position.tick as usize,
+        sig_num: time_signature.numerator as f32,
+        sig_denom: time_signature.denominator as f32,
+        ticks_per_beat: TICKS_PER_BEAT as f64,
+        bpm: clock.bpm() as f64,
+        bar_start_tick: bar_start_beat * TICKS_PER_BEAT as f64,
+    }
+}
+
+/// Timebase master registration
+///
+/// The `jack` crate does not wrap `jack_set_timebase_callback`, so this is
+/// the one place the backend talks to `jack-sys` directly.
+#[allow(unsafe_code)]
+mod timebase {
+    use super::{transport_bbt, AudioError, MasterClock};
+    use jack_sys as j;
+    use std::{ffi::c_void, sync::Arc};
+
+    /// Keeps the clock shared with the timebase callback alive until released
+    pub(super) struct Registration {
+        clock: *const MasterClock,
+    }
+
+    // The pointer is only dereferenced by JACK's process thread and released on drop
+    unsafe impl Send for Registration {}
+
+    impl Registration {
+        /// Register as unconditional timebase master
+        pub(super) fn new(client: &jack::Client, clock: Arc<MasterClock>) -> Result<Self, AudioError> {
+            let clock = Arc::into_raw(clock);
+            // SAFETY: `clock` stays valid until `release`, which unregisters the callback first
+            let result = unsafe {
+                j::jack_set_timebase_callback(client.raw(), 0, Some(callback), clock as *mut c_void)
+            };
+            if result != 0 {
+                // SAFETY: the callback was not registered, so nothing else holds the pointer
+                unsafe { drop(Arc::from_raw(clock)) };
+                return Err(AudioError::JackError(jack::Error::UnknownError { error_code: result }));
+            }
+            Ok(Self { clock })
+        }
+
+        /// Give up timebase mastership and drop the shared clock
+        pub(super) fn release(self, client: &jack::Client) {
+            // SAFETY: after `jack_release_timebase` returns the callback is no longer invoked
+            unsafe {
+                j::jack_release_timebase(client.raw());
+                drop(Arc::from_raw(self.clock));
+            }
+        }
+    }
+
+    /// Called by JACK after the process callback with the position for the next cycle
+    unsafe extern "C" fn callback(
+        _state: j::jack_transport_state_t,
+        _n_frames: j::jack_nframes_t,
+        pos: *mut j::jack_position_t,
+        new_pos: std::os::raw::c_int,
+        arg: *mut c_void,
+    ) {
+        let clock = &*(arg as *const MasterClock);
+        // `TransportPosition` is a transparent wrapper around `jack_position_t`
+        let position = &mut *(pos as *mut jack::TransportPosition);
+
+        if new_pos != 0 {
+            clock.seek_beats(position.frame() as f64 / clock.samples_per_beat_exact());
+        }
+        let _ = position.set_bbt(Some(transport_bbt(clock)));
+    }
+}
+
 impl Drop for JackAudio {
     fn drop(&mut self) {
         if self.is_active() {
             let _ = self.shutdown();
+        } else if let Some(registration) = self.timebase.take() {
+            // Mastership can be taken before activation, and the clock must not outlive it
+            registration.release(self.client.as_client());
         }
     }
 }
\ No newline at end of file
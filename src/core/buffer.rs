@@ -16,7 +16,6 @@ use std::{
 };
 use crossbeam_queue::SegQueue;
 use dashmap::DashMap;
-use realfft::RealFftPlanner;
 use crate::prelude::{AudioError, AudioError::JackError};
 
 /// Main audio buffer structure with multi-channel support
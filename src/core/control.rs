@@ -0,0 +1,88 @@
+﻿//! Engine control queue
+//!
+//! MIDI, the keyboard and remote control run on their own threads and hand
+//! control actions to the audio thread through this queue, which the engine
+//! drains at the start of every block. Pushing never blocks or allocates; if
+//! the queue is full the event is dropped and counted.
+
+use crate::core::engine::ControlAction;
+use crossbeam_queue::ArrayQueue;
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+
+/// Events held in the queue before the engine drains them.
+pub const CONTROL_QUEUE_CAPACITY: usize = 256;
+
+/// A control pressed or released by an input source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControlEvent {
+    /// The action the control is bound to
+    pub action: ControlAction,
+    /// `true` when the control was pressed, `false` when it was released
+    pub pressed: bool,
+    /// When the control was operated, so taps keep their timing through the queue
+    pub at: Instant,
+}
+
+/// Lock-free queue of control events for the audio thread.
+#[derive(Debug)]
+pub struct ControlQueue {
+    events: ArrayQueue<ControlEvent>,
+    dropped: AtomicUsize,
+}
+
+impl Default for ControlQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ControlQueue {
+    /// Creates an empty queue.
+    pub fn new() -> Self {
+        Self {
+            events: ArrayQueue::new(CONTROL_QUEUE_CAPACITY),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Queues the press of a control.
+    ///
+    /// # Returns
+    /// * `bool` - `false` if the queue was full and the event was dropped.
+    pub fn press(&self, action: ControlAction) -> bool {
+        self.push(ControlEvent { action, pressed: true, at: Instant::now() })
+    }
+
+    /// Queues the release of a held control.
+    ///
+    /// # Returns
+    /// * `bool` - `false` if the queue was full and the event was dropped.
+    pub fn release(&self, action: ControlAction) -> bool {
+        self.push(ControlEvent { action, pressed: false, at: Instant::now() })
+    }
+
+    /// Queues an event.
+    ///
+    /// # Returns
+    /// * `bool` - `false` if the queue was full and the event was dropped.
+    pub fn push(&self, event: ControlEvent) -> bool {
+        if self.events.push(event).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        true
+    }
+
+    /// Takes the oldest queued event.
+    pub fn pop(&self) -> Option<ControlEvent> {
+        self.events.pop()
+    }
+
+    /// Gets how many events have been dropped because the queue was full.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
@@ -1,37 +1,663 @@
 ﻿//! Main audio engine implementation
 
 use crate::{
-    core::{track::Track, buffer::AudioBuffer},
-    audio::effects::EffectsProcessor,
+    core::{track::Track, buffer::AudioBuffer, control::ControlQueue, telemetry::Telemetry},
+    audio::{
+        analysis::{
+            bpm::BpmDetector,
+            meter::{LevelMeter, MeterSettings},
+            tuner::{Tuner, TunerSettings},
+        },
+        effects::{
+            compressor::MAX_LOOKAHEAD, delay::DelayLine, AudioEffect, BeatRepeat, Compressor, CompressorSettings,
+            EffectsProcessor, Limiter, NoiseGate, NoiseReduction, Sidechain, Slicer,
+        },
+    },
     error::types::AudioError,
-    sync::clock::MasterClock,
+    sync::{
+        auto_tempo::{infer_tempo, AutoTempoSettings, TempoEstimate},
+        clock::MasterClock,
+        tap_tempo::TapTempo,
+        tempo_map::TempoMap,
+    },
 };
+#[cfg(feature = "link")]
+use crate::sync::link::LinkSync;
+#[cfg(feature = "midi")]
+use crate::midi::clock::{ClockGenerator, ClockMessage, MidiClockOutput};
+#[cfg(feature = "midi")]
+use std::sync::atomic::{AtomicBool, Ordering};
 use jack::{ProcessHandler, ProcessScope, Control};
 use std::sync::Arc;
 
+/// Time for a panic to fade the outputs to silence, in seconds
+const PANIC_FADE_SECONDS: f32 = 0.05;
+
+/// Shortest MIDI clock pulse spacing, in samples, the event list is sized for
+#[cfg(feature = "midi")]
+const MIN_SAMPLES_PER_PULSE: usize = 64;
+
+/// Largest block the engine is prepared for until `prepare` is called
+pub const DEFAULT_MAX_BLOCK_SIZE: usize = 8192;
+
+/// Input channels metered until `prepare` is called
+const DEFAULT_INPUT_CHANNELS: usize = 2;
+
 pub struct AudioEngine {
+    /// Tracks in mix order; add and remove them with `add_track` and `remove_track`
     pub tracks: Vec<Track>,
     pub bpm_detector: BpmDetector,
     pub effects_processor: EffectsProcessor,
-    pub clock: MasterClock,
+    pub clock: Arc<MasterClock>,
+    /// Optional tempo map driving the clock through ramps and meter changes
+    pub tempo_map: Option<TempoMap>,
+    /// Tap tempo detector feeding the clock
+    pub tap_tempo: TapTempo,
+    /// Whether the clock advances; cleared while following a stopped transport
+    pub transport_running: bool,
+    /// Tempo range and analysis used to infer the tempo of the first free-played loop
+    pub auto_tempo: AutoTempoSettings,
+    /// Ableton Link session keeping the clock in step with peers on the network
+    #[cfg(feature = "link")]
+    pub link: Option<Arc<LinkSync>>,
+    /// Start/Stop state of an external MIDI clock, from `MidiClockInput::transport_state`
+    #[cfg(feature = "midi")]
+    pub midi_transport: Option<Arc<AtomicBool>>,
+    /// MIDI clock sent from the master clock; follows the engine transport
+    #[cfg(feature = "midi")]
+    pub midi_clock: Option<ClockGenerator>,
+    /// Port the MIDI clock is sent to
+    #[cfg(feature = "midi")]
+    pub midi_clock_output: Option<MidiClockOutput>,
+    /// MIDI clock messages generated for the last block
+    #[cfg(feature = "midi")]
+    midi_clock_events: Vec<(usize, ClockMessage)>,
+    /// Meter readings and transport state published for user interfaces
+    pub telemetry: Arc<Telemetry>,
+    /// Control events from MIDI, the keyboard and remote control, applied at the start of each block
+    pub controls: Arc<ControlQueue>,
+    /// Peak hold and clip level used by all meters
+    pub meter_settings: MeterSettings,
+    input_meter: Option<LevelMeter>,
+    track_meters: Vec<LevelMeter>,
+    master_meter: Option<LevelMeter>,
+    /// Chromatic tuner listening to one input channel
+    pub tuner: Tuner,
+    /// Compressor inserted on each track, indexed like `tracks`
+    track_compressors: Vec<Option<Compressor>>,
+    /// Buffers the tracks render into before they are mixed
+    track_buffers: Vec<Vec<f32>>,
+    /// Uncompressed copy of each track keying another track's compressor, indexed like `tracks`
+    sidechain_buffers: Vec<Vec<f32>>,
+    /// Delay lines lining the tracks up with the longest compressor lookahead, indexed like `tracks`
+    latency_lines: Vec<DelayLine>,
+    /// Largest block the scratch buffers are sized for
+    max_block_size: usize,
+    /// Noise reduction on the recorded input, before it reaches the tracks
+    pub input_noise_reduction: Option<NoiseReduction>,
+    /// Noise gate on the recorded input, after noise reduction
+    pub input_gate: Option<NoiseGate>,
+    /// The recorded input after the input effects
+    input_scratch: Vec<f32>,
+    /// Beat repeat on the master bus, held with `ControlAction::BeatRepeat`
+    pub beat_repeat: BeatRepeat,
+    /// Slicer on the master bus, held with `ControlAction::Slicer`
+    pub slicer: Slicer,
+    /// True-peak limiter protecting the outputs, last on the master bus
+    pub limiter: Limiter,
+    /// Whether the limiter runs; on by default
+    pub limiter_enabled: bool,
+    /// Gain ramping the outputs to silence after a panic and back after a resume
+    output_gain: f32,
+    panicked: bool,
+}
+
+/// Engine actions that can be triggered from MIDI, the keyboard or remote control
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlAction {
+    /// Register a tap for tap tempo
+    TapTempo,
+    /// Switch the chromatic tuner on or off
+    ToggleTuner,
+    /// Repeat slices of the master bus while held
+    BeatRepeat,
+    /// Gate the master bus with the slicer pattern while held
+    Slicer,
+    /// Fade all outputs to silence
+    Panic,
+    /// Fade the outputs back in after a panic
+    ResumeOutput,
+    /// Clear the clip indicators and held peaks of all meters
+    ResetMeterClips,
 }
 
-pub struct BpmDetector;
+impl ControlAction {
+    /// Whether the action lasts only while its control is held
+    pub fn is_momentary(&self) -> bool {
+        matches!(self, ControlAction::BeatRepeat | ControlAction::Slicer)
+    }
+}
 
 impl AudioEngine {
     pub fn new(sample_rate: u32, max_tracks: usize) -> Result<Self, AudioError> {
-        Ok(Self {
+        let clock = Arc::new(MasterClock::new(sample_rate, 120.0));
+        let mut engine = Self {
             tracks: Vec::with_capacity(max_tracks),
-            bpm_detector: BpmDetector,
+            bpm_detector: BpmDetector::new(sample_rate),
             effects_processor: EffectsProcessor::new(sample_rate),
-            clock: MasterClock::new(sample_rate, 120.0),
-        })
+            beat_repeat: BeatRepeat::new(clock.clone()),
+            slicer: Slicer::new(clock.clone()),
+            limiter: Limiter::new(sample_rate),
+            limiter_enabled: true,
+            output_gain: 1.0,
+            panicked: false,
+            clock,
+            tempo_map: None,
+            tap_tempo: TapTempo::default(),
+            transport_running: true,
+            auto_tempo: AutoTempoSettings::default(),
+            #[cfg(feature = "link")]
+            link: None,
+            #[cfg(feature = "midi")]
+            midi_transport: None,
+            #[cfg(feature = "midi")]
+            midi_clock: None,
+            #[cfg(feature = "midi")]
+            midi_clock_output: None,
+            #[cfg(feature = "midi")]
+            midi_clock_events: Vec::new(),
+            telemetry: Arc::new(Telemetry::new()),
+            controls: Arc::new(ControlQueue::new()),
+            meter_settings: MeterSettings::default(),
+            input_meter: None,
+            track_meters: Vec::with_capacity(max_tracks),
+            master_meter: None,
+            tuner: Tuner::new(sample_rate, TunerSettings::default()),
+            track_compressors: Vec::with_capacity(max_tracks),
+            track_buffers: Vec::with_capacity(max_tracks),
+            sidechain_buffers: Vec::with_capacity(max_tracks),
+            latency_lines: Vec::with_capacity(max_tracks),
+            max_block_size: 0,
+            input_noise_reduction: None,
+            input_gate: None,
+            input_scratch: Vec::new(),
+        };
+        engine.prepare(DEFAULT_INPUT_CHANNELS, DEFAULT_MAX_BLOCK_SIZE);
+        Ok(engine)
+    }
+
+    /// Size the meters and scratch buffers for the audio interface
+    ///
+    /// This allocates, so call it before processing starts or when the
+    /// backend changes its channel count or buffer size, never from the
+    /// audio thread. Inputs beyond `input_channels` are not metered.
+    ///
+    /// # Arguments
+    /// * `input_channels` - Number of input channels to meter.
+    /// * `max_block_size` - Largest block `process` will be called with.
+    pub fn prepare(&mut self, input_channels: usize, max_block_size: usize) {
+        let sample_rate = self.clock.sample_rate();
+        let settings = self.meter_settings;
+        self.input_meter = (input_channels > 0).then(|| LevelMeter::new(sample_rate, input_channels, settings));
+        self.master_meter = Some(LevelMeter::new(sample_rate, 2, settings));
+        self.max_block_size = max_block_size;
+        let scratch = self.track_buffers.iter_mut().chain(&mut self.sidechain_buffers);
+        for buffer in scratch.chain([&mut self.input_scratch]) {
+            buffer.resize(max_block_size, 0.0);
+        }
+        #[cfg(feature = "midi")]
+        self.midi_clock_events
+            .reserve(max_block_size / MIN_SAMPLES_PER_PULSE + 4);
+    }
+
+    /// Add a track along with its meter and render buffers
+    ///
+    /// # Returns
+    /// * `usize` - The index of the new track.
+    pub fn add_track(&mut self, track: Track) -> usize {
+        let sample_rate = self.clock.sample_rate();
+        self.tracks.push(track);
+        self.track_meters.push(LevelMeter::new(sample_rate, 1, self.meter_settings));
+        self.track_compressors.push(None);
+        self.track_buffers.push(vec![0.0; self.max_block_size]);
+        self.sidechain_buffers.push(vec![0.0; self.max_block_size]);
+        self.latency_lines.push(DelayLine::new((MAX_LOOKAHEAD * sample_rate as f32) as usize + 1));
+        self.tracks.len() - 1
+    }
+
+    /// Remove a track along with its meter, compressor and render buffers
+    pub fn remove_track(&mut self, track_index: usize) -> Result<Track, AudioError> {
+        if track_index >= self.tracks.len() {
+            return Err(AudioError::TrackError(format!("No track {}", track_index)));
+        }
+        self.track_meters.remove(track_index);
+        self.track_compressors.remove(track_index);
+        self.track_buffers.remove(track_index);
+        self.sidechain_buffers.remove(track_index);
+        self.latency_lines.remove(track_index);
+        // Keep track sidechains pointing at the same source; a removed key falls back to internal
+        for compressor in self.track_compressors.iter_mut().flatten() {
+            let sidechain = match compressor.settings().sidechain {
+                Sidechain::Track(source) if source == track_index => Sidechain::Internal,
+                Sidechain::Track(source) if source > track_index => Sidechain::Track(source - 1),
+                _ => continue,
+            };
+            compressor.set_settings(CompressorSettings { sidechain, ..*compressor.settings() });
+        }
+        Ok(self.tracks.remove(track_index))
+    }
+
+    /// Handle a control action from any input source
+    pub fn handle_action(&mut self, action: ControlAction) {
+        match action {
+            ControlAction::TapTempo => {
+                self.tap_tempo.tap(std::time::Instant::now(), &self.clock);
+            }
+            ControlAction::ToggleTuner => {
+                let enabled = !self.tuner.is_enabled();
+                self.tuner.set_enabled(enabled);
+            }
+            ControlAction::BeatRepeat => self.beat_repeat.engage(),
+            ControlAction::Slicer => self.slicer.engage(),
+            ControlAction::Panic => self.panic(),
+            ControlAction::ResumeOutput => self.resume_output(),
+            ControlAction::ResetMeterClips => self.reset_meter_clips(),
+        }
+    }
+
+    /// Handle the release of a held control; only momentary actions respond
+    pub fn release_action(&mut self, action: ControlAction) {
+        match action {
+            ControlAction::BeatRepeat => self.beat_repeat.release(),
+            ControlAction::Slicer => self.slicer.release(),
+            ControlAction::TapTempo
+            | ControlAction::ToggleTuner
+            | ControlAction::Panic
+            | ControlAction::ResumeOutput
+            | ControlAction::ResetMeterClips => {}
+        }
+    }
+
+    /// Apply the control events queued since the last block
+    ///
+    /// Taps use the time they were queued so the tempo is not skewed by the block size.
+    fn drain_controls(&mut self) {
+        while let Some(event) = self.controls.pop() {
+            match (event.action, event.pressed) {
+                (ControlAction::TapTempo, true) => {
+                    self.tap_tempo.tap(event.at, &self.clock);
+                }
+                (action, true) => self.handle_action(action),
+                (action, false) => self.release_action(action),
+            }
+        }
+    }
+
+    /// Fade all outputs to silence and drop any held performance effects
+    ///
+    /// The outputs stay silent until `resume_output` is called.
+    pub fn panic(&mut self) {
+        self.panicked = true;
+        self.beat_repeat.reset();
+        self.slicer.release();
+    }
+
+    /// Fade the outputs back in after a panic
+    pub fn resume_output(&mut self) {
+        self.panicked = false;
+    }
+
+    /// Whether a panic is holding the outputs silent
+    pub fn is_panicked(&self) -> bool {
+        self.panicked
     }
     
+    /// Stop recording on a track, inferring the session tempo from it if it is the first loop
+    ///
+    /// The clock is set to the inferred tempo and restarted at bar one so it
+    /// lines up with the loop, which starts playing from its beginning. Every
+    /// track records the tempo it was played at, so tempo follow can adjust
+    /// its playback when the tempo changes later. The loop's key and chords
+    /// are then estimated bar by bar.
+    ///
+    /// This analyses the whole loop and allocates, so it must not be called
+    /// from the audio process callback; call it from a control thread between
+    /// blocks, see [`Track::stop_recording`].
+    pub fn stop_recording(&mut self, track_index: usize) -> Result<Option<TempoEstimate>, AudioError> {
+        let is_first_loop = self
+            .tracks
+            .iter()
+            .enumerate()
+            .all(|(i, track)| i == track_index || track.loop_length().is_none());
+        let track = self
+            .tracks
+            .get_mut(track_index)
+            .ok_or_else(|| AudioError::TrackError(format!("No track {}", track_index)))?;
+        track.stop_recording()?;
+
+        let mut estimate = None;
+        if is_first_loop && self.tempo_map.is_none() {
+            estimate = infer_tempo(
+                track.onsets(),
+                self.clock.sample_rate(),
+                track.loop_length().unwrap_or(0),
+                self.clock.time_signature().numerator as u32,
+                &self.auto_tempo,
+            );
+            if let Some(estimate) = estimate {
+                self.clock.set_bpm_exact(estimate.bpm);
+                self.clock.seek_beats(0.0);
+            }
+        }
+        track.set_recorded_tempo(self.clock.bpm());
+        track.analyze_harmony(self.clock.samples_per_bar().round() as usize)?;
+        Ok(estimate)
+    }
+
     pub fn process(&mut self, input: &[&[f32]], output: &mut [&mut [f32]]) -> Result<(), AudioError> {
-        // Implement audio processing
+        let block_size = output.first().map_or(0, |channel| channel.len());
+
+        if let Some(tempo_map) = &self.tempo_map {
+            self.clock.follow_tempo_map(tempo_map, block_size);
+        }
+        self.drain_controls();
+        self.tap_tempo.poll(&self.clock);
+        #[cfg(feature = "link")]
+        if let Some(link) = &self.link {
+            if let Some(playing) = link.sync_clock(&self.clock) {
+                self.transport_running = playing;
+            }
+        }
+        #[cfg(feature = "midi")]
+        if let Some(running) = &self.midi_transport {
+            self.transport_running = running.load(Ordering::Acquire);
+        }
+        let bpm = self.clock.bpm();
+        for track in &mut self.tracks {
+            track.set_playback_tempo(bpm);
+        }
+
+        if block_size > self.max_block_size {
+            return Err(AudioError::BufferError(format!(
+                "Block of {} samples exceeds the prepared {}",
+                block_size, self.max_block_size
+            )));
+        }
+        if self.track_buffers.len() != self.tracks.len() {
+            return Err(AudioError::TrackError("Tracks must be added with add_track".into()));
+        }
+        if let Some(meter) = &mut self.input_meter {
+            meter.process(input);
+        }
+        if let Some(channel) = input.get(self.tuner.settings().input_channel) {
+            self.tuner.process(channel)?;
+        }
+
+        for channel in output.iter_mut() {
+            channel.fill(0.0);
+        }
+        let recorded = self.process_input_effects(input, block_size)?;
+        let input_latency = self.input_latency();
+        for (track, buffer) in self.tracks.iter_mut().zip(&mut self.track_buffers) {
+            if let Some(len) = recorded {
+                track.set_input_latency(input_latency);
+                track.process_input(&self.input_scratch[..len]);
+            }
+            let buffer = &mut buffer[..block_size];
+            buffer.fill(0.0);
+            track.process_output(buffer)?;
+        }
+        // Sidechain keys are copied before any compressor runs, so every track keys from the dry signal
+        for (source, (buffer, key)) in self.track_buffers.iter().zip(&mut self.sidechain_buffers).enumerate() {
+            let keyed = |compressor: &Compressor| compressor.settings().sidechain == Sidechain::Track(source);
+            if self.track_compressors.iter().flatten().any(keyed) {
+                key[..block_size].copy_from_slice(&buffer[..block_size]);
+            }
+        }
+        for index in 0..self.tracks.len() {
+            self.compress_track(index, input, block_size)?;
+        }
+        self.compensate_latency(block_size);
+        for (buffer, meter) in self.track_buffers.iter().zip(&mut self.track_meters) {
+            let buffer = &buffer[..block_size];
+            meter.process(&[buffer]);
+            for channel in output.iter_mut() {
+                for (out, sample) in channel.iter_mut().zip(buffer) {
+                    *out += sample;
+                }
+            }
+        }
+        self.process_master_bus(output)?;
+        if self.tuner.mutes_outputs() {
+            for channel in output.iter_mut() {
+                channel.fill(0.0);
+            }
+        }
+        if let Some(meter) = &mut self.master_meter {
+            let channels: [&[f32]; 2] = [
+                output.first().map_or(&[][..], |c| &c[..]),
+                output.get(1).map_or(&[][..], |c| &c[..]),
+            ];
+            meter.process(&channels[..output.len().min(2)]);
+        }
+
+        #[cfg(feature = "midi")]
+        self.process_midi_clock(block_size);
+        if self.transport_running {
+            self.clock.advance(block_size);
+        }
+        self.publish_telemetry();
         Ok(())
     }
+
+    /// MIDI clock messages generated for the last processed block, with their sample offsets
+    #[cfg(feature = "midi")]
+    pub fn midi_clock_events(&self) -> &[(usize, ClockMessage)] {
+        &self.midi_clock_events
+    }
+
+    /// Generate the MIDI clock for the block and hand it to the output port
+    ///
+    /// The generator starts and stops with the engine transport, sending
+    /// Start from the top of the song and Continue from anywhere else.
+    /// Must run before the clock is advanced for the block.
+    #[cfg(feature = "midi")]
+    fn process_midi_clock(&mut self, block_size: usize) {
+        self.midi_clock_events.clear();
+        let Some(generator) = &mut self.midi_clock else {
+            return;
+        };
+        if self.transport_running && !generator.is_running() {
+            if self.clock.beat_position() == 0.0 {
+                generator.start();
+            } else {
+                generator.resume(&self.clock);
+            }
+        } else if !self.transport_running && generator.is_running() {
+            generator.stop();
+        }
+        generator.process(&self.clock, block_size, &mut self.midi_clock_events);
+        if let Some(output) = &self.midi_clock_output {
+            output.schedule(&self.midi_clock_events, self.clock.sample_rate());
+        }
+    }
+
+    /// Run the input effects on the first input, returning how many samples were recorded
+    fn process_input_effects(&mut self, input: &[&[f32]], block_size: usize) -> Result<Option<usize>, AudioError> {
+        let Some(channel) = input.first() else {
+            return Ok(None);
+        };
+        let len = block_size.min(channel.len());
+        let recorded = &mut self.input_scratch[..len];
+        recorded.copy_from_slice(&channel[..len]);
+        if let Some(noise_reduction) = &mut self.input_noise_reduction {
+            noise_reduction.process(recorded)?;
+        }
+        if let Some(gate) = &mut self.input_gate {
+            gate.process(recorded)?;
+        }
+        Ok(Some(len))
+    }
+
+    /// Delay the input effects add to the recorded input, in samples
+    ///
+    /// Tracks are told this so recordings and overdubs line up with the loop.
+    pub fn input_latency(&self) -> usize {
+        let noise_reduction = self.input_noise_reduction.as_ref().map_or(0, NoiseReduction::latency);
+        let gate = self.input_gate.as_ref().map_or(0, AudioEffect::latency);
+        noise_reduction + gate
+    }
+
+    /// Run the performance effects, the limiter and the panic fade on the master bus
+    ///
+    /// The master bus is the first two outputs. Any further outputs are left
+    /// with the dry mix, as direct outputs for external processing, so they
+    /// skip the performance effects and the limiter; the panic fade still
+    /// silences every output.
+    fn process_master_bus(&mut self, output: &mut [&mut [f32]]) -> Result<(), AudioError> {
+        let [left, rest @ ..] = &mut *output else {
+            return Ok(());
+        };
+        match rest.first_mut() {
+            Some(right) => {
+                self.beat_repeat.process_stereo(left, right)?;
+                self.slicer.process_stereo(left, right)?;
+                if self.limiter_enabled {
+                    self.limiter.process_stereo(left, right)?;
+                }
+            }
+            None => {
+                self.beat_repeat.process(left)?;
+                self.slicer.process(left)?;
+                if self.limiter_enabled {
+                    self.limiter.process(left)?;
+                }
+            }
+        }
+
+        if self.panicked || self.output_gain < 1.0 {
+            let target = if self.panicked { 0.0 } else { 1.0 };
+            let step = 1.0 / (PANIC_FADE_SECONDS * self.clock.sample_rate() as f32);
+            let block_size = output.first().map_or(0, |channel| channel.len());
+            for i in 0..block_size {
+                self.output_gain = if self.output_gain < target {
+                    (self.output_gain + step).min(target)
+                } else {
+                    (self.output_gain - step).max(target)
+                };
+                for sample in output.iter_mut().filter_map(|channel| channel.get_mut(i)) {
+                    *sample *= self.output_gain;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Insert a compressor on a track, or remove it with `None`
+    ///
+    /// A compressor with lookahead delays its track; all other tracks are
+    /// delayed to match, so the whole mix is late by the longest lookahead.
+    /// A track sidechain must key from another existing track.
+    pub fn set_track_compressor(&mut self, track_index: usize, compressor: Option<Compressor>) -> Result<(), AudioError> {
+        if let Some(Sidechain::Track(source)) = compressor.as_ref().map(|c| c.settings().sidechain) {
+            if source == track_index || source >= self.tracks.len() {
+                return Err(AudioError::TrackError(format!(
+                    "Track {} cannot key from track {}",
+                    track_index, source
+                )));
+            }
+        }
+        let slot = self
+            .track_compressors
+            .get_mut(track_index)
+            .ok_or_else(|| AudioError::TrackError(format!("No track {}", track_index)))?;
+        *slot = compressor;
+        Ok(())
+    }
+
+    /// Get the compressor on a track
+    pub fn track_compressor_mut(&mut self, track_index: usize) -> Option<&mut Compressor> {
+        self.track_compressors.get_mut(track_index)?.as_mut()
+    }
+
+    /// Run a track's compressor, keyed from its sidechain source
+    fn compress_track(&mut self, index: usize, input: &[&[f32]], block_size: usize) -> Result<(), AudioError> {
+        let Some(compressor) = self.track_compressors.get_mut(index).and_then(Option::as_mut) else {
+            return Ok(());
+        };
+        let key = match compressor.settings().sidechain {
+            Sidechain::Track(source) if source != index && source < self.sidechain_buffers.len() => {
+                Some(&self.sidechain_buffers[source][..block_size])
+            }
+            Sidechain::Input(channel) => input
+                .get(channel)
+                .filter(|channel| channel.len() >= block_size)
+                .map(|channel| &channel[..block_size]),
+            _ => None,
+        };
+        let buffer = &mut self.track_buffers[index][..block_size];
+        match key {
+            Some(key) => compressor.process_with_sidechain(buffer, key),
+            None => compressor.process(buffer),
+        }
+    }
+
+    /// Delay each track by the longest compressor lookahead less its own, so the tracks stay aligned
+    fn compensate_latency(&mut self, block_size: usize) {
+        let latency = |compressor: &Option<Compressor>| compressor.as_ref().map_or(0, Compressor::latency);
+        let longest = self.track_compressors.iter().map(latency).max().unwrap_or(0);
+        if longest == 0 {
+            return;
+        }
+        let tracks = self.track_buffers.iter_mut().zip(&mut self.latency_lines);
+        for ((buffer, line), compressor) in tracks.zip(&self.track_compressors) {
+            let delay = (longest - latency(compressor)) as f64;
+            for sample in &mut buffer[..block_size] {
+                line.write(*sample);
+                *sample = line.read(delay + 1.0);
+            }
+        }
+    }
+
+    /// Meter readings for a track, if it has been processed
+    pub fn track_meter(&self, track_index: usize) -> Option<&LevelMeter> {
+        self.track_meters.get(track_index)
+    }
+
+    /// Clear the clip indicators and held peaks of all meters
+    pub fn reset_meter_clips(&mut self) {
+        let meters = self.input_meter.iter_mut().chain(&mut self.track_meters).chain(&mut self.master_meter);
+        meters.for_each(LevelMeter::reset_clip);
+    }
+
+    /// Restart integrated loudness measurement on all meters
+    pub fn reset_integrated_loudness(&mut self) {
+        let meters = self.input_meter.iter_mut().chain(&mut self.track_meters).chain(&mut self.master_meter);
+        meters.for_each(LevelMeter::reset_integrated);
+    }
+
+    /// Copy meter readings and transport state into the shared telemetry
+    fn publish_telemetry(&self) {
+        self.telemetry.publish(|snapshot| {
+            snapshot.input = self.input_meter.as_ref().map(LevelMeter::reading).unwrap_or_default();
+            snapshot.master = self.master_meter.as_ref().map(LevelMeter::reading).unwrap_or_default();
+            snapshot.master_gain_reduction = if self.limiter_enabled { self.limiter.gain_reduction() } else { 0.0 };
+            snapshot.panicked = self.panicked;
+            snapshot.tracks.clear();
+            snapshot.tracks.extend(self.track_meters.iter().map(LevelMeter::reading));
+            snapshot.gain_reduction.clear();
+            snapshot.gain_reduction.extend(
+                self.track_compressors
+                    .iter()
+                    .map(|compressor| compressor.as_ref().map_or(0.0, Compressor::gain_reduction)),
+            );
+            snapshot.bpm = self.clock.bpm();
+            snapshot.beat = self.clock.beat_position();
+            snapshot.transport_running = self.transport_running;
+            snapshot.tuner_enabled = self.tuner.is_enabled();
+            snapshot.tuner = self.tuner.reading();
+        });
+    }
 }
 
 // Implement the ProcessHandler trait for AudioEngine
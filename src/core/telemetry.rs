@@ -0,0 +1,67 @@
+﻿//! Engine telemetry
+//!
+//! The audio thread publishes meter readings and transport state after every
+//! block; user interfaces read a copy at their own rate. Publishing never
+//! blocks: if a reader holds the lock, that block's update is skipped.
+
+use crate::audio::analysis::{meter::MeterReading, tuner::TunerReading};
+use parking_lot::Mutex;
+
+/// State published by the engine after each processed block.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TelemetrySnapshot {
+    /// Meter readings for the input channels, metered together
+    pub input: MeterReading,
+    /// Meter readings for each track, in track order
+    pub tracks: Vec<MeterReading>,
+    /// Compressor gain reduction for each track in dB, zero where no compressor is inserted
+    pub gain_reduction: Vec<f32>,
+    /// Meter readings for the master bus
+    pub master: MeterReading,
+    /// Master limiter gain reduction in dB
+    pub master_gain_reduction: f32,
+    /// Whether the outputs are fading or faded to silence after a panic
+    pub panicked: bool,
+    /// Current tempo in beats per minute
+    pub bpm: f32,
+    /// Current position in beats
+    pub beat: f64,
+    /// Whether the transport is running
+    pub transport_running: bool,
+    /// Whether the tuner is on
+    pub tuner_enabled: bool,
+    /// Latest tuner reading, if a pitch is heard
+    pub tuner: Option<TunerReading>,
+}
+
+/// Shared telemetry written by the audio thread and read by user interfaces.
+#[derive(Debug, Default)]
+pub struct Telemetry {
+    snapshot: Mutex<TelemetrySnapshot>,
+}
+
+impl Telemetry {
+    /// Creates empty telemetry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Gets a copy of the latest snapshot.
+    pub fn snapshot(&self) -> TelemetrySnapshot {
+        self.snapshot.lock().clone()
+    }
+
+    /// Updates the snapshot in place without blocking.
+    ///
+    /// # Returns
+    /// * `bool` - `false` if a reader held the lock and the update was skipped.
+    pub fn publish(&self, update: impl FnOnce(&mut TelemetrySnapshot)) -> bool {
+        match self.snapshot.try_lock() {
+            Some(mut snapshot) => {
+                update(&mut snapshot);
+                true
+            }
+            None => false,
+        }
+    }
+}
@@ -7,9 +7,12 @@
 //! with state management, effects processing, and synchronization.
 
 use crate::{
-    audio::effects::{EffectsProcessor, AudioEffect}, 
+    audio::analysis::harmony::{Chord, HarmonyAnalyzer, Key},
+    audio::analysis::onset::{nearest_zero_crossing, Onset, OnsetDetector, OnsetSettings},
+    audio::effects::{EffectsChain, AudioEffect}, 
     prelude::AudioError,
     sync::clock::{Quantizer, MasterClock}, // Changed to MasterClock
+    sync::quantize::GrooveSettings,
 };
 
 use std::{
@@ -19,7 +22,7 @@ use std::{
 };
 use dashmap::DashMap;
 use parking_lot::Mutex;
-use realfft::RealFftPlanner;
+use serde::{Deserialize, Serialize};
 
 /// Track state machine variants
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -38,6 +41,47 @@ pub enum TrackState {
     Muted,
 }
 
+/// How a looped track follows tempo changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TempoFollow {
+    /// Play at the recorded speed regardless of tempo
+    Off,
+    /// Change playback speed, shifting pitch with tempo
+    Repitch,
+    /// Change playback speed while keeping pitch (granular time-stretch)
+    Stretch,
+}
+
+/// Grain length used for time-stretched playback
+const STRETCH_GRAIN: usize = 2048;
+
+/// Clean-up applied to a loop when recording stops
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AutoTrim {
+    /// Drop silence before the first sound
+    pub trim_silence: bool,
+    /// Move the loop start and end to the nearest zero crossings
+    pub snap_to_zero_crossings: bool,
+    /// Level below which audio counts as silence
+    pub silence_threshold: f32,
+    /// Audio kept before the first sound when trimming
+    pub pre_roll: Duration,
+    /// How far a cut point may move to reach a zero crossing
+    pub snap_radius: Duration,
+}
+
+impl Default for AutoTrim {
+    fn default() -> Self {
+        Self {
+            trim_silence: false,
+            snap_to_zero_crossings: false,
+            silence_threshold: 0.003, // about -50 dBFS
+            pre_roll: Duration::from_millis(5),
+            snap_radius: Duration::from_millis(2),
+        }
+    }
+}
+
 /// Track audio buffer with multi-channel support
 #[derive(Clone)]
 pub struct AudioBuffer {
@@ -68,8 +112,10 @@ pub struct Track {
     state: TrackState,
     /// Audio buffers (multi-channel)
     buffer: AudioBuffer,
-    /// Effects processor
-    effects: EffectsProcessor,
+    /// Effects rendered into the loop by `apply_effects`
+    effects_chain: EffectsChain,
+    /// Effects run on the loop as it plays
+    inserts: EffectsChain,
     /// Current playhead position
     cursor_pos: usize,
     /// Loop length in samples
@@ -84,14 +130,44 @@ pub struct Track {
     quantizer: Quantizer,
     /// Sample rate
     sample_rate: u32,
+    /// Tempo following mode
+    tempo_follow: TempoFollow,
+    /// Tempo the loop was recorded at
+    recorded_bpm: Option<f32>,
+    /// Playback speed relative to the recording
+    playback_rate: f64,
+    /// Fractional playhead used when the playback rate is not 1.0
+    read_pos: f64,
+    /// Source start of the older and newer stretch grains
+    grain_starts: [f64; 2],
+    /// Position inside the current stretch hop
+    grain_phase: usize,
+    /// Transient detection run when recording stops
+    onset_detector: OnsetDetector,
+    /// Transients in the recorded loop
+    onsets: Vec<Onset>,
+    /// Trimming applied when recording stops
+    auto_trim: AutoTrim,
+    /// How far the recorded input lags the playhead, in samples
+    input_latency: usize,
+    /// Latency samples still to drop from the start of a recording
+    latency_skip: usize,
 }
 
 /// Track metadata
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TrackMetadata {
     pub id: usize,
     pub name: String,
     pub color: (u8, u8, u8),
+    #[serde(skip, default = "std::time::Instant::now")]
     pub created_at: std::time::Instant,
+    /// Estimated key of the loop
+    #[serde(default)]
+    pub key: Option<Key>,
+    /// Estimated chord in each bar of the loop
+    #[serde(default)]
+    pub chords: Vec<Option<Chord>>,
 }
 
 impl Track {
@@ -105,7 +181,8 @@ impl Track {
         Self {
             state: TrackState::Idle,
             buffer: AudioBuffer::new(sample_rate, channels),
-            effects: EffectsProcessor::new(sample_rate),
+            effects_chain: EffectsChain::new(),
+            inserts: EffectsChain::new(),
             cursor_pos: 0,
             loop_length: None,
             undo_stack: VecDeque::with_capacity(32),
@@ -115,9 +192,22 @@ impl Track {
                 name,
                 color: (255, 0, 0), // Default red
                 created_at: std::time::Instant::now(),
+                key: None,
+                chords: Vec::new(),
             },
-            quantizer: Quantizer,
+            quantizer: Quantizer::default(),
             sample_rate,
+            tempo_follow: TempoFollow::Off,
+            recorded_bpm: None,
+            playback_rate: 1.0,
+            read_pos: 0.0,
+            grain_starts: [0.0; 2],
+            grain_phase: 0,
+            onset_detector: OnsetDetector::new(sample_rate, OnsetSettings::default()),
+            onsets: Vec::new(),
+            auto_trim: AutoTrim::default(),
+            input_latency: 0,
+            latency_skip: 0,
         }
     }
 
@@ -128,6 +218,7 @@ impl Track {
                 self.save_to_history();
                 self.buffer.clear();
                 self.cursor_pos = 0;
+                self.latency_skip = self.input_latency;
                 self.state = TrackState::Recording;
                 Ok(())
             }
@@ -136,9 +227,20 @@ impl Track {
     }
 
     /// Stop recording and commit to buffer
+    ///
+    /// Transients are detected and, depending on the auto-trim settings, the
+    /// loop is trimmed and its cut points moved to zero crossings.
+    ///
+    /// Onset detection runs an STFT over the whole loop and allocates, so this
+    /// must not be called from the audio process callback.
     pub fn stop_recording(&mut self) -> Result<(), AudioError> {
         if self.state == TrackState::Recording {
             self.loop_length = Some(self.cursor_pos);
+            // Input still inside the latency when recording stopped is lost; pad to the loop length
+            for channel in self.buffer.samples.iter_mut() {
+                channel.resize(self.cursor_pos, 0.0);
+            }
+            self.finish_recording()?;
             self.state = TrackState::Playing;
             Ok(())
         } else {
@@ -146,6 +248,67 @@ impl Track {
         }
     }
 
+    /// Detect onsets, then trim and snap the new loop
+    fn finish_recording(&mut self) -> Result<(), AudioError> {
+        let len = self.loop_length.unwrap_or(0).min(self.buffer.len());
+        let samples_for = |duration: Duration| (duration.as_secs_f64() * self.sample_rate as f64) as usize;
+        let channels = self.buffer.channels as f32;
+        let mono: Vec<f32> = (0..len)
+            .map(|i| self.buffer.samples.iter().map(|c| c[i]).sum::<f32>() / channels)
+            .collect();
+
+        let mut start = 0;
+        let mut end = len;
+        if self.auto_trim.trim_silence {
+            if let Some(first) = mono.iter().position(|s| s.abs() > self.auto_trim.silence_threshold) {
+                start = first.saturating_sub(samples_for(self.auto_trim.pre_roll));
+            }
+        }
+        if self.auto_trim.snap_to_zero_crossings {
+            let radius = samples_for(self.auto_trim.snap_radius);
+            if start > 0 {
+                start = nearest_zero_crossing(&mono, start, radius);
+            }
+            let snapped_end = nearest_zero_crossing(&mono, end, radius);
+            if snapped_end > start {
+                end = snapped_end;
+            }
+        }
+
+        if start > 0 || end < self.buffer.len() {
+            for channel in self.buffer.samples.iter_mut() {
+                channel.truncate(end);
+                channel.drain(..start);
+            }
+        }
+        let len = end - start;
+        self.loop_length = Some(len);
+        self.cursor_pos = len;
+
+        self.onsets = self.onset_detector.detect(&mono[start..end])?;
+        Ok(())
+    }
+
+    /// Get the transients found in the loop when recording stopped
+    pub fn onsets(&self) -> &[Onset] {
+        &self.onsets
+    }
+
+    /// Set the trimming applied when recording stops
+    pub fn set_auto_trim(&mut self, settings: AutoTrim) {
+        self.auto_trim = settings;
+    }
+
+    /// Get the trimming applied when recording stops
+    pub fn auto_trim(&self) -> &AutoTrim {
+        &self.auto_trim
+    }
+
+    /// Set the onset detection settings
+    pub fn set_onset_settings(&mut self, settings: OnsetSettings) {
+        self.onset_detector.set_settings(settings);
+    }
+
     /// Start overdub recording
     pub fn start_overdub(&mut self) -> Result<(), AudioError> {
         match self.state {
@@ -158,65 +321,292 @@ impl Track {
         }
     }
 
+    /// Set how far the recorded input lags the playhead, in samples
+    ///
+    /// Recordings drop this many samples at their start and overdubs are
+    /// written this far behind the playhead, so input delayed by effects
+    /// lands where it was played.
+    pub fn set_input_latency(&mut self, samples: usize) {
+        self.input_latency = samples;
+    }
+
     /// Process audio input (recording/overdub)
+    ///
+    /// Overdubs are written relative to the playhead, which `process_output`
+    /// advances, so call this before `process_output` for each block.
     pub fn process_input(&mut self, input: &[f32]) {
         match self.state {
-            TrackState::Recording | TrackState::Overdubbing => {
-                if self.state == TrackState::Overdubbing {
-                    // Mix new audio with existing
+            TrackState::Recording => {
+                let skip = self.latency_skip.min(input.len());
+                self.latency_skip -= skip;
+                self.buffer.append(&input[skip..]);
+                self.cursor_pos += input.len();
+            }
+            TrackState::Overdubbing => {
+                // Mix new audio into every channel, as recording does
+                let len = self.buffer.len();
+                if len == 0 {
+                    return;
+                }
+                let start = self.cursor_pos % len + len - self.input_latency % len;
+                for channel in self.buffer.samples.iter_mut().take(self.buffer.channels) {
                     for (i, sample) in input.iter().enumerate() {
-                        let pos = (self.cursor_pos + i) % self.buffer.len();
-                        self.buffer.samples[0][pos] += sample; // Simple mono mix
+                        channel[(start + i) % len] += sample;
                     }
-                } else {
-                    self.buffer.append(input);
                 }
-                self.cursor_pos += input.len();
             }
             _ => {}
         }
     }
 
     /// Process audio output (playback)
-    pub fn process_output(&mut self, output: &mut [f32]) {
+    ///
+    /// The loop is rendered and run through the insert effects, which are
+    /// told each time the loop wraps around.
+    pub fn process_output(&mut self, output: &mut [f32]) -> Result<(), AudioError> {
         if self.state == TrackState::Playing || self.state == TrackState::Overdubbing {
             if !self.buffer.samples.is_empty() {
                 let len = self.loop_length.unwrap_or(self.buffer.len());
-                
-                for out_sample in output.iter_mut() {
-                    let sample = self.buffer.samples[0][self.cursor_pos % len];
-                    *out_sample = self.effects.process_sample(sample);
-                    
-                    self.cursor_pos += 1;
-                    if self.cursor_pos >= len {
-                        self.cursor_pos = 0;
+                if len == 0 {
+                    return Ok(());
+                }
+
+                let follows_tempo = self.tempo_follow != TempoFollow::Off
+                    && (self.playback_rate - 1.0).abs() > f64::EPSILON;
+                let mut start = 0;
+                while start < output.len() {
+                    let segment = &mut output[start..];
+                    let wrapped_after = match (follows_tempo, self.tempo_follow) {
+                        (false, _) => self.render_direct(segment, len),
+                        (true, TempoFollow::Repitch) => self.render_repitched(segment, len),
+                        (true, _) => self.render_stretched(segment, len),
+                    };
+                    let end = wrapped_after.map_or(output.len(), |rendered| start + rendered);
+                    self.inserts
+                        .process(&mut output[start..end])
+                        .map_err(|e| AudioError::EffectError(e.to_string()))?;
+                    if wrapped_after.is_some() {
                         self.quantizer.on_loop();
+                        self.inserts.on_loop_start();
                     }
+                    start = end;
+                }
+                if follows_tempo {
+                    self.cursor_pos = self.read_pos as usize;
+                } else {
+                    self.read_pos = self.cursor_pos as f64;
                 }
             }
         }
+        Ok(())
+    }
+
+    /// Set how this track follows tempo changes
+    pub fn set_tempo_follow(&mut self, mode: TempoFollow) {
+        self.tempo_follow = mode;
+        self.grain_starts = [self.read_pos; 2];
+        self.grain_phase = 0;
+    }
+
+    /// Set the tempo the loop was recorded at
+    pub fn set_recorded_tempo(&mut self, bpm: f32) {
+        self.recorded_bpm = Some(bpm);
+        self.playback_rate = 1.0;
+    }
+
+    /// Update playback speed for the current tempo
+    ///
+    /// Has no effect until a recorded tempo is known.
+    pub fn set_playback_tempo(&mut self, bpm: f32) {
+        if let Some(recorded) = self.recorded_bpm.filter(|&r| r > 0.0) {
+            self.playback_rate = bpm as f64 / recorded as f64;
+        }
+    }
+
+    /// Get the tempo the loop was recorded at
+    pub fn recorded_tempo(&self) -> Option<f32> {
+        self.recorded_bpm
+    }
+
+    /// Get the loop length in samples, once recording has stopped
+    pub fn loop_length(&self) -> Option<usize> {
+        self.loop_length
+    }
+
+    /// Get the recorded audio, one `Vec` per channel
+    pub fn samples(&self) -> &[Vec<f32>] {
+        self.buffer.get_samples()
+    }
+
+    /// Get the track metadata
+    pub fn metadata(&self) -> &TrackMetadata {
+        &self.metadata
+    }
+
+    /// Get mutable access to the track metadata
+    pub fn metadata_mut(&mut self) -> &mut TrackMetadata {
+        &mut self.metadata
+    }
+
+    /// Estimate the key and per-bar chords of the loop and store them in the metadata
+    pub fn analyze_harmony(&mut self, bar_length: usize) -> Result<(), AudioError> {
+        let len = self.loop_length.unwrap_or(0).min(self.buffer.len());
+        let channels: Vec<Vec<f32>> = self.buffer.samples.iter().map(|c| c[..len].to_vec()).collect();
+        let analysis = HarmonyAnalyzer::new(self.sample_rate).analyze(&channels, bar_length)?;
+        self.metadata.key = analysis.key;
+        self.metadata.chords = analysis.chords;
+        Ok(())
+    }
+
+    /// Plain playback at the recorded tempo
+    ///
+    /// Rendering stops after the sample that wraps the loop; returns how
+    /// many samples were rendered if that happened.
+    fn render_direct(&mut self, output: &mut [f32], len: usize) -> Option<usize> {
+        // Recording leaves the cursor at the loop end, where playback starts over
+        self.cursor_pos %= len;
+        for (i, out_sample) in output.iter_mut().enumerate() {
+            *out_sample = self.buffer.samples[0][self.cursor_pos];
+
+            self.cursor_pos += 1;
+            if self.cursor_pos >= len {
+                self.cursor_pos = 0;
+                return Some(i + 1);
+            }
+        }
+        None
+    }
+
+    /// Varispeed playback: speed and pitch follow the tempo
+    ///
+    /// Stops at the loop wrap like `render_direct`.
+    fn render_repitched(&mut self, output: &mut [f32], len: usize) -> Option<usize> {
+        for (i, out_sample) in output.iter_mut().enumerate() {
+            *out_sample = read_interpolated(&self.buffer.samples[0][..len], self.read_pos);
+
+            self.read_pos += self.playback_rate;
+            if self.read_pos >= len as f64 {
+                self.read_pos -= len as f64;
+                return Some(i + 1);
+            }
+        }
+        None
+    }
+
+    /// Granular playback: grains play at the original speed while their
+    /// start points advance at the playback rate, keeping pitch constant
+    ///
+    /// Stops at the loop wrap like `render_direct`.
+    fn render_stretched(&mut self, output: &mut [f32], len: usize) -> Option<usize> {
+        let hop = STRETCH_GRAIN / 2;
+        let channel = &self.buffer.samples[0][..len];
+
+        for (i, out_sample) in output.iter_mut().enumerate() {
+            let older = self.grain_phase + hop;
+            let newer = self.grain_phase;
+            let sample = read_interpolated(channel, self.grain_starts[0] + older as f64)
+                * stretch_window(older)
+                + read_interpolated(channel, self.grain_starts[1] + newer as f64)
+                    * stretch_window(newer);
+            *out_sample = sample;
+
+            let mut wrapped = false;
+            self.read_pos += self.playback_rate;
+            if self.read_pos >= len as f64 {
+                self.read_pos -= len as f64;
+                wrapped = true;
+            }
+
+            self.grain_phase += 1;
+            if self.grain_phase >= hop {
+                self.grain_phase = 0;
+                self.grain_starts = [self.grain_starts[1], self.read_pos];
+            }
+            if wrapped {
+                return Some(i + 1);
+            }
+        }
+        None
+    }
+
+    /// Get the effects chain rendered by `apply_effects`
+    pub fn effects_chain(&self) -> &EffectsChain {
+        &self.effects_chain
+    }
+
+    /// Get mutable access to the effects chain rendered by `apply_effects`
+    pub fn effects_chain_mut(&mut self) -> &mut EffectsChain {
+        &mut self.effects_chain
+    }
+
+    /// Get the insert effects run on the loop as it plays
+    pub fn inserts(&self) -> &EffectsChain {
+        &self.inserts
+    }
+
+    /// Get mutable access to the insert effects run on the loop as it plays
+    pub fn inserts_mut(&mut self) -> &mut EffectsChain {
+        &mut self.inserts
     }
 
     /// Apply effects chain to entire buffer
+    ///
+    /// Every channel of the loop is rendered through the chain as if it were
+    /// playing in a cycle: the chain is pre-rolled with a full pass of the
+    /// loop, so tails from its end wrap round into its start, and its latency
+    /// is compensated, so the result stays seamless and in time. Tails longer
+    /// than the loop only carry over from that one pass. Effects are told
+    /// where each pass starts, so modulation lines up with the loop, and are
+    /// reset before each channel so no state carries over from the previous
+    /// one. The previous buffer is kept in history.
     pub fn apply_effects(&mut self) -> Result<(), AudioError> {
+        let len = self.loop_length.unwrap_or(self.buffer.len()).min(self.buffer.len());
+        if len == 0 {
+            return Ok(());
+        }
         self.save_to_history();
-        self.effects.process_buffer(&mut self.buffer.samples[0])
-            .map_err(|e| AudioError::EffectError(e.to_string()))
+        let latency = self.effects_chain.latency();
+        let start = len - latency % len;
+        for channel in self.buffer.samples.iter_mut() {
+            self.effects_chain.reset();
+            let mut rendered: Vec<f32> = (0..2 * (len + latency)).map(|i| channel[(start + i) % len]).collect();
+            let (lead_in, passes) = rendered.split_at_mut(latency);
+            let (pre_roll, looped) = passes.split_at_mut(len);
+            self.effects_chain
+                .process(lead_in)
+                .map_err(|e| AudioError::EffectError(e.to_string()))?;
+            for pass in [pre_roll, looped] {
+                self.effects_chain.on_loop_start();
+                self.effects_chain
+                    .process(pass)
+                    .map_err(|e| AudioError::EffectError(e.to_string()))?;
+            }
+            channel[..len].copy_from_slice(&rendered[len + 2 * latency..]);
+        }
+        Ok(())
     }
 
     /// Quantize buffer to nearest beat
+    ///
+    /// Transients in the recorded loop are time-warped onto the groove grid.
+    /// The previous buffer is kept in history so the operation can be undone.
     pub fn quantize(&mut self, clock: &MasterClock) -> Result<(), AudioError> {
-        self.save_to_history();
         let beat_length = clock.samples_per_beat();
-        let mut buffer_adapter = crate::core::buffer::AudioBuffer::new(
+        let mut buffer_adapter = crate::core::buffer::AudioBuffer::from_data(
+            self.buffer.get_samples().clone(),
             self.buffer.sample_rate,
-            self.buffer.channels,
-        );
+        )?;
         self.quantizer.quantize(&mut buffer_adapter, beat_length)?;
+        self.save_to_history();
         self.buffer.set_samples(buffer_adapter.samples().to_vec());
         Ok(())
     }
 
+    /// Set groove settings used by `quantize`
+    pub fn set_groove(&mut self, settings: GrooveSettings) {
+        self.quantizer.set_settings(settings);
+    }
+
     /// Undo last operation
     pub fn undo(&mut self) -> Result<(), AudioError> {
         if let Some(history) = self.undo_stack.pop_back() {
@@ -262,6 +652,23 @@ impl Track {
     // ... additional methods for state/parameter access ...
 }
 
+/// Read a looped channel at a fractional position with linear interpolation
+fn read_interpolated(channel: &[f32], position: f64) -> f32 {
+    let len = channel.len();
+    let wrapped = position.rem_euclid(len as f64);
+    let index = wrapped as usize % len;
+    let frac = (wrapped - wrapped.floor()) as f32;
+    let a = channel[index];
+    let b = channel[(index + 1) % len];
+    a + (b - a) * frac
+}
+
+/// Squared-sine grain window; two grains offset by half a grain sum to unity
+fn stretch_window(offset: usize) -> f32 {
+    let x = std::f32::consts::PI * offset as f32 / STRETCH_GRAIN as f32;
+    x.sin() * x.sin()
+}
+
 impl AudioBuffer {
     /// Create new empty buffer
     pub fn new(sample_rate: u32, channels: usize) -> Self {
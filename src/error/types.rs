@@ -39,6 +39,21 @@ pub enum AudioError {
 
     #[error("No state to redo")]
     NothingToRedo,
+
+    #[error("Synchronization error: {0}")]
+    SyncError(String),
+
+    #[error("MIDI error: {0}")]
+    MidiError(String),
+
+    #[error("Project error: {0}")]
+    ProjectError(String),
+
+    #[error("File error: {0}")]
+    FileError(String),
+
+    #[error("Remote control error: {0}")]
+    RemoteError(String),
     
 }
 
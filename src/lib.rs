@@ -11,6 +11,8 @@ pub mod core {
     pub mod engine;
     pub mod track;
     pub mod buffer;
+    pub mod telemetry;
+    pub mod control;
 }
 
 pub mod audio {
@@ -28,6 +30,25 @@ pub mod sync {
     //! Synchronization and timing
     pub mod clock;
     pub mod quantize;
+    pub mod tempo_map;
+    pub mod tap_tempo;
+    pub mod auto_tempo;
+    #[cfg(feature = "link")]
+    pub mod link;
+}
+
+#[cfg(feature = "midi")]
+pub mod midi {
+    //! MIDI control and clock synchronization
+    pub mod handler;
+    pub mod mapping;
+    pub mod clock;
+}
+
+pub mod ui {
+    //! User interfaces and remote control
+    pub mod tui;
+    pub mod remote;
 }
 
 pub mod error {
@@ -0,0 +1,655 @@
+﻿//! MIDI clock output and external clock sync
+//!
+//! `ClockGenerator` turns `MasterClock` positions into 24-ppqn timing clock,
+//! Start/Stop/Continue and Song Position Pointer messages with sample offsets
+//! inside the current audio block. `ClockFollower` does the reverse: it locks
+//! the master clock to incoming MIDI clock, smoothing pulse jitter before the
+//! tempo is applied. The midir-backed `MidiClockOutput` and `MidiClockInput`
+//! connect both to real or virtual MIDI ports.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use parking_lot::Mutex;
+use crate::{
+    error::types::AudioError,
+    sync::clock::MasterClock,
+};
+
+/// MIDI clock resolution, in pulses per quarter note.
+pub const PULSES_PER_QUARTER: u32 = 24;
+
+/// Number of scheduled messages the output thread can hold.
+const OUTPUT_QUEUE: usize = 1024;
+
+/// Largest drift between the audio and MIDI clocks the follower tracks, as a fraction of the sample rate.
+const MAX_CLOCK_DRIFT: f64 = 1e-4;
+
+/// MIDI real-time and song position messages used for clock sync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockMessage {
+    /// Timing clock pulse (0xF8)
+    TimingClock,
+    /// Start from the beginning of the song (0xFA)
+    Start,
+    /// Continue from the current song position (0xFB)
+    Continue,
+    /// Stop (0xFC)
+    Stop,
+    /// Song position in sixteenth notes (0xF2)
+    SongPosition(u16),
+}
+
+impl ClockMessage {
+    /// Encodes the message as raw MIDI bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            ClockMessage::TimingClock => vec![0xF8],
+            ClockMessage::Start => vec![0xFA],
+            ClockMessage::Continue => vec![0xFB],
+            ClockMessage::Stop => vec![0xFC],
+            ClockMessage::SongPosition(position) => {
+                vec![0xF2, (position & 0x7F) as u8, ((position >> 7) & 0x7F) as u8]
+            }
+        }
+    }
+
+    /// Decodes raw MIDI bytes, ignoring anything that is not a clock message.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [0xF8, ..] => Some(ClockMessage::TimingClock),
+            [0xFA, ..] => Some(ClockMessage::Start),
+            [0xFB, ..] => Some(ClockMessage::Continue),
+            [0xFC, ..] => Some(ClockMessage::Stop),
+            [0xF2, lsb, msb, ..] => Some(ClockMessage::SongPosition(
+                (*lsb as u16 & 0x7F) | ((*msb as u16 & 0x7F) << 7),
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// Number of clock pulses per beat of the clock's current meter.
+fn pulses_per_beat(clock: &MasterClock) -> f64 {
+    PULSES_PER_QUARTER as f64 * 4.0 / clock.time_signature().denominator as f64
+}
+
+/// Converts a beat position into a Song Position Pointer value.
+fn song_position(clock: &MasterClock, beats: f64) -> u16 {
+    let quarters = beats * 4.0 / clock.time_signature().denominator as f64;
+    (quarters * 4.0).floor().clamp(0.0, 0x3FFF as f64) as u16
+}
+
+/// Generates MIDI clock from the master clock.
+#[derive(Debug, Clone, Default)]
+pub struct ClockGenerator {
+    running: bool,
+    next_pulse: Option<u64>,
+    transport: Vec<ClockMessage>,
+}
+
+impl ClockGenerator {
+    /// Creates a stopped clock generator.
+    pub fn new() -> Self {
+        Self {
+            // Room for Song Position and Continue, so queueing never allocates on the audio thread
+            transport: Vec::with_capacity(4),
+            ..Self::default()
+        }
+    }
+
+    /// Checks whether timing pulses are being generated.
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Queues a Start message. Followers restart from the top of the song,
+    /// so the master clock should be at position zero.
+    pub fn start(&mut self) {
+        self.running = true;
+        self.next_pulse = None;
+        self.transport.push(ClockMessage::Start);
+    }
+
+    /// Queues a Stop message and stops generating pulses.
+    pub fn stop(&mut self) {
+        self.running = false;
+        self.transport.push(ClockMessage::Stop);
+    }
+
+    /// Queues the current song position followed by Continue.
+    ///
+    /// # Arguments
+    /// * `clock` - The master clock providing the position.
+    pub fn resume(&mut self, clock: &MasterClock) {
+        self.locate(clock);
+        self.running = true;
+        self.next_pulse = None;
+        self.transport.push(ClockMessage::Continue);
+    }
+
+    /// Queues a Song Position Pointer for the clock's current position.
+    ///
+    /// # Arguments
+    /// * `clock` - The master clock providing the position.
+    pub fn locate(&mut self, clock: &MasterClock) {
+        let position = song_position(clock, clock.beat_position());
+        self.transport.push(ClockMessage::SongPosition(position));
+        self.next_pulse = None;
+    }
+
+    /// Emits the messages falling inside the next audio block.
+    ///
+    /// Must be called before the clock is advanced for the block. Each event
+    /// carries its sample offset from the start of the block.
+    ///
+    /// # Arguments
+    /// * `clock` - The master clock driving the output.
+    /// * `block_size` - The number of samples in the block.
+    /// * `events` - Output list; events are appended in time order.
+    pub fn process(
+        &mut self,
+        clock: &MasterClock,
+        block_size: usize,
+        events: &mut Vec<(usize, ClockMessage)>,
+    ) {
+        events.extend(self.transport.drain(..).map(|message| (0, message)));
+        if !self.running {
+            return;
+        }
+
+        let block_start = clock.sample_position();
+        let block_end = block_start + block_size as u64;
+        let pulses_per_beat = pulses_per_beat(clock);
+        let current_pulse = (clock.beat_position() * pulses_per_beat).ceil() as u64;
+
+        // Resynchronise after seeks or when starting
+        let mut pulse = match self.next_pulse {
+            Some(next) if next.abs_diff(current_pulse) <= 1 => next,
+            _ => current_pulse,
+        };
+
+        loop {
+            let sample = clock.sample_at_beat(pulse as f64 / pulses_per_beat);
+            if sample >= block_end {
+                break;
+            }
+            let offset = sample.saturating_sub(block_start) as usize;
+            events.push((offset, ClockMessage::TimingClock));
+            pulse += 1;
+        }
+        self.next_pulse = Some(pulse);
+    }
+}
+
+/// Settings for following an external MIDI clock.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FollowerSettings {
+    /// Number of pulses the interval smoothing averages over
+    pub smoothing_pulses: f64,
+    /// Minimum tempo difference, in BPM, before the clock is updated
+    pub tempo_deadband: f64,
+    /// Phase error, in pulses, at the arrival of a pulse above which the clock position is corrected
+    pub phase_tolerance: f64,
+}
+
+impl Default for FollowerSettings {
+    fn default() -> Self {
+        Self {
+            smoothing_pulses: 24.0,
+            tempo_deadband: 0.05,
+            phase_tolerance: 1.0,
+        }
+    }
+}
+
+/// Locks the master clock to an incoming MIDI clock.
+#[derive(Debug, Clone, Default)]
+pub struct ClockFollower {
+    settings: FollowerSettings,
+    running: bool,
+    last_pulse: Option<f64>,
+    interval: Option<f64>,
+    pulses_since_locate: u64,
+    locate_beat: f64,
+    /// How far the clock's sample position runs ahead of `timestamp * sample_rate`
+    sample_offset: Option<f64>,
+}
+
+impl ClockFollower {
+    /// Creates a follower with the given settings.
+    pub fn new(settings: FollowerSettings) -> Self {
+        Self {
+            settings,
+            ..Default::default()
+        }
+    }
+
+    /// Checks whether the external transport is running.
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Gets the smoothed external tempo in quarter notes per minute.
+    pub fn tempo(&self) -> Option<f64> {
+        self.interval
+            .map(|interval| 60.0 / (interval * PULSES_PER_QUARTER as f64))
+    }
+
+    /// Handles an incoming clock message.
+    ///
+    /// # Arguments
+    /// * `message` - The received message.
+    /// * `timestamp` - Arrival time of the message, in seconds.
+    /// * `clock` - The master clock to lock to the external clock.
+    pub fn handle(&mut self, message: ClockMessage, timestamp: f64, clock: &MasterClock) {
+        match message {
+            ClockMessage::TimingClock => self.handle_pulse(timestamp, clock),
+            ClockMessage::Start => {
+                self.running = true;
+                self.locate(0.0, clock);
+            }
+            ClockMessage::Continue => {
+                self.running = true;
+                self.last_pulse = None;
+                self.sample_offset = None;
+            }
+            ClockMessage::Stop => {
+                self.running = false;
+            }
+            ClockMessage::SongPosition(sixteenths) => {
+                let quarters = sixteenths as f64 / 4.0;
+                let beats = quarters * clock.time_signature().denominator as f64 / 4.0;
+                self.locate(beats, clock);
+            }
+        }
+    }
+
+    fn locate(&mut self, beats: f64, clock: &MasterClock) {
+        self.locate_beat = beats;
+        self.pulses_since_locate = 0;
+        self.last_pulse = None;
+        self.sample_offset = None;
+        clock.seek_beats(beats);
+    }
+
+    /// Estimates the clock's beat position at the arrival time of a message.
+    ///
+    /// The clock only advances once per audio block, so its position lags
+    /// by anything up to a block. The lag is smallest just after an advance,
+    /// so the largest offset seen between the clock's sample position and
+    /// the message timestamps is kept, decaying slowly to follow drift
+    /// between the audio and MIDI clocks.
+    fn beat_at(&mut self, timestamp: f64, clock: &MasterClock) -> f64 {
+        let sample_rate = clock.sample_rate() as f64;
+        let observed = clock.sample_position() as f64 - timestamp * sample_rate;
+        let offset = match (self.sample_offset, self.last_pulse) {
+            (Some(offset), Some(last)) => {
+                let drift = (timestamp - last).max(0.0) * sample_rate * MAX_CLOCK_DRIFT;
+                observed.max(offset - drift)
+            }
+            _ => observed,
+        };
+        self.sample_offset = Some(offset);
+        clock.beat_at_sample((timestamp * sample_rate + offset).round().max(0.0) as u64)
+    }
+
+    fn handle_pulse(&mut self, timestamp: f64, clock: &MasterClock) {
+        let position = self.running.then(|| self.beat_at(timestamp, clock));
+        if let Some(last) = self.last_pulse {
+            let measured = timestamp - last;
+            if measured > 0.0 {
+                self.interval = Some(match self.interval {
+                    // Pulses far off the running average are dropouts or doubled sends
+                    Some(interval) if measured > interval * 2.0 || measured < interval * 0.5 => interval,
+                    Some(interval) => interval + (measured - interval) / self.settings.smoothing_pulses.max(1.0),
+                    None => measured,
+                });
+            }
+        }
+        self.last_pulse = Some(timestamp);
+
+        if let Some(quarters_per_minute) = self.tempo() {
+            let bpm = quarters_per_minute * clock.time_signature().denominator as f64 / 4.0;
+            if (bpm - clock.bpm() as f64).abs() > self.settings.tempo_deadband {
+                clock.set_bpm_exact(bpm);
+            }
+        }
+
+        if let Some(position) = position {
+            // The first pulse after Start or a locate falls on the located position
+            let pulses_per_beat = pulses_per_beat(clock);
+            let expected = self.locate_beat + self.pulses_since_locate as f64 / pulses_per_beat;
+            self.pulses_since_locate += 1;
+            let error = (position - expected) * pulses_per_beat;
+            if error.abs() > self.settings.phase_tolerance {
+                // Seeking moves the clock's current position, which is `position` less the lag
+                clock.seek_beats(clock.beat_position() + expected - position);
+            }
+        }
+    }
+}
+
+/// Sends scheduled clock messages to a MIDI output port.
+///
+/// Messages are handed to a worker thread with their due time, so the audio
+/// thread never blocks on the MIDI driver.
+pub struct MidiClockOutput {
+    events: Option<Sender<(Instant, ClockMessage)>>,
+    /// Messages dropped by `schedule` since the worker last reported them
+    dropped: Arc<AtomicUsize>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl MidiClockOutput {
+    /// Connects to an existing output port.
+    ///
+    /// # Arguments
+    /// * `port_index` - Index into the list of available output ports.
+    pub fn connect(port_index: usize) -> Result<Self, AudioError> {
+        let output = MidiOutput::new("loop_station_clock")
+            .map_err(|e| AudioError::MidiError(e.to_string()))?;
+        let ports = output.ports();
+        let port = ports
+            .get(port_index)
+            .ok_or_else(|| AudioError::MidiError(format!("No MIDI output port {}", port_index)))?;
+        let connection = output
+            .connect(port, "clock_out")
+            .map_err(|e| AudioError::MidiError(e.to_string()))?;
+        Ok(Self::spawn(connection))
+    }
+
+    /// Creates a virtual output port other applications can connect to.
+    #[cfg(unix)]
+    pub fn create_virtual(port_name: &str) -> Result<Self, AudioError> {
+        use midir::os::unix::VirtualOutput;
+
+        let output = MidiOutput::new("loop_station_clock")
+            .map_err(|e| AudioError::MidiError(e.to_string()))?;
+        let connection = output
+            .create_virtual(port_name)
+            .map_err(|e| AudioError::MidiError(e.to_string()))?;
+        Ok(Self::spawn(connection))
+    }
+
+    fn spawn(connection: MidiOutputConnection) -> Self {
+        let (sender, receiver) = crossbeam_channel::bounded(OUTPUT_QUEUE);
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let worker_dropped = dropped.clone();
+        let worker = std::thread::spawn(move || send_scheduled(connection, receiver, worker_dropped));
+        Self {
+            events: Some(sender),
+            dropped,
+            worker: Some(worker),
+        }
+    }
+
+    /// Schedules the events produced by `ClockGenerator::process` for one block.
+    ///
+    /// Never blocks or logs; events are dropped if the worker has fallen
+    /// behind, and the worker reports how many once it catches up.
+    ///
+    /// # Arguments
+    /// * `events` - Events with sample offsets relative to the block start.
+    /// * `sample_rate` - The engine sample rate.
+    pub fn schedule(&self, events: &[(usize, ClockMessage)], sample_rate: u32) {
+        let Some(sender) = &self.events else {
+            return;
+        };
+        let block_start = Instant::now();
+        for &(offset, message) in events {
+            let due = block_start + Duration::from_secs_f64(offset as f64 / sample_rate as f64);
+            if let Err(TrySendError::Full(_)) = sender.try_send((due, message)) {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl Drop for MidiClockOutput {
+    fn drop(&mut self) {
+        // Closing the channel ends the worker loop
+        self.events.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Worker loop sending each message at its due time.
+fn send_scheduled(
+    mut connection: MidiOutputConnection,
+    events: Receiver<(Instant, ClockMessage)>,
+    dropped: Arc<AtomicUsize>,
+) {
+    for (due, message) in events {
+        let count = dropped.swap(0, Ordering::Relaxed);
+        if count > 0 {
+            tracing::warn!("MIDI clock output queue full, dropped {} messages", count);
+        }
+        let now = Instant::now();
+        if due > now {
+            std::thread::sleep(due - now);
+        }
+        if let Err(e) = connection.send(&message.to_bytes()) {
+            tracing::error!("MIDI clock send failed: {}", e);
+        }
+    }
+    connection.close();
+}
+
+/// Receives MIDI clock from an input port and drives the master clock.
+pub struct MidiClockInput {
+    _connection: MidiInputConnection<()>,
+    follower: Arc<Mutex<ClockFollower>>,
+    running: Arc<AtomicBool>,
+}
+
+impl MidiClockInput {
+    /// Connects to an existing input port.
+    ///
+    /// # Arguments
+    /// * `port_index` - Index into the list of available input ports.
+    /// * `clock` - The master clock to slave to the incoming clock.
+    /// * `settings` - Jitter smoothing and phase correction settings.
+    pub fn connect(
+        port_index: usize,
+        clock: Arc<MasterClock>,
+        settings: FollowerSettings,
+    ) -> Result<Self, AudioError> {
+        let input = Self::midi_input()?;
+        let ports = input.ports();
+        let port = ports
+            .get(port_index)
+            .ok_or_else(|| AudioError::MidiError(format!("No MIDI input port {}", port_index)))?;
+
+        let follower = Arc::new(Mutex::new(ClockFollower::new(settings)));
+        let running = Arc::new(AtomicBool::new(false));
+        let callback = Self::callback(follower.clone(), running.clone(), clock);
+        let connection = input
+            .connect(port, "clock_in", callback, ())
+            .map_err(|e| AudioError::MidiError(e.to_string()))?;
+        Ok(Self {
+            _connection: connection,
+            follower,
+            running,
+        })
+    }
+
+    /// Creates a virtual input port other applications can send clock to.
+    #[cfg(unix)]
+    pub fn create_virtual(
+        port_name: &str,
+        clock: Arc<MasterClock>,
+        settings: FollowerSettings,
+    ) -> Result<Self, AudioError> {
+        use midir::os::unix::VirtualInput;
+
+        let input = Self::midi_input()?;
+        let follower = Arc::new(Mutex::new(ClockFollower::new(settings)));
+        let running = Arc::new(AtomicBool::new(false));
+        let callback = Self::callback(follower.clone(), running.clone(), clock);
+        let connection = input
+            .create_virtual(port_name, callback, ())
+            .map_err(|e| AudioError::MidiError(e.to_string()))?;
+        Ok(Self {
+            _connection: connection,
+            follower,
+            running,
+        })
+    }
+
+    /// Checks whether the external transport is running.
+    pub fn is_running(&self) -> bool {
+        self.follower.lock().is_running()
+    }
+
+    /// Gets the smoothed external tempo in quarter notes per minute.
+    pub fn tempo(&self) -> Option<f64> {
+        self.follower.lock().tempo()
+    }
+
+    /// Gets the external Start/Stop state, readable from the audio thread without locking.
+    ///
+    /// Set it as `AudioEngine::midi_transport` to start and stop the engine
+    /// transport with the external clock.
+    pub fn transport_state(&self) -> Arc<AtomicBool> {
+        self.running.clone()
+    }
+
+    fn midi_input() -> Result<MidiInput, AudioError> {
+        let mut input = MidiInput::new("loop_station_clock")
+            .map_err(|e| AudioError::MidiError(e.to_string()))?;
+        // Real-time messages are filtered out by default
+        input.ignore(midir::Ignore::SysexAndActiveSense);
+        Ok(input)
+    }
+
+    fn callback(
+        follower: Arc<Mutex<ClockFollower>>,
+        running: Arc<AtomicBool>,
+        clock: Arc<MasterClock>,
+    ) -> impl FnMut(u64, &[u8], &mut ()) + Send + 'static {
+        move |timestamp_us, bytes, _| {
+            if let Some(message) = ClockMessage::parse(bytes) {
+                let mut follower = follower.lock();
+                follower.handle(message, timestamp_us as f64 / 1_000_000.0, &clock);
+                running.store(follower.is_running(), Ordering::Release);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_song_position_round_trip() {
+        let bytes = ClockMessage::SongPosition(1000).to_bytes();
+        assert_eq!(ClockMessage::parse(&bytes), Some(ClockMessage::SongPosition(1000)));
+    }
+
+    #[test]
+    fn test_generator_emits_24_pulses_per_beat() {
+        let clock = MasterClock::new(48000, 120.0);
+        let mut generator = ClockGenerator::new();
+        generator.start();
+
+        let mut events = Vec::new();
+        let mut pulses = 0;
+        for _ in 0..(48000 / 256) {
+            events.clear();
+            generator.process(&clock, 256, &mut events);
+            pulses += events.iter().filter(|(_, m)| *m == ClockMessage::TimingClock).count();
+            clock.advance(256);
+        }
+        // 187 blocks of 256 samples = 1.994 beats at 120 BPM, pulses at 0, 1000, ...
+        assert_eq!(pulses, 48);
+    }
+
+    #[test]
+    fn test_input_publishes_transport_state() {
+        let clock = Arc::new(MasterClock::new(48000, 120.0));
+        let follower = Arc::new(Mutex::new(ClockFollower::default()));
+        let running = Arc::new(AtomicBool::new(false));
+        let mut callback = MidiClockInput::callback(follower, running.clone(), clock);
+
+        callback(0, &[0xFA], &mut ());
+        assert!(running.load(Ordering::Acquire));
+        callback(500_000, &[0xFC], &mut ());
+        assert!(!running.load(Ordering::Acquire));
+        callback(1_000_000, &[0xFB], &mut ());
+        assert!(running.load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn test_follower_locks_tempo() {
+        let clock = MasterClock::new(48000, 120.0);
+        let mut follower = ClockFollower::default();
+        follower.handle(ClockMessage::Start, 0.0, &clock);
+
+        // 100 BPM with +-0.5 ms of jitter
+        let interval = 60.0 / (100.0 * 24.0);
+        for pulse in 0..240 {
+            let jitter = if pulse % 2 == 0 { 0.0005 } else { -0.0005 };
+            follower.handle(ClockMessage::TimingClock, pulse as f64 * interval + jitter, &clock);
+        }
+        assert!((follower.tempo().unwrap() - 100.0).abs() < 0.5);
+        assert!((clock.bpm() - 100.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn test_follower_first_pulse_after_start_is_the_downbeat() {
+        let clock = MasterClock::new(48000, 120.0);
+        let mut follower = ClockFollower::new(FollowerSettings {
+            phase_tolerance: 0.5,
+            ..FollowerSettings::default()
+        });
+        follower.handle(ClockMessage::Start, 0.0, &clock);
+
+        // 120 BPM: one pulse every 1000 samples
+        let interval = 60.0 / (120.0 * 24.0);
+        for pulse in 0..48 {
+            follower.handle(ClockMessage::TimingClock, pulse as f64 * interval, &clock);
+            assert!((clock.beat_position() - pulse as f64 / 24.0).abs() < 1e-9);
+            clock.advance(1000);
+        }
+    }
+
+    #[test]
+    fn test_follower_does_not_seek_on_block_granularity() {
+        let sample_rate = 48000;
+        let clock = MasterClock::new(sample_rate, 120.0);
+        let mut follower = ClockFollower::default();
+        follower.handle(ClockMessage::Start, 0.0, &clock);
+
+        // 120 BPM pulses every 1000 samples with up to 0.5 ms of jitter,
+        // while the engine advances the clock in blocks of 1024 samples
+        let interval = 60.0 / (120.0 * 24.0);
+        let block = 1024;
+        let mut state = 0x9e37_79b9u32;
+        for pulse in 0..2400 {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            let jitter = 0.0005 * (state as f64 / u32::MAX as f64 * 2.0 - 1.0);
+            let timestamp = 0.001 + pulse as f64 * interval + jitter;
+            while ((clock.sample_position() + block) as f64) <= timestamp * sample_rate as f64 {
+                clock.advance(block as usize);
+            }
+            let before = clock.beat_position();
+            follower.handle(ClockMessage::TimingClock, timestamp, &clock);
+            assert!((clock.beat_position() - before).abs() < 1e-9, "seeked at pulse {}", pulse);
+        }
+        let expected = clock.sample_position() as f64 / sample_rate as f64 * 2.0;
+        assert!((clock.beat_position() - expected).abs() < 1.0 / 24.0);
+    }
+}
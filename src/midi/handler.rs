@@ -1,31 +1,57 @@
 ﻿//! MIDI event handler
 
+use crate::{
+    core::control::ControlQueue,
+    error::types::AudioError,
+    midi::mapping::MidiMapping,
+};
+use std::sync::Arc;
+
 // src/midi/handler.rs
 pub struct MidiHandler {
-    input: midir::MidiInput,
     connections: Vec<midir::MidiInputConnection<()>>,
 }
 
 impl MidiHandler {
-    pub fn new() -> Result<Self> {
-        let input = midir::MidiInput::new("loop_station_midi")?;
+    pub fn new() -> Result<Self, AudioError> {
         Ok(Self {
-            input,
             connections: Vec::new(),
         })
     }
 
-    pub fn connect<F>(&mut self, port_index: usize, callback: F) -> Result<()>
+    pub fn connect<F>(&mut self, port_index: usize, callback: F) -> Result<(), AudioError>
     where
         F: FnMut(u64, &[u8], &mut ()) + Send + 'static
     {
-        let conn = self.input.connect(
-            port_index,
+        let input = midir::MidiInput::new("loop_station_midi")
+            .map_err(|e| AudioError::MidiError(e.to_string()))?;
+        let ports = input.ports();
+        let port = ports
+            .get(port_index)
+            .ok_or_else(|| AudioError::MidiError(format!("No MIDI input port {}", port_index)))?;
+        let conn = input.connect(
+            port,
             "midi_in",
             callback,
             (),
-        )?;
+        )
+        .map_err(|e| AudioError::MidiError(e.to_string()))?;
         self.connections.push(conn);
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Connect an input port whose messages fire the actions bound in `mapping`
+    ///
+    /// Presses and releases of bound controls are queued for the engine,
+    /// which applies them at the start of its next block.
+    pub fn connect_controls(
+        &mut self,
+        port_index: usize,
+        mut mapping: MidiMapping,
+        controls: Arc<ControlQueue>,
+    ) -> Result<(), AudioError> {
+        self.connect(port_index, move |_, message, _| {
+            mapping.dispatch(message, &controls);
+        })
+    }
+}
@@ -1 +1,118 @@
 ﻿//! MIDI control mapping
+
+use crate::core::{control::ControlQueue, engine::ControlAction};
+
+/// MIDI message that can be bound to a control action
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MidiTrigger {
+    /// Note-on with non-zero velocity; released by the matching note-off
+    Note {
+        /// MIDI channel, 0 to 15
+        channel: u8,
+        /// Note number
+        note: u8,
+    },
+    /// Control change crossing up to 64 or above; released on crossing back below 64
+    ControlChange {
+        /// MIDI channel, 0 to 15
+        channel: u8,
+        /// Controller number
+        controller: u8,
+    },
+}
+
+/// A trigger bound to an action, with whether the trigger is held
+#[derive(Debug, Clone, Copy)]
+struct Binding {
+    trigger: MidiTrigger,
+    action: ControlAction,
+    held: bool,
+}
+
+/// Table of MIDI triggers and the actions they fire
+#[derive(Debug, Clone, Default)]
+pub struct MidiMapping {
+    bindings: Vec<Binding>,
+}
+
+impl MidiMapping {
+    /// Create an empty mapping
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bind a trigger to an action, replacing any existing binding for the trigger
+    pub fn bind(&mut self, trigger: MidiTrigger, action: ControlAction) {
+        self.unbind(trigger);
+        self.bindings.push(Binding { trigger, action, held: false });
+    }
+
+    /// Remove the binding for a trigger
+    pub fn unbind(&mut self, trigger: MidiTrigger) {
+        self.bindings.retain(|binding| binding.trigger != trigger);
+    }
+
+    /// Look up the action a raw MIDI message presses, ignoring whether its trigger is already held
+    pub fn action_for(&self, message: &[u8]) -> Option<ControlAction> {
+        match parse_trigger(message)? {
+            (trigger, true) => self.bound_action(trigger),
+            (_, false) => None,
+        }
+    }
+
+    /// Look up the momentary action a raw MIDI message releases, ignoring whether its trigger is held
+    pub fn release_for(&self, message: &[u8]) -> Option<ControlAction> {
+        match parse_trigger(message)? {
+            (trigger, false) => self.bound_action(trigger).filter(ControlAction::is_momentary),
+            (_, true) => None,
+        }
+    }
+
+    /// Queue the press or release carried by a raw MIDI message for the engine
+    ///
+    /// Every note-on presses. A controller presses only when its value
+    /// crosses up to 64 or above, so sweeping a pedal or knob fires once.
+    /// Note-offs and controllers crossing back below 64 release momentary
+    /// actions and are ignored for the rest.
+    ///
+    /// # Returns
+    /// * `bool` - `true` if the message was bound to an action and queued.
+    pub fn dispatch(&mut self, message: &[u8], controls: &ControlQueue) -> bool {
+        let Some((trigger, pressed)) = parse_trigger(message) else {
+            return false;
+        };
+        let Some(binding) = self.bindings.iter_mut().find(|binding| binding.trigger == trigger) else {
+            return false;
+        };
+        let was_held = std::mem::replace(&mut binding.held, pressed);
+        let is_note = matches!(trigger, MidiTrigger::Note { .. });
+        if pressed && (is_note || !was_held) {
+            controls.press(binding.action)
+        } else if !pressed && was_held && binding.action.is_momentary() {
+            controls.release(binding.action)
+        } else {
+            false
+        }
+    }
+
+    fn bound_action(&self, trigger: MidiTrigger) -> Option<ControlAction> {
+        self.bindings
+            .iter()
+            .find(|binding| binding.trigger == trigger)
+            .map(|binding| binding.action)
+    }
+}
+
+/// Decode note and control-change messages into a trigger and whether it was pressed
+fn parse_trigger(message: &[u8]) -> Option<(MidiTrigger, bool)> {
+    let (&status, data) = message.split_first()?;
+    let channel = status & 0x0F;
+    match (status & 0xF0, data) {
+        (0x90, &[note, velocity, ..]) => Some((MidiTrigger::Note { channel, note }, velocity > 0)),
+        (0x80, &[note, ..]) => Some((MidiTrigger::Note { channel, note }, false)),
+        (0xB0, &[controller, value, ..]) => {
+            Some((MidiTrigger::ControlChange { channel, controller }, value >= 64))
+        }
+        _ => None,
+    }
+}
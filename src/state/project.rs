@@ -1 +1,67 @@
 ﻿//! Project/session management
+//!
+//! A project records the session tempo and the metadata of every track,
+//! including the key and chords found by harmony analysis, as JSON.
+
+use std::{fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    core::{engine::AudioEngine, track::TrackMetadata},
+    error::types::AudioError,
+};
+
+/// Saved session state.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Project {
+    /// Project name
+    pub name: String,
+    /// Session tempo in beats per minute
+    pub bpm: f32,
+    /// Metadata of each track, in track order
+    pub tracks: Vec<TrackMetadata>,
+}
+
+impl Project {
+    /// Captures the current session from an engine.
+    pub fn from_engine(name: impl Into<String>, engine: &AudioEngine) -> Self {
+        Self {
+            name: name.into(),
+            bpm: engine.clock.bpm(),
+            tracks: engine.tracks.iter().map(|track| track.metadata().clone()).collect(),
+        }
+    }
+
+    /// Restores the tempo and the metadata of tracks with matching ids.
+    pub fn apply_to(&self, engine: &mut AudioEngine) {
+        engine.clock.set_bpm(self.bpm);
+        for saved in &self.tracks {
+            if let Some(track) = engine.tracks.iter_mut().find(|t| t.metadata().id == saved.id) {
+                let created_at = track.metadata().created_at;
+                *track.metadata_mut() = TrackMetadata { created_at, ..saved.clone() };
+            }
+        }
+    }
+
+    /// Serializes the project to JSON.
+    pub fn to_json(&self) -> Result<String, AudioError> {
+        serde_json::to_string_pretty(self).map_err(|e| AudioError::ProjectError(e.to_string()))
+    }
+
+    /// Parses a project from JSON.
+    pub fn from_json(json: &str) -> Result<Self, AudioError> {
+        serde_json::from_str(json).map_err(|e| AudioError::ProjectError(e.to_string()))
+    }
+
+    /// Writes the project to a file.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), AudioError> {
+        fs::write(path, self.to_json()?).map_err(|e| AudioError::ProjectError(e.to_string()))
+    }
+
+    /// Reads a project from a file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, AudioError> {
+        let json = fs::read_to_string(path).map_err(|e| AudioError::ProjectError(e.to_string()))?;
+        Self::from_json(&json)
+    }
+}
@@ -0,0 +1,202 @@
+﻿//! Tempo inference for freely recorded loops
+//!
+//! When the first loop is played without a click, its length is the only hard
+//! fact about the tempo: the loop holds a whole number of bars, but any number
+//! of them. Candidate bar counts are scored by how well the detected onsets
+//! line up with the resulting beat grid, how many beats carry an onset, and a
+//! mild preference for common tempos and power-of-two bar counts.
+
+use crate::audio::analysis::onset::Onset;
+
+/// Settings for inferring a tempo from a loop length.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AutoTempoSettings {
+    /// Slowest tempo that may be chosen
+    pub min_bpm: f64,
+    /// Fastest tempo that may be chosen
+    pub max_bpm: f64,
+    /// Tempo favoured when the onsets do not decide
+    pub preferred_bpm: f64,
+    /// Largest number of bars considered
+    pub max_bars: u32,
+}
+
+impl Default for AutoTempoSettings {
+    fn default() -> Self {
+        Self {
+            min_bpm: 70.0,
+            max_bpm: 160.0,
+            preferred_bpm: 110.0,
+            max_bars: 16,
+        }
+    }
+}
+
+/// Result of tempo inference.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TempoEstimate {
+    /// Inferred tempo in beats per minute
+    pub bpm: f64,
+    /// Number of bars in the loop
+    pub bars: u32,
+    /// Relative score of the winning candidate, from 0.0 to 1.0
+    pub confidence: f32,
+}
+
+/// Width of the tolerance window around a beat line, as a fraction of a beat.
+const ALIGNMENT_WIDTH: f64 = 0.08;
+
+/// Infers the most plausible tempo and bar count for a loop.
+///
+/// The onsets are typically those found when recording stopped, see
+/// [`Track::onsets`](crate::core::track::Track::onsets).
+///
+/// # Arguments
+/// * `onsets` - Onsets detected in the loop; any past `loop_length` are ignored.
+/// * `sample_rate` - The sample rate of the audio.
+/// * `loop_length` - The loop length in samples.
+/// * `beats_per_bar` - Beats in one bar of the current meter.
+/// * `settings` - Tempo range and analysis settings.
+///
+/// # Returns
+/// * `Option<TempoEstimate>` - The best candidate, or `None` if no bar count yields a tempo in range.
+pub fn infer_tempo(
+    onsets: &[Onset],
+    sample_rate: u32,
+    loop_length: usize,
+    beats_per_bar: u32,
+    settings: &AutoTempoSettings,
+) -> Option<TempoEstimate> {
+    if loop_length == 0 || sample_rate == 0 || beats_per_bar == 0 {
+        return None;
+    }
+    let loop_seconds = loop_length as f64 / sample_rate as f64;
+    let onsets: Vec<usize> = onsets
+        .iter()
+        .map(|onset| onset.position)
+        .filter(|&position| position < loop_length)
+        .collect();
+
+    let mut candidates: Vec<(f64, u32, f64)> = (1..=settings.max_bars.max(1))
+        .filter_map(|bars| {
+            let beats = bars * beats_per_bar;
+            let bpm = beats as f64 * 60.0 / loop_seconds;
+            (settings.min_bpm..=settings.max_bpm)
+                .contains(&bpm)
+                .then(|| (bpm, bars, score(&onsets, loop_length, beats, bars, bpm, settings)))
+        })
+        .collect();
+    candidates.sort_by(|a, b| b.2.total_cmp(&a.2));
+
+    let (bpm, bars, best) = *candidates.first()?;
+    let total: f64 = candidates.iter().map(|c| c.2).sum();
+    Some(TempoEstimate {
+        bpm,
+        bars,
+        confidence: if total > 0.0 { (best / total) as f32 } else { 1.0 },
+    })
+}
+
+/// Scores one candidate grid of `beats` beats across the loop.
+fn score(
+    onsets: &[usize],
+    loop_length: usize,
+    beats: u32,
+    bars: u32,
+    bpm: f64,
+    settings: &AutoTempoSettings,
+) -> f64 {
+    // Prior: tempos near the preferred one (on a log scale) and power-of-two phrase lengths
+    let tempo_prior = (-(bpm / settings.preferred_bpm).log2().powi(2) * 2.0).exp();
+    let phrase_prior = if bars.is_power_of_two() { 1.0 } else { 0.6 };
+    let prior = 0.2 * tempo_prior * phrase_prior;
+    if onsets.is_empty() {
+        return prior;
+    }
+
+    let beat_length = loop_length as f64 / beats as f64;
+    let mut covered = vec![false; beats as usize];
+    let alignment: f64 = onsets
+        .iter()
+        .map(|&onset| {
+            let position = onset as f64 / beat_length;
+            let nearest = position.round();
+            covered[nearest as usize % beats as usize] = true;
+            let distance = (position - nearest).abs() / ALIGNMENT_WIDTH;
+            (-distance * distance).exp()
+        })
+        .sum::<f64>()
+        / onsets.len() as f64;
+    let coverage = covered.iter().filter(|&&c| c).count() as f64 / beats as f64;
+
+    0.5 * alignment + 0.3 * coverage + prior
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::analysis::onset::{OnsetDetector, OnsetSettings};
+
+    fn clicks(len: usize, positions: impl Iterator<Item = usize>) -> Vec<Vec<f32>> {
+        let mut channel = vec![0.0; len];
+        for pos in positions {
+            for i in 0..64 {
+                channel[pos + i] = 1.0 - i as f32 / 64.0;
+            }
+        }
+        vec![channel]
+    }
+
+    fn onsets(audio: &[Vec<f32>], sample_rate: u32) -> Vec<Onset> {
+        OnsetDetector::new(sample_rate, OnsetSettings::default())
+            .detect_channels(audio)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_infers_tempo_from_beats() {
+        // Two bars of 4/4 at 100 BPM
+        let sample_rate = 44100;
+        let beat = 26460;
+        let len = beat * 8;
+        let audio = clicks(len, (0..8).map(|i| i * beat));
+
+        let estimate = infer_tempo(&onsets(&audio, sample_rate), sample_rate, len, 4, &AutoTempoSettings::default())
+            .unwrap();
+        assert!((estimate.bpm - 100.0).abs() < 1e-6);
+        assert_eq!(estimate.bars, 2);
+    }
+
+    #[test]
+    fn test_onsets_outweigh_preferred_tempo() {
+        // One bar of 4/4 at 75 BPM with eighth notes; 150 BPM has hits on every beat
+        let sample_rate = 44100;
+        let eighth = 17640;
+        let len = eighth * 8;
+        let audio = clicks(len, (0..8).map(|i| i * eighth));
+
+        // The preference sits on the 75 BPM reading, but onsets on every beat still win
+        let settings = AutoTempoSettings {
+            preferred_bpm: 80.0,
+            ..Default::default()
+        };
+        let estimate = infer_tempo(&onsets(&audio, sample_rate), sample_rate, len, 4, &settings).unwrap();
+        assert!((estimate.bpm - 150.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_respects_tempo_range() {
+        let sample_rate = 48000;
+        let len = sample_rate as usize * 3;
+        let settings = AutoTempoSettings {
+            min_bpm: 130.0,
+            max_bpm: 170.0,
+            ..Default::default()
+        };
+
+        // Three seconds: 4 beats = 80 BPM, 8 beats = 160 BPM
+        let estimate = infer_tempo(&[], sample_rate, len, 4, &settings).unwrap();
+        assert!((estimate.bpm - 160.0).abs() < 1e-6);
+        assert!(infer_tempo(&[], sample_rate, len, 4, &AutoTempoSettings { max_bpm: 75.0, ..settings }).is_none());
+    }
+}
@@ -1,16 +1,269 @@
 ﻿//! Clock synchronization implementation
+//!
+//! The master clock keeps a 64-bit sample timeline and derives musical
+//! positions from it. Tempo and meter are stored in atomics so the audio
+//! thread never blocks; tempo changes re-anchor the timeline so that the
+//! fractional samples-per-beat never accumulates rounding drift. A
+//! `TempoMap` can drive the clock through ramps and meter changes.
 
-use std::sync::{
-    Arc,
-    atomic::{AtomicUsize, Ordering},
+use std::{
+    fmt,
+    sync::atomic::{fence, AtomicU32, AtomicU64, Ordering},
 };
-use parking_lot::Mutex;
+use crate::sync::{
+    quantize::{groove_quantize, GrooveSettings},
+    tempo_map::TempoMap,
+};
+
+/// Number of meters an anchor remembers, the current one included, so
+/// positions before recent meter changes keep the meter they had.
+const METER_HISTORY: usize = 4;
+
+/// Resolution of musical positions, in ticks per beat.
+pub const TICKS_PER_BEAT: u32 = 960;
+
+/// Lowest tempo accepted by the clock.
+pub const MIN_BPM: f64 = 20.0;
+
+/// Highest tempo accepted by the clock.
+pub const MAX_BPM: f64 = 400.0;
+
+/// A musical meter such as 4/4 or 6/8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeSignature {
+    /// Beats per bar
+    pub numerator: u8,
+    /// Note value of one beat (4 = quarter note, 8 = eighth note)
+    pub denominator: u8,
+}
+
+impl TimeSignature {
+    /// Creates a new time signature, rejecting zero-length bars and
+    /// denominators that are not a power of two.
+    pub fn new(numerator: u8, denominator: u8) -> Option<Self> {
+        if numerator == 0 || denominator == 0 || !denominator.is_power_of_two() {
+            return None;
+        }
+        Some(Self { numerator, denominator })
+    }
+
+    fn pack(self) -> u32 {
+        (self.numerator as u32) << 8 | self.denominator as u32
+    }
+
+    fn unpack(packed: u32) -> Self {
+        Self {
+            numerator: (packed >> 8) as u8,
+            denominator: packed as u8,
+        }
+    }
+}
+
+impl Default for TimeSignature {
+    fn default() -> Self {
+        Self { numerator: 4, denominator: 4 }
+    }
+}
+
+impl fmt::Display for TimeSignature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.numerator, self.denominator)
+    }
+}
+
+/// A position on the musical timeline.
+///
+/// All fields are zero-based; the `Display` implementation prints the
+/// conventional one-based `bar.beat.tick` form.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MusicalPosition {
+    /// Bar number
+    pub bar: u32,
+    /// Beat within the bar
+    pub beat: u32,
+    /// Tick within the beat, in `0..TICKS_PER_BEAT`
+    pub tick: u32,
+}
+
+impl MusicalPosition {
+    /// Converts the position into an absolute beat count for the given meter.
+    pub fn to_beats(&self, time_signature: TimeSignature) -> f64 {
+        (self.bar as f64 * time_signature.numerator as f64)
+            + self.beat as f64
+            + self.tick as f64 / TICKS_PER_BEAT as f64
+    }
+
+    /// Builds a position from an absolute beat count for the given meter.
+    pub fn from_beats(beats: f64, time_signature: TimeSignature) -> Self {
+        let beats = beats.max(0.0);
+        let total_ticks = (beats * TICKS_PER_BEAT as f64).floor() as u64;
+        let whole_beats = total_ticks / TICKS_PER_BEAT as u64;
+        let numerator = time_signature.numerator as u64;
+        Self {
+            bar: (whole_beats / numerator) as u32,
+            beat: (whole_beats % numerator) as u32,
+            tick: (total_ticks % TICKS_PER_BEAT as u64) as u32,
+        }
+    }
+}
+
+impl fmt::Display for MusicalPosition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{:03}", self.bar + 1, self.beat + 1, self.tick)
+    }
+}
 
 /// The `MasterClock` struct is responsible for managing the tempo (BPM) and synchronizing beats.
+///
+/// `advance` is the only operation the audio thread needs to perform and is a
+/// single atomic add. Tempo changes, meter changes, seeks and resets store a
+/// new anchor (a sample/beat pair plus the tempo and meter in effect from there
+/// on). Anchors are double-buffered: a writer fills the slot readers are not
+/// using and then flips a sequence counter, so readers on any thread see a
+/// consistent anchor without taking a lock or waiting for a writer.
 pub struct MasterClock {
-    bpm: Arc<Mutex<f32>>, // Shared BPM value, protected by a mutex for thread-safe access.
-    sample_rate: u32,     // The sample rate of the audio system.
-    beat_counter: AtomicUsize, // Atomic counter for tracking the number of beats.
+    sample_rate: u32,              // The sample rate of the audio system.
+    sample_position: AtomicU64,    // Samples elapsed on the timeline.
+    anchor_sequence: AtomicU64,    // Odd while an update is in progress; `(sequence / 2) % 2` is the current slot.
+    anchor_slots: [AnchorSlot; 2], // The current anchor and the one being written.
+}
+
+/// Storage for one anchor, as atomics so a torn read is detected rather than undefined.
+struct AnchorSlot {
+    sample: AtomicU64,            // Timeline sample at which the anchor was taken.
+    beat: AtomicU64,              // Beat position at the anchor, as `f64` bits.
+    bpm: AtomicU64,               // Tempo from the anchor onwards, as `f64` bits.
+    meters: [MeterSlot; METER_HISTORY], // Current meter first, then the ones before it.
+}
+
+/// Storage for one meter of an anchor's history.
+struct MeterSlot {
+    time_signature: AtomicU32,    // Packed `TimeSignature`.
+    start_beat: AtomicU64,        // Beat at which the meter started, as `f64` bits.
+    start_bar: AtomicU32,         // Bar number at which the meter started.
+}
+
+impl AnchorSlot {
+    fn new(anchor: Anchor) -> Self {
+        Self {
+            sample: AtomicU64::new(anchor.sample),
+            beat: AtomicU64::new(anchor.beat.to_bits()),
+            bpm: AtomicU64::new(anchor.bpm.to_bits()),
+            meters: anchor.meters.map(|meter| MeterSlot {
+                time_signature: AtomicU32::new(meter.time_signature.pack()),
+                start_beat: AtomicU64::new(meter.start_beat.to_bits()),
+                start_bar: AtomicU32::new(meter.start_bar),
+            }),
+        }
+    }
+
+    fn load(&self) -> Anchor {
+        Anchor {
+            sample: self.sample.load(Ordering::Relaxed),
+            beat: f64::from_bits(self.beat.load(Ordering::Relaxed)),
+            bpm: f64::from_bits(self.bpm.load(Ordering::Relaxed)),
+            meters: std::array::from_fn(|i| Meter {
+                time_signature: TimeSignature::unpack(self.meters[i].time_signature.load(Ordering::Relaxed)),
+                start_beat: f64::from_bits(self.meters[i].start_beat.load(Ordering::Relaxed)),
+                start_bar: self.meters[i].start_bar.load(Ordering::Relaxed),
+            }),
+        }
+    }
+
+    fn store(&self, anchor: Anchor) {
+        self.sample.store(anchor.sample, Ordering::Relaxed);
+        self.beat.store(anchor.beat.to_bits(), Ordering::Relaxed);
+        self.bpm.store(anchor.bpm.to_bits(), Ordering::Relaxed);
+        for (slot, meter) in self.meters.iter().zip(anchor.meters) {
+            slot.time_signature.store(meter.time_signature.pack(), Ordering::Relaxed);
+            slot.start_beat.store(meter.start_beat.to_bits(), Ordering::Relaxed);
+            slot.start_bar.store(meter.start_bar, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A meter and the point on the timeline where it took effect.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Meter {
+    time_signature: TimeSignature,
+    start_beat: f64,
+    start_bar: u32,
+}
+
+impl Meter {
+    /// The given meter in effect from the very start.
+    fn initial(time_signature: TimeSignature) -> Self {
+        Self {
+            time_signature,
+            start_beat: 0.0,
+            start_bar: 0,
+        }
+    }
+}
+
+/// Consistent snapshot of the clock's anchor.
+#[derive(Debug, Clone, Copy)]
+struct Anchor {
+    sample: u64,
+    beat: f64,
+    bpm: f64,
+    meters: [Meter; METER_HISTORY],
+}
+
+impl Anchor {
+    /// Gets the meter currently in effect.
+    fn time_signature(&self) -> TimeSignature {
+        self.meters[0].time_signature
+    }
+
+    /// Beat position at a timeline sample.
+    fn beat_at_sample(&self, sample: u64, sample_rate: u32) -> f64 {
+        let elapsed = sample as f64 - self.sample as f64;
+        (self.beat + elapsed / samples_per_beat(sample_rate, self.bpm)).max(0.0)
+    }
+
+    /// Gets the oldest meter remembered, used for positions before all recorded changes.
+    fn oldest_meter(&self) -> &Meter {
+        &self.meters[METER_HISTORY - 1]
+    }
+
+    /// Beat at which the given bar starts under the meter in effect at that bar.
+    fn bar_start_beat(&self, bar: u32) -> f64 {
+        match self.meters.iter().find(|meter| bar >= meter.start_bar) {
+            Some(meter) => meter.start_beat + (bar - meter.start_bar) as f64 * meter.time_signature.numerator as f64,
+            None => bar as f64 * self.oldest_meter().time_signature.numerator as f64,
+        }
+    }
+
+    /// Bar/beat/tick position of a beat under the meter in effect at that beat.
+    fn musical_position(&self, beats: f64) -> MusicalPosition {
+        match self.meters.iter().find(|meter| beats >= meter.start_beat) {
+            Some(meter) => {
+                let mut position = MusicalPosition::from_beats(beats - meter.start_beat, meter.time_signature);
+                position.bar += meter.start_bar;
+                position
+            }
+            None => MusicalPosition::from_beats(beats, self.oldest_meter().time_signature),
+        }
+    }
+
+    /// Meter history with a new meter taking effect at the start of `bar`.
+    ///
+    /// Meters starting at or after that bar are replaced; once the history
+    /// is full the oldest meter is forgotten.
+    fn with_meter(&self, time_signature: TimeSignature, bar: u32) -> [Meter; METER_HISTORY] {
+        let mut meters = [Meter {
+            time_signature,
+            start_beat: self.bar_start_beat(bar),
+            start_bar: bar,
+        }; METER_HISTORY];
+        let mut earlier = self.meters.iter().filter(|meter| meter.start_bar < bar).copied();
+        for i in 1..METER_HISTORY {
+            // Unused entries repeat the oldest meter so it still covers the start of the timeline
+            meters[i] = earlier.next().unwrap_or(meters[i - 1]);
+        }
+        meters
+    }
 }
 
 impl MasterClock {
@@ -23,20 +276,50 @@ impl MasterClock {
     /// # Returns
     /// * `MasterClock` - A new instance of the clock.
     pub fn new(sample_rate: u32, initial_bpm: f32) -> Self {
+        let anchor = Anchor {
+            sample: 0,
+            beat: 0.0,
+            bpm: clamp_bpm(initial_bpm as f64),
+            meters: [Meter::initial(TimeSignature::default()); METER_HISTORY],
+        };
         Self {
-            bpm: Arc::new(Mutex::new(initial_bpm)),
             sample_rate,
-            beat_counter: AtomicUsize::new(0),
+            sample_position: AtomicU64::new(0),
+            anchor_sequence: AtomicU64::new(0),
+            anchor_slots: [AnchorSlot::new(anchor), AnchorSlot::new(anchor)],
         }
     }
 
+    /// Gets the sample rate the clock runs at.
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Gets the current tempo.
+    ///
+    /// # Returns
+    /// * `f32` - The tempo in beats per minute.
+    pub fn bpm(&self) -> f32 {
+        self.load_anchor().bpm as f32
+    }
+
     /// Calculates the number of samples per beat based on the current BPM and sample rate.
     ///
+    /// The value is rounded to the nearest whole sample; positions on the
+    /// timeline are computed from `samples_per_beat_exact` and do not drift.
+    ///
     /// # Returns
     /// * `usize` - The number of samples per beat.
     pub fn samples_per_beat(&self) -> usize {
-        let bpm = self.bpm.lock(); // Lock the mutex to access the BPM value.
-        ((60.0 / *bpm) * self.sample_rate as f32) as usize
+        self.samples_per_beat_exact().round() as usize
+    }
+
+    /// Calculates the exact, fractional number of samples per beat.
+    ///
+    /// # Returns
+    /// * `f64` - The number of samples per beat.
+    pub fn samples_per_beat_exact(&self) -> f64 {
+        samples_per_beat(self.sample_rate, self.load_anchor().bpm)
     }
 
     /// Gets the current position of the clock in terms of beats and beat progress.
@@ -44,37 +327,298 @@ impl MasterClock {
     /// # Returns
     /// * `(usize, f32)` - A tuple containing the current beat number and the progress within the beat.
     pub fn get_position(&self) -> (usize, f32) {
-        let samples_per_beat = self.samples_per_beat();
-        let total_samples = self.beat_counter.load(Ordering::Relaxed); // Load the beat counter atomically.
-        let current_beat = total_samples / samples_per_beat;
-        let beat_progress = (total_samples % samples_per_beat) as f32 / samples_per_beat as f32;
-        (current_beat, beat_progress)
+        let beats = self.beat_position();
+        (beats.floor() as usize, beats.fract() as f32)
+    }
+
+    /// Gets the current position as an absolute, fractional beat count.
+    pub fn beat_position(&self) -> f64 {
+        self.beat_at_sample(self.sample_position.load(Ordering::Acquire))
+    }
+
+    /// Gets the current position in bars, beats and ticks.
+    pub fn musical_position(&self) -> MusicalPosition {
+        self.musical_position_at_beat(self.beat_position())
     }
 
-    /// Advances the beat counter by a given number of samples.
+    /// Converts a beat position into bars, beats and ticks.
+    ///
+    /// Beats before a meter change are counted in the meter that was in
+    /// effect then.
+    ///
+    /// # Arguments
+    /// * `beats` - An absolute beat position.
+    ///
+    /// # Returns
+    /// * `MusicalPosition` - The bar/beat/tick position of that beat.
+    pub fn musical_position_at_beat(&self, beats: f64) -> MusicalPosition {
+        self.load_anchor().musical_position(beats)
+    }
+
+    /// Converts a bar/beat/tick position into an absolute beat count, taking meter changes into account.
+    ///
+    /// # Arguments
+    /// * `position` - The bar/beat/tick position.
+    ///
+    /// # Returns
+    /// * `f64` - The absolute beat position.
+    pub fn position_to_beats(&self, position: MusicalPosition) -> f64 {
+        self.load_anchor().bar_start_beat(position.bar)
+            + position.beat as f64
+            + position.tick as f64 / TICKS_PER_BEAT as f64
+    }
+
+    /// Gets the number of samples elapsed on the timeline.
+    pub fn sample_position(&self) -> u64 {
+        self.sample_position.load(Ordering::Acquire)
+    }
+
+    /// Converts a timeline sample into a beat position using the current anchor.
+    ///
+    /// # Arguments
+    /// * `sample` - A sample on the timeline.
+    ///
+    /// # Returns
+    /// * `f64` - The beat position at that sample.
+    pub fn beat_at_sample(&self, sample: u64) -> f64 {
+        self.load_anchor().beat_at_sample(sample, self.sample_rate)
+    }
+
+    /// Converts a beat position into a timeline sample using the current anchor.
+    ///
+    /// # Arguments
+    /// * `beat` - An absolute beat position.
+    ///
+    /// # Returns
+    /// * `u64` - The timeline sample at which that beat falls.
+    pub fn sample_at_beat(&self, beat: f64) -> u64 {
+        let anchor = self.load_anchor();
+        let offset = (beat - anchor.beat) * samples_per_beat(self.sample_rate, anchor.bpm);
+        (anchor.sample as f64 + offset).round().max(0.0) as u64
+    }
+
+    /// Gets the current time signature.
+    pub fn time_signature(&self) -> TimeSignature {
+        self.load_anchor().time_signature()
+    }
+
+    /// Updates the time signature.
+    ///
+    /// The new meter applies from the start of the current bar; the beat
+    /// position and bar number are kept. Positions before that bar keep the
+    /// meter they had, for up to `METER_HISTORY - 1` earlier changes.
+    ///
+    /// # Arguments
+    /// * `time_signature` - The new meter.
+    pub fn set_time_signature(&self, time_signature: TimeSignature) {
+        self.update_anchor(|anchor| {
+            let beat = anchor.beat_at_sample(self.sample_position.load(Ordering::Acquire), self.sample_rate);
+            let current_bar = anchor.musical_position(beat).bar;
+            Anchor {
+                meters: anchor.with_meter(time_signature, current_bar),
+                ..anchor
+            }
+        });
+    }
+
+    /// Gets the length of one bar in samples at the current tempo and meter.
+    pub fn samples_per_bar(&self) -> f64 {
+        self.samples_per_beat_exact() * self.time_signature().numerator as f64
+    }
+
+    /// Advances the timeline by a given number of samples.
     ///
     /// # Arguments
     /// * `samples` - The number of samples to advance.
     pub fn advance(&self, samples: usize) {
-        self.beat_counter.fetch_add(samples, Ordering::Relaxed);
+        self.sample_position.fetch_add(samples as u64, Ordering::AcqRel);
     }
 
     /// Updates the BPM value.
     ///
+    /// The beat position reached so far is kept; the new tempo applies from
+    /// the current sample onwards.
+    ///
     /// # Arguments
     /// * `new_bpm` - The new BPM value to set.
     pub fn set_bpm(&self, new_bpm: f32) {
-        let mut bpm = self.bpm.lock();
-        *bpm = new_bpm;
+        self.set_bpm_exact(new_bpm as f64);
+    }
+
+    /// Updates the BPM value without rounding it to `f32` precision.
+    ///
+    /// # Arguments
+    /// * `new_bpm` - The new BPM value to set.
+    pub fn set_bpm_exact(&self, new_bpm: f64) {
+        if !new_bpm.is_finite() {
+            return;
+        }
+        self.update_anchor(|anchor| {
+            let sample = self.sample_position.load(Ordering::Acquire);
+            Anchor {
+                sample,
+                beat: anchor.beat_at_sample(sample, self.sample_rate),
+                bpm: clamp_bpm(new_bpm),
+                ..anchor
+            }
+        });
+    }
+
+    /// Updates tempo and meter from a tempo map.
+    ///
+    /// Intended to be called once per audio block, before `advance`. The
+    /// tempo is sampled at the middle of the upcoming block so ramps are
+    /// followed without lagging behind.
+    ///
+    /// # Arguments
+    /// * `tempo_map` - The tempo map to follow.
+    /// * `block_size` - The number of samples about to be processed.
+    pub fn follow_tempo_map(&self, tempo_map: &TempoMap, block_size: usize) {
+        let anchor = self.load_anchor();
+        let beat = self.beat_position();
+        let midpoint = beat + block_size as f64 / 2.0 / samples_per_beat(self.sample_rate, anchor.bpm);
+
+        let bpm = clamp_bpm(tempo_map.tempo_at_beat(midpoint));
+        if (bpm - anchor.bpm).abs() > 1e-9 {
+            self.set_bpm_exact(bpm);
+        }
+
+        let time_signature = tempo_map.time_signature_at_beat(beat);
+        if time_signature != anchor.time_signature() {
+            self.set_time_signature(time_signature);
+        }
+    }
+
+    /// Moves the musical position without touching the sample timeline.
+    ///
+    /// # Arguments
+    /// * `position` - The bar/beat/tick position to jump to.
+    pub fn seek(&self, position: MusicalPosition) {
+        self.seek_beats(self.position_to_beats(position));
+    }
+
+    /// Moves the musical position to an absolute beat count.
+    ///
+    /// # Arguments
+    /// * `beats` - The beat position to jump to.
+    pub fn seek_beats(&self, beats: f64) {
+        self.update_anchor(|anchor| Anchor {
+            sample: self.sample_position.load(Ordering::Acquire),
+            beat: beats.max(0.0),
+            ..anchor
+        });
+    }
+
+    /// Resets the sample timeline and musical position to zero, keeping tempo and meter.
+    pub fn reset(&self) {
+        self.sample_position.store(0, Ordering::Release);
+        self.update_anchor(|anchor| Anchor {
+            sample: 0,
+            beat: 0.0,
+            meters: [Meter::initial(anchor.time_signature()); METER_HISTORY],
+            ..anchor
+        });
+    }
+
+    /// Reads a consistent anchor.
+    ///
+    /// A writer only ever fills the slot that is not current, so a reader
+    /// never waits for one; it retries only if two updates completed while it
+    /// was reading, the second one reusing its slot.
+    fn load_anchor(&self) -> Anchor {
+        loop {
+            let before = self.anchor_sequence.load(Ordering::Acquire);
+            let anchor = self.anchor_slots[(before / 2 % 2) as usize].load();
+            // Orders the slot reads before the re-check of the sequence
+            fence(Ordering::Acquire);
+            let after = self.anchor_sequence.load(Ordering::Relaxed);
+            // The slot is next overwritten by the update claimed at the second even value after `before`
+            if after < before / 2 * 2 + 3 {
+                return anchor;
+            }
+        }
+    }
+
+    /// Publishes a new anchor computed from the current one.
+    ///
+    /// Concurrent writers are serialised on the sequence counter, and `update`
+    /// runs once the sequence is claimed, so no writer's change is lost to
+    /// another writing back an anchor it read earlier. `update` must not
+    /// change the anchor itself.
+    fn update_anchor(&self, update: impl FnOnce(Anchor) -> Anchor) {
+        let mut sequence = self.anchor_sequence.load(Ordering::Acquire);
+        loop {
+            if sequence % 2 == 1 {
+                std::hint::spin_loop();
+                sequence = self.anchor_sequence.load(Ordering::Acquire);
+                continue;
+            }
+            match self.anchor_sequence.compare_exchange_weak(
+                sequence,
+                sequence + 1,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => break,
+                Err(current) => sequence = current,
+            }
+        }
+        // No other writer can touch either slot while the sequence is claimed
+        let anchor = update(self.anchor_slots[(sequence / 2 % 2) as usize].load());
+        // Readers that see any of the slot writes below also see the claim above
+        fence(Ordering::Release);
+        self.anchor_slots[((sequence / 2 + 1) % 2) as usize].store(anchor);
+        self.anchor_sequence.store(sequence + 2, Ordering::Release);
     }
 }
 
+/// Exact samples per beat for a tempo.
+fn samples_per_beat(sample_rate: u32, bpm: f64) -> f64 {
+    60.0 / bpm * sample_rate as f64
+}
+
+/// Keeps a tempo within the supported range.
+fn clamp_bpm(bpm: f64) -> f64 {
+    bpm.clamp(MIN_BPM, MAX_BPM)
+}
+
 /// The `Quantizer` struct is responsible for quantizing audio buffers to align with beats.
-pub struct Quantizer;
+#[derive(Debug, Clone, Default)]
+pub struct Quantizer {
+    settings: GrooveSettings, // Grid, strength and swing used by `quantize`.
+}
 
 impl Quantizer {
+    /// Creates a new `Quantizer` with the given groove settings.
+    ///
+    /// # Arguments
+    /// * `settings` - The grid, strength and swing to apply.
+    ///
+    /// # Returns
+    /// * `Quantizer` - A new instance of the quantizer.
+    pub fn new(settings: GrooveSettings) -> Self {
+        Self { settings }
+    }
+
+    /// Gets the current groove settings.
+    pub fn settings(&self) -> &GrooveSettings {
+        &self.settings
+    }
+
+    /// Updates the groove settings used by subsequent calls to `quantize`.
+    ///
+    /// # Arguments
+    /// * `settings` - The new grid, strength and swing.
+    pub fn set_settings(&mut self, settings: GrooveSettings) {
+        self.settings = settings;
+    }
+
     /// Quantizes an audio buffer to align with the given beat length.
     ///
+    /// Onsets are detected in the buffer and the audio between them is
+    /// time-warped so onsets land on the configured grid. The buffer length
+    /// is left unchanged.
+    ///
     /// # Arguments
     /// * `buffer` - A mutable reference to the audio buffer to be quantized.
     /// * `beat_length` - The length of a beat in samples.
@@ -86,7 +630,24 @@ impl Quantizer {
         buffer: &mut crate::core::buffer::AudioBuffer,
         beat_length: usize,
     ) -> Result<(), crate::error::types::AudioError> {
-        // TODO: Implement quantization logic here.
+        if beat_length == 0 {
+            return Err(crate::error::types::AudioError::BufferError(
+                "Beat length must be greater than zero".into(),
+            ));
+        }
+        if buffer.is_empty() {
+            return Ok(());
+        }
+
+        let quantized = groove_quantize(
+            buffer.samples(),
+            buffer.sample_rate(),
+            beat_length,
+            &self.settings,
+        )?;
+        for (channel, warped) in buffer.samples_mut().iter_mut().zip(quantized) {
+            *channel = warped;
+        }
         Ok(())
     }
 
@@ -94,4 +655,109 @@ impl Quantizer {
     pub fn on_loop(&self) {
         // TODO: Implement loop callback logic here.
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_drift_with_fractional_beat_length() {
+        // 44100 * 60 / 130 = 20353.846... samples per beat
+        let clock = MasterClock::new(44100, 130.0);
+        for _ in 0..(44100 * 60 / 64) {
+            clock.advance(64);
+        }
+        clock.advance(44100 * 60 % 64);
+        let beats = clock.beat_position();
+        assert!((beats - 130.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_tempo_change_keeps_position() {
+        let clock = MasterClock::new(48000, 120.0);
+        clock.advance(48000); // two beats
+        clock.set_bpm(60.0);
+        assert!((clock.beat_position() - 2.0).abs() < 1e-9);
+        clock.advance(48000); // one more beat at 60 BPM
+        assert!((clock.beat_position() - 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_bars_beats_ticks() {
+        let clock = MasterClock::new(48000, 120.0);
+        clock.set_time_signature(TimeSignature::new(3, 4).unwrap());
+        clock.advance(24000 * 7 + 12000); // 7.5 beats
+        let position = clock.musical_position();
+        assert_eq!(position, MusicalPosition { bar: 2, beat: 1, tick: TICKS_PER_BEAT / 2 });
+        assert_eq!(position.to_string(), "3.2.480");
+
+        clock.seek(MusicalPosition { bar: 1, beat: 0, tick: 0 });
+        assert!((clock.beat_position() - 3.0).abs() < 1e-9);
+
+        clock.advance(24000 * 3); // downbeat of the third bar
+        clock.set_time_signature(TimeSignature::new(4, 4).unwrap());
+        clock.advance(24000 * 3);
+        assert_eq!(clock.musical_position(), MusicalPosition { bar: 2, beat: 3, tick: 0 });
+
+        clock.reset();
+        assert_eq!(clock.sample_position(), 0);
+        assert_eq!(clock.beat_position(), 0.0);
+    }
+    #[test]
+    fn test_positions_before_a_meter_change_keep_their_meter() {
+        let clock = MasterClock::new(48000, 120.0);
+        clock.advance(24000 * 8); // two bars of 4/4
+        clock.set_time_signature(TimeSignature::new(3, 4).unwrap());
+        clock.advance(24000 * 4);
+        assert_eq!(clock.musical_position(), MusicalPosition { bar: 3, beat: 1, tick: 0 });
+
+        let before = MusicalPosition { bar: 1, beat: 3, tick: 0 };
+        assert_eq!(clock.musical_position_at_beat(7.0), before);
+        assert!((clock.position_to_beats(before) - 7.0).abs() < 1e-9);
+        assert_eq!(clock.musical_position_at_beat(9.0), MusicalPosition { bar: 2, beat: 1, tick: 0 });
+
+        // A second change still leaves the first two bars in 4/4
+        clock.set_time_signature(TimeSignature::new(7, 8).unwrap());
+        assert_eq!(clock.musical_position_at_beat(7.0), before);
+        assert_eq!(clock.musical_position_at_beat(12.0), MusicalPosition { bar: 3, beat: 1, tick: 0 });
+    }
+
+
+    #[test]
+    fn test_concurrent_writers_keep_each_others_changes() {
+        let clock = std::sync::Arc::new(MasterClock::new(48000, 120.0));
+        // One thread only changes the tempo and the other only the meter, so each must read back its own change
+        let tempo = {
+            let clock = clock.clone();
+            std::thread::spawn(move || {
+                for i in 0..20_000 {
+                    let bpm = 60.0 + (i % 100) as f32;
+                    clock.set_bpm(bpm);
+                    assert_eq!(clock.bpm(), bpm);
+                }
+            })
+        };
+        for i in 0..20_000 {
+            let time_signature = TimeSignature::new(2 + (i % 10) as u8, 4).unwrap();
+            clock.set_time_signature(time_signature);
+            clock.seek_beats(i as f64);
+            assert_eq!(clock.time_signature(), time_signature);
+        }
+        tempo.join().unwrap();
+        assert_eq!(clock.bpm(), 159.0);
+        assert_eq!(clock.time_signature(), TimeSignature::new(11, 4).unwrap());
+    }
+
+    #[test]
+    fn test_readers_do_not_wait_for_a_stalled_writer() {
+        let clock = MasterClock::new(48000, 120.0);
+        clock.set_bpm(90.0);
+        // A writer preempted right after claiming the sequence
+        let sequence = clock.anchor_sequence.load(Ordering::Acquire);
+        clock.anchor_sequence.store(sequence + 1, Ordering::Release);
+        assert_eq!(clock.bpm(), 90.0);
+        clock.advance(32000);
+        assert!((clock.beat_position() - 1.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,797 @@
+﻿//! Ableton Link tempo and phase synchronization
+//!
+//! Peers announce themselves with UDP multicast on 224.76.78.75:20808 using
+//! Link's v1 discovery framing: an `_asdp_v\x01` header, a message header
+//! (type, TTL, group, node id) and a list of `key/size/value` payload
+//! entries carrying the session timeline (`tmln`), session membership
+//! (`sess`), start/stop state (`stst`) and measurement endpoint (`mep4`).
+//!
+//! Each session shares a timeline expressed in "ghost time", a clock common to
+//! all members. When a peer from another session is seen, its ghost clock is
+//! measured with a ping/pong exchange against its measurement endpoint and the
+//! longer-running session wins, so groups of peers converge on one tempo.
+//! `LinkSync::sync_clock` then keeps `MasterClock` tempo and bar phase aligned
+//! with the session.
+
+use std::{
+    collections::{hash_map::RandomState, HashMap, HashSet},
+    hash::{BuildHasher, Hasher},
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use parking_lot::Mutex;
+use socket2::{Domain, Protocol, Socket, Type};
+use crate::{
+    error::types::AudioError,
+    sync::clock::{MasterClock, MAX_BPM, MIN_BPM},
+};
+
+/// Multicast group used for peer discovery.
+pub const LINK_MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(224, 76, 78, 75);
+
+/// Port used for peer discovery.
+pub const LINK_PORT: u16 = 20808;
+
+const DISCOVERY_HEADER: &[u8; 8] = b"_asdp_v\x01";
+const MEASUREMENT_HEADER: &[u8; 8] = b"_link_v\x01";
+
+const MESSAGE_ALIVE: u8 = 1;
+const MESSAGE_RESPONSE: u8 = 2;
+const MESSAGE_BYEBYE: u8 = 3;
+const MESSAGE_PING: u8 = 1;
+const MESSAGE_PONG: u8 = 2;
+
+const KEY_TIMELINE: u32 = u32::from_be_bytes(*b"tmln");
+const KEY_SESSION: u32 = u32::from_be_bytes(*b"sess");
+const KEY_START_STOP: u32 = u32::from_be_bytes(*b"stst");
+const KEY_ENDPOINT: u32 = u32::from_be_bytes(*b"mep4");
+const KEY_HOST_TIME: u32 = u32::from_be_bytes(*b"__ht");
+const KEY_GHOST_TIME: u32 = u32::from_be_bytes(*b"__gt");
+
+/// Seconds a peer announcement stays valid.
+const PEER_TTL: u8 = 5;
+
+/// Interval between multicast announcements.
+const BROADCAST_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Socket read timeout, bounding how long shutdown takes.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Ping/pong rounds per clock measurement.
+const MEASUREMENT_ROUNDS: usize = 5;
+
+/// Sessions whose ghost clocks differ by less than this are considered equally old.
+const SESSION_EPSILON_MICROS: i64 = 500_000;
+
+/// Largest UDP payload we send or accept.
+const MAX_MESSAGE: usize = 512;
+
+/// Foreign sessions that can wait to be measured at once.
+const MEASUREMENT_QUEUE: usize = 16;
+
+/// Identifier of a peer or session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId(pub [u8; 8]);
+
+impl NodeId {
+    /// Generates a random identifier.
+    pub fn random() -> Self {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u32(std::process::id());
+        hasher.write_u128(
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos()),
+        );
+        Self(hasher.finish().to_be_bytes())
+    }
+}
+
+/// A session's shared mapping between ghost time and beats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeline {
+    /// Tempo, as microseconds per beat
+    pub micros_per_beat: i64,
+    /// Beat at `time_origin`, in millionths of a beat
+    pub beat_origin: i64,
+    /// Ghost time at which `beat_origin` falls, in microseconds
+    pub time_origin: i64,
+}
+
+impl Timeline {
+    /// Creates a timeline with beat zero at the given ghost time.
+    pub fn new(bpm: f64, time_origin: i64) -> Self {
+        Self {
+            micros_per_beat: bpm_to_micros(bpm),
+            beat_origin: 0,
+            time_origin,
+        }
+    }
+
+    /// Gets the tempo in beats per minute.
+    pub fn bpm(&self) -> f64 {
+        60_000_000.0 / self.micros_per_beat as f64
+    }
+
+    /// Gets the beat position at a ghost time.
+    pub fn beat_at_time(&self, ghost_micros: i64) -> f64 {
+        self.beat_origin as f64 / 1e6
+            + (ghost_micros - self.time_origin) as f64 / self.micros_per_beat as f64
+    }
+
+    /// Gets the ghost time at which a beat falls.
+    pub fn time_at_beat(&self, beat: f64) -> i64 {
+        self.time_origin + ((beat - self.beat_origin as f64 / 1e6) * self.micros_per_beat as f64) as i64
+    }
+
+    /// Returns a timeline with a new tempo that keeps the beat reached at `ghost_micros`.
+    pub fn with_tempo(&self, bpm: f64, ghost_micros: i64) -> Self {
+        Self {
+            micros_per_beat: bpm_to_micros(bpm),
+            beat_origin: (self.beat_at_time(ghost_micros) * 1e6).round() as i64,
+            time_origin: ghost_micros,
+        }
+    }
+}
+
+/// A session's shared transport state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StartStopState {
+    /// Whether the session is playing
+    pub playing: bool,
+    /// Beat at which the state changed, in millionths of a beat
+    pub beats: i64,
+    /// Ghost time of the change, in microseconds; later changes win
+    pub timestamp: i64,
+}
+
+fn bpm_to_micros(bpm: f64) -> i64 {
+    (60_000_000.0 / bpm.clamp(MIN_BPM, MAX_BPM)).round() as i64
+}
+
+/// Settings for joining a Link session.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkSettings {
+    /// Interface used for multicast and measurement; `UNSPECIFIED` selects the default
+    pub interface: Ipv4Addr,
+    /// Discovery port
+    pub port: u16,
+    /// Length of a phase cycle in beats, usually one bar
+    pub quantum: f64,
+    /// Share start/stop with the session
+    pub start_stop_sync: bool,
+    /// Phase difference in beats tolerated before the clock is moved
+    pub phase_tolerance: f64,
+}
+
+impl Default for LinkSettings {
+    fn default() -> Self {
+        Self {
+            interface: Ipv4Addr::UNSPECIFIED,
+            port: LINK_PORT,
+            quantum: 4.0,
+            start_stop_sync: false,
+            phase_tolerance: 0.02,
+        }
+    }
+}
+
+/// What a peer last announced.
+#[derive(Debug, Clone, Copy)]
+struct PeerState {
+    session: NodeId,
+    timeline: Timeline,
+    start_stop: Option<StartStopState>,
+    endpoint: Option<SocketAddrV4>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Peer {
+    state: PeerState,
+    expires: Instant,
+}
+
+/// Session state shared between the network threads and the audio thread.
+#[derive(Debug)]
+struct SessionState {
+    node: NodeId,
+    session: NodeId,
+    timeline: Timeline,
+    start_stop: StartStopState,
+    /// Offset from host time to the session's ghost time, in microseconds
+    ghost_offset: i64,
+    peers: HashMap<NodeId, Peer>,
+    /// Foreign sessions already measured, with their ghost offset
+    measured: HashMap<NodeId, i64>,
+    /// Foreign sessions queued for or being measured
+    measuring: HashSet<NodeId>,
+    /// Set when the local state changed and should be announced right away
+    announce: bool,
+}
+
+struct Shared {
+    epoch: Instant,
+    settings: LinkSettings,
+    state: Mutex<SessionState>,
+    running: AtomicBool,
+    /// Tempo last written to the master clock, as `f64` bits
+    applied_bpm: AtomicU64,
+    endpoint: SocketAddrV4,
+}
+
+impl Shared {
+    fn host_micros(&self) -> i64 {
+        self.epoch.elapsed().as_micros() as i64
+    }
+}
+
+/// Membership in a Link session.
+///
+/// Dropping it leaves the session and announces the departure to peers.
+pub struct LinkSync {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl LinkSync {
+    /// Starts a new session and begins discovering peers.
+    ///
+    /// # Arguments
+    /// * `settings` - Interface, port, quantum and start/stop settings.
+    /// * `initial_bpm` - Tempo of the new session until it merges with another.
+    ///
+    /// # Returns
+    /// * `Result<LinkSync, AudioError>` - The running session, or an error if the sockets cannot be opened.
+    pub fn enable(settings: LinkSettings, initial_bpm: f64) -> Result<Self, AudioError> {
+        let discovery = discovery_socket(&settings)?;
+        let measurement = UdpSocket::bind(SocketAddrV4::new(settings.interface, 0))
+            .map_err(|e| AudioError::SyncError(format!("Link measurement socket: {}", e)))?;
+        measurement
+            .set_read_timeout(Some(POLL_INTERVAL))
+            .map_err(|e| AudioError::SyncError(e.to_string()))?;
+        let endpoint = match measurement.local_addr() {
+            Ok(SocketAddr::V4(addr)) => SocketAddrV4::new(advertised_ip(&settings), addr.port()),
+            _ => return Err(AudioError::SyncError("Link measurement socket is not IPv4".into())),
+        };
+
+        let node = NodeId::random();
+        let shared = Arc::new(Shared {
+            epoch: Instant::now(),
+            settings,
+            state: Mutex::new(SessionState {
+                node,
+                session: node,
+                timeline: Timeline::new(initial_bpm, 0),
+                start_stop: StartStopState::default(),
+                ghost_offset: 0,
+                peers: HashMap::new(),
+                measured: HashMap::new(),
+                measuring: HashSet::new(),
+                announce: true,
+            }),
+            running: AtomicBool::new(true),
+            applied_bpm: AtomicU64::new(0f64.to_bits()),
+            endpoint,
+        });
+
+        let (requests, pending) = crossbeam_channel::bounded(MEASUREMENT_QUEUE);
+        let discovery_shared = shared.clone();
+        let measurement_shared = shared.clone();
+        let merge_shared = shared.clone();
+        let workers = vec![
+            std::thread::spawn(move || run_discovery(discovery_shared, discovery, requests)),
+            std::thread::spawn(move || run_measurement_responder(measurement_shared, measurement)),
+            std::thread::spawn(move || run_session_merge(merge_shared, pending)),
+        ];
+        Ok(Self { shared, workers })
+    }
+
+    /// Gets the number of peers currently in the same session.
+    pub fn num_peers(&self) -> usize {
+        let state = self.shared.state.lock();
+        state
+            .peers
+            .values()
+            .filter(|peer| peer.state.session == state.session)
+            .count()
+    }
+
+    /// Gets the session tempo.
+    pub fn tempo(&self) -> f64 {
+        self.shared.state.lock().timeline.bpm()
+    }
+
+    /// Gets the session beat position now.
+    pub fn beat_now(&self) -> f64 {
+        let state = self.shared.state.lock();
+        state
+            .timeline
+            .beat_at_time(self.shared.host_micros() + state.ghost_offset)
+    }
+
+    /// Proposes a new session tempo, keeping the current beat position.
+    pub fn set_tempo(&self, bpm: f64) {
+        let mut state = self.shared.state.lock();
+        let ghost = self.shared.host_micros() + state.ghost_offset;
+        state.timeline = state.timeline.with_tempo(bpm, ghost);
+        state.announce = true;
+    }
+
+    /// Gets the session's start/stop state.
+    pub fn is_playing(&self) -> bool {
+        self.shared.state.lock().start_stop.playing
+    }
+
+    /// Starts or stops the session; only shared when start/stop sync is enabled.
+    pub fn set_playing(&self, playing: bool) {
+        let mut state = self.shared.state.lock();
+        let ghost = self.shared.host_micros() + state.ghost_offset;
+        state.start_stop = StartStopState {
+            playing,
+            beats: (state.timeline.beat_at_time(ghost) * 1e6).round() as i64,
+            timestamp: ghost,
+        };
+        state.announce = true;
+    }
+
+    /// Aligns the master clock's tempo and bar phase with the session.
+    ///
+    /// Call once per audio block before the clock is advanced. Tempo changes
+    /// made on the clock since the previous call are proposed to the session.
+    /// Never blocks: if the network threads hold the session state, the block
+    /// is skipped.
+    ///
+    /// # Arguments
+    /// * `clock` - The master clock to keep in sync.
+    ///
+    /// # Returns
+    /// * `Option<bool>` - The session's playing state when start/stop sync is enabled.
+    pub fn sync_clock(&self, clock: &MasterClock) -> Option<bool> {
+        let mut state = self.shared.state.try_lock()?;
+        let ghost = self.shared.host_micros() + state.ghost_offset;
+
+        let applied = f64::from_bits(self.shared.applied_bpm.load(Ordering::Acquire));
+        let local = clock.bpm() as f64;
+        if applied != 0.0 && (local - applied).abs() > 1e-3 {
+            // The tempo was changed locally since we last wrote it
+            state.timeline = state.timeline.with_tempo(local, ghost);
+            state.announce = true;
+        }
+
+        let bpm = state.timeline.bpm();
+        if (bpm - local).abs() > 1e-6 {
+            clock.set_bpm_exact(bpm);
+        }
+        self.shared.applied_bpm.store((clock.bpm() as f64).to_bits(), Ordering::Release);
+
+        let quantum = self.shared.settings.quantum.max(f64::EPSILON);
+        let session_beat = state.timeline.beat_at_time(ghost);
+        let local_beat = clock.beat_position();
+        let phase_error = (session_beat - local_beat).rem_euclid(quantum);
+        let phase_error = if phase_error > quantum / 2.0 { phase_error - quantum } else { phase_error };
+        if phase_error.abs() > self.shared.settings.phase_tolerance {
+            clock.seek_beats(local_beat + phase_error);
+        }
+
+        self.shared
+            .settings
+            .start_stop_sync
+            .then_some(state.start_stop.playing)
+    }
+}
+
+impl Drop for LinkSync {
+    fn drop(&mut self) {
+        self.shared.running.store(false, Ordering::Release);
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Address other peers should use to reach our measurement endpoint.
+///
+/// Without a configured interface this is the address of the interface the
+/// multicast traffic leaves on, found by connecting a UDP socket to the group
+/// (nothing is sent). Loopback is the fallback when there is no route.
+fn advertised_ip(settings: &LinkSettings) -> Ipv4Addr {
+    if !settings.interface.is_unspecified() {
+        return settings.interface;
+    }
+    UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))
+        .and_then(|socket| {
+            socket.connect(SocketAddrV4::new(LINK_MULTICAST_ADDR, settings.port))?;
+            socket.local_addr()
+        })
+        .ok()
+        .and_then(|addr| match addr.ip() {
+            IpAddr::V4(ip) if !ip.is_unspecified() => Some(ip),
+            _ => None,
+        })
+        .unwrap_or(Ipv4Addr::LOCALHOST)
+}
+
+/// Opens the shared discovery socket and joins the multicast group.
+fn discovery_socket(settings: &LinkSettings) -> Result<UdpSocket, AudioError> {
+    let error = |e: std::io::Error| AudioError::SyncError(format!("Link discovery socket: {}", e));
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).map_err(error)?;
+    socket.set_reuse_address(true).map_err(error)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true).map_err(error)?;
+    socket
+        .bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, settings.port).into())
+        .map_err(error)?;
+    socket
+        .join_multicast_v4(&LINK_MULTICAST_ADDR, &settings.interface)
+        .map_err(error)?;
+    socket.set_multicast_loop_v4(true).map_err(error)?;
+    if !settings.interface.is_unspecified() {
+        socket.set_multicast_if_v4(&settings.interface).map_err(error)?;
+    }
+    socket.set_read_timeout(Some(POLL_INTERVAL)).map_err(error)?;
+    Ok(socket.into())
+}
+
+/// Discovery loop: announces our state, tracks peers and queues foreign sessions for measurement.
+fn run_discovery(shared: Arc<Shared>, socket: UdpSocket, measurements: Sender<(PeerState, SocketAddrV4)>) {
+    let group = SocketAddrV4::new(LINK_MULTICAST_ADDR, shared.settings.port);
+    let mut last_broadcast: Option<Instant> = None;
+    let mut buffer = [0u8; MAX_MESSAGE];
+
+    while shared.running.load(Ordering::Acquire) {
+        let due = {
+            let mut state = shared.state.lock();
+            let due = state.announce || last_broadcast.is_none_or(|t| t.elapsed() >= BROADCAST_INTERVAL);
+            state.announce = false;
+            let now = Instant::now();
+            state.peers.retain(|_, peer| peer.expires > now);
+            due.then(|| encode_discovery(MESSAGE_ALIVE, &state, &shared))
+        };
+        if let Some(message) = due {
+            if let Err(e) = socket.send_to(&message, group) {
+                tracing::warn!("Link announcement failed: {}", e);
+            }
+            last_broadcast = Some(Instant::now());
+        }
+
+        match socket.recv_from(&mut buffer) {
+            Ok((len, from)) => handle_discovery(&shared, &socket, &buffer[..len], from, &measurements),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(e) => tracing::warn!("Link discovery receive failed: {}", e),
+        }
+    }
+
+    let state = shared.state.lock();
+    let _ = socket.send_to(&encode_discovery(MESSAGE_BYEBYE, &state, &shared), group);
+}
+
+fn handle_discovery(
+    shared: &Shared,
+    socket: &UdpSocket,
+    message: &[u8],
+    from: SocketAddr,
+    measurements: &Sender<(PeerState, SocketAddrV4)>,
+) {
+    let Some((message_type, ttl, node, entries)) = decode_discovery(message) else {
+        return;
+    };
+    let mut state = shared.state.lock();
+    if node == state.node {
+        return;
+    }
+
+    if message_type == MESSAGE_BYEBYE {
+        state.peers.remove(&node);
+        return;
+    }
+    let Some(peer) = parse_peer_state(&entries) else {
+        return;
+    };
+    state.peers.insert(
+        node,
+        Peer {
+            state: peer,
+            expires: Instant::now() + Duration::from_secs(ttl as u64),
+        },
+    );
+
+    if message_type == MESSAGE_ALIVE {
+        let response = encode_discovery(MESSAGE_RESPONSE, &state, shared);
+        let _ = socket.send_to(&response, from);
+    }
+
+    if peer.session == state.session {
+        // Timelines are anchored at the ghost time they were changed; the newest change wins
+        if peer.timeline.time_origin >= state.timeline.time_origin {
+            state.timeline = peer.timeline;
+        }
+        if let Some(start_stop) = peer.start_stop {
+            if shared.settings.start_stop_sync && start_stop.timestamp > state.start_stop.timestamp {
+                state.start_stop = start_stop;
+            }
+        }
+        return;
+    }
+
+    if state.measured.contains_key(&peer.session) || state.measuring.contains(&peer.session) {
+        return;
+    }
+    let Some(endpoint) = peer.endpoint else {
+        return;
+    };
+    // Measuring takes several round trips, so it runs on its own thread
+    if measurements.try_send((peer, endpoint)).is_ok() {
+        state.measuring.insert(peer.session);
+    }
+}
+
+/// Measures foreign sessions and joins them if they are older than ours.
+fn run_session_merge(shared: Arc<Shared>, measurements: Receiver<(PeerState, SocketAddrV4)>) {
+    while shared.running.load(Ordering::Acquire) {
+        let (peer, endpoint) = match measurements.recv_timeout(POLL_INTERVAL) {
+            Ok(request) => request,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        let offset = measure_ghost_offset(&shared, endpoint);
+
+        let mut state = shared.state.lock();
+        state.measuring.remove(&peer.session);
+        if let Some(offset) = offset {
+            merge_session(&mut state, peer, offset);
+        }
+    }
+}
+
+/// Records a measured session and switches to it if it is the older one.
+fn merge_session(state: &mut SessionState, peer: PeerState, offset: i64) {
+    // The session may have announced a newer timeline while it was measured
+    let peer = state
+        .peers
+        .values()
+        .map(|announced| announced.state)
+        .find(|announced| announced.session == peer.session)
+        .unwrap_or(peer);
+    state.measured.insert(peer.session, offset);
+    let difference = offset - state.ghost_offset;
+    let older = difference > SESSION_EPSILON_MICROS
+        || (difference.abs() <= SESSION_EPSILON_MICROS && peer.session < state.session);
+    if older {
+        tracing::info!("Joining Link session at {:.2} BPM", peer.timeline.bpm());
+        state.session = peer.session;
+        state.ghost_offset = offset;
+        state.timeline = peer.timeline;
+        if let Some(start_stop) = peer.start_stop {
+            state.start_stop = start_stop;
+        }
+        state.measured.clear();
+        state.announce = true;
+    }
+}
+
+/// Estimates the offset from our host time to a peer's ghost time.
+fn measure_ghost_offset(shared: &Shared, endpoint: SocketAddrV4) -> Option<i64> {
+    let socket = UdpSocket::bind(SocketAddrV4::new(shared.settings.interface, 0)).ok()?;
+    socket.set_read_timeout(Some(Duration::from_millis(50))).ok()?;
+    let mut buffer = [0u8; MAX_MESSAGE];
+    let mut offsets = Vec::with_capacity(MEASUREMENT_ROUNDS);
+
+    for _ in 0..MEASUREMENT_ROUNDS {
+        let sent = shared.host_micros();
+        let mut ping = MEASUREMENT_HEADER.to_vec();
+        ping.push(MESSAGE_PING);
+        push_entry(&mut ping, KEY_HOST_TIME, &sent.to_be_bytes());
+        socket.send_to(&ping, endpoint).ok()?;
+
+        let Ok(len) = socket.recv(&mut buffer) else {
+            continue;
+        };
+        let received = shared.host_micros();
+        let Some((MESSAGE_PONG, entries)) = decode_measurement(&buffer[..len]) else {
+            continue;
+        };
+        let echoed = entry_i64(&entries, KEY_HOST_TIME);
+        let ghost = entry_i64(&entries, KEY_GHOST_TIME);
+        if let (Some(echoed), Some(ghost)) = (echoed, ghost) {
+            if echoed == sent {
+                offsets.push(ghost - (sent + received) / 2);
+            }
+        }
+    }
+
+    if offsets.is_empty() {
+        return None;
+    }
+    offsets.sort_unstable();
+    Some(offsets[offsets.len() / 2])
+}
+
+/// Answers pings with our ghost time.
+fn run_measurement_responder(shared: Arc<Shared>, socket: UdpSocket) {
+    let mut buffer = [0u8; MAX_MESSAGE];
+    while shared.running.load(Ordering::Acquire) {
+        let (len, from) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            Err(e) => {
+                tracing::warn!("Link measurement receive failed: {}", e);
+                continue;
+            }
+        };
+        let Some((MESSAGE_PING, entries)) = decode_measurement(&buffer[..len]) else {
+            continue;
+        };
+        let Some(host_time) = entry_i64(&entries, KEY_HOST_TIME) else {
+            continue;
+        };
+
+        let (session, ghost) = {
+            let state = shared.state.lock();
+            (state.session, shared.host_micros() + state.ghost_offset)
+        };
+        let mut pong = MEASUREMENT_HEADER.to_vec();
+        pong.push(MESSAGE_PONG);
+        push_entry(&mut pong, KEY_SESSION, &session.0);
+        push_entry(&mut pong, KEY_GHOST_TIME, &ghost.to_be_bytes());
+        push_entry(&mut pong, KEY_HOST_TIME, &host_time.to_be_bytes());
+        let _ = socket.send_to(&pong, from);
+    }
+}
+
+fn encode_discovery(message_type: u8, state: &SessionState, shared: &Shared) -> Vec<u8> {
+    let mut message = DISCOVERY_HEADER.to_vec();
+    message.push(message_type);
+    message.push(PEER_TTL);
+    message.extend_from_slice(&0u16.to_be_bytes()); // group id
+    message.extend_from_slice(&state.node.0);
+
+    let mut timeline = Vec::with_capacity(24);
+    timeline.extend_from_slice(&state.timeline.micros_per_beat.to_be_bytes());
+    timeline.extend_from_slice(&state.timeline.beat_origin.to_be_bytes());
+    timeline.extend_from_slice(&state.timeline.time_origin.to_be_bytes());
+    push_entry(&mut message, KEY_TIMELINE, &timeline);
+    push_entry(&mut message, KEY_SESSION, &state.session.0);
+
+    let mut start_stop = Vec::with_capacity(17);
+    start_stop.push(state.start_stop.playing as u8);
+    start_stop.extend_from_slice(&state.start_stop.beats.to_be_bytes());
+    start_stop.extend_from_slice(&state.start_stop.timestamp.to_be_bytes());
+    push_entry(&mut message, KEY_START_STOP, &start_stop);
+
+    let mut endpoint = shared.endpoint.ip().octets().to_vec();
+    endpoint.extend_from_slice(&shared.endpoint.port().to_be_bytes());
+    push_entry(&mut message, KEY_ENDPOINT, &endpoint);
+    message
+}
+
+fn push_entry(message: &mut Vec<u8>, key: u32, value: &[u8]) {
+    message.extend_from_slice(&key.to_be_bytes());
+    message.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    message.extend_from_slice(value);
+}
+
+type Entries<'a> = Vec<(u32, &'a [u8])>;
+
+fn decode_entries(mut payload: &[u8]) -> Option<Entries<'_>> {
+    let mut entries = Vec::new();
+    while !payload.is_empty() {
+        let key = u32::from_be_bytes(payload.get(0..4)?.try_into().ok()?);
+        let size = u32::from_be_bytes(payload.get(4..8)?.try_into().ok()?) as usize;
+        let value = payload.get(8..8 + size)?;
+        entries.push((key, value));
+        payload = &payload[8 + size..];
+    }
+    Some(entries)
+}
+
+fn decode_discovery(message: &[u8]) -> Option<(u8, u8, NodeId, Entries<'_>)> {
+    let body = message.strip_prefix(DISCOVERY_HEADER)?;
+    let message_type = *body.first()?;
+    let ttl = *body.get(1)?;
+    let node = NodeId(body.get(4..12)?.try_into().ok()?);
+    Some((message_type, ttl, node, decode_entries(&body[12..])?))
+}
+
+fn decode_measurement(message: &[u8]) -> Option<(u8, Entries<'_>)> {
+    let body = message.strip_prefix(MEASUREMENT_HEADER)?;
+    Some((*body.first()?, decode_entries(&body[1..])?))
+}
+
+fn entry<'a>(entries: &Entries<'a>, key: u32) -> Option<&'a [u8]> {
+    entries.iter().find(|(k, _)| *k == key).map(|(_, value)| *value)
+}
+
+fn entry_i64(entries: &Entries<'_>, key: u32) -> Option<i64> {
+    Some(i64::from_be_bytes(entry(entries, key)?.get(0..8)?.try_into().ok()?))
+}
+
+fn read_i64(bytes: &[u8], offset: usize) -> Option<i64> {
+    Some(i64::from_be_bytes(bytes.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+fn parse_peer_state(entries: &Entries<'_>) -> Option<PeerState> {
+    let timeline = entry(entries, KEY_TIMELINE)?;
+    let timeline = Timeline {
+        micros_per_beat: read_i64(timeline, 0)?,
+        beat_origin: read_i64(timeline, 8)?,
+        time_origin: read_i64(timeline, 16)?,
+    };
+    if timeline.micros_per_beat <= 0 {
+        return None;
+    }
+    let session = NodeId(entry(entries, KEY_SESSION)?.get(0..8)?.try_into().ok()?);
+    let start_stop = entry(entries, KEY_START_STOP).and_then(|value| {
+        Some(StartStopState {
+            playing: *value.first()? != 0,
+            beats: read_i64(value, 1)?,
+            timestamp: read_i64(value, 9)?,
+        })
+    });
+    let endpoint = entry(entries, KEY_ENDPOINT).and_then(|value| {
+        let ip: [u8; 4] = value.get(0..4)?.try_into().ok()?;
+        let port = u16::from_be_bytes(value.get(4..6)?.try_into().ok()?);
+        Some(SocketAddrV4::new(Ipv4Addr::from(ip), port))
+    });
+
+    Some(PeerState {
+        session,
+        timeline,
+        start_stop,
+        endpoint,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timeline_tempo_change_keeps_beat() {
+        let timeline = Timeline::new(120.0, 1_000_000);
+        assert!((timeline.beat_at_time(3_000_000) - 4.0).abs() < 1e-9);
+
+        let faster = timeline.with_tempo(150.0, 3_000_000);
+        assert!((faster.beat_at_time(3_000_000) - 4.0).abs() < 1e-6);
+        assert!((faster.beat_at_time(3_400_000) - 5.0).abs() < 1e-6);
+        assert_eq!(faster.time_at_beat(5.0), 3_400_000);
+    }
+
+    #[test]
+    fn test_discovery_message_round_trip() {
+        let node = NodeId::random();
+        let shared = Shared {
+            epoch: Instant::now(),
+            settings: LinkSettings::default(),
+            state: Mutex::new(SessionState {
+                node,
+                session: node,
+                timeline: Timeline::new(93.0, 42),
+                start_stop: StartStopState { playing: true, beats: 8_000_000, timestamp: 7 },
+                ghost_offset: 0,
+                peers: HashMap::new(),
+                measured: HashMap::new(),
+                measuring: HashSet::new(),
+                announce: false,
+            }),
+            running: AtomicBool::new(false),
+            applied_bpm: AtomicU64::new(0),
+            endpoint: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 4242),
+        };
+        let message = encode_discovery(MESSAGE_ALIVE, &shared.state.lock(), &shared);
+
+        let (message_type, ttl, decoded_node, entries) = decode_discovery(&message).unwrap();
+        assert_eq!((message_type, ttl, decoded_node), (MESSAGE_ALIVE, PEER_TTL, node));
+        let peer = parse_peer_state(&entries).unwrap();
+        assert_eq!(peer.session, node);
+        assert_eq!(peer.timeline, Timeline::new(93.0, 42));
+        assert_eq!(peer.start_stop.unwrap().beats, 8_000_000);
+        assert_eq!(peer.endpoint, Some(shared.endpoint));
+    }
+}
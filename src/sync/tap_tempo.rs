@@ -0,0 +1,261 @@
+﻿//! Tap tempo
+//!
+//! Estimates tempo from a sequence of taps. Recent tap intervals are
+//! averaged after rejecting intervals that stray too far from the median, so a
+//! single late or doubled tap does not throw the estimate off.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+use crate::sync::clock::{MasterClock, MAX_BPM, MIN_BPM};
+
+/// Largest number of taps kept for the estimate.
+///
+/// Taps are registered on the audio thread, so the history and the scratch
+/// space for the estimate are sized for this many taps up front.
+pub const MAX_TAPS: usize = 16;
+
+/// Settings for tap tempo detection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TapTempoSettings {
+    /// Number of most recent taps used for the estimate, from 2 up to `MAX_TAPS`
+    pub max_taps: usize,
+    /// Pause after which the next tap starts a new sequence
+    pub timeout: Duration,
+    /// Maximum relative deviation from the median interval before a tap interval is ignored
+    pub outlier_tolerance: f64,
+    /// Defer the new tempo until the next bar line instead of applying it immediately
+    pub apply_at_next_bar: bool,
+}
+
+impl Default for TapTempoSettings {
+    fn default() -> Self {
+        Self {
+            max_taps: 8,
+            timeout: Duration::from_secs(2),
+            outlier_tolerance: 0.25,
+            apply_at_next_bar: false,
+        }
+    }
+}
+
+/// Tap tempo detector.
+#[derive(Debug, Clone)]
+pub struct TapTempo {
+    settings: TapTempoSettings,
+    taps: VecDeque<Instant>,
+    pending: Option<PendingTempo>,
+}
+
+/// A tapped tempo waiting for a bar line.
+#[derive(Debug, Clone, Copy)]
+struct PendingTempo {
+    bpm: f64,
+    bar: u32,
+}
+
+impl TapTempo {
+    /// Creates a new `TapTempo` detector.
+    ///
+    /// # Arguments
+    /// * `settings` - Tap history, timeout, outlier and apply settings.
+    ///
+    /// # Returns
+    /// * `TapTempo` - A new detector with no taps recorded.
+    pub fn new(settings: TapTempoSettings) -> Self {
+        Self {
+            settings,
+            taps: VecDeque::with_capacity(MAX_TAPS),
+            pending: None,
+        }
+    }
+
+    /// Gets the current settings.
+    pub fn settings(&self) -> &TapTempoSettings {
+        &self.settings
+    }
+
+    /// Updates the settings. Recorded taps are kept.
+    pub fn set_settings(&mut self, settings: TapTempoSettings) {
+        self.settings = settings;
+        while self.taps.len() > self.tap_limit() {
+            self.taps.pop_front();
+        }
+    }
+
+    /// Registers a tap and applies the resulting tempo to the clock.
+    ///
+    /// # Arguments
+    /// * `now` - The time of the tap.
+    /// * `clock` - The clock whose tempo should follow the taps.
+    ///
+    /// # Returns
+    /// * `Option<f64>` - The estimated tempo, once at least two taps are known.
+    pub fn tap(&mut self, now: Instant, clock: &MasterClock) -> Option<f64> {
+        let bpm = self.register_tap(now)?;
+        if self.settings.apply_at_next_bar {
+            self.pending = Some(PendingTempo {
+                bpm,
+                bar: clock.musical_position().bar + 1,
+            });
+        } else {
+            self.pending = None;
+            clock.set_bpm_exact(bpm);
+        }
+        Some(bpm)
+    }
+
+    /// Applies a deferred tempo once the clock reaches its bar.
+    ///
+    /// Call once per audio block when `apply_at_next_bar` is enabled.
+    ///
+    /// # Arguments
+    /// * `clock` - The clock to update.
+    pub fn poll(&mut self, clock: &MasterClock) {
+        if let Some(pending) = self.pending {
+            if clock.musical_position().bar >= pending.bar {
+                clock.set_bpm_exact(pending.bpm);
+                self.pending = None;
+            }
+        }
+    }
+
+    /// Gets the tempo waiting for the next bar line, if any.
+    pub fn pending_tempo(&self) -> Option<f64> {
+        self.pending.map(|pending| pending.bpm)
+    }
+
+    /// Discards recorded taps and any pending tempo.
+    pub fn reset(&mut self) {
+        self.taps.clear();
+        self.pending = None;
+    }
+
+    /// Records a tap and estimates the tempo without touching a clock.
+    ///
+    /// # Arguments
+    /// * `now` - The time of the tap.
+    ///
+    /// # Returns
+    /// * `Option<f64>` - The estimated tempo, once at least two taps are known.
+    pub fn register_tap(&mut self, now: Instant) -> Option<f64> {
+        let timed_out = self
+            .taps
+            .back()
+            .is_some_and(|&last| now.saturating_duration_since(last) > self.settings.timeout);
+        if timed_out {
+            self.taps.clear();
+        }
+
+        // Make room first so the deque never grows past its capacity on the audio thread
+        while self.taps.len() >= self.tap_limit() {
+            self.taps.pop_front();
+        }
+        self.taps.push_back(now);
+
+        self.estimate()
+    }
+
+    /// Number of taps kept, `max_taps` clamped to the supported range.
+    fn tap_limit(&self) -> usize {
+        self.settings.max_taps.clamp(2, MAX_TAPS)
+    }
+
+    /// Averages tap intervals that lie close to the median interval.
+    fn estimate(&self) -> Option<f64> {
+        let mut scratch = [0.0f64; MAX_TAPS];
+        let mut count = 0;
+        for (a, b) in self.taps.iter().zip(self.taps.iter().skip(1)) {
+            let interval = b.saturating_duration_since(*a).as_secs_f64();
+            if interval > 0.0 {
+                scratch[count] = interval;
+                count += 1;
+            }
+        }
+        if count == 0 {
+            return None;
+        }
+
+        let intervals = &mut scratch[..count];
+        intervals.sort_unstable_by(f64::total_cmp);
+        let median = intervals[count / 2];
+
+        let (sum, kept) = intervals
+            .iter()
+            .filter(|&&interval| (interval - median).abs() <= median * self.settings.outlier_tolerance)
+            .fold((0.0, 0), |(sum, kept), &interval| (sum + interval, kept + 1));
+        let average = sum / kept as f64;
+
+        let bpm = 60.0 / average;
+        (MIN_BPM..=MAX_BPM).contains(&bpm).then_some(bpm)
+    }
+}
+
+impl Default for TapTempo {
+    fn default() -> Self {
+        Self::new(TapTempoSettings::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tap_at(tap_tempo: &mut TapTempo, start: Instant, millis: &[u64]) -> Option<f64> {
+        millis
+            .iter()
+            .map(|&ms| tap_tempo.register_tap(start + Duration::from_millis(ms)))
+            .last()
+            .flatten()
+    }
+
+    #[test]
+    fn test_average_with_outlier() {
+        let mut tap_tempo = TapTempo::default();
+        // 500 ms taps (120 BPM) with one late tap
+        let bpm = tap_at(&mut tap_tempo, Instant::now(), &[0, 500, 1000, 1700, 2000, 2500]).unwrap();
+        assert!((bpm - 120.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn test_timeout_starts_new_sequence() {
+        let mut tap_tempo = TapTempo::default();
+        let start = Instant::now();
+        tap_at(&mut tap_tempo, start, &[0, 500, 1000]);
+        let bpm = tap_at(&mut tap_tempo, start, &[5000, 5750, 6500]).unwrap();
+        assert!((bpm - 80.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_tap_history_is_capped() {
+        let mut tap_tempo = TapTempo::new(TapTempoSettings {
+            max_taps: MAX_TAPS * 4,
+            ..Default::default()
+        });
+        let capacity = tap_tempo.taps.capacity();
+        let taps: Vec<u64> = (0..MAX_TAPS as u64 * 3).map(|n| n * 400).collect();
+        let bpm = tap_at(&mut tap_tempo, Instant::now(), &taps).unwrap();
+        assert!((bpm - 150.0).abs() < 1e-6);
+        assert_eq!(tap_tempo.taps.len(), MAX_TAPS);
+        assert_eq!(tap_tempo.taps.capacity(), capacity);
+    }
+
+    #[test]
+    fn test_apply_at_next_bar() {
+        let clock = MasterClock::new(48000, 120.0);
+        let mut tap_tempo = TapTempo::new(TapTempoSettings {
+            apply_at_next_bar: true,
+            ..Default::default()
+        });
+        let start = Instant::now();
+        tap_tempo.tap(start, &clock);
+        tap_tempo.tap(start + Duration::from_millis(1000), &clock);
+        assert_eq!(clock.bpm(), 120.0);
+
+        clock.advance(48000 * 2); // one 4/4 bar at 120 BPM
+        tap_tempo.poll(&clock);
+        assert!((clock.bpm() - 60.0).abs() < 1e-3);
+        assert!(tap_tempo.pending_tempo().is_none());
+    }
+}
@@ -0,0 +1,416 @@
+﻿//! Tempo map with tempo ramps and time-signature changes
+//!
+//! A tempo map is a list of timestamped tempo events (positioned in beats)
+//! and meter events (positioned in bars). Each tempo event describes how the
+//! tempo travels to the next event, which allows accelerando and ritardando
+//! sections to be built into a set. `MasterClock::follow_tempo_map` keeps the
+//! clock, and everything that reads tempo from it, in step with the map.
+
+use crate::{
+    error::types::AudioError,
+    sync::clock::{MusicalPosition, TimeSignature, MAX_BPM, MIN_BPM},
+};
+
+/// Number of integration steps used for curved ramps.
+const CURVE_STEPS: usize = 64;
+
+/// Number of bisection iterations used to invert time into beats.
+const BISECTION_STEPS: usize = 64;
+
+/// Shape of the tempo change between one tempo event and the next.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TempoRamp {
+    /// Tempo holds until the next event, then jumps
+    Step,
+    /// Tempo changes linearly over beats towards the next event
+    Linear,
+    /// Tempo follows `progress^curve`; values below 1.0 change quickly at first,
+    /// values above 1.0 change slowly at first
+    Curved(f32),
+}
+
+/// A tempo change at a beat position.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TempoEvent {
+    /// Absolute beat position of the event
+    pub beat: f64,
+    /// Tempo at the event, in beats per minute
+    pub bpm: f64,
+    /// How the tempo moves from this event to the next one
+    pub ramp: TempoRamp,
+}
+
+/// A time-signature change at the start of a bar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MeterEvent {
+    /// Bar number at which the meter takes effect
+    pub bar: u32,
+    /// The new meter
+    pub time_signature: TimeSignature,
+}
+
+/// Timeline of tempo and meter events.
+#[derive(Debug, Clone)]
+pub struct TempoMap {
+    tempo_events: Vec<TempoEvent>,
+    meter_events: Vec<MeterEvent>,
+    /// Seconds elapsed at each tempo event, kept in sync with `tempo_events`
+    event_seconds: Vec<f64>,
+    /// Beat at which each meter event's bar starts
+    meter_beats: Vec<f64>,
+}
+
+impl TempoMap {
+    /// Creates a tempo map with a constant tempo and meter.
+    ///
+    /// # Arguments
+    /// * `bpm` - The tempo at beat zero.
+    /// * `time_signature` - The meter at bar zero.
+    ///
+    /// # Returns
+    /// * `TempoMap` - A new map containing one tempo and one meter event.
+    pub fn new(bpm: f64, time_signature: TimeSignature) -> Self {
+        let mut map = Self {
+            tempo_events: vec![TempoEvent {
+                beat: 0.0,
+                bpm: bpm.clamp(MIN_BPM, MAX_BPM),
+                ramp: TempoRamp::Step,
+            }],
+            meter_events: vec![MeterEvent { bar: 0, time_signature }],
+            event_seconds: Vec::new(),
+            meter_beats: Vec::new(),
+        };
+        map.rebuild();
+        map
+    }
+
+    /// Gets the tempo events in beat order.
+    pub fn tempo_events(&self) -> &[TempoEvent] {
+        &self.tempo_events
+    }
+
+    /// Gets the meter events in bar order.
+    pub fn meter_events(&self) -> &[MeterEvent] {
+        &self.meter_events
+    }
+
+    /// Adds a tempo event, replacing any event at the same beat.
+    ///
+    /// # Arguments
+    /// * `beat` - Absolute beat position of the event.
+    /// * `bpm` - Tempo at the event.
+    /// * `ramp` - How the tempo moves from this event to the next one.
+    ///
+    /// # Returns
+    /// * `Result<(), AudioError>` - Returns an error if the position or tempo is out of range.
+    pub fn add_tempo(&mut self, beat: f64, bpm: f64, ramp: TempoRamp) -> Result<(), AudioError> {
+        if !beat.is_finite() || beat < 0.0 {
+            return Err(AudioError::SyncError(format!("Invalid tempo event position: {}", beat)));
+        }
+        if !(MIN_BPM..=MAX_BPM).contains(&bpm) {
+            return Err(AudioError::SyncError(format!("Tempo out of range: {}", bpm)));
+        }
+        if let TempoRamp::Curved(curve) = ramp {
+            if !curve.is_finite() || curve <= 0.0 {
+                return Err(AudioError::SyncError(format!("Invalid ramp curve: {}", curve)));
+            }
+        }
+
+        let event = TempoEvent { beat, bpm, ramp };
+        match self.tempo_events.iter().position(|e| e.beat >= beat) {
+            Some(index) if self.tempo_events[index].beat == beat => self.tempo_events[index] = event,
+            Some(index) => self.tempo_events.insert(index, event),
+            None => self.tempo_events.push(event),
+        }
+        self.rebuild();
+        Ok(())
+    }
+
+    /// Adds a meter event, replacing any event at the same bar.
+    ///
+    /// # Arguments
+    /// * `bar` - Bar number at which the meter takes effect.
+    /// * `time_signature` - The new meter.
+    pub fn add_meter(&mut self, bar: u32, time_signature: TimeSignature) {
+        let event = MeterEvent { bar, time_signature };
+        match self.meter_events.iter().position(|e| e.bar >= bar) {
+            Some(index) if self.meter_events[index].bar == bar => self.meter_events[index] = event,
+            Some(index) => self.meter_events.insert(index, event),
+            None => self.meter_events.push(event),
+        }
+        self.rebuild();
+    }
+
+    /// Removes the tempo event at a beat. The event at beat zero cannot be removed.
+    ///
+    /// # Returns
+    /// * `bool` - Whether an event was removed.
+    pub fn remove_tempo(&mut self, beat: f64) -> bool {
+        let before = self.tempo_events.len();
+        self.tempo_events.retain(|e| e.beat == 0.0 || e.beat != beat);
+        let removed = self.tempo_events.len() != before;
+        if removed {
+            self.rebuild();
+        }
+        removed
+    }
+
+    /// Removes the meter event at a bar. The event at bar zero cannot be removed.
+    ///
+    /// # Returns
+    /// * `bool` - Whether an event was removed.
+    pub fn remove_meter(&mut self, bar: u32) -> bool {
+        let before = self.meter_events.len();
+        self.meter_events.retain(|e| e.bar == 0 || e.bar != bar);
+        let removed = self.meter_events.len() != before;
+        if removed {
+            self.rebuild();
+        }
+        removed
+    }
+
+    /// Gets the tempo at a beat position.
+    ///
+    /// # Arguments
+    /// * `beat` - Absolute beat position.
+    ///
+    /// # Returns
+    /// * `f64` - The tempo in beats per minute.
+    pub fn tempo_at_beat(&self, beat: f64) -> f64 {
+        let index = self.tempo_index(beat);
+        self.segment_tempo(index, beat)
+    }
+
+    /// Gets the meter in effect at a beat position.
+    pub fn time_signature_at_beat(&self, beat: f64) -> TimeSignature {
+        self.meter_events[self.meter_index_at_beat(beat)].time_signature
+    }
+
+    /// Gets the beat at which a bar starts, taking meter changes into account.
+    pub fn bar_start_beat(&self, bar: u32) -> f64 {
+        let index = self
+            .meter_events
+            .iter()
+            .rposition(|e| e.bar <= bar)
+            .unwrap_or(0);
+        let event = &self.meter_events[index];
+        self.meter_beats[index] + (bar - event.bar) as f64 * event.time_signature.numerator as f64
+    }
+
+    /// Converts a beat position into bars, beats and ticks.
+    pub fn musical_position(&self, beat: f64) -> MusicalPosition {
+        let beat = beat.max(0.0);
+        let index = self.meter_index_at_beat(beat);
+        let event = &self.meter_events[index];
+        let mut position = MusicalPosition::from_beats(beat - self.meter_beats[index], event.time_signature);
+        position.bar += event.bar;
+        position
+    }
+
+    /// Converts a bar/beat/tick position into an absolute beat count.
+    pub fn position_to_beats(&self, position: MusicalPosition) -> f64 {
+        self.bar_start_beat(position.bar)
+            + position.beat as f64
+            + position.tick as f64 / crate::sync::clock::TICKS_PER_BEAT as f64
+    }
+
+    /// Gets the time elapsed from beat zero to a beat position.
+    ///
+    /// # Arguments
+    /// * `beat` - Absolute beat position.
+    ///
+    /// # Returns
+    /// * `f64` - Elapsed time in seconds.
+    pub fn seconds_at_beat(&self, beat: f64) -> f64 {
+        let beat = beat.max(0.0);
+        let index = self.tempo_index(beat);
+        self.event_seconds[index] + self.segment_seconds(index, beat)
+    }
+
+    /// Gets the beat position reached after a given time.
+    ///
+    /// # Arguments
+    /// * `seconds` - Elapsed time since beat zero.
+    ///
+    /// # Returns
+    /// * `f64` - The absolute beat position.
+    pub fn beat_at_seconds(&self, seconds: f64) -> f64 {
+        let seconds = seconds.max(0.0);
+        let index = self
+            .event_seconds
+            .iter()
+            .rposition(|&s| s <= seconds)
+            .unwrap_or(0);
+        let start = self.tempo_events[index].beat;
+        let remaining = seconds - self.event_seconds[index];
+
+        let event = &self.tempo_events[index];
+        let is_last = index + 1 == self.tempo_events.len();
+        if is_last || event.ramp == TempoRamp::Step {
+            return start + remaining * event.bpm / 60.0;
+        }
+
+        // Seconds grow monotonically with beats, so bisection always converges
+        let (mut low, mut high) = (start, self.tempo_events[index + 1].beat);
+        for _ in 0..BISECTION_STEPS {
+            let mid = (low + high) / 2.0;
+            if self.segment_seconds(index, mid) < remaining {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        (low + high) / 2.0
+    }
+
+    /// Converts a beat position into a sample offset from beat zero.
+    pub fn sample_at_beat(&self, beat: f64, sample_rate: u32) -> u64 {
+        (self.seconds_at_beat(beat) * sample_rate as f64).round() as u64
+    }
+
+    /// Converts a sample offset from beat zero into a beat position.
+    pub fn beat_at_sample(&self, sample: u64, sample_rate: u32) -> f64 {
+        self.beat_at_seconds(sample as f64 / sample_rate as f64)
+    }
+
+    /// Index of the tempo event governing a beat position.
+    fn tempo_index(&self, beat: f64) -> usize {
+        self.tempo_events
+            .iter()
+            .rposition(|e| e.beat <= beat)
+            .unwrap_or(0)
+    }
+
+    /// Index of the meter event governing a beat position.
+    fn meter_index_at_beat(&self, beat: f64) -> usize {
+        self.meter_beats
+            .iter()
+            .rposition(|&b| b <= beat)
+            .unwrap_or(0)
+    }
+
+    /// Tempo inside the segment starting at `index`.
+    fn segment_tempo(&self, index: usize, beat: f64) -> f64 {
+        let event = &self.tempo_events[index];
+        let Some(next) = self.tempo_events.get(index + 1) else {
+            return event.bpm;
+        };
+        let progress = ((beat - event.beat) / (next.beat - event.beat)).clamp(0.0, 1.0);
+        let shaped = match event.ramp {
+            TempoRamp::Step => 0.0,
+            TempoRamp::Linear => progress,
+            TempoRamp::Curved(curve) => progress.powf(curve as f64),
+        };
+        event.bpm + (next.bpm - event.bpm) * shaped
+    }
+
+    /// Seconds from the start of the segment at `index` to `beat` within it.
+    fn segment_seconds(&self, index: usize, beat: f64) -> f64 {
+        let event = &self.tempo_events[index];
+        let span = beat - event.beat;
+        if span <= 0.0 {
+            return 0.0;
+        }
+        let next = match self.tempo_events.get(index + 1) {
+            Some(next) if event.ramp != TempoRamp::Step => next,
+            _ => return span * 60.0 / event.bpm,
+        };
+
+        match event.ramp {
+            TempoRamp::Linear if (next.bpm - event.bpm).abs() > 1e-9 => {
+                // Closed form of the integral of 60 / bpm(b) over a linear ramp
+                let slope = (next.bpm - event.bpm) / (next.beat - event.beat);
+                60.0 / slope * (self.segment_tempo(index, beat) / event.bpm).ln()
+            }
+            _ => {
+                // Simpson's rule; the integrand is smooth over the segment
+                let h = span / CURVE_STEPS as f64;
+                let f = |b: f64| 60.0 / self.segment_tempo(index, b);
+                let mut sum = f(event.beat) + f(beat);
+                for step in 1..CURVE_STEPS {
+                    let weight = if step % 2 == 1 { 4.0 } else { 2.0 };
+                    sum += weight * f(event.beat + step as f64 * h);
+                }
+                sum * h / 3.0
+            }
+        }
+    }
+
+    /// Recomputes cached event times after an edit.
+    fn rebuild(&mut self) {
+        self.event_seconds = Vec::with_capacity(self.tempo_events.len());
+        let mut elapsed = 0.0;
+        for index in 0..self.tempo_events.len() {
+            if index > 0 {
+                elapsed += self.segment_seconds(index - 1, self.tempo_events[index].beat);
+            }
+            self.event_seconds.push(elapsed);
+        }
+
+        self.meter_beats = Vec::with_capacity(self.meter_events.len());
+        let mut beat = 0.0;
+        for index in 0..self.meter_events.len() {
+            if index > 0 {
+                let previous = &self.meter_events[index - 1];
+                let bars = self.meter_events[index].bar - previous.bar;
+                beat += bars as f64 * previous.time_signature.numerator as f64;
+            }
+            self.meter_beats.push(beat);
+        }
+    }
+}
+
+impl Default for TempoMap {
+    fn default() -> Self {
+        Self::new(120.0, TimeSignature::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_constant_tempo() {
+        let map = TempoMap::new(120.0, TimeSignature::default());
+        assert!((map.seconds_at_beat(8.0) - 4.0).abs() < 1e-9);
+        assert!((map.beat_at_seconds(4.0) - 8.0).abs() < 1e-9);
+        assert_eq!(map.sample_at_beat(1.0, 48000), 24000);
+    }
+
+    #[test]
+    fn test_linear_ramp() {
+        let mut map = TempoMap::new(60.0, TimeSignature::default());
+        map.add_tempo(0.0, 60.0, TempoRamp::Linear).unwrap();
+        map.add_tempo(4.0, 120.0, TempoRamp::Step).unwrap();
+
+        assert!((map.tempo_at_beat(2.0) - 90.0).abs() < 1e-9);
+        // Integral of 60 / (60 + 15b) from 0 to 4 = 4 ln 2
+        let expected = 4.0 * 2.0f64.ln();
+        assert!((map.seconds_at_beat(4.0) - expected).abs() < 1e-9);
+        assert!((map.beat_at_seconds(expected) - 4.0).abs() < 1e-6);
+        assert!((map.seconds_at_beat(6.0) - (expected + 1.0)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_curved_ramp_matches_linear_when_curve_is_one() {
+        let mut linear = TempoMap::new(80.0, TimeSignature::default());
+        linear.add_tempo(0.0, 80.0, TempoRamp::Linear).unwrap();
+        linear.add_tempo(16.0, 140.0, TempoRamp::Step).unwrap();
+        let mut curved = linear.clone();
+        curved.add_tempo(0.0, 80.0, TempoRamp::Curved(1.0)).unwrap();
+
+        assert!((linear.seconds_at_beat(16.0) - curved.seconds_at_beat(16.0)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_meter_changes() {
+        let mut map = TempoMap::new(120.0, TimeSignature::default());
+        map.add_meter(2, TimeSignature::new(7, 8).unwrap());
+
+        assert_eq!(map.bar_start_beat(2), 8.0);
+        assert_eq!(map.bar_start_beat(3), 15.0);
+        assert_eq!(map.time_signature_at_beat(9.0).numerator, 7);
+        assert_eq!(map.musical_position(16.0), MusicalPosition { bar: 3, beat: 1, tick: 0 });
+    }
+}
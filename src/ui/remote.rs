@@ -0,0 +1,147 @@
+﻿//! Remote control over UDP
+//!
+//! Each datagram carries one text command. Commands bound to a control
+//! action are queued for the engine like MIDI and keyboard input; queries
+//! ending in `?` are answered from the engine telemetry with a datagram back
+//! to the sender. Unknown commands are answered with `error`.
+//!
+//! | Command  | Reply                                                            |
+//! |----------|------------------------------------------------------------------|
+//! | `tap`    | none; registers a tap for tap tempo                              |
+//! | `tuner`  | none; switches the tuner on or off                               |
+//! | `bpm?`   | `bpm <tempo>`                                                    |
+//! | `tuner?` | `tuner off`, `tuner --` with no pitch, or `tuner A4 +1.5 440.38` |
+
+use std::{
+    io::ErrorKind,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+use crate::{
+    core::{control::ControlQueue, engine::ControlAction, telemetry::Telemetry},
+    error::types::AudioError,
+};
+
+/// Default port the remote control listens on.
+pub const REMOTE_PORT: u16 = 9000;
+
+/// How often the worker checks whether it should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Longest command accepted, in bytes.
+const MAX_COMMAND: usize = 256;
+
+/// A UDP remote control listening for commands.
+///
+/// Dropping it stops the listener.
+pub struct RemoteControl {
+    local_addr: SocketAddr,
+    running: Arc<AtomicBool>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl RemoteControl {
+    /// Starts listening for commands.
+    ///
+    /// # Arguments
+    /// * `addr` - Address to listen on, e.g. `("0.0.0.0", REMOTE_PORT)`.
+    /// * `controls` - The engine's control queue.
+    /// * `telemetry` - The engine's telemetry, used to answer queries.
+    ///
+    /// # Returns
+    /// * `Result<RemoteControl, AudioError>` - The running listener, or an error if the socket cannot be opened.
+    pub fn start(
+        addr: impl ToSocketAddrs,
+        controls: Arc<ControlQueue>,
+        telemetry: Arc<Telemetry>,
+    ) -> Result<Self, AudioError> {
+        let error = |e: std::io::Error| AudioError::RemoteError(e.to_string());
+        let socket = UdpSocket::bind(addr).map_err(error)?;
+        socket.set_read_timeout(Some(POLL_INTERVAL)).map_err(error)?;
+        let local_addr = socket.local_addr().map_err(error)?;
+
+        let running = Arc::new(AtomicBool::new(true));
+        let worker_running = running.clone();
+        let worker = std::thread::spawn(move || run(socket, worker_running, controls, telemetry));
+        Ok(Self {
+            local_addr,
+            running,
+            worker: Some(worker),
+        })
+    }
+
+    /// Gets the address the listener is bound to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for RemoteControl {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Release);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Listener loop: applies commands and answers queries until stopped.
+fn run(socket: UdpSocket, running: Arc<AtomicBool>, controls: Arc<ControlQueue>, telemetry: Arc<Telemetry>) {
+    let mut buffer = [0u8; MAX_COMMAND];
+    while running.load(Ordering::Acquire) {
+        let (len, sender) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            Err(e) => {
+                tracing::warn!("Remote control receive failed: {}", e);
+                continue;
+            }
+        };
+        let command = String::from_utf8_lossy(&buffer[..len]);
+        if let Some(reply) = handle_command(command.trim(), &controls, &telemetry) {
+            if let Err(e) = socket.send_to(reply.as_bytes(), sender) {
+                tracing::warn!("Remote control reply failed: {}", e);
+            }
+        }
+    }
+}
+
+/// Applies one command, returning the reply to send, if any.
+fn handle_command(command: &str, controls: &ControlQueue, telemetry: &Telemetry) -> Option<String> {
+    if let Some(query) = command.strip_suffix('?') {
+        let snapshot = telemetry.snapshot();
+        return Some(match query {
+            "bpm" => format!("bpm {:.2}", snapshot.bpm),
+            "tuner" => match (snapshot.tuner_enabled, snapshot.tuner) {
+                (false, _) => "tuner off".to_string(),
+                (true, None) => "tuner --".to_string(),
+                (true, Some(reading)) => format!(
+                    "tuner {}{} {:+.1} {:.2}",
+                    reading.note, reading.octave, reading.cents, reading.frequency
+                ),
+            },
+            _ => format!("error unknown query {}", query),
+        });
+    }
+    match command_action(command) {
+        Some(action) => {
+            controls.press(action);
+            None
+        }
+        None => Some(format!("error unknown command {}", command)),
+    }
+}
+
+/// Maps a command to the control action it fires.
+fn command_action(command: &str) -> Option<ControlAction> {
+    match command {
+        "tap" => Some(ControlAction::TapTempo),
+        "tuner" => Some(ControlAction::ToggleTuner),
+        _ => None,
+    }
+}
@@ -0,0 +1,117 @@
+﻿//! TUI event loop
+
+use std::{
+    io,
+    sync::Arc,
+    time::Duration,
+};
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEventKind},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use tui::{
+    backend::{Backend, CrosstermBackend},
+    layout::{Constraint, Direction, Layout},
+    widgets::{Block, Borders, Paragraph},
+    Terminal,
+};
+use crate::{
+    core::{
+        control::ControlQueue,
+        engine::ControlAction,
+        telemetry::{Telemetry, TelemetrySnapshot},
+    },
+    ui::tui::display::{render_meters, render_tuner},
+};
+
+/// Interval between redraws when no key is pressed.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Runs the TUI until `q` is pressed.
+///
+/// Keys fire control actions through the engine's control queue and the
+/// display is redrawn from the engine telemetry.
+///
+/// # Arguments
+/// * `controls` - The engine's control queue.
+/// * `telemetry` - The engine's telemetry.
+///
+/// # Returns
+/// * `io::Result<()>` - An error if the terminal cannot be driven.
+pub fn run_tui(controls: Arc<ControlQueue>, telemetry: Arc<Telemetry>) -> io::Result<()> {
+    enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    execute!(stdout, EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+
+    let result = event_loop(&mut terminal, &controls, &telemetry);
+
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+    result
+}
+
+fn event_loop<B: Backend>(
+    terminal: &mut Terminal<B>,
+    controls: &ControlQueue,
+    telemetry: &Telemetry,
+) -> io::Result<()> {
+    loop {
+        let snapshot = telemetry.snapshot();
+        terminal.draw(|f| {
+            let tuner_height = if snapshot.tuner_enabled { 4 } else { 0 };
+            let rows = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([Constraint::Length(3), Constraint::Length(tuner_height), Constraint::Min(0)])
+                .split(f.size());
+            f.render_widget(transport(&snapshot), rows[0]);
+            if snapshot.tuner_enabled {
+                render_tuner(f, rows[1], snapshot.tuner.as_ref());
+            }
+            render_meters(f, rows[2], &snapshot);
+        })?;
+
+        if !event::poll(REFRESH_INTERVAL)? {
+            continue;
+        }
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        if key.code == KeyCode::Char('q') {
+            return Ok(());
+        }
+        if let Some(action) = key_action(key.code) {
+            controls.press(action);
+        }
+    }
+}
+
+/// Maps a key to the control action it fires.
+fn key_action(code: KeyCode) -> Option<ControlAction> {
+    match code {
+        KeyCode::Char('t') => Some(ControlAction::TapTempo),
+        KeyCode::Char('c') => Some(ControlAction::ResetMeterClips),
+        KeyCode::Char('u') => Some(ControlAction::ToggleTuner),
+        KeyCode::Esc => Some(ControlAction::Panic),
+        KeyCode::Char('o') => Some(ControlAction::ResumeOutput),
+        _ => None,
+    }
+}
+
+/// Builds the transport line: tempo, position, whether the transport runs and whether a panic muted the outputs.
+fn transport(snapshot: &TelemetrySnapshot) -> Paragraph<'static> {
+    let beat = snapshot.beat.max(0.0);
+    Paragraph::new(format!(
+        "{:6.2} BPM   beat {:8.2}   {}{}   [t] tap  [c] clear clips  [u] tuner  [esc] panic  [o] resume  [q] quit",
+        snapshot.bpm,
+        beat,
+        if snapshot.transport_running { "playing" } else { "stopped" },
+        if snapshot.panicked { "   MUTED" } else { "" },
+    ))
+    .block(Block::default().title("Transport").borders(Borders::ALL))
+}
@@ -1 +1,97 @@
 ﻿//! TUI display implementation
+
+use crate::{
+    audio::analysis::{meter::MeterReading, tuner::TunerReading},
+    core::telemetry::TelemetrySnapshot,
+};
+use tui::{
+    backend::Backend,
+    layout::{Constraint, Direction, Layout, Rect},
+    style::{Color, Modifier, Style},
+    text::{Span, Spans},
+    widgets::{Block, Borders, Gauge, Paragraph},
+    Frame,
+};
+
+/// Lowest level shown on the meter bars, in dBFS.
+const METER_FLOOR_DB: f32 = -60.0;
+
+/// Renders one horizontal level bar per input, track and the master bus.
+pub fn render_meters<B: Backend>(f: &mut Frame<B>, area: Rect, snapshot: &TelemetrySnapshot) {
+    let block = Block::default().title("Levels").borders(Borders::ALL);
+    let inner = block.inner(area);
+    f.render_widget(block, area);
+
+    let mut meters = vec![("In".to_string(), snapshot.input, 0.0)];
+    meters.extend(snapshot.tracks.iter().enumerate().map(|(i, reading)| {
+        let reduction = snapshot.gain_reduction.get(i).copied().unwrap_or(0.0);
+        (format!("T{}", i + 1), *reading, reduction)
+    }));
+    meters.push(("Master".to_string(), snapshot.master, snapshot.master_gain_reduction));
+
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(meters.iter().map(|_| Constraint::Length(1)).collect::<Vec<_>>())
+        .split(inner);
+    for ((name, reading, reduction), row) in meters.iter().zip(rows.iter()) {
+        f.render_widget(meter_gauge(name, reading, *reduction), *row);
+    }
+}
+
+/// Builds a gauge showing the falling peak, with numbers for hold, RMS, loudness and gain reduction.
+fn meter_gauge<'a>(name: &str, reading: &MeterReading, gain_reduction: f32) -> Gauge<'a> {
+    let ratio = ((reading.peak - METER_FLOOR_DB) / -METER_FLOOR_DB).clamp(0.0, 1.0);
+    let color = if reading.clipped {
+        Color::Red
+    } else if reading.peak_hold > -6.0 {
+        Color::Yellow
+    } else {
+        Color::Green
+    };
+    let label = format!(
+        "{:<6} pk {:>6.1}  tp {:>6.1}  rms {:>6.1}  M {:>6.1}  S {:>6.1}  I {:>6.1}{}{}",
+        name,
+        reading.peak_hold,
+        reading.max_true_peak,
+        reading.rms,
+        reading.momentary,
+        reading.short_term,
+        reading.integrated,
+        if gain_reduction >= 0.1 { format!("  GR -{:.1}", gain_reduction) } else { String::new() },
+        if reading.clipped { "  CLIP" } else { "" },
+    );
+    Gauge::default()
+        .gauge_style(Style::default().fg(color))
+        .ratio(ratio as f64)
+        .label(label)
+}
+
+/// Cents within which a note counts as in tune.
+const IN_TUNE_CENTS: f32 = 3.0;
+
+/// Renders the tuner readout: note name, a needle from -50 to +50 cents and the frequency.
+pub fn render_tuner<B: Backend>(f: &mut Frame<B>, area: Rect, reading: Option<&TunerReading>) {
+    let block = Block::default().title("Tuner").borders(Borders::ALL);
+    let Some(reading) = reading else {
+        f.render_widget(Paragraph::new("--").block(block), area);
+        return;
+    };
+
+    let color = if reading.cents.abs() <= IN_TUNE_CENTS { Color::Green } else { Color::Red };
+    let width = area.width.saturating_sub(2).max(1) as usize;
+    let needle = (((reading.cents + 50.0) / 100.0).clamp(0.0, 1.0) * (width - 1) as f32).round() as usize;
+    let scale: String = (0..width)
+        .map(|i| if i == needle { '|' } else if i == width / 2 { '+' } else { '-' })
+        .collect();
+    let lines = vec![
+        Spans::from(vec![
+            Span::styled(
+                format!("{}{}", reading.note, reading.octave),
+                Style::default().fg(color).add_modifier(Modifier::BOLD),
+            ),
+            Span::raw(format!("  {:+5.1} cents  {:7.2} Hz", reading.cents, reading.frequency)),
+        ]),
+        Spans::from(Span::styled(scale, Style::default().fg(color))),
+    ];
+    f.render_widget(Paragraph::new(lines).block(block), area);
+}
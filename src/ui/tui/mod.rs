@@ -1,2 +1,3 @@
 ﻿//! Terminal user interface (optional)
 pub mod display;
+pub mod app;
\ No newline at end of file
@@ -1,10 +1,57 @@
 ﻿//! Audio processing tests
 
 // tests/audio_tests.rs
+mod common;
+
+use common::{corpus, generate_test_signal, render, Pattern};
+use loop_station::audio::analysis::bpm::BpmDetector;
+
+fn assert_corpus(detector: &BpmDetector, tempos: &[f32]) {
+    let sample_rate = 44100;
+    for signal in corpus(tempos, sample_rate) {
+        let estimate = detector.analyze(&signal.samples).unwrap();
+        assert!(
+            (estimate.bpm - signal.bpm).abs() < 1.0,
+            "{:?} at {} BPM detected as {}",
+            signal.pattern,
+            signal.bpm,
+            estimate.bpm
+        );
+
+        let beat = 60.0 * sample_rate as f32 / signal.bpm;
+        let offset = (estimate.beat_offset as f32 - signal.first_beat as f32).rem_euclid(beat);
+        let phase_error = offset.min(beat - offset) / beat;
+        assert!(phase_error < 0.1, "{:?} at {} BPM phase off by {}", signal.pattern, signal.bpm, phase_error);
+    }
+}
+
 #[test]
 fn test_bpm_detection() {
     let detector = BpmDetector::new(44100);
     let test_signal = generate_test_signal(120.0, 44100);
     let bpm = detector.detect(&test_signal).unwrap();
     assert!((bpm - 120.0).abs() < 1.0);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_corpus_tempo_and_phase() {
+    assert_corpus(&BpmDetector::new(44100), &[84.0, 96.0, 105.0, 120.0, 128.0, 140.0, 152.0]);
+}
+
+#[test]
+fn test_range_resolves_metrical_level() {
+    let mut detector = BpmDetector::new(44100);
+    detector.set_range(50.0, 100.0);
+    assert_corpus(&detector, &[64.0, 72.0]);
+    detector.set_range(130.0, 200.0);
+    assert_corpus(&detector, &[174.0, 185.0]);
+}
+
+#[test]
+fn test_confidence() {
+    let detector = BpmDetector::new(44100);
+    let backbeat = detector.analyze(&generate_test_signal(120.0, 44100)).unwrap();
+    let noise = render(Pattern::Clicks, 120.0, 44100, 10.0, 44100 * 20);
+    let noise = detector.analyze(&noise).unwrap();
+    assert!(backbeat.confidence > noise.confidence);
+}
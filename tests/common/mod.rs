@@ -0,0 +1,188 @@
+﻿//! Synthetic signals with known tempo for testing tempo detection
+
+#![allow(dead_code)]
+
+use std::f32::consts::PI;
+
+/// Rhythmic patterns available in the corpus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pattern {
+    /// A click on every beat
+    Clicks,
+    /// Kick on 1 and 3, snare on 2 and 4, closed hi-hat on eighths
+    Backbeat,
+    /// Four-on-the-floor kick with off-beat open hi-hats
+    FourOnTheFloor,
+    /// Syncopated kick with a snare backbeat and sixteenth hi-hats
+    Syncopated,
+    /// Plucked bass notes on beats and the "and" of 2 and 4
+    Bassline,
+}
+
+impl Pattern {
+    /// Every pattern in the corpus.
+    pub const ALL: [Pattern; 5] = [
+        Pattern::Clicks,
+        Pattern::Backbeat,
+        Pattern::FourOnTheFloor,
+        Pattern::Syncopated,
+        Pattern::Bassline,
+    ];
+}
+
+/// A signal from the corpus together with its ground truth.
+#[derive(Debug, Clone)]
+pub struct TestSignal {
+    /// Pattern the signal was built from
+    pub pattern: Pattern,
+    /// Tempo of the signal
+    pub bpm: f32,
+    /// Sample position of the first beat
+    pub first_beat: usize,
+    /// Mono audio
+    pub samples: Vec<f32>,
+}
+
+/// Generates ten seconds of a backbeat drum pattern.
+///
+/// # Arguments
+/// * `bpm` - Tempo of the pattern.
+/// * `sample_rate` - The sample rate of the signal.
+///
+/// # Returns
+/// * `Vec<f32>` - Mono audio with the first beat at sample zero.
+pub fn generate_test_signal(bpm: f32, sample_rate: u32) -> Vec<f32> {
+    render(Pattern::Backbeat, bpm, sample_rate, 10.0, 0)
+}
+
+/// Generates every pattern of the corpus at each of the given tempos.
+///
+/// Each signal starts with a short pickup of silence so the first beat is
+/// not at sample zero, and has a low level of noise mixed in.
+///
+/// # Arguments
+/// * `tempos` - Tempos to render.
+/// * `sample_rate` - The sample rate of the signals.
+///
+/// # Returns
+/// * `Vec<TestSignal>` - One signal per pattern and tempo.
+pub fn corpus(tempos: &[f32], sample_rate: u32) -> Vec<TestSignal> {
+    let mut signals = Vec::new();
+    for &bpm in tempos {
+        for (i, &pattern) in Pattern::ALL.iter().enumerate() {
+            let first_beat = (sample_rate as usize / 20) * (i + 1);
+            signals.push(TestSignal {
+                pattern,
+                bpm,
+                first_beat,
+                samples: render(pattern, bpm, sample_rate, 12.0, first_beat),
+            });
+        }
+    }
+    signals
+}
+
+/// Renders a pattern for `seconds`, starting at `first_beat`.
+pub fn render(pattern: Pattern, bpm: f32, sample_rate: u32, seconds: f32, first_beat: usize) -> Vec<f32> {
+    let len = (seconds * sample_rate as f32) as usize;
+    let mut out = vec![0.0; len];
+    let beat = 60.0 * sample_rate as f32 / bpm;
+    let mut noise = Noise::new(bpm.to_bits() ^ pattern as u32);
+
+    // Events in sixteenths per bar of four beats
+    let (kicks, snares, hats, accents): (&[u32], &[u32], &[u32], &[u32]) = match pattern {
+        Pattern::Clicks => (&[], &[], &[], &[0, 4, 8, 12]),
+        Pattern::Backbeat => (&[0, 8], &[4, 12], &[0, 2, 4, 6, 8, 10, 12, 14], &[]),
+        Pattern::FourOnTheFloor => (&[0, 4, 8, 12], &[], &[2, 6, 10, 14], &[]),
+        Pattern::Syncopated => (
+            &[0, 8, 10],
+            &[4, 12],
+            &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+            &[],
+        ),
+        Pattern::Bassline => (&[], &[], &[], &[0, 4, 6, 8, 12, 14]),
+    };
+
+    let sixteenth = beat / 4.0;
+    let mut step = 0u32;
+    loop {
+        let start = first_beat + (step as f32 * sixteenth).round() as usize;
+        if start >= len {
+            break;
+        }
+        let position = step % 16;
+        if kicks.contains(&position) {
+            add_kick(&mut out, start, sample_rate);
+        }
+        if snares.contains(&position) {
+            add_noise_burst(&mut out, start, sample_rate, 0.12, 0.5, &mut noise);
+        }
+        if hats.contains(&position) {
+            add_noise_burst(&mut out, start, sample_rate, 0.03, 0.15, &mut noise);
+        }
+        if accents.contains(&position) {
+            match pattern {
+                Pattern::Bassline => add_pluck(&mut out, start, sample_rate, 55.0 * (1 + position % 3) as f32),
+                _ => add_click(&mut out, start, sample_rate),
+            }
+        }
+        step += 1;
+    }
+
+    for sample in out.iter_mut() {
+        *sample += noise.next() * 0.01;
+    }
+    out
+}
+
+fn add_kick(out: &mut [f32], start: usize, sample_rate: u32) {
+    let len = (0.25 * sample_rate as f32) as usize;
+    let mut phase = 0.0f32;
+    for i in 0..len.min(out.len() - start) {
+        let t = i as f32 / sample_rate as f32;
+        let frequency = 50.0 + 100.0 * (-t * 30.0).exp();
+        phase += 2.0 * PI * frequency / sample_rate as f32;
+        out[start + i] += phase.sin() * (-t * 12.0).exp() * 0.9;
+    }
+}
+
+fn add_noise_burst(out: &mut [f32], start: usize, sample_rate: u32, seconds: f32, level: f32, noise: &mut Noise) {
+    let len = (seconds * sample_rate as f32) as usize;
+    for i in 0..len.min(out.len() - start) {
+        let t = i as f32 / len as f32;
+        out[start + i] += noise.next() * level * (1.0 - t).powi(3);
+    }
+}
+
+fn add_click(out: &mut [f32], start: usize, sample_rate: u32) {
+    let len = (0.01 * sample_rate as f32) as usize;
+    for i in 0..len.min(out.len() - start) {
+        let t = i as f32 / sample_rate as f32;
+        out[start + i] += (2.0 * PI * 1000.0 * t).sin() * (1.0 - i as f32 / len as f32);
+    }
+}
+
+fn add_pluck(out: &mut [f32], start: usize, sample_rate: u32, frequency: f32) {
+    let len = (0.3 * sample_rate as f32) as usize;
+    for i in 0..len.min(out.len() - start) {
+        let t = i as f32 / sample_rate as f32;
+        let tone = (2.0 * PI * frequency * t).sin() + 0.5 * (4.0 * PI * frequency * t).sin();
+        out[start + i] += tone * (-t * 10.0).exp() * 0.6;
+    }
+}
+
+/// Deterministic white noise so the corpus is reproducible.
+struct Noise(u32);
+
+impl Noise {
+    fn new(seed: u32) -> Self {
+        Self(seed | 1)
+    }
+
+    fn next(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0 as f32 / u32::MAX as f32 * 2.0 - 1.0
+    }
+}
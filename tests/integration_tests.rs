@@ -1 +1,1009 @@
 ﻿//! Integration tests
+
+#[cfg(all(target_os = "linux", feature = "midi"))]
+mod midi_clock {
+    use loop_station::{
+        midi::clock::{ClockGenerator, FollowerSettings, MidiClockInput, MidiClockOutput},
+        sync::clock::MasterClock,
+    };
+    use std::{sync::Arc, thread, time::Duration};
+
+    #[test]
+    #[ignore = "requires the ALSA sequencer (snd-seq)"]
+    fn test_clock_over_virtual_port() {
+        let sample_rate = 48000;
+        let block_size = 480;
+        let master = MasterClock::new(sample_rate, 90.0);
+        let slave = Arc::new(MasterClock::new(sample_rate, 120.0));
+
+        let input = MidiClockInput::create_virtual(
+            "loop_station_test_in",
+            slave.clone(),
+            FollowerSettings::default(),
+        )
+        .unwrap();
+        let ports = midir::MidiOutput::new("loop_station_test").unwrap();
+        let port_index = ports
+            .ports()
+            .iter()
+            .position(|p| ports.port_name(p).unwrap_or_default().contains("loop_station_test_in"))
+            .expect("virtual input port not found");
+        let output = MidiClockOutput::connect(port_index).unwrap();
+
+        let mut generator = ClockGenerator::new();
+        generator.start();
+        let mut events = Vec::new();
+        // Three seconds of 10 ms blocks
+        for _ in 0..300 {
+            events.clear();
+            generator.process(&master, block_size, &mut events);
+            output.schedule(&events, sample_rate);
+            master.advance(block_size);
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert!(input.is_running());
+        assert!((input.tempo().unwrap() - 90.0).abs() < 1.0);
+        assert!((slave.bpm() - 90.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn test_engine_follows_external_start_stop() {
+        use loop_station::core::engine::AudioEngine;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let mut engine = AudioEngine::new(48000, 1).unwrap();
+        let running = Arc::new(AtomicBool::new(false));
+        engine.midi_transport = Some(running.clone());
+        let mut out = vec![0.0; 480];
+
+        engine.process(&[], &mut [&mut out]).unwrap();
+        assert!(!engine.transport_running);
+        assert_eq!(engine.clock.beat_position(), 0.0);
+
+        running.store(true, Ordering::Release);
+        engine.process(&[], &mut [&mut out]).unwrap();
+        assert!(engine.transport_running);
+        assert!(engine.clock.beat_position() > 0.0);
+    }
+    #[test]
+    fn test_engine_sends_24_pulses_per_beat() {
+        use loop_station::{core::engine::AudioEngine, midi::clock::ClockMessage};
+
+        let mut engine = AudioEngine::new(48000, 1).unwrap();
+        engine.midi_clock = Some(ClockGenerator::new());
+        let mut out = vec![0.0; 480];
+
+        // Four beats at 120 BPM
+        let mut pulses = 0;
+        let mut started = false;
+        for _ in 0..200 {
+            engine.process(&[], &mut [&mut out]).unwrap();
+            for &(_, message) in engine.midi_clock_events() {
+                match message {
+                    ClockMessage::Start => started = true,
+                    ClockMessage::TimingClock => pulses += 1,
+                    _ => {}
+                }
+            }
+        }
+        assert!(started);
+        assert_eq!(pulses, 4 * 24);
+    }
+}
+
+#[cfg(feature = "jack_backend")]
+mod jack_transport {
+    use loop_station::{
+        audio::io::jack::{JackAudio, TransportMode},
+        core::engine::AudioEngine,
+    };
+    use std::{thread, time::Duration};
+
+    #[test]
+    #[ignore = "requires a running JACK server, e.g. `jackd -d dummy`"]
+    fn test_timebase_master_and_follower() {
+        let master_engine = AudioEngine::new(48000, 4).unwrap();
+        master_engine.clock.set_bpm(97.0);
+        let master_clock = master_engine.clock.clone();
+        let mut master = JackAudio::new(master_engine, "loop_station_master", 0, 0).unwrap();
+        master.set_transport_mode(TransportMode::TimebaseMaster).unwrap();
+
+        let follower_engine = AudioEngine::new(48000, 4).unwrap();
+        let follower_clock = follower_engine.clock.clone();
+        let mut follower = JackAudio::new(follower_engine, "loop_station_follower", 0, 0).unwrap();
+        follower.set_transport_mode(TransportMode::Follow).unwrap();
+
+        master.locate_transport(0).unwrap();
+        master.start_transport().unwrap();
+        thread::sleep(Duration::from_millis(500));
+        assert!(follower.transport_rolling().unwrap());
+        assert!((follower_clock.bpm() - 97.0).abs() < 1e-3);
+        assert!((follower_clock.beat_position() - master_clock.beat_position()).abs() < 0.25);
+
+        master.stop_transport().unwrap();
+        thread::sleep(Duration::from_millis(200));
+        let stopped_at = follower_clock.beat_position();
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(follower_clock.beat_position(), stopped_at);
+
+        master.shutdown().unwrap();
+        follower.shutdown().unwrap();
+    }
+}
+
+#[cfg(feature = "link")]
+mod link_session {
+    use loop_station::sync::{
+        clock::MasterClock,
+        link::{LinkSettings, LinkSync},
+    };
+    use std::{net::Ipv4Addr, thread, time::Duration};
+
+    #[test]
+    #[ignore = "requires multicast on the loopback interface"]
+    fn test_two_peers_on_loopback() {
+        let settings = LinkSettings {
+            interface: Ipv4Addr::LOCALHOST,
+            port: 20909,
+            start_stop_sync: true,
+            ..Default::default()
+        };
+        let first = LinkSync::enable(settings, 128.0).unwrap();
+        thread::sleep(Duration::from_millis(600));
+        let second = LinkSync::enable(settings, 90.0).unwrap();
+        thread::sleep(Duration::from_secs(2));
+
+        assert_eq!(first.num_peers(), 1);
+        assert_eq!(second.num_peers(), 1);
+        assert!((second.tempo() - 128.0).abs() < 1e-6);
+        assert!((first.beat_now() - second.beat_now()).abs() < 0.05);
+
+        let clock = MasterClock::new(48000, 90.0);
+        second.sync_clock(&clock);
+        assert!((clock.bpm() - 128.0).abs() < 1e-3);
+        let phase_error = (second.beat_now() - clock.beat_position()).rem_euclid(4.0);
+        assert!(phase_error.min(4.0 - phase_error) < 0.05);
+
+        first.set_tempo(100.0);
+        first.set_playing(true);
+        thread::sleep(Duration::from_millis(500));
+        assert!((second.tempo() - 100.0).abs() < 1e-6);
+        assert_eq!(second.sync_clock(&clock), Some(true));
+        assert!((clock.bpm() - 100.0).abs() < 1e-3);
+    }
+}
+
+mod controls {
+    use loop_station::{
+        core::engine::{AudioEngine, ControlAction},
+        ui::remote::RemoteControl,
+    };
+    use std::{net::UdpSocket, thread, time::Duration};
+
+    #[cfg(feature = "midi")]
+    #[test]
+    fn test_midi_taps_set_the_tempo() {
+        use loop_station::midi::mapping::{MidiMapping, MidiTrigger};
+
+        let mut engine = AudioEngine::new(48000, 1).unwrap();
+        let mut mapping = MidiMapping::new();
+        mapping.bind(MidiTrigger::Note { channel: 0, note: 60 }, ControlAction::TapTempo);
+
+        // Four taps 300 ms apart on channel 1, middle C; note-offs and unbound notes do nothing
+        for tap in 0..4 {
+            if tap > 0 {
+                thread::sleep(Duration::from_millis(300));
+            }
+            assert!(mapping.dispatch(&[0x90, 60, 100], &engine.controls));
+            assert!(!mapping.dispatch(&[0x80, 60, 0], &engine.controls));
+            assert!(!mapping.dispatch(&[0x90, 61, 100], &engine.controls));
+        }
+        let mut out = vec![0.0; 256];
+        engine.process(&[], &mut [&mut out]).unwrap();
+        assert!((engine.clock.bpm() - 200.0).abs() < 5.0, "{}", engine.clock.bpm());
+    }
+
+    #[cfg(feature = "midi")]
+    #[test]
+    fn test_midi_holds_beat_repeat_and_slicer() {
+        use loop_station::midi::mapping::{MidiMapping, MidiTrigger};
+
+        let mut engine = AudioEngine::new(48000, 1).unwrap();
+        let mut mapping = MidiMapping::new();
+        mapping.bind(MidiTrigger::Note { channel: 9, note: 36 }, ControlAction::BeatRepeat);
+        mapping.bind(MidiTrigger::ControlChange { channel: 0, controller: 80 }, ControlAction::Slicer);
+        let mut out = vec![0.0; 256];
+
+        // Pad down on channel 10 and a footswitch CC pressed
+        assert!(mapping.dispatch(&[0x99, 36, 127], &engine.controls));
+        assert!(mapping.dispatch(&[0xB0, 80, 127], &engine.controls));
+        engine.process(&[], &mut [&mut out]).unwrap();
+        assert!(engine.beat_repeat.is_engaged());
+        assert!(engine.slicer.is_engaged());
+
+        // A note-on with zero velocity releases like a note-off; the CC releases below 64
+        assert!(mapping.dispatch(&[0x99, 36, 0], &engine.controls));
+        assert!(mapping.dispatch(&[0xB0, 80, 10], &engine.controls));
+        engine.process(&[], &mut [&mut out]).unwrap();
+        assert!(!engine.beat_repeat.is_engaged());
+        assert!(!engine.slicer.is_engaged());
+    }
+
+    #[cfg(feature = "midi")]
+    #[test]
+    fn test_midi_controller_sweep_presses_once() {
+        use loop_station::midi::mapping::{MidiMapping, MidiTrigger};
+
+        let engine = AudioEngine::new(48000, 1).unwrap();
+        let mut mapping = MidiMapping::new();
+        mapping.bind(MidiTrigger::ControlChange { channel: 0, controller: 11 }, ControlAction::ToggleTuner);
+        mapping.bind(MidiTrigger::ControlChange { channel: 0, controller: 64 }, ControlAction::Slicer);
+
+        // An expression pedal swept up presses once, on crossing 64
+        let pressed: Vec<bool> = [64, 80, 100, 127]
+            .iter()
+            .map(|&value| mapping.dispatch(&[0xB0, 11, value], &engine.controls))
+            .collect();
+        assert_eq!(pressed, [true, false, false, false]);
+        assert!(!mapping.dispatch(&[0xB0, 11, 20], &engine.controls));
+        assert!(mapping.dispatch(&[0xB0, 11, 90], &engine.controls));
+
+        // A momentary action releases once on crossing back below 64
+        assert!(mapping.dispatch(&[0xB0, 64, 127], &engine.controls));
+        assert!(mapping.dispatch(&[0xB0, 64, 40], &engine.controls));
+        assert!(!mapping.dispatch(&[0xB0, 64, 0], &engine.controls));
+
+        let mut events = Vec::new();
+        while let Some(event) = engine.controls.pop() {
+            events.push((event.action, event.pressed));
+        }
+        assert_eq!(
+            events,
+            [
+                (ControlAction::ToggleTuner, true),
+                (ControlAction::ToggleTuner, true),
+                (ControlAction::Slicer, true),
+                (ControlAction::Slicer, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_remote_commands_and_queries() {
+        let mut engine = AudioEngine::new(48000, 1).unwrap();
+        let remote = RemoteControl::start("127.0.0.1:0", engine.controls.clone(), engine.telemetry.clone()).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let mut reply = [0u8; 256];
+        let mut query = |command: &str| {
+            client.send_to(command.as_bytes(), remote.local_addr()).unwrap();
+            let len = client.recv(&mut reply).unwrap();
+            String::from_utf8_lossy(&reply[..len]).into_owned()
+        };
+
+        let mut out = vec![0.0; 256];
+        engine.process(&[], &mut [&mut out]).unwrap();
+        assert_eq!(query("bpm?"), "bpm 120.00");
+        assert!(query("jump").starts_with("error"));
+
+        // Commands are applied by the engine at the start of a block
+        client.send_to(b"tap", remote.local_addr()).unwrap();
+        let event = (0..200)
+            .find_map(|_| {
+                thread::sleep(Duration::from_millis(5));
+                engine.controls.pop()
+            })
+            .expect("tap not queued");
+        assert_eq!(event.action, ControlAction::TapTempo);
+        assert!(event.pressed);
+    }
+
+    #[test]
+    fn test_remote_tuner_toggle_and_readout() {
+        let sample_rate = 48000;
+        let mut engine = AudioEngine::new(sample_rate, 1).unwrap();
+        let remote = RemoteControl::start("127.0.0.1:0", engine.controls.clone(), engine.telemetry.clone()).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let mut reply = [0u8; 256];
+        let mut query = |command: &str| {
+            client.send_to(command.as_bytes(), remote.local_addr()).unwrap();
+            let len = client.recv(&mut reply).unwrap();
+            String::from_utf8_lossy(&reply[..len]).into_owned()
+        };
+
+        let mut out = vec![0.0; 480];
+        engine.process(&[], &mut [&mut out]).unwrap();
+        assert_eq!(query("tuner?"), "tuner off");
+
+        client.send_to(b"tuner", remote.local_addr()).unwrap();
+        while !engine.tuner.is_enabled() {
+            thread::sleep(Duration::from_millis(5));
+            engine.process(&[], &mut [&mut out]).unwrap();
+        }
+
+        // Half a second of A4 on the first input
+        let sine: Vec<f32> = (0..sample_rate as usize / 2)
+            .map(|i| 0.5 * (std::f32::consts::TAU * 440.0 * i as f32 / sample_rate as f32).sin())
+            .collect();
+        for block in sine.chunks(480) {
+            engine.process(&[block], &mut [&mut out]).unwrap();
+        }
+        let readout = query("tuner?");
+        assert!(readout.starts_with("tuner A4 "), "{}", readout);
+    }
+}
+
+mod auto_trim {
+    use loop_station::core::track::{AutoTrim, Track};
+
+    #[test]
+    fn test_stop_recording_trims_and_marks_onsets() {
+        let sample_rate = 44100;
+        let mut track = Track::new(0, "trim".into(), sample_rate, 1);
+        track.set_auto_trim(AutoTrim {
+            trim_silence: true,
+            snap_to_zero_crossings: true,
+            ..Default::default()
+        });
+
+        // Half a second of silence, then decaying 220 Hz notes every 0.25 s
+        let mut recording = vec![0.0f32; 22050];
+        for note in 0..4 {
+            recording.extend((0..11025).map(|i| {
+                let t = i as f32 / sample_rate as f32;
+                (2.0 * std::f32::consts::PI * 220.0 * t + 0.3).sin() * (-t * 8.0).exp() * (note as f32 + 1.0) / 4.0
+            }));
+        }
+        track.start_recording().unwrap();
+        for block in recording.chunks(512) {
+            track.process_input(block);
+        }
+        track.stop_recording().unwrap();
+
+        let trimmed = recording.len() - track.loop_length().unwrap();
+        assert!(trimmed > 21000 && trimmed <= 22050, "trimmed {} samples", trimmed);
+        assert_eq!(track.samples()[0].len(), track.loop_length().unwrap());
+        assert!(track.samples()[0][0].abs() < 0.05);
+
+        let onsets: Vec<usize> = track.onsets().iter().map(|o| o.position + trimmed).collect();
+        assert_eq!(onsets.len(), 4, "{:?}", onsets);
+        for (onset, expected) in onsets.iter().zip((0..4).map(|n| 22050 + n * 11025)) {
+            assert!(onset.abs_diff(expected) < 128, "{:?}", onsets);
+        }
+    }
+
+    #[test]
+    fn test_trim_threshold_applies_to_the_channel_average() {
+        let sample_rate = 44100;
+        let mut track = Track::new(0, "stereo".into(), sample_rate, 2);
+        track.set_auto_trim(AutoTrim {
+            trim_silence: true,
+            ..Default::default()
+        });
+
+        // Hiss just under the threshold on both channels must still count as silence
+        let mut recording = vec![0.002f32; 22050];
+        recording.extend(vec![0.5f32; 22050]);
+        track.start_recording().unwrap();
+        track.process_input(&recording);
+        track.stop_recording().unwrap();
+
+        let trimmed = recording.len() - track.loop_length().unwrap();
+        assert!(trimmed > 21000 && trimmed <= 22050, "trimmed {} samples", trimmed);
+    }
+}
+
+mod tempo_follow {
+    use loop_station::{
+        core::{
+            engine::AudioEngine,
+            track::{TempoFollow, Track},
+        },
+        sync::{clock::TimeSignature, tempo_map::TempoMap},
+    };
+
+    #[test]
+    fn test_tracks_recorded_under_a_tempo_map_follow_tempo_changes() {
+        let sample_rate = 48000;
+        let mut engine = AudioEngine::new(sample_rate, 2).unwrap();
+        engine.tempo_map = Some(TempoMap::new(120.0, TimeSignature::default()));
+        let mut track = Track::new(0, "clicks".into(), sample_rate, 1);
+        track.set_tempo_follow(TempoFollow::Repitch);
+        engine.add_track(track);
+
+        // One beat at 120 BPM with a click on the downbeat
+        let mut recording = vec![0.0f32; 24000];
+        recording[..8].fill(0.9);
+        engine.tracks[0].start_recording().unwrap();
+        let mut out = vec![0.0f32; 480];
+        for block in recording.chunks(480) {
+            engine.process(&[block], &mut [&mut out]).unwrap();
+        }
+        assert_eq!(engine.stop_recording(0).unwrap(), None);
+        assert_eq!(engine.tracks[0].recorded_tempo(), Some(120.0));
+        let len = engine.tracks[0].loop_length().unwrap();
+
+        // Doubling the tempo plays the loop twice as fast
+        engine.tempo_map = Some(TempoMap::new(240.0, TimeSignature::default()));
+        let silence = vec![0.0f32; 480];
+        let mut rendered = Vec::new();
+        for _ in 0..200 {
+            engine.process(&[&silence], &mut [&mut out]).unwrap();
+            rendered.extend_from_slice(&out);
+        }
+        let clicks: Vec<usize> = (1..rendered.len())
+            .filter(|&i| rendered[i] > 0.5 && rendered[i - 1] <= 0.5)
+            .collect();
+        assert!(clicks.len() > 4, "{:?}", clicks);
+        for pair in clicks.windows(2) {
+            assert!((pair[1] - pair[0]).abs_diff(len / 2) <= 1, "{:?} for a loop of {}", clicks, len);
+        }
+    }
+}
+
+mod metering {
+    use loop_station::{
+        audio::effects::{Compressor, CompressorSettings, Sidechain},
+        core::{
+            engine::{AudioEngine, ControlAction},
+            track::Track,
+        },
+    };
+
+    #[test]
+    fn test_engine_publishes_track_and_master_levels() {
+        let sample_rate = 48000;
+        let mut engine = AudioEngine::new(sample_rate, 4).unwrap();
+        let mut track = Track::new(0, "sine".into(), sample_rate, 1);
+        let loop_audio: Vec<f32> = (0..sample_rate as usize)
+            .map(|i| 0.5 * (2.0 * std::f32::consts::PI * 1000.0 * i as f32 / sample_rate as f32).sin())
+            .collect();
+        track.start_recording().unwrap();
+        track.process_input(&loop_audio);
+        track.stop_recording().unwrap();
+        engine.add_track(track);
+
+        let silence = vec![0.0f32; 480];
+        let (mut left, mut right) = (vec![0.0f32; 480], vec![0.0f32; 480]);
+        for _ in 0..200 {
+            engine.process(&[&silence], &mut [&mut left, &mut right]).unwrap();
+        }
+
+        let snapshot = engine.telemetry.snapshot();
+        assert_eq!(snapshot.tracks.len(), 1);
+        let track = snapshot.tracks[0];
+        assert!((track.peak_hold + 6.02).abs() < 0.1, "{:?}", track);
+        assert!((track.rms + 9.03).abs() < 0.2, "{:?}", track);
+        // Loudness sums both master channels: -9.03 dB per channel + 3.01 dB
+        assert!((snapshot.master.momentary + 6.02).abs() < 0.2, "{:?}", snapshot.master);
+        assert!(!snapshot.master.clipped);
+        assert!(snapshot.input.peak <= -120.0);
+    }
+
+    #[test]
+    fn test_input_sidechain_ducks_track() {
+        let sample_rate = 48000;
+        let mut engine = AudioEngine::new(sample_rate, 4).unwrap();
+        let mut track = Track::new(0, "pad".into(), sample_rate, 1);
+        track.start_recording().unwrap();
+        track.process_input(&vec![0.25; sample_rate as usize]);
+        track.stop_recording().unwrap();
+        engine.add_track(track);
+
+        let mut compressor = Compressor::new(sample_rate);
+        compressor.set_settings(CompressorSettings {
+            threshold: -30.0,
+            ratio: 10.0,
+            sidechain: Sidechain::Input(1),
+            ..Default::default()
+        });
+        engine.set_track_compressor(0, Some(compressor)).unwrap();
+
+        let silence = vec![0.0f32; 480];
+        let kick = vec![0.8f32; 480];
+        let mut out = vec![0.0f32; 480];
+        for _ in 0..20 {
+            engine.process(&[&silence, &silence], &mut [&mut out]).unwrap();
+        }
+        assert!((out[479] - 0.25).abs() < 1e-3);
+        assert_eq!(engine.telemetry.snapshot().gain_reduction, [0.0]);
+
+        for _ in 0..20 {
+            engine.process(&[&silence, &kick], &mut [&mut out]).unwrap();
+        }
+        let reduction = engine.telemetry.snapshot().gain_reduction[0];
+        assert!(reduction > 20.0, "{}", reduction);
+        assert!(out[479] < 0.02);
+    }
+
+    #[test]
+    fn test_track_sidechain_keys_from_dry_signal() {
+        let sample_rate = 48000;
+        let mut engine = AudioEngine::new(sample_rate, 4).unwrap();
+        for (id, level) in [(0, 0.5f32), (1, 0.25)] {
+            let mut track = Track::new(id, "pad".into(), sample_rate, 1);
+            track.start_recording().unwrap();
+            track.process_input(&vec![level; sample_rate as usize]);
+            track.stop_recording().unwrap();
+            engine.add_track(track);
+        }
+
+        // Track 0 squashes itself far below the -10 dB key threshold of track 1
+        let mut squash = Compressor::new(sample_rate);
+        squash.set_settings(CompressorSettings {
+            threshold: -40.0,
+            ratio: 20.0,
+            ..Default::default()
+        });
+        let mut duck = Compressor::new(sample_rate);
+        duck.set_settings(CompressorSettings {
+            threshold: -10.0,
+            ratio: 10.0,
+            sidechain: Sidechain::Track(0),
+            ..Default::default()
+        });
+        engine.set_track_compressor(0, Some(squash)).unwrap();
+        engine.set_track_compressor(1, Some(duck)).unwrap();
+
+        let silence = vec![0.0f32; 480];
+        let mut out = vec![0.0f32; 480];
+        for _ in 0..20 {
+            engine.process(&[&silence], &mut [&mut out]).unwrap();
+        }
+        let reduction = engine.telemetry.snapshot().gain_reduction;
+        assert!(reduction[0] > 20.0, "{:?}", reduction);
+        assert!(reduction[1] > 2.0, "{:?}", reduction);
+    }
+
+    #[test]
+    fn test_removing_a_track_remaps_sidechains_keyed_from_it() {
+        let sample_rate = 48000;
+        let mut engine = AudioEngine::new(sample_rate, 4).unwrap();
+        for id in 0..4 {
+            engine.add_track(Track::new(id, "pad".into(), sample_rate, 1));
+        }
+        let keyed = |source| {
+            let mut compressor = Compressor::new(sample_rate);
+            compressor.set_settings(CompressorSettings {
+                sidechain: Sidechain::Track(source),
+                ..Default::default()
+            });
+            compressor
+        };
+        assert!(engine.set_track_compressor(1, Some(keyed(1))).is_err());
+        assert!(engine.set_track_compressor(1, Some(keyed(4))).is_err());
+        engine.set_track_compressor(0, Some(keyed(1))).unwrap();
+        engine.set_track_compressor(3, Some(keyed(2))).unwrap();
+
+        engine.remove_track(1).unwrap();
+        let sidechain = |engine: &mut AudioEngine, index| engine.track_compressor_mut(index).unwrap().settings().sidechain;
+        assert_eq!(sidechain(&mut engine, 0), Sidechain::Internal);
+        assert_eq!(sidechain(&mut engine, 2), Sidechain::Track(1));
+
+        let silence = vec![0.0f32; 480];
+        let mut out = vec![0.0f32; 480];
+        engine.process(&[&silence], &mut [&mut out]).unwrap();
+    }
+
+    #[test]
+    fn test_compressor_lookahead_delays_every_track() {
+        let sample_rate = 48000;
+        let click_position = |lookahead: Option<f32>| {
+            let mut engine = AudioEngine::new(sample_rate, 4).unwrap();
+            let mut click = vec![0.0f32; sample_rate as usize];
+            click[0] = 1.0;
+            for (id, audio) in [(0, click), (1, vec![0.0f32; sample_rate as usize])] {
+                let mut track = Track::new(id, "click".into(), sample_rate, 1);
+                track.start_recording().unwrap();
+                track.process_input(&audio);
+                track.stop_recording().unwrap();
+                engine.add_track(track);
+            }
+            assert!(engine.set_track_compressor(2, None).is_err());
+            if let Some(lookahead) = lookahead {
+                let mut compressor = Compressor::new(sample_rate);
+                compressor.set_settings(CompressorSettings {
+                    lookahead,
+                    ..Default::default()
+                });
+                engine.set_track_compressor(1, Some(compressor)).unwrap();
+            }
+
+            let silence = vec![0.0f32; 2048];
+            let mut out = vec![0.0f32; 2048];
+            engine.process(&[&silence], &mut [&mut out]).unwrap();
+            out.iter().position(|&s| s > 0.5).unwrap()
+        };
+
+        // 5 ms of lookahead on the silent track holds back the click on the other one
+        assert_eq!(click_position(Some(0.005)), click_position(None) + 240);
+    }
+    #[test]
+    fn test_master_limiter_holds_the_ceiling_and_panic_fades_out() {
+        let sample_rate = 48000;
+        let mut engine = AudioEngine::new(sample_rate, 2).unwrap();
+        let loop_audio: Vec<f32> = (0..sample_rate as usize)
+            .map(|i| 0.8 * (2.0 * std::f32::consts::PI * 440.0 * i as f32 / sample_rate as f32).sin())
+            .collect();
+        for id in 0..2 {
+            let mut track = Track::new(id, "lead".into(), sample_rate, 1);
+            track.start_recording().unwrap();
+            track.process_input(&loop_audio);
+            track.stop_recording().unwrap();
+            engine.add_track(track);
+        }
+
+        // Two tracks in phase sum to 1.6, well over the -1 dBTP default ceiling
+        let ceiling = 10f32.powf(-1.0 / 20.0);
+        let silence = vec![0.0f32; 480];
+        let (mut left, mut right) = (vec![0.0f32; 480], vec![0.0f32; 480]);
+        let mut loudest = 0.0f32;
+        for _ in 0..50 {
+            engine.process(&[&silence], &mut [&mut left, &mut right]).unwrap();
+            loudest = left.iter().chain(&right).fold(loudest, |max, s| max.max(s.abs()));
+        }
+        assert!(loudest <= ceiling && loudest > ceiling * 0.95, "{}", loudest);
+        let snapshot = engine.telemetry.snapshot();
+        assert!((snapshot.master_gain_reduction - 20.0 * (1.6 / ceiling).log10()).abs() < 0.5);
+        assert!(!snapshot.master.clipped);
+
+        // A panic fades to silence within 50 ms and holds it until resumed
+        engine.handle_action(ControlAction::Panic);
+        for _ in 0..6 {
+            engine.process(&[&silence], &mut [&mut left, &mut right]).unwrap();
+        }
+        assert!(left.iter().chain(&right).all(|&s| s == 0.0));
+        assert!(engine.telemetry.snapshot().panicked);
+        engine.handle_action(ControlAction::ResumeOutput);
+        for _ in 0..6 {
+            engine.process(&[&silence], &mut [&mut left, &mut right]).unwrap();
+        }
+        assert!(left.iter().any(|s| s.abs() > 0.5));
+    }
+
+    #[test]
+    fn test_outputs_beyond_the_master_bus_carry_the_dry_mix() {
+        let sample_rate = 48000;
+        let mut engine = AudioEngine::new(sample_rate, 1).unwrap();
+        let mut track = Track::new(0, "loud".into(), sample_rate, 1);
+        track.start_recording().unwrap();
+        track.process_input(&vec![1.5; sample_rate as usize]);
+        track.stop_recording().unwrap();
+        engine.add_track(track);
+
+        // The limiter holds the master bus down but leaves the direct output alone
+        let silence = vec![0.0f32; 480];
+        let (mut left, mut right, mut direct) = (vec![0.0f32; 480], vec![0.0f32; 480], vec![0.0f32; 480]);
+        for _ in 0..10 {
+            engine.process(&[&silence], &mut [&mut left, &mut right, &mut direct]).unwrap();
+        }
+        assert!(left.iter().all(|s| s.abs() < 1.0));
+        assert!(direct.iter().all(|&s| s == 1.5));
+
+        // A panic still silences it
+        engine.handle_action(ControlAction::Panic);
+        for _ in 0..6 {
+            engine.process(&[&silence], &mut [&mut left, &mut right, &mut direct]).unwrap();
+        }
+        assert!(direct.iter().all(|&s| s == 0.0));
+    }
+}
+
+mod harmony {
+    use loop_station::{
+        core::{engine::AudioEngine, track::Track},
+        state::project::Project,
+    };
+
+    #[test]
+    fn test_track_key_and_chords_survive_project_round_trip() {
+        let sample_rate = 44100;
+        let bar_length = sample_rate as usize * 2;
+        // C, Am, F, G as triads with the root an octave below
+        let progression: [&[i32]; 4] = [&[48, 60, 64, 67], &[45, 57, 60, 64], &[41, 53, 57, 60], &[43, 55, 59, 62]];
+        let mut recording = Vec::new();
+        for notes in progression {
+            recording.extend((0..bar_length).map(|i| {
+                let t = i as f32 / sample_rate as f32;
+                notes
+                    .iter()
+                    .map(|&note| {
+                        let frequency = 440.0 * 2f32.powf((note - 69) as f32 / 12.0);
+                        0.1 * (2.0 * std::f32::consts::PI * frequency * t).sin()
+                    })
+                    .sum::<f32>()
+            }));
+        }
+
+        let mut track = Track::new(3, "keys".into(), sample_rate, 1);
+        track.start_recording().unwrap();
+        track.process_input(&recording);
+        track.stop_recording().unwrap();
+        track.analyze_harmony(bar_length).unwrap();
+
+        let metadata = track.metadata();
+        assert_eq!(metadata.key.unwrap().to_string(), "C major");
+        let chords: Vec<String> = metadata.chords.iter().map(|c| c.unwrap().to_string()).collect();
+        assert_eq!(chords, ["C", "Am", "F", "G"]);
+
+        let mut engine = AudioEngine::new(sample_rate, 4).unwrap();
+        engine.add_track(track);
+        let json = Project::from_engine("song", &engine).to_json().unwrap();
+
+        let mut restored = AudioEngine::new(sample_rate, 4).unwrap();
+        restored.add_track(Track::new(3, "untitled".into(), sample_rate, 1));
+        Project::from_json(&json).unwrap().apply_to(&mut restored);
+        let metadata = restored.tracks[0].metadata();
+        assert_eq!(metadata.name, "keys");
+        assert_eq!(metadata.key, engine.tracks[0].metadata().key);
+        assert_eq!(metadata.chords, engine.tracks[0].metadata().chords);
+    }
+}
+
+mod effects {
+    use loop_station::{
+        audio::{
+            analysis::pitch::PitchDetector,
+            effects::{AudioEffect, NoiseGate, NoiseReduction, PitchShiftSettings, PitchShifter, Reverb},
+        },
+        core::{
+            engine::{AudioEngine, ControlAction},
+            track::Track,
+        },
+        error::types::AudioError,
+    };
+
+    #[test]
+    fn test_apply_effects_prints_pitch_shift_into_loop() {
+        let sample_rate = 48000;
+        let loop_audio: Vec<f32> = (0..sample_rate as usize)
+            .map(|i| 0.5 * (2.0 * std::f32::consts::PI * 200.0 * i as f32 / sample_rate as f32).sin())
+            .collect();
+        let mut track = Track::new(0, "bass".into(), sample_rate, 1);
+        track.start_recording().unwrap();
+        track.process_input(&loop_audio);
+        track.stop_recording().unwrap();
+
+        let mut shifter = PitchShifter::new(sample_rate).unwrap();
+        shifter.set_settings(PitchShiftSettings { semitones: 12.0, ..Default::default() });
+        track.effects_chain_mut().add(Box::new(shifter));
+        track.apply_effects().unwrap();
+
+        // Latency is compensated, so even the start of the loop is shifted
+        let samples = &track.samples()[0];
+        assert_eq!(samples.len(), loop_audio.len());
+        let mut detector = PitchDetector::new(sample_rate, 4096);
+        for frame in [&samples[..4096], &samples[samples.len() - 4096..]] {
+            let pitch = detector.detect(frame).unwrap().unwrap().frequency;
+            assert!((1200.0 * (pitch / 400.0).log2()).abs() < 10.0, "{} Hz", pitch);
+        }
+
+        track.undo().unwrap();
+        assert_eq!(track.samples()[0], loop_audio);
+    }
+
+    #[test]
+    fn test_apply_effects_renders_every_channel_alike() {
+        let sample_rate = 48000;
+        let mut loop_audio = vec![0.0f32; sample_rate as usize];
+        loop_audio[..480].fill(0.5);
+        let mut track = Track::new(0, "stereo".into(), sample_rate, 2);
+        track.start_recording().unwrap();
+        track.process_input(&loop_audio);
+        track.stop_recording().unwrap();
+
+        // The reverb tail of the left channel must not spill into the right
+        track.effects_chain_mut().add(Box::new(Reverb::new(sample_rate)));
+        track.apply_effects().unwrap();
+        let samples = track.samples();
+        assert_ne!(samples[0], loop_audio);
+        assert_eq!(samples[0], samples[1]);
+    }
+
+    #[test]
+    fn test_apply_effects_wraps_tails_into_the_loop_start() {
+        let sample_rate = 48000;
+        let mut loop_audio = vec![0.0f32; sample_rate as usize];
+        let len = loop_audio.len();
+        loop_audio[len - 480..].fill(0.5);
+        let mut track = Track::new(0, "hit".into(), sample_rate, 1);
+        track.start_recording().unwrap();
+        track.process_input(&loop_audio);
+        track.stop_recording().unwrap();
+
+        // The reverb has no latency, but the tail of the hit at the end
+        // of the loop must still ring on at its start
+        track.effects_chain_mut().add(Box::new(Reverb::new(sample_rate)));
+        track.apply_effects().unwrap();
+        let head = &track.samples()[0][..4800];
+        assert!(head.iter().any(|s| s.abs() > 1e-4));
+    }
+
+    /// Marks the first sample after each loop start.
+    #[derive(Default)]
+    struct LoopMarker {
+        armed: bool,
+    }
+
+    impl AudioEffect for LoopMarker {
+        fn process(&mut self, buffer: &mut [f32]) -> Result<(), AudioError> {
+            if let Some(first) = buffer.first_mut().filter(|_| self.armed) {
+                *first = 1.0;
+                self.armed = false;
+            }
+            Ok(())
+        }
+
+        fn reset(&mut self) {
+            self.armed = false;
+        }
+
+        fn on_loop_start(&mut self) {
+            self.armed = true;
+        }
+    }
+
+    #[test]
+    fn test_inserts_hear_the_loop_start() {
+        let sample_rate = 48000;
+        let mut track = Track::new(0, "pad".into(), sample_rate, 1);
+        track.start_recording().unwrap();
+        track.process_input(&vec![0.1; 1000]);
+        track.stop_recording().unwrap();
+        let len = track.loop_length().unwrap();
+        track.inserts_mut().add(Box::new(LoopMarker::default()));
+
+        // Blocks do not line up with the loop, so the wraps fall mid-block
+        let mut rendered = Vec::new();
+        let mut block = vec![0.0f32; 256];
+        for _ in 0..16 {
+            track.process_output(&mut block).unwrap();
+            rendered.extend_from_slice(&block);
+        }
+        let marks: Vec<usize> = (0..rendered.len()).filter(|&i| rendered[i] == 1.0).collect();
+        let expected: Vec<usize> = (1..).map(|n| n * len).take_while(|&i| i < rendered.len()).collect();
+        assert_eq!(marks, expected);
+    }
+
+    #[test]
+    fn test_held_beat_repeat_loops_the_master_bus() {
+        let sample_rate = 48000;
+        let mut engine = AudioEngine::new(sample_rate, 1).unwrap();
+        let mut track = Track::new(0, "ramp".into(), sample_rate, 1);
+        track.start_recording().unwrap();
+        track.process_input(&(0..sample_rate).map(|i| i as f32 / sample_rate as f32).collect::<Vec<_>>());
+        track.stop_recording().unwrap();
+        engine.add_track(track);
+
+        // At 120 BPM a sixteenth is 6000 samples: held from 3000, the bus
+        // captures 6000..12000 and replays it from 12000
+        let silence = vec![0.0f32; 1000];
+        let mut out = vec![0.0f32; 1000];
+        let mut played = Vec::new();
+        for block in 0..30 {
+            match block {
+                3 => engine.handle_action(ControlAction::BeatRepeat),
+                24 => engine.release_action(ControlAction::BeatRepeat),
+                _ => {}
+            }
+            engine.process(&[&silence], &mut [&mut out]).unwrap();
+            played.extend_from_slice(&out);
+        }
+        assert!(ControlAction::BeatRepeat.is_momentary());
+        for offset in [500, 3000, 5500] {
+            assert!((played[12000 + offset] - played[6000 + offset]).abs() < 1e-6);
+            assert!((played[18000 + offset] - played[6000 + offset]).abs() < 1e-6);
+        }
+        assert!((played[26000] - played[25000] - 1000.0 / 48000.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_input_gate_keeps_hiss_out_of_recordings() {
+        let sample_rate = 48000;
+        let mut engine = AudioEngine::new(sample_rate, 1).unwrap();
+        engine.input_gate = Some(NoiseGate::new(sample_rate));
+        let mut track = Track::new(0, "guitar".into(), sample_rate, 1);
+        track.start_recording().unwrap();
+        engine.add_track(track);
+
+        // Half a second of -60 dB hiss, then a -12 dB note
+        let hiss: Vec<f32> = (0..480).map(|i| if i % 2 == 0 { 0.001 } else { -0.001 }).collect();
+        let note: Vec<f32> = (0..480)
+            .map(|i| 0.25 * (2.0 * std::f32::consts::PI * 1000.0 * i as f32 / sample_rate as f32).sin())
+            .collect();
+        let mut out = vec![0.0f32; 480];
+        for block in 0..100 {
+            let input = if block < 50 { &hiss } else { &note };
+            engine.process(&[input], &mut [&mut out]).unwrap();
+        }
+        engine.stop_recording(0).unwrap();
+
+        let recorded = &engine.tracks[0].samples()[0];
+        assert!(recorded[..24000].iter().all(|s| s.abs() < 1e-6));
+        assert!(recorded[25000..].iter().any(|s| s.abs() > 0.2));
+    }
+
+    /// Record a second of silence, overdub a click 9700 samples into the loop and return where it landed
+    fn overdub_click(noise_reduction: bool) -> usize {
+        let sample_rate = 48000;
+        let mut engine = AudioEngine::new(sample_rate, 1).unwrap();
+        if noise_reduction {
+            engine.input_noise_reduction = Some(NoiseReduction::new(sample_rate).unwrap());
+        }
+        let mut track = Track::new(0, "perc".into(), sample_rate, 1);
+        track.start_recording().unwrap();
+        engine.add_track(track);
+
+        let silence = vec![0.0f32; 480];
+        let mut out = vec![0.0f32; 480];
+        for _ in 0..100 {
+            engine.process(&[&silence], &mut [&mut out]).unwrap();
+        }
+        engine.stop_recording(0).unwrap();
+        assert_eq!(engine.tracks[0].loop_length(), Some(48000));
+
+        engine.tracks[0].start_overdub().unwrap();
+        let mut click = silence.clone();
+        click[100] = 1.0;
+        for block in 0..30 {
+            let input = if block == 20 { &click } else { &silence };
+            engine.process(&[input], &mut [&mut out]).unwrap();
+        }
+        let recorded = &engine.tracks[0].samples()[0];
+        (0..recorded.len())
+            .max_by(|&a, &b| recorded[a].abs().total_cmp(&recorded[b].abs()))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_overdubs_land_on_the_loop_through_noise_reduction() {
+        assert!(NoiseReduction::new(48000).unwrap().latency() > 0);
+        assert_eq!(overdub_click(false), 9700);
+        assert_eq!(overdub_click(true), 9700);
+    }
+}
+
+#[cfg(feature = "file_io")]
+mod impulse_files {
+    use loop_station::audio::effects::{Convolution, ConvolutionMode, ImpulseResponse};
+
+    #[test]
+    fn test_cabinet_ir_loads_from_wav_at_the_engine_rate() {
+        // A 24-bit stereo IR at 44.1 kHz: a click in each channel, the right one 10 ms later
+        let path = std::env::temp_dir().join(format!("loop_station_ir_{}.wav", std::process::id()));
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 44100,
+            bits_per_sample: 24,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for i in 0..4410 {
+            writer.write_sample(if i == 0 { 4_194_304i32 } else { 0 }).unwrap();
+            writer.write_sample(if i == 441 { 4_194_304i32 } else { 0 }).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let impulse = ImpulseResponse::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(impulse.sample_rate(), 44100);
+        assert_eq!(impulse.channels().len(), 2);
+        assert_eq!(impulse.channels()[1][441], 0.5);
+
+        let mut convolution = Convolution::new(48000, &impulse, ConvolutionMode::Stereo).unwrap();
+        let mut left = vec![0.0f32; 4800];
+        let mut right = vec![0.0f32; 4800];
+        left[0] = 1.0;
+        right[0] = 1.0;
+        convolution.process_stereo(&mut left, &mut right).unwrap();
+
+        // The clicks come back 10 ms apart at the new rate, their peaks scaled
+        // by the rate change so the frequency response stays the same
+        let latency = convolution.latency();
+        let peak = 0.5 * 44100.0 / 48000.0;
+        assert!((left[latency] - peak).abs() < 0.01, "{}", left[latency]);
+        assert!((right[latency + 480] - peak).abs() < 0.01, "{}", right[latency + 480]);
+    }
+}